@@ -0,0 +1,90 @@
+//! Checks the data directory of a stopped kvs service, and rebuilds it from the entries that
+//! can still be read when run with `--repair`
+//!
+//! Usage: `kvs-fsck [--repair] [data directory]`
+
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+
+use kvs::configuration::{Configuration, StorageEngine};
+use kvs::encryption::Keyring;
+use kvs::fsck;
+use kvs::page_storage;
+use kvs::tree_node::PageOptions;
+
+const CONFIGURATION_PATH: &str = "config.json";
+const DEFAULT_DATA_PATH: &str = "data";
+const REPAIR_FLAG: &str = "--repair";
+
+fn main() -> ExitCode {
+    env_logger::init();
+
+    let mut repair = false;
+    let mut path = PathBuf::from(DEFAULT_DATA_PATH);
+    for arg in std::env::args().skip(1) {
+        if arg == REPAIR_FLAG {
+            repair = true;
+        } else {
+            path = PathBuf::from(arg);
+        }
+    }
+
+    let configuration =
+        Configuration::read(CONFIGURATION_PATH).expect("Failed to read the configuration");
+    if configuration.storage() == StorageEngine::Memory {
+        println!("The memory storage engine doesn't store pages: nothing to check");
+        return ExitCode::SUCCESS;
+    }
+
+    let options = PageOptions {
+        case_sensitive: configuration.case_sensitive_keys(),
+        compaction_ratio: configuration.compaction_ratio(),
+        compression: configuration.compression(),
+        encryption: configuration.encryption().map(|e| {
+            Arc::new(
+                Keyring::load(&e.key, &e.previous_keys)
+                    .expect("Failed to load the encryption keys"),
+            )
+        }),
+        storage: page_storage::open(configuration.storage(), &path)
+            .expect("Failed to open the storage"),
+        ..PageOptions::default()
+    };
+
+    let report = fsck::check(&path, &options).expect("Failed to check the data directory");
+    for warning in &report.warnings {
+        println!("warning: {warning}");
+    }
+    for problem in &report.problems {
+        println!("error: {problem}");
+    }
+    println!(
+        "{} pages, {} entries: {} errors, {} warnings",
+        report.pages,
+        report.entries(),
+        report.problems.len(),
+        report.warnings.len()
+    );
+
+    if report.is_clean() {
+        return ExitCode::SUCCESS;
+    }
+
+    if !repair {
+        println!("Run with {REPAIR_FLAG} to rebuild the data directory from the readable entries");
+        return ExitCode::FAILURE;
+    }
+
+    let damaged_path = fsck::repair(
+        &path,
+        report,
+        configuration.storage(),
+        configuration.cache_size(),
+        options,
+    )
+    .expect("Failed to repair the data directory");
+    println!("Rebuilt {path:?}. The damaged data directory was moved to {damaged_path:?}");
+
+    ExitCode::SUCCESS
+}
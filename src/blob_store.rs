@@ -0,0 +1,570 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Error, ErrorKind, Read, Seek, SeekFrom, Write},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use log::info;
+
+use crate::encryption::{Cipher, Keyring, OpenResult, FRAME_OVERHEAD, KEY_CHECK_LENGTH};
+
+/// Directory of the data directory holding the values too large to be stored in a page
+pub const BLOB_DIRECTORY: &str = "blobs";
+/// Size of a blob reference once serialized in a page record
+pub const BLOB_REF_LENGTH: usize = size_of::<u64>() * 2 + size_of::<u32>();
+
+const BLOB_EXTENSION: &str = "blob";
+/// Encrypted blobs start with these bytes, the key check and the length of the value. The
+/// value follows in frames of `CHUNK_LENGTH` bytes, bound to the blob id and their position
+const ENCRYPTED_MAGIC: &[u8; 4] = b"KVSB";
+const ENCRYPTED_HEADER_LENGTH: usize = ENCRYPTED_MAGIC.len() + KEY_CHECK_LENGTH + size_of::<u64>();
+const CHUNK_LENGTH: usize = 64 * 1024;
+
+/// Location and checksum of a value stored in a blob file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlobRef {
+    pub id: u64,
+    pub length: u64,
+    pub checksum: u32,
+}
+
+/// Stores large values in their own files, next to the pages. Blobs are encrypted with the
+/// current key when encryption is configured
+pub struct BlobStore {
+    path: PathBuf,
+    next_id: AtomicU64,
+    encryption: Option<Arc<Keyring>>,
+}
+
+/// Writes a blob chunk by chunk. The file is removed if the writer is dropped before
+/// `finish` is called
+pub struct BlobWriter {
+    id: u64,
+    path: PathBuf,
+    writer: Option<BufWriter<File>>,
+    length: u64,
+    hasher: crc32fast::Hasher,
+    cipher: Option<Arc<Cipher>>,
+    /// Data of an encrypted blob not sealed yet. The last chunk is sealed by `finish`
+    pending: Vec<u8>,
+    chunks: u64,
+}
+
+/// Reads a blob, decrypting it if needed. Readers don't share their position, even when
+/// cloned
+pub struct BlobReader {
+    file: File,
+    id: u64,
+    /// Key and length of the value of an encrypted blob
+    encryption: Option<(Arc<Cipher>, u64)>,
+    position: u64,
+    /// Decrypted chunk holding the position, with its index
+    chunk: Option<(u64, Vec<u8>)>,
+}
+
+impl BlobStore {
+    /// Opens the blob directory of a data directory. Ids are allocated after the largest
+    /// existing one
+    pub fn open(
+        base_path: &Path,
+        encryption: Option<Arc<Keyring>>,
+    ) -> Result<BlobStore, std::io::Error> {
+        let path = base_path.join(BLOB_DIRECTORY);
+
+        let mut next_id = 0;
+        for id in Self::list(&path)? {
+            next_id = next_id.max(id + 1);
+        }
+
+        Ok(BlobStore {
+            path,
+            next_id: AtomicU64::new(next_id),
+            encryption,
+        })
+    }
+
+    /// Creates a new blob file
+    pub fn create(&self) -> Result<BlobWriter, std::io::Error> {
+        fs::create_dir_all(&self.path)?;
+
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.create_at(id, blob_path(&self.path, id))
+    }
+
+    fn create_at(&self, id: u64, path: PathBuf) -> Result<BlobWriter, std::io::Error> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+
+        let cipher = self.encryption.as_ref().map(|k| k.current().clone());
+        let mut writer = BufWriter::new(file);
+        if cipher.is_some() {
+            // The header holds the length of the value, so it is written by `finish`
+            writer.write_all(&[0; ENCRYPTED_HEADER_LENGTH])?;
+        }
+
+        Ok(BlobWriter {
+            id,
+            path,
+            writer: Some(writer),
+            length: 0,
+            hasher: crc32fast::Hasher::new(),
+            cipher,
+            pending: vec![],
+            chunks: 0,
+        })
+    }
+
+    /// Writes a value to a new blob file
+    pub fn write(&self, value: &[u8]) -> Result<BlobRef, std::io::Error> {
+        let mut writer = self.create()?;
+        writer.write(value)?;
+
+        writer.finish()
+    }
+
+    /// Opens a blob for reading
+    pub fn open_blob(&self, blob: &BlobRef) -> Result<BlobReader, std::io::Error> {
+        self.open_id(blob.id)
+    }
+
+    fn open_id(&self, id: u64) -> Result<BlobReader, std::io::Error> {
+        let file = File::open(blob_path(&self.path, id))?;
+
+        // Blobs written before a key was configured are stored as they are
+        let mut header = [0; ENCRYPTED_HEADER_LENGTH];
+        let file_length = file.metadata()?.len();
+        let is_encrypted = file.read_exact_at(&mut header, 0).is_ok()
+            && header.starts_with(ENCRYPTED_MAGIC)
+            && Some(file_length)
+                == encrypted_length(u64::from_le_bytes(
+                    header[ENCRYPTED_HEADER_LENGTH - 8..].try_into().unwrap(),
+                ));
+
+        let encryption = if is_encrypted {
+            let key_check =
+                &header[ENCRYPTED_MAGIC.len()..ENCRYPTED_MAGIC.len() + KEY_CHECK_LENGTH];
+            let Some(cipher) = self.encryption.as_ref().and_then(|k| k.find(key_check)) else {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("blob {id:016x} is encrypted with an unknown key"),
+                ));
+            };
+            let length =
+                u64::from_le_bytes(header[ENCRYPTED_HEADER_LENGTH - 8..].try_into().unwrap());
+
+            Some((cipher.clone(), length))
+        } else {
+            None
+        };
+
+        Ok(BlobReader {
+            file,
+            id,
+            encryption,
+            position: 0,
+            chunk: None,
+        })
+    }
+
+    /// Reads a whole blob, checking its length and checksum
+    pub fn read(&self, blob: &BlobRef) -> Result<Option<Vec<u8>>, std::io::Error> {
+        let mut value = Vec::with_capacity(blob.length as usize);
+        match self.open_blob(blob)?.read_to_end(&mut value) {
+            Err(e) if e.kind() == ErrorKind::InvalidData => return Ok(None),
+            result => result?,
+        };
+
+        if value.len() as u64 != blob.length || crc32fast::hash(&value) != blob.checksum {
+            return Ok(None);
+        }
+
+        Ok(Some(value))
+    }
+
+    /// Removes a blob. Blobs that don't exist anymore are ignored
+    pub fn remove(base_path: &Path, blob: &BlobRef) -> Result<(), std::io::Error> {
+        match fs::remove_file(blob_path(&base_path.join(BLOB_DIRECTORY), blob.id)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the id of the next blob. Blobs are created with increasing ids
+    pub fn next_id(&self) -> u64 {
+        self.next_id.load(Ordering::SeqCst)
+    }
+
+    /// Removes the blobs that are not referenced by any page: uploads interrupted by a crash
+    /// and blobs of entries that were overwritten before their page was reloaded. Only the
+    /// blobs whose id is below the given one are removed: the others may belong to uploads in
+    /// progress
+    pub fn remove_unreferenced(
+        &self,
+        referenced: &HashSet<u64>,
+        below_id: u64,
+    ) -> Result<usize, std::io::Error> {
+        let mut removed = 0;
+
+        for id in Self::list(&self.path)? {
+            if id < below_id && !referenced.contains(&id) {
+                info!("Removing unreferenced blob: {id:016x}");
+                fs::remove_file(blob_path(&self.path, id))?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Returns the ids of the stored blobs
+    pub fn ids(&self) -> Result<Vec<u64>, std::io::Error> {
+        Self::list(&self.path)
+    }
+
+    /// Rewrites the blobs that aren't encrypted with the current key, including the blobs
+    /// written before a key was configured. Returns the number of blobs rewritten
+    pub fn rotate_keys(&self) -> Result<usize, std::io::Error> {
+        let Some(current) = self.encryption.as_ref().map(|k| k.current().key_check()) else {
+            return Ok(0);
+        };
+
+        let mut rotated = 0;
+        for id in self.ids()? {
+            let mut reader = self.open_id(id)?;
+            if reader.encryption.as_ref().map(|(c, _)| c.key_check()) == Some(current) {
+                continue;
+            }
+
+            // The blob keeps its id: it is written to a temporary file renamed over the old one
+            let path = blob_path(&self.path, id);
+            let mut temp_path = path.clone().into_os_string();
+            temp_path.push(".tmp");
+            let temp_path = PathBuf::from(temp_path);
+            match fs::remove_file(&temp_path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+
+            let mut writer = self.create_at(id, temp_path.clone())?;
+            let mut buffer = vec![0; CHUNK_LENGTH];
+            loop {
+                match reader.read(&mut buffer)? {
+                    0 => break,
+                    n => writer.write(&buffer[..n])?,
+                }
+            }
+            writer.finish()?;
+
+            fs::rename(&temp_path, &path)?;
+            rotated += 1;
+        }
+
+        if rotated > 0 {
+            File::open(&self.path)?.sync_all()?;
+        }
+
+        Ok(rotated)
+    }
+
+    /// Returns the ids of the blobs in the directory
+    fn list(path: &Path) -> Result<Vec<u64>, std::io::Error> {
+        let entries = match fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+
+        let mut ids = vec![];
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == BLOB_EXTENSION) {
+                if let Some(id) = path
+                    .file_stem()
+                    .and_then(|s| s.to_str())
+                    .and_then(|s| u64::from_str_radix(s, 16).ok())
+                {
+                    ids.push(id);
+                }
+            }
+        }
+
+        Ok(ids)
+    }
+}
+
+impl BlobWriter {
+    pub fn write(&mut self, chunk: &[u8]) -> Result<(), std::io::Error> {
+        self.hasher.update(chunk);
+        self.length += chunk.len() as u64;
+
+        if self.cipher.is_none() {
+            return self.writer.as_mut().unwrap().write_all(chunk);
+        }
+
+        // A full chunk is kept pending until more data follows: `finish` seals the last one
+        self.pending.extend_from_slice(chunk);
+        while self.pending.len() > CHUNK_LENGTH {
+            let rest = self.pending.split_off(CHUNK_LENGTH);
+            let chunk = std::mem::replace(&mut self.pending, rest);
+            self.seal_chunk(&chunk)?;
+        }
+
+        Ok(())
+    }
+
+    fn seal_chunk(&mut self, chunk: &[u8]) -> Result<(), std::io::Error> {
+        let cipher = self.cipher.as_ref().unwrap();
+        let frame = cipher.seal(chunk, &chunk_associated_data(self.id, self.chunks));
+        self.writer.as_mut().unwrap().write_all(&frame)?;
+        self.chunks += 1;
+
+        Ok(())
+    }
+
+    /// Durably writes the blob. The blob must be on disk before a page references it
+    pub fn finish(mut self) -> Result<BlobRef, std::io::Error> {
+        if let Some(cipher) = self.cipher.clone() {
+            let chunk = std::mem::take(&mut self.pending);
+            self.seal_chunk(&chunk)?;
+
+            let mut header = ENCRYPTED_MAGIC.to_vec();
+            header.extend_from_slice(&cipher.key_check());
+            header.extend_from_slice(&self.length.to_le_bytes());
+            let writer = self.writer.as_mut().unwrap();
+            writer.seek(SeekFrom::Start(0))?;
+            writer.write_all(&header)?;
+        }
+
+        let mut writer = self.writer.take().unwrap();
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+
+        Ok(BlobRef {
+            id: self.id,
+            length: self.length,
+            checksum: self.hasher.clone().finalize(),
+        })
+    }
+}
+
+impl Drop for BlobWriter {
+    fn drop(&mut self) {
+        if self.writer.take().is_some() {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+impl BlobReader {
+    /// Returns another reader of the blob, starting at its beginning
+    pub fn try_clone(&self) -> Result<BlobReader, std::io::Error> {
+        Ok(BlobReader {
+            file: self.file.try_clone()?,
+            id: self.id,
+            encryption: self.encryption.clone(),
+            position: 0,
+            chunk: None,
+        })
+    }
+
+    /// Decrypts the chunk of an encrypted blob at the given index
+    fn read_chunk(&self, index: u64) -> Result<Vec<u8>, std::io::Error> {
+        let (cipher, length) = self.encryption.as_ref().unwrap();
+        let chunk_length = (length - index * CHUNK_LENGTH as u64).min(CHUNK_LENGTH as u64);
+        let offset =
+            ENCRYPTED_HEADER_LENGTH as u64 + index * (CHUNK_LENGTH + FRAME_OVERHEAD) as u64;
+
+        let mut frame = vec![0; chunk_length as usize + FRAME_OVERHEAD];
+        self.file.read_exact_at(&mut frame, offset)?;
+
+        match cipher.open(&frame, &chunk_associated_data(self.id, index)) {
+            OpenResult::Opened(chunk, _) if chunk.len() as u64 == chunk_length => Ok(chunk),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("blob {:016x}: authentication failed", self.id),
+            )),
+        }
+    }
+}
+
+impl Read for BlobReader {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, std::io::Error> {
+        let Some((_, length)) = &self.encryption else {
+            let read = self.file.read_at(buffer, self.position)?;
+            self.position += read as u64;
+            return Ok(read);
+        };
+
+        if self.position >= *length || buffer.is_empty() {
+            return Ok(0);
+        }
+
+        let index = self.position / CHUNK_LENGTH as u64;
+        if self.chunk.as_ref().is_none_or(|(i, _)| *i != index) {
+            self.chunk = Some((index, self.read_chunk(index)?));
+        }
+
+        let chunk = &self.chunk.as_ref().unwrap().1;
+        let start = (self.position % CHUNK_LENGTH as u64) as usize;
+        let read = (chunk.len() - start).min(buffer.len());
+        buffer[..read].copy_from_slice(&chunk[start..start + read]);
+        self.position += read as u64;
+
+        Ok(read)
+    }
+}
+
+impl std::fmt::Debug for BlobReader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BlobReader")
+            .field("id", &self.id)
+            .field("encrypted", &self.encryption.is_some())
+            .field("position", &self.position)
+            .finish()
+    }
+}
+
+impl Seek for BlobReader {
+    fn seek(&mut self, position: SeekFrom) -> Result<u64, std::io::Error> {
+        let length = match &self.encryption {
+            Some((_, length)) => *length,
+            None => self.file.metadata()?.len(),
+        };
+        let position = match position {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(p) => length.checked_add_signed(p),
+            SeekFrom::Current(p) => self.position.checked_add_signed(p),
+        };
+
+        self.position = position.ok_or_else(|| {
+            Error::new(ErrorKind::InvalidInput, "seek before the start of the blob")
+        })?;
+
+        Ok(self.position)
+    }
+}
+
+impl BlobRef {
+    pub fn encode(&self) -> [u8; BLOB_REF_LENGTH] {
+        let mut buffer = [0; BLOB_REF_LENGTH];
+        buffer[0..8].copy_from_slice(&self.id.to_le_bytes());
+        buffer[8..16].copy_from_slice(&self.length.to_le_bytes());
+        buffer[16..20].copy_from_slice(&self.checksum.to_le_bytes());
+
+        buffer
+    }
+
+    pub fn decode(buffer: &[u8]) -> Option<BlobRef> {
+        if buffer.len() != BLOB_REF_LENGTH {
+            return None;
+        }
+
+        Some(BlobRef {
+            id: u64::from_le_bytes(buffer[0..8].try_into().unwrap()),
+            length: u64::from_le_bytes(buffer[8..16].try_into().unwrap()),
+            checksum: u32::from_le_bytes(buffer[16..20].try_into().unwrap()),
+        })
+    }
+}
+
+fn blob_path(directory: &Path, id: u64) -> PathBuf {
+    directory.join(format!("{id:016x}.{BLOB_EXTENSION}"))
+}
+
+/// Returns the length of the file of an encrypted blob. Even empty values have a frame
+fn encrypted_length(length: u64) -> Option<u64> {
+    let chunks = length.div_ceil(CHUNK_LENGTH as u64).max(1);
+
+    chunks
+        .checked_mul(FRAME_OVERHEAD as u64)?
+        .checked_add(length)?
+        .checked_add(ENCRYPTED_HEADER_LENGTH as u64)
+}
+
+/// Frames are bound to their blob and their position, so that they can't be moved around
+fn chunk_associated_data(id: u64, index: u64) -> [u8; 16] {
+    let mut associated_data = [0; 16];
+    associated_data[..8].copy_from_slice(&id.to_le_bytes());
+    associated_data[8..].copy_from_slice(&index.to_le_bytes());
+
+    associated_data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::KeySource;
+    use tempfile::tempdir;
+
+    fn keyring(path: &Path, key: u8, previous: &[u8]) -> Arc<Keyring> {
+        let source = |key: u8| {
+            let key_path = path.join(format!("key{key}"));
+            fs::write(&key_path, format!("{key:02x}").repeat(32)).unwrap();
+            KeySource::File(key_path)
+        };
+        let previous: Vec<KeySource> = previous.iter().map(|k| source(*k)).collect();
+
+        Arc::new(Keyring::load(&source(key), &previous).unwrap())
+    }
+
+    #[test]
+    fn test_encrypted_blobs() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path();
+        let value: Vec<u8> = (0..CHUNK_LENGTH * 2 + 100).map(|i| i as u8).collect();
+
+        // A blob written before a key was configured stays readable
+        let plain = BlobStore::open(path, None).unwrap().write(&value).unwrap();
+
+        let store = BlobStore::open(path, Some(keyring(path, 1, &[]))).unwrap();
+        for length in [0, 100, CHUNK_LENGTH, value.len()] {
+            let blob = store.write(&value[..length]).unwrap();
+            let file = fs::read(blob_path(&store.path, blob.id)).unwrap();
+            assert!(!file.windows(64).any(|w| w == &value[..64]));
+            assert_eq!(store.read(&blob).unwrap().unwrap(), &value[..length]);
+        }
+        assert_eq!(store.read(&plain).unwrap().unwrap(), value);
+
+        let blob = store.write(&value).unwrap();
+        let mut reader = store.open_blob(&blob).unwrap();
+        reader
+            .seek(SeekFrom::Start(CHUNK_LENGTH as u64 + 10))
+            .unwrap();
+        let mut buffer = vec![];
+        reader.read_to_end(&mut buffer).unwrap();
+        assert_eq!(buffer, &value[CHUNK_LENGTH + 10..]);
+
+        // Frames can't be moved from a blob to another
+        let other = store.write(&value).unwrap();
+        fs::copy(
+            blob_path(&store.path, other.id),
+            blob_path(&store.path, blob.id),
+        )
+        .unwrap();
+        assert!(store.read(&blob).unwrap().is_none());
+        let error = store
+            .open_blob(&blob)
+            .unwrap()
+            .read_to_end(&mut vec![])
+            .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        BlobStore::remove(path, &blob).unwrap();
+
+        // Rotation rewrites the plain blobs and the ones encrypted with a previous key
+        let store = BlobStore::open(path, Some(keyring(path, 2, &[1]))).unwrap();
+        assert_eq!(store.rotate_keys().unwrap(), store.ids().unwrap().len());
+        let store = BlobStore::open(path, Some(keyring(path, 2, &[]))).unwrap();
+        assert_eq!(store.rotate_keys().unwrap(), 0);
+        assert_eq!(store.read(&plain).unwrap().unwrap(), value);
+        assert_eq!(store.read(&other).unwrap().unwrap(), value);
+    }
+}
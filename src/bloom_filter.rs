@@ -0,0 +1,98 @@
+/// Bits allocated per key: about 1% of false positives with `HASH_COUNT` hashes
+const BITS_PER_KEY: usize = 10;
+const HASH_COUNT: u8 = 7;
+const MIN_BITS: usize = 64;
+
+/// Probabilistic set of the keys of a page: a key that was not inserted is reported as absent
+/// most of the time, a key that was inserted is always reported as present
+#[derive(Debug, PartialEq)]
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    hash_count: u8,
+}
+
+impl BloomFilter {
+    /// Creates an empty filter sized for the given number of keys
+    pub fn new(key_count: usize) -> BloomFilter {
+        let bit_count = (key_count * BITS_PER_KEY).max(MIN_BITS);
+
+        BloomFilter {
+            bits: vec![0; bit_count.div_ceil(8)],
+            hash_count: HASH_COUNT,
+        }
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        for bit in self.bit_positions(key) {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+        }
+    }
+
+    /// Returns false if the key was never inserted
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.bit_positions(key)
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(self.bits.len() + 1);
+        buffer.push(self.hash_count);
+        buffer.extend_from_slice(&self.bits);
+
+        buffer
+    }
+
+    pub fn decode(buffer: &[u8]) -> Option<BloomFilter> {
+        let (hash_count, bits) = buffer.split_first()?;
+        if *hash_count == 0 || bits.is_empty() {
+            return None;
+        }
+
+        Some(BloomFilter {
+            bits: bits.to_vec(),
+            hash_count: *hash_count,
+        })
+    }
+
+    /// Positions of the bits of a key, by double hashing. The hashes must never change, since
+    /// filters are persisted
+    fn bit_positions(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let bit_count = (self.bits.len() * 8) as u64;
+        let h1 = fnv1a(key);
+        let h2 = (crc32fast::hash(key) as u64) | 1;
+
+        (0..self.hash_count as u64)
+            .map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % bit_count) as usize)
+    }
+}
+
+fn fnv1a(key: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in key {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inserted_keys_are_always_found() {
+        let mut filter = BloomFilter::new(10000);
+        for i in 0..10000 {
+            filter.insert(format!("key{i}").as_bytes());
+        }
+
+        let filter = BloomFilter::decode(&filter.encode()).unwrap();
+        assert!((0..10000).all(|i| filter.may_contain(format!("key{i}").as_bytes())));
+
+        let false_positives = (0..10000)
+            .filter(|i| filter.may_contain(format!("missing{i}").as_bytes()))
+            .count();
+        assert!(false_positives < 300, "{false_positives} false positives");
+    }
+}
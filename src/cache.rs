@@ -18,6 +18,7 @@ impl<K: Eq + Hash + Clone + Ord, V> Cache<K, V> {
         }
     }
 
+    #[cfg(test)]
     pub fn get(&mut self, key: &K) -> Option<&mut V> {
         self.map.get_mut(key).map(|(v, _)| v)
     }
@@ -41,6 +42,15 @@ impl<K: Eq + Hash + Clone + Ord, V> Cache<K, V> {
         }
     }
 
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.map.values().map(|(v, _)| v)
+    }
+
+    pub fn clear(&mut self) {
+        self.map.clear();
+        self.fifo.clear();
+    }
+
     pub fn remove(&mut self, key: &K) -> Option<V> {
         if let Some((v, n)) = self.map.remove(key) {
             if let Ok(ix) = self.fifo.binary_search(&(n, key.clone())) {
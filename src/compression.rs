@@ -0,0 +1,106 @@
+use serde::{Deserialize, Serialize};
+
+const BLOCK_HEADER_LENGTH: usize = size_of::<u32>() * 2;
+const CHECKSUM_LENGTH: usize = size_of::<u32>();
+
+/// Codec of the blocks of sorted records of a page. The identifiers are stored in the page
+/// headers, so they must never change
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl Compression {
+    pub fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+            Compression::Zstd => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Compression> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Lz4),
+            2 => Some(Compression::Zstd),
+            _ => None,
+        }
+    }
+
+    /// Compresses a block of records: the compressed length, the uncompressed length, the
+    /// compressed bytes and a checksum of the whole block
+    pub fn encode_block(self, data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        let compressed = match self {
+            Compression::None => data.to_vec(),
+            Compression::Lz4 => lz4_flex::block::compress(data),
+            Compression::Zstd => zstd::bulk::compress(data, zstd::DEFAULT_COMPRESSION_LEVEL)?,
+        };
+
+        let mut buffer = Vec::with_capacity(BLOCK_HEADER_LENGTH + compressed.len() + 4);
+        buffer.extend_from_slice(&(compressed.len() as u32).to_le_bytes());
+        buffer.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        buffer.extend_from_slice(&compressed);
+        buffer.extend_from_slice(&crc32fast::hash(&buffer).to_le_bytes());
+
+        Ok(buffer)
+    }
+
+    /// Decompresses the block at the start of the buffer. Returns its records and the length
+    /// of the block, or `None` if the block is damaged
+    pub fn decode_block(self, buffer: &[u8]) -> Option<(Vec<u8>, usize)> {
+        let read_u32 = |offset: usize| {
+            Some(u32::from_le_bytes(buffer.get(offset..offset + 4)?.try_into().unwrap()) as usize)
+        };
+        let compressed_length = read_u32(0)?;
+        let length = read_u32(4)?;
+        let content_length = BLOCK_HEADER_LENGTH.checked_add(compressed_length)?;
+        let block_length = content_length.checked_add(CHECKSUM_LENGTH)?;
+
+        let content = buffer.get(..content_length)?;
+        if crc32fast::hash(content) != read_u32(content_length)? as u32 {
+            return None;
+        }
+
+        let compressed = &content[BLOCK_HEADER_LENGTH..];
+        let data = match self {
+            Compression::None => compressed.to_vec(),
+            Compression::Lz4 => lz4_flex::block::decompress(compressed, length).ok()?,
+            Compression::Zstd => zstd::bulk::decompress(compressed, length).ok()?,
+        };
+
+        (data.len() == length).then_some((data, block_length))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocks_round_trip() {
+        let data = br#"{"name": "value", "name": "value", "name": "value"}"#.repeat(100);
+
+        for compression in [Compression::None, Compression::Lz4, Compression::Zstd] {
+            let mut block = compression.encode_block(&data).unwrap();
+            if compression != Compression::None {
+                assert!(block.len() < data.len() / 4, "{compression:?}");
+            }
+
+            let block_length = block.len();
+            block.extend_from_slice(b"next block");
+            assert_eq!(
+                compression.decode_block(&block),
+                Some((data.clone(), block_length))
+            );
+
+            block[10] ^= 0xFF;
+            assert_eq!(compression.decode_block(&block), None);
+            assert_eq!(compression.decode_block(&block[..5]), None);
+        }
+    }
+}
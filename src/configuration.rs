@@ -1,8 +1,12 @@
-use std::{fs::File, path::Path};
+use std::{fs::File, num::NonZeroU64, path::Path};
 
 use log::error;
 use serde::{Deserialize, Serialize};
 
+use crate::compression::Compression;
+use crate::encryption::KeySource;
+use crate::tree_node::DEFAULT_COMPACTION_RATIO;
+
 const DEFAULT_PORT: u16 = 3030;
 const DEFAULT_REPLICATION_PORT: u16 = 3040;
 const DEFAULT_CACHE_SIZE_MB: usize = 500;
@@ -16,33 +20,62 @@ pub struct Configuration {
     cache_size: Option<usize>,
     replicas: Option<Vec<String>>,
     is_replica: Option<bool>,
+    compaction_ratio: Option<f64>,
+    case_sensitive_keys: Option<bool>,
+    storage: Option<StorageEngine>,
+    compression: Option<Compression>,
+    encryption: Option<Encryption>,
+    memory_mapped_reads: Option<bool>,
 }
 
-#[derive(Serialize, Deserialize, Copy, Clone, PartialEq)]
-#[serde(rename_all = "lowercase")]
+/// When the written pages are synced. Writes acknowledged since the last sync are lost by a
+/// crash of the host
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
 pub enum FSyncStrategy {
+    /// Pages are written back by the operating system
+    #[default]
     Default,
+    /// Every write is synced before it is acknowledged
     Strict,
+    /// The written pages are synced every N writes
+    EveryOperations(NonZeroU64),
+    /// The written pages are synced every N seconds
+    EverySeconds(NonZeroU64),
 }
 
-impl Configuration {
-    pub fn read(configuration_path: &str) -> Result<Configuration, ()> {
-        let path = Path::new(configuration_path);
-        if !path.exists() {
-            return Err(());
-        }
+#[derive(Serialize, Deserialize, Copy, Clone, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageEngine {
+    /// One file per page
+    Files,
+    /// All the pages packed in a few segment files
+    Segments,
+    /// Entries kept in memory only, lost when the process stops
+    Memory,
+}
 
-        let file = match File::open(path) {
-            Ok(f) => f,
-            Err(_) => return Err(()),
-        };
+/// Encryption of the pages at rest
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Encryption {
+    /// Key of the pages written from now on
+    pub key: KeySource,
+    /// Keys of the pages that haven't been rotated yet, still used to read them
+    #[serde(default)]
+    pub previous_keys: Vec<KeySource>,
+}
+
+impl Configuration {
+    /// Reads the configuration file. Returns `None` if it is missing or invalid
+    pub fn read(configuration_path: &str) -> Option<Configuration> {
+        let file = File::open(Path::new(configuration_path)).ok()?;
 
         match serde_json::from_reader::<_, Configuration>(file) {
-            Ok(r) => Ok(r),
+            Ok(r) => Some(r),
             Err(e) => {
                 error!("{e:#?}");
 
-                Err(())
+                None
             }
         }
     }
@@ -52,7 +85,7 @@ impl Configuration {
     }
 
     pub fn fsync(&self) -> FSyncStrategy {
-        self.fsync.unwrap_or(FSyncStrategy::Default)
+        self.fsync.unwrap_or_default()
     }
 
     pub fn port(&self) -> u16 {
@@ -74,4 +107,28 @@ impl Configuration {
     pub fn is_replica(&self) -> bool {
         self.is_replica.unwrap_or(false)
     }
+
+    pub fn compaction_ratio(&self) -> f64 {
+        self.compaction_ratio.unwrap_or(DEFAULT_COMPACTION_RATIO)
+    }
+
+    pub fn case_sensitive_keys(&self) -> bool {
+        self.case_sensitive_keys.unwrap_or(false)
+    }
+
+    pub fn storage(&self) -> StorageEngine {
+        self.storage.unwrap_or(StorageEngine::Files)
+    }
+
+    pub fn compression(&self) -> Compression {
+        self.compression.unwrap_or_default()
+    }
+
+    pub fn encryption(&self) -> Option<&Encryption> {
+        self.encryption.as_ref()
+    }
+
+    pub fn memory_mapped_reads(&self) -> bool {
+        self.memory_mapped_reads.unwrap_or(false)
+    }
 }
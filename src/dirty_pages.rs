@@ -0,0 +1,228 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{Error, ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde::Serialize;
+
+/// File written by a clean shutdown, once every page has been flushed
+pub const CLEAN_SHUTDOWN_FILE: &str = "clean_shutdown";
+/// Journal of the pages written since the last clean shutdown, one hex-encoded prefix per line
+pub const DIRTY_PAGES_FILE: &str = "dirty_pages";
+
+/// How the store was stopped before it was opened
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Shutdown {
+    Clean,
+    /// The pages written since the last clean shutdown may hold torn writes
+    Crash,
+    /// The store was never stopped cleanly by a build tracking the written pages
+    Unknown,
+}
+
+/// Tracks the pages written since the last clean shutdown, so that only these pages need to be
+/// checked for torn writes after a crash. Pages are only torn by appends and header updates:
+/// rewrites go through a temporary file
+pub struct DirtyPages {
+    base_path: PathBuf,
+    shutdown: Shutdown,
+    /// Pages written before the crash, if the store crashed
+    crashed_pages: Vec<Vec<u8>>,
+    journal: Mutex<Journal>,
+}
+
+struct Journal {
+    /// `None` until the whole store has been checked, when the previous shutdown is unknown
+    file: Option<File>,
+    pages: HashSet<Vec<u8>>,
+}
+
+impl DirtyPages {
+    /// Reads how the store was stopped. From now on the store is considered crashed until
+    /// `close` is called: after a clean shutdown, the journal is emptied and the marker removed
+    pub fn open(base_path: &Path) -> Result<DirtyPages, Error> {
+        let marker_path = base_path.join(CLEAN_SHUTDOWN_FILE);
+        let journal_path = base_path.join(DIRTY_PAGES_FILE);
+
+        let mut dirty_pages = DirtyPages {
+            base_path: base_path.to_path_buf(),
+            shutdown: Shutdown::Unknown,
+            crashed_pages: vec![],
+            journal: Mutex::new(Journal {
+                file: None,
+                pages: HashSet::new(),
+            }),
+        };
+
+        if marker_path.exists() {
+            dirty_pages.shutdown = Shutdown::Clean;
+            dirty_pages.start()?;
+            fs::remove_file(&marker_path)?;
+            File::open(base_path)?.sync_all()?;
+
+            return Ok(dirty_pages);
+        }
+
+        let journal = match fs::read_to_string(&journal_path) {
+            Ok(journal) => journal,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(dirty_pages),
+            Err(e) => return Err(e),
+        };
+
+        // A line torn by the crash belongs to a page that wasn't written yet
+        dirty_pages.shutdown = Shutdown::Crash;
+        for prefix in journal.lines().filter_map(decode_hex) {
+            if !dirty_pages.crashed_pages.contains(&prefix) {
+                dirty_pages.crashed_pages.push(prefix);
+            }
+        }
+
+        // The pages stay in the journal until the next clean shutdown, in case the store
+        // crashes again before they are checked. The journal is written again without the
+        // torn line, which the next entries would be appended to
+        let mut file = File::create(&journal_path)?;
+        for prefix in &dirty_pages.crashed_pages {
+            file.write_all(format!("{}\n", encode_hex(prefix)).as_bytes())?;
+        }
+        file.sync_all()?;
+
+        let mut journal = dirty_pages.journal.lock().unwrap();
+        journal.file = Some(file);
+        journal.pages = dirty_pages.crashed_pages.iter().cloned().collect();
+        drop(journal);
+
+        Ok(dirty_pages)
+    }
+
+    pub fn shutdown(&self) -> Shutdown {
+        self.shutdown
+    }
+
+    /// Returns the pages that must be checked before serving requests, or `None` if they are
+    /// unknown and the whole store must be checked
+    pub fn pages_to_check(&self) -> Option<&[Vec<u8>]> {
+        match self.shutdown {
+            Shutdown::Clean | Shutdown::Crash => Some(&self.crashed_pages),
+            Shutdown::Unknown => None,
+        }
+    }
+
+    /// Starts tracking the written pages, once the whole store has been checked
+    pub fn start(&self) -> Result<(), Error> {
+        let mut journal = self.journal.lock().unwrap();
+        if journal.file.is_none() {
+            let file = File::create(self.base_path.join(DIRTY_PAGES_FILE))?;
+            file.sync_all()?;
+            File::open(&self.base_path)?.sync_all()?;
+
+            journal.file = Some(file);
+        }
+
+        Ok(())
+    }
+
+    /// Records that a page is about to be written. The first write of a page is preceded by a
+    /// durable entry in the journal
+    pub fn mark(&self, prefix: &[u8]) -> Result<(), Error> {
+        let mut journal = self.journal.lock().unwrap();
+        if journal.pages.contains(prefix) {
+            return Ok(());
+        }
+
+        // Until the store has been checked, a crash leads to another check of the whole store
+        let Some(file) = journal.file.as_mut() else {
+            return Ok(());
+        };
+
+        file.write_all(format!("{}\n", encode_hex(prefix)).as_bytes())?;
+        file.sync_data()?;
+        journal.pages.insert(prefix.to_vec());
+
+        Ok(())
+    }
+
+    /// Records a clean shutdown. The pages must have been flushed, and no page can be written
+    /// afterwards
+    pub fn close(&self) -> Result<(), Error> {
+        if self.journal.lock().unwrap().file.is_none() {
+            return Ok(());
+        }
+
+        File::create(self.base_path.join(CLEAN_SHUTDOWN_FILE))?.sync_all()?;
+        File::open(&self.base_path)?.sync_all()
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+
+    (0..text.len())
+        .step_by(2)
+        .map(|ix| u8::from_str_radix(&text[ix..ix + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_written_pages_are_checked_after_a_crash() {
+        let temp_dir = tempdir().unwrap();
+        let base_path = temp_dir.path();
+
+        let dirty_pages = DirtyPages::open(base_path).unwrap();
+        assert_eq!(dirty_pages.shutdown(), Shutdown::Unknown);
+        assert!(dirty_pages.pages_to_check().is_none());
+        dirty_pages.mark(b"ignored").unwrap();
+        dirty_pages.start().unwrap();
+        dirty_pages.mark(b"a/").unwrap();
+        dirty_pages.mark(b"a/").unwrap();
+        dirty_pages.mark(b"").unwrap();
+        drop(dirty_pages);
+
+        // Crash, with a torn line at the end of the journal
+        let mut journal = OpenOptions::new()
+            .append(true)
+            .open(base_path.join(DIRTY_PAGES_FILE))
+            .unwrap();
+        journal.write_all(b"6").unwrap();
+
+        let dirty_pages = DirtyPages::open(base_path).unwrap();
+        assert_eq!(dirty_pages.shutdown(), Shutdown::Crash);
+        assert_eq!(
+            dirty_pages.pages_to_check().unwrap(),
+            &[b"a/".to_vec(), b"".to_vec()]
+        );
+        dirty_pages.mark(b"b").unwrap();
+        assert_eq!(
+            fs::read_to_string(base_path.join(DIRTY_PAGES_FILE)).unwrap(),
+            "612f\n\n62\n"
+        );
+        dirty_pages.close().unwrap();
+        drop(dirty_pages);
+
+        let dirty_pages = DirtyPages::open(base_path).unwrap();
+        assert_eq!(dirty_pages.shutdown(), Shutdown::Clean);
+        assert!(dirty_pages.pages_to_check().unwrap().is_empty());
+        assert!(!base_path.join(CLEAN_SHUTDOWN_FILE).exists());
+        drop(dirty_pages);
+
+        // Crash without any write since the restart
+        let dirty_pages = DirtyPages::open(base_path).unwrap();
+        assert_eq!(dirty_pages.shutdown(), Shutdown::Crash);
+        assert!(dirty_pages.pages_to_check().unwrap().is_empty());
+    }
+}
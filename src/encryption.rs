@@ -0,0 +1,316 @@
+use std::{
+    env, fs,
+    io::{Error, ErrorKind},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use aes_gcm::{
+    aead::{Generate, Nonce},
+    AeadInOut, Aes256Gcm, KeyInit, Tag,
+};
+use serde::{Deserialize, Serialize};
+
+pub const KEY_LENGTH: usize = 32;
+pub const KEY_CHECK_LENGTH: usize = 8;
+pub const NONCE_LENGTH: usize = 12;
+pub const TAG_LENGTH: usize = 16;
+/// Size added to the data sealed in a frame: its length, nonce and tag
+pub const FRAME_OVERHEAD: usize = size_of::<u32>() + NONCE_LENGTH + TAG_LENGTH;
+
+/// Frames hold a record or a block of records, far below this size
+const MAX_FRAME_LENGTH: usize = 16 * 1024 * 1024;
+const KEY_CHECK_CONTEXT: &[u8] = b"kvs key check";
+
+/// Where a key is read from. Keys are 32 bytes written as 64 hexadecimal characters
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum KeySource {
+    /// A local file holding the key
+    File(PathBuf),
+    /// An environment variable holding the key
+    Env(String),
+}
+
+/// Encrypts data with AES-256-GCM, under a random nonce for every frame
+pub struct Cipher {
+    aes: Aes256Gcm,
+    key_check: [u8; KEY_CHECK_LENGTH],
+}
+
+/// The key used to encrypt the pages written from now on, and the keys of the pages that
+/// haven't been rotated yet
+pub struct Keyring {
+    current: Arc<Cipher>,
+    previous: Vec<Arc<Cipher>>,
+}
+
+/// Outcome of opening the frame at the start of a buffer
+pub enum OpenResult {
+    /// The decrypted data and the length of the frame
+    Opened(Vec<u8>, usize),
+    /// The buffer ends before the frame
+    Incomplete,
+    /// The frame is damaged or was sealed with another key. The length of the frame is given
+    /// when it could be read
+    Invalid(Option<usize>),
+}
+
+impl KeySource {
+    pub fn read(&self) -> Result<[u8; KEY_LENGTH], Error> {
+        let (text, origin) = match self {
+            KeySource::File(path) => (fs::read_to_string(path)?, format!("key file {path:?}")),
+            KeySource::Env(name) => (
+                env::var(name).map_err(|e| {
+                    Error::new(
+                        ErrorKind::NotFound,
+                        format!("environment variable {name}: {e}"),
+                    )
+                })?,
+                format!("environment variable {name}"),
+            ),
+        };
+
+        decode_hex(text.trim()).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{origin}: expected {} hexadecimal characters",
+                    KEY_LENGTH * 2
+                ),
+            )
+        })
+    }
+}
+
+impl Cipher {
+    pub fn new(key: &[u8; KEY_LENGTH]) -> Cipher {
+        let aes = Aes256Gcm::new_from_slice(key).unwrap();
+
+        // The tag of an empty message identifies the key without revealing it
+        let tag = aes
+            .encrypt_inout_detached(
+                &Nonce::<Aes256Gcm>::default(),
+                KEY_CHECK_CONTEXT,
+                (&mut [][..]).into(),
+            )
+            .unwrap();
+        let key_check = tag[..KEY_CHECK_LENGTH].try_into().unwrap();
+
+        Cipher { aes, key_check }
+    }
+
+    /// Identifies the key, stored in the header of the pages it encrypts
+    pub fn key_check(&self) -> [u8; KEY_CHECK_LENGTH] {
+        self.key_check
+    }
+
+    /// Encrypts data into a frame: the length of the rest of the frame, the nonce, then the
+    /// encrypted data followed by its tag. The associated data must be given again to open it
+    pub fn seal(&self, data: &[u8], associated_data: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(data.len() + FRAME_OVERHEAD);
+        frame.extend_from_slice(&((data.len() + NONCE_LENGTH + TAG_LENGTH) as u32).to_le_bytes());
+
+        let nonce = Nonce::<Aes256Gcm>::generate();
+        frame.extend_from_slice(&nonce);
+        frame.extend_from_slice(data);
+
+        let tag = self.seal_in_place(
+            &nonce,
+            &mut frame[FRAME_OVERHEAD - TAG_LENGTH..],
+            associated_data,
+        );
+        frame.extend_from_slice(&tag);
+
+        frame
+    }
+
+    /// Decrypts the frame at the start of the buffer
+    pub fn open(&self, buffer: &[u8], associated_data: &[u8]) -> OpenResult {
+        let Some(length) = buffer.get(..size_of::<u32>()) else {
+            return OpenResult::Incomplete;
+        };
+
+        let length = u32::from_le_bytes(length.try_into().unwrap()) as usize;
+        if !(NONCE_LENGTH + TAG_LENGTH..=MAX_FRAME_LENGTH).contains(&length) {
+            return OpenResult::Invalid(None);
+        }
+
+        let frame_length = size_of::<u32>() + length;
+        let Some(frame) = buffer.get(size_of::<u32>()..frame_length) else {
+            return OpenResult::Incomplete;
+        };
+
+        let (nonce, frame) = frame.split_at(NONCE_LENGTH);
+        let (data, tag) = frame.split_at(frame.len() - TAG_LENGTH);
+        let mut data = data.to_vec();
+        if !self.open_in_place(nonce, &mut data, tag, associated_data) {
+            return OpenResult::Invalid(Some(frame_length));
+        }
+
+        OpenResult::Opened(data, frame_length)
+    }
+
+    /// Encrypts data in place. Returns the random nonce followed by the tag, which must be kept
+    /// next to the data to decrypt it
+    pub fn seal_detached(
+        &self,
+        data: &mut [u8],
+        associated_data: &[u8],
+    ) -> [u8; NONCE_LENGTH + TAG_LENGTH] {
+        let nonce = Nonce::<Aes256Gcm>::generate();
+        let tag = self.seal_in_place(&nonce, data, associated_data);
+
+        let mut seal = [0; NONCE_LENGTH + TAG_LENGTH];
+        seal[..NONCE_LENGTH].copy_from_slice(&nonce);
+        seal[NONCE_LENGTH..].copy_from_slice(&tag);
+
+        seal
+    }
+
+    /// Decrypts data encrypted by `seal_detached` in place. Returns false if it was altered
+    pub fn open_detached(&self, data: &mut [u8], seal: &[u8], associated_data: &[u8]) -> bool {
+        let (nonce, tag) = seal.split_at(NONCE_LENGTH);
+
+        self.open_in_place(nonce, data, tag, associated_data)
+    }
+
+    fn seal_in_place(
+        &self,
+        nonce: &Nonce<Aes256Gcm>,
+        data: &mut [u8],
+        associated_data: &[u8],
+    ) -> Tag {
+        self.aes
+            .encrypt_inout_detached(nonce, associated_data, data.into())
+            .expect("Failed to encrypt")
+    }
+
+    fn open_in_place(
+        &self,
+        nonce: &[u8],
+        data: &mut [u8],
+        tag: &[u8],
+        associated_data: &[u8],
+    ) -> bool {
+        let (Ok(nonce), Ok(tag)) = (nonce.try_into(), tag.try_into()) else {
+            return false;
+        };
+
+        self.aes
+            .decrypt_inout_detached(nonce, associated_data, data.into(), tag)
+            .is_ok()
+    }
+}
+
+impl Keyring {
+    /// Reads the current key and the previous ones
+    pub fn load(current: &KeySource, previous: &[KeySource]) -> Result<Keyring, Error> {
+        let mut keys = vec![];
+        for source in previous {
+            keys.push(Arc::new(Cipher::new(&source.read()?)));
+        }
+
+        Ok(Keyring {
+            current: Arc::new(Cipher::new(&current.read()?)),
+            previous: keys,
+        })
+    }
+
+    pub fn current(&self) -> &Arc<Cipher> {
+        &self.current
+    }
+
+    /// Returns the cipher of the key with the given key check
+    pub fn find(&self, key_check: &[u8]) -> Option<&Arc<Cipher>> {
+        std::iter::once(&self.current)
+            .chain(&self.previous)
+            .find(|c| c.key_check == key_check)
+    }
+}
+
+/// Returns a random number drawn from the generator of the nonces
+pub fn random_u64() -> u64 {
+    let nonce = Nonce::<Aes256Gcm>::generate();
+    u64::from_le_bytes(nonce[..size_of::<u64>()].try_into().unwrap())
+}
+
+fn decode_hex(text: &str) -> Option<[u8; KEY_LENGTH]> {
+    if text.len() != KEY_LENGTH * 2 || !text.is_ascii() {
+        return None;
+    }
+
+    let mut key = [0; KEY_LENGTH];
+    for (ix, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[ix * 2..ix * 2 + 2], 16).ok()?;
+    }
+
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames_round_trip() {
+        let cipher = Cipher::new(&[7; KEY_LENGTH]);
+        let other = Cipher::new(&[8; KEY_LENGTH]);
+        assert_ne!(cipher.key_check(), other.key_check());
+
+        let mut frame = cipher.seal(b"some record", b"prefix");
+        assert_eq!(frame.len(), 11 + FRAME_OVERHEAD);
+        assert!(!frame.windows(6).any(|w| w == b"record"));
+
+        let frame_length = frame.len();
+        frame.extend_from_slice(b"next frame");
+        assert!(matches!(
+            cipher.open(&frame, b"prefix"),
+            OpenResult::Opened(data, length) if data == b"some record" && length == frame_length
+        ));
+        assert!(matches!(
+            cipher.open(&frame, b"other"),
+            OpenResult::Invalid(Some(_))
+        ));
+        assert!(matches!(
+            other.open(&frame, b"prefix"),
+            OpenResult::Invalid(Some(_))
+        ));
+        assert!(matches!(
+            cipher.open(&frame[..20], b"prefix"),
+            OpenResult::Incomplete
+        ));
+        assert!(matches!(
+            cipher.open(&[0; 64], b"prefix"),
+            OpenResult::Invalid(None)
+        ));
+
+        let mut data = *b"prefix and children";
+        let seal = cipher.seal_detached(&mut data, b"header");
+        assert_ne!(&data, b"prefix and children");
+        assert!(!other.open_detached(&mut data.clone(), &seal, b"header"));
+        assert!(cipher.open_detached(&mut data, &seal, b"header"));
+        assert_eq!(&data, b"prefix and children");
+    }
+
+    #[test]
+    fn test_keys_are_read_from_their_source() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("key");
+        fs::write(&path, format!("{}\n", "0a".repeat(KEY_LENGTH))).unwrap();
+
+        assert_eq!(
+            KeySource::File(path.clone()).read().unwrap(),
+            [10; KEY_LENGTH]
+        );
+        assert!(KeySource::Env("KVS_TEST_MISSING_KEY".to_string())
+            .read()
+            .is_err());
+
+        fs::write(&path, "0a0b").unwrap();
+        assert_eq!(
+            KeySource::File(path).read().unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+    }
+}
@@ -0,0 +1,469 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use log::info;
+
+use crate::{
+    blob_store::{BlobStore, BLOB_DIRECTORY},
+    configuration::StorageEngine,
+    page_storage,
+    storage::Storage,
+    transaction::Version,
+    tree_node::{self, PageOptions, TreeNode, TrieError, Value},
+    trie_storage::TrieStorage,
+    wal::{Wal, WAL_FILE},
+};
+
+/// Findings of the check of a data directory
+pub struct Report {
+    /// Number of pages reached from the root page
+    pub pages: usize,
+    /// Damage found in the pages and the blobs. The entries they affect are lost by a repair
+    pub problems: Vec<String>,
+    /// Files left behind by a crash, cleaned up when the service starts
+    pub warnings: Vec<String>,
+    /// Whether the root page was written by a case-sensitive store, if it could be read
+    case_sensitive: Option<bool>,
+    /// The entries that could be read
+    entries: BTreeMap<String, Found>,
+}
+
+/// An entry read by a check, and the page holding it
+struct Found {
+    value: Value,
+    expires_at: Option<u64>,
+    version: Version,
+    prefix: Vec<u8>,
+    /// False if the key doesn't belong to the page
+    owned: bool,
+}
+
+impl Report {
+    /// Number of entries that could be read, including expired ones
+    pub fn entries(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Checks the pages of a stopped store without modifying them: the pages are walked from the
+/// root, and every page must match its file name and hold the keys of its prefix only. Pages
+/// that aren't reached from the root, duplicated keys and missing blobs are reported as well.
+/// The entries of the pages that aren't reached are kept when no reached page holds them
+pub fn check(base_path: &Path, options: &PageOptions) -> Result<Report, TrieError> {
+    let storage = options.storage.clone();
+    let mut report = Report {
+        pages: 0,
+        problems: vec![],
+        warnings: vec![],
+        case_sensitive: None,
+        entries: BTreeMap::new(),
+    };
+    let mut reached = HashSet::new();
+
+    // Parents are read before their children, so that the entries of the deepest pages win
+    let mut nodes = vec![vec![]];
+    while let Some(prefix) = nodes.pop() {
+        let page = page_name(base_path, &prefix);
+        reached.insert(TreeNode::file_name(base_path, &prefix));
+
+        let scan = TreeNode::from(
+            base_path.to_path_buf(),
+            &prefix,
+            true,
+            false,
+            options.clone(),
+        )
+        .and_then(|mut node| Ok((node.scan()?, node)));
+        let (scan, node) = match scan {
+            Ok(r) => r,
+            Err(e) => {
+                report
+                    .problems
+                    .push(format!("{page}: unreadable page: {e:?}"));
+                continue;
+            }
+        };
+
+        report.pages += 1;
+        if prefix.is_empty() {
+            report.case_sensitive = Some(node.is_case_sensitive());
+        }
+
+        for problem in scan.problems {
+            report.problems.push(format!("{page}: {problem}"));
+        }
+        if let Some(torn_tail) = scan.torn_tail {
+            report.warnings.push(format!(
+                "{page}: torn write, truncated by the next startup: {torn_tail}"
+            ));
+        }
+
+        let children = node.get_children_prefixes();
+        if node.is_leaf() && !children.is_empty() {
+            report
+                .problems
+                .push(format!("{page}: leaf page with children"));
+        }
+
+        for child in children.into_iter().rev() {
+            if storage.exists(&TreeNode::file_name(base_path, &child)) {
+                nodes.push(child);
+            } else {
+                report.problems.push(format!(
+                    "{page}: child {} has no page file",
+                    page_name(base_path, &child)
+                ));
+            }
+        }
+
+        let misplaced_keys: HashSet<String> = scan.misplaced_keys.into_iter().collect();
+        for (key, (value, expires_at, version)) in scan.entries {
+            let owned = !misplaced_keys.contains(&key);
+            if !owned {
+                report
+                    .problems
+                    .push(format!("{page}: key {key:?} is outside of the page prefix"));
+            }
+
+            if let Some(previous) = report.entries.get(&key) {
+                report.problems.push(format!(
+                    "key {key:?} is stored in {} and {page}",
+                    page_name(base_path, &previous.prefix)
+                ));
+
+                if previous.owned && !owned {
+                    continue;
+                }
+            }
+
+            let found = Found {
+                value,
+                expires_at,
+                version,
+                prefix: prefix.clone(),
+                owned,
+            };
+            report.entries.insert(key, found);
+        }
+    }
+
+    let mut orphans = vec![];
+    for path in storage.list(base_path)? {
+        if reached.contains(&path) || path.is_dir() {
+            continue;
+        }
+
+        let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        match extension {
+            "dat" => {
+                report.problems.push(format!("{name}: orphan page"));
+                orphans.push(path.clone());
+            }
+            tree_node::BLOOM_FILTER_EXTENSION if !reached.contains(&path.with_extension("dat")) => {
+                report.warnings.push(format!("{name}: orphan Bloom filter"))
+            }
+            "tmp" => report
+                .warnings
+                .push(format!("{name}: incomplete page rewrite")),
+            tree_node::SPLIT_INTENT_EXTENSION | tree_node::MERGE_INTENT_EXTENSION => report
+                .warnings
+                .push(format!("{name}: interrupted split or merge")),
+            _ => {}
+        }
+    }
+
+    check_orphans(base_path, options, orphans, &mut report);
+    check_blobs(base_path, options, &mut report)?;
+
+    match Wal::unapplied(base_path, options.encryption.as_deref()) {
+        Ok(writes) if writes.is_empty() => {}
+        Ok(writes) => report.warnings.push(format!(
+            "{WAL_FILE}: {} writes may not have reached the pages, they are applied by the next \
+             startup",
+            writes.len()
+        )),
+        Err(e) => report
+            .problems
+            .push(format!("{WAL_FILE}: unreadable log: {e:?}")),
+    }
+
+    Ok(report)
+}
+
+/// Rebuilds the pages of a stopped store from the entries read by a check, leaving out the
+/// expired entries and the ones whose blob is damaged. The blobs and the WAL are moved to the
+/// rebuilt directory, then the damaged directory is kept next to it. Returns the path of the
+/// damaged directory
+pub fn repair(
+    base_path: &Path,
+    report: Report,
+    engine: StorageEngine,
+    cache_size: usize,
+    mut options: PageOptions,
+) -> Result<PathBuf, TrieError> {
+    // A directory left by an interrupted repair only holds rebuilt pages
+    let rebuild_path = sibling_path(base_path, "rebuild");
+    if rebuild_path.exists() {
+        fs::remove_dir_all(&rebuild_path)?;
+    }
+    fs::create_dir_all(&rebuild_path)?;
+
+    options.storage = page_storage::open(engine, &rebuild_path)?;
+    options.case_sensitive = report.case_sensitive.unwrap_or(options.case_sensitive);
+    let blob_store = BlobStore::open(base_path, options.encryption.clone())?;
+    let mut storage = TrieStorage::open(rebuild_path.clone(), cache_size, options)?;
+
+    let now = tree_node::unix_time();
+    let mut rebuilt = 0;
+    for (key, found) in report.entries {
+        if found.expires_at.is_some_and(|t| t <= now) {
+            continue;
+        }
+
+        if let Value::Blob(blob) = &found.value {
+            if !matches!(blob_store.read(blob), Ok(Some(_))) {
+                continue;
+            }
+        }
+
+        storage.put(key, found.value, found.expires_at, found.version)?;
+        rebuilt += 1;
+    }
+    storage.flush()?;
+    drop(storage);
+    info!("Rebuilt {rebuilt} entries in {rebuild_path:?}");
+
+    for file in [BLOB_DIRECTORY, WAL_FILE] {
+        if base_path.join(file).exists() {
+            fs::rename(base_path.join(file), rebuild_path.join(file))?;
+        }
+    }
+
+    let damaged_path = sibling_path(base_path, &format!("damaged-{now}"));
+    fs::rename(base_path, &damaged_path)?;
+    fs::rename(&rebuild_path, base_path)?;
+
+    Ok(damaged_path)
+}
+
+/// Reads the entries of the pages that aren't reached from the root, e.g. a child whose parent
+/// lost track of it. They are kept when no reached page holds their key, the deepest orphan
+/// page winning like for the reached ones
+fn check_orphans(
+    base_path: &Path,
+    options: &PageOptions,
+    orphans: Vec<PathBuf>,
+    report: &mut Report,
+) {
+    let mut orphan_entries: BTreeMap<String, Found> = BTreeMap::new();
+    for path in orphans {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let scan = TreeNode::from_file(base_path.to_path_buf(), path.clone(), options.clone())
+            .and_then(|mut node| Ok((node.scan()?, node)));
+        let (scan, node) = match scan {
+            Ok(r) => r,
+            Err(e) => {
+                report
+                    .problems
+                    .push(format!("{name}: unreadable page: {e:?}"));
+                continue;
+            }
+        };
+
+        for problem in scan.problems {
+            report.problems.push(format!("{name}: {problem}"));
+        }
+
+        let misplaced_keys: HashSet<String> = scan.misplaced_keys.into_iter().collect();
+        for (key, (value, expires_at, version)) in scan.entries {
+            if report.entries.contains_key(&key) {
+                continue;
+            }
+
+            let prefix = node.prefix().to_vec();
+            if orphan_entries
+                .get(&key)
+                .is_some_and(|previous| previous.prefix.len() > prefix.len())
+            {
+                continue;
+            }
+
+            let found = Found {
+                value,
+                expires_at,
+                version,
+                owned: !misplaced_keys.contains(&key),
+                prefix,
+            };
+            orphan_entries.insert(key, found);
+        }
+    }
+
+    report.entries.append(&mut orphan_entries);
+}
+
+/// Reports the blobs referenced by entries that are missing or damaged, and the blobs no
+/// entry references
+fn check_blobs(
+    base_path: &Path,
+    options: &PageOptions,
+    report: &mut Report,
+) -> Result<(), TrieError> {
+    let blob_store = BlobStore::open(base_path, options.encryption.clone())?;
+    let mut referenced = HashSet::new();
+
+    for (key, found) in &report.entries {
+        let Value::Blob(blob) = &found.value else {
+            continue;
+        };
+
+        referenced.insert(blob.id);
+        match blob_store.read(blob) {
+            Ok(Some(_)) => {}
+            Ok(None) => report
+                .problems
+                .push(format!("key {key:?}: blob {:016x} is damaged", blob.id)),
+            Err(e) => report.problems.push(format!(
+                "key {key:?}: blob {:016x} can't be read: {e}",
+                blob.id
+            )),
+        }
+    }
+
+    for id in blob_store.ids()? {
+        if !referenced.contains(&id) {
+            report
+                .warnings
+                .push(format!("blob {id:016x} is not referenced by any entry"));
+        }
+    }
+
+    Ok(())
+}
+
+fn page_name(base_path: &Path, prefix: &[u8]) -> String {
+    TreeNode::file_name(base_path, prefix)
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Returns the path of a directory next to the data directory, named after it
+fn sibling_path(base_path: &Path, suffix: &str) -> PathBuf {
+    let mut name = base_path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{suffix}"));
+
+    base_path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_damaged_pages_are_reported_and_repaired() {
+        let temp_dir = tempdir().unwrap();
+        let base_path = temp_dir.path().join("data");
+        fs::create_dir_all(&base_path).unwrap();
+        let options = PageOptions::default();
+        let insert = |node: &mut TreeNode, key: &str, value: &str| {
+            node.insert(key.to_string(), Value::Inline(value.into()), None, 1)
+                .unwrap()
+        };
+
+        let mut root = TreeNode::create(base_path.clone(), b"", options.clone()).unwrap();
+        insert(&mut root, "a1", "stale");
+        insert(&mut root, "z", "root");
+
+        let mut child = TreeNode::create(base_path.clone(), b"a", options.clone()).unwrap();
+        insert(&mut child, "a1", "fresh");
+        insert(&mut child, "a2", "damaged");
+        insert(&mut child, "a3", "kept");
+        drop(child);
+
+        let child_path = TreeNode::file_name(&base_path, b"a");
+        let mut data = fs::read(&child_path).unwrap();
+        let position = data.windows(7).position(|w| w == b"damaged").unwrap();
+        data[position] ^= 0xFF;
+        fs::write(&child_path, data).unwrap();
+
+        root.register_child(b"a");
+        root.register_child(b"b");
+        root.save_metadata().unwrap();
+        drop(root);
+        let mut orphan = TreeNode::create(base_path.clone(), b"c", options.clone()).unwrap();
+        insert(&mut orphan, "c1", "orphan");
+        drop(orphan);
+
+        // A record torn at the end of a page is truncated by the next startup
+        let root_path = TreeNode::file_name(&base_path, b"");
+        let mut data = fs::read(&root_path).unwrap();
+        data.extend_from_slice(&[0, 2, b'z']);
+        fs::write(&root_path, data).unwrap();
+
+        let report = check(&base_path, &options).unwrap();
+        assert_eq!(report.pages, 2);
+        assert_eq!(report.entries(), 4);
+        assert!(
+            report
+                .warnings
+                .iter()
+                .any(|w| w.starts_with("_root.dat: torn write")),
+            "{:?}",
+            report.warnings
+        );
+        for problem in [
+            "_root.dat: leaf page with children",
+            "_root.dat: child b.dat has no page file",
+            "a.dat: checksum mismatch at offset",
+            "key \"a1\" is stored in _root.dat and a.dat",
+            "c.dat: orphan page",
+        ] {
+            assert!(
+                report.problems.iter().any(|p| p.starts_with(problem)),
+                "{problem} in {:?}",
+                report.problems
+            );
+        }
+        assert_eq!(report.problems.len(), 5);
+
+        let damaged_path = repair(
+            &base_path,
+            report,
+            StorageEngine::Files,
+            1024 * 1024,
+            options.clone(),
+        )
+        .unwrap();
+        assert!(damaged_path.join("c.dat").exists());
+
+        let report = check(&base_path, &options).unwrap();
+        assert!(report.is_clean(), "{:?}", report.problems);
+
+        let mut storage = TrieStorage::open(base_path, 1024 * 1024, options).unwrap();
+        assert_eq!(
+            storage.get("a1").unwrap().0,
+            Value::Inline(b"fresh".to_vec())
+        );
+        assert_eq!(
+            storage.get("a3").unwrap().0,
+            Value::Inline(b"kept".to_vec())
+        );
+        assert_eq!(storage.get("z").unwrap().0, Value::Inline(b"root".to_vec()));
+        assert_eq!(
+            storage.get("c1").unwrap(),
+            (Value::Inline(b"orphan".to_vec()), 1)
+        );
+        assert!(matches!(storage.get("a2"), Err(TrieError::NotFound)));
+    }
+}
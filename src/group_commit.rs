@@ -0,0 +1,160 @@
+use std::{
+    io,
+    sync::{Arc, Condvar, Mutex, RwLock},
+};
+
+use crate::{node_reader::NodeReader, tree_node::TrieError};
+
+/// Syncs of the `Strict` strategy shared by concurrent writers. Writes aren't synced while the
+/// store is held: each writer waits for a sync covering its write once it releases the store,
+/// and a single sync covers every writer waiting meanwhile
+#[derive(Default)]
+pub struct GroupCommit {
+    state: Mutex<CommitState>,
+    synced: Condvar,
+}
+
+#[derive(Default)]
+struct CommitState {
+    /// Sequence number of the last write
+    written: u64,
+    /// Writes up to this sequence number are durable
+    synced: u64,
+    /// Writes up to this sequence number may have been lost by a failed sync
+    failed: u64,
+    /// Whether a writer is syncing the store for the others
+    syncing: bool,
+    /// Number of syncs run so far
+    syncs: u64,
+}
+
+/// A write waiting to be durable
+pub struct Commit {
+    group: Arc<GroupCommit>,
+    sequence: u64,
+}
+
+impl GroupCommit {
+    /// Records a write made while holding the store, with the sequence number of the WAL.
+    /// Returns the sync it must wait for
+    pub fn record_write(self: &Arc<Self>, sequence: u64) -> Commit {
+        self.state.lock().unwrap().written = sequence;
+
+        Commit {
+            group: self.clone(),
+            sequence,
+        }
+    }
+
+    /// Syncs the writes made so far. Returns the sequence number of the last write synced
+    fn sync(&self, store: &RwLock<NodeReader>) -> Result<u64, TrieError> {
+        let (pending, written) = {
+            let mut store = store
+                .write()
+                .map_err(|_| io::Error::other("the store lock is poisoned"))?;
+
+            (store.pending_sync()?, self.state.lock().unwrap().written)
+        };
+
+        pending.sync()?;
+
+        Ok(written)
+    }
+}
+
+impl Commit {
+    /// Waits until the write is durable. When no sync is running, the writer syncs the store
+    /// for every writer waiting, otherwise it waits for the running sync and the next one
+    pub fn wait(&self, store: &RwLock<NodeReader>) -> Result<(), TrieError> {
+        let group = &self.group;
+        let mut state = group.state.lock().unwrap();
+
+        loop {
+            if state.synced >= self.sequence {
+                return Ok(());
+            }
+
+            if state.failed >= self.sequence {
+                return Err(TrieError::IoError(io::Error::other(
+                    "the write may have been lost by a failed sync",
+                )));
+            }
+
+            if state.syncing {
+                state = group.synced.wait(state).unwrap();
+                continue;
+            }
+
+            state.syncing = true;
+            drop(state);
+            let result = group.sync(store);
+
+            state = group.state.lock().unwrap();
+            state.syncing = false;
+            state.syncs += 1;
+            match result {
+                Ok(written) => state.synced = state.synced.max(written),
+                // The pages of the failed sync won't be synced again
+                Err(_) => state.failed = state.written,
+            }
+            group.synced.notify_all();
+
+            result?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::FSyncStrategy;
+    use crate::tree_node::PageOptions;
+    use std::thread;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_concurrent_writes_share_syncs() {
+        let temp_dir = tempdir().unwrap();
+        let group_commit = Arc::new(GroupCommit::default());
+        let options = PageOptions {
+            fsync: FSyncStrategy::Strict,
+            group_commit: Some(group_commit.clone()),
+            ..PageOptions::default()
+        };
+        assert!(!options.sync_after_write());
+
+        let reader =
+            NodeReader::new(temp_dir.path().to_path_buf(), 1024 * 1024, None, options).unwrap();
+        let store = Arc::new(RwLock::new(reader));
+
+        thread::scope(|scope| {
+            for t in 0..8 {
+                let store = &store;
+                scope.spawn(move || {
+                    for i in 0..50 {
+                        let commit = {
+                            let mut store = store.write().unwrap();
+                            store
+                                .insert(format!("key{t}-{i}"), b"value".to_vec())
+                                .unwrap();
+                            store.commit().unwrap().unwrap()
+                        };
+
+                        commit.wait(store).unwrap();
+                        assert!(commit.group.state.lock().unwrap().synced >= commit.sequence);
+                    }
+                });
+            }
+        });
+
+        // Writers waiting while a sync runs share the next one
+        let state = group_commit.state.lock().unwrap();
+        assert_eq!(state.written, 400);
+        assert_eq!(state.synced, 400);
+        assert!(state.syncs < 400, "{} syncs for 400 writes", state.syncs);
+
+        let mut reader = store.write().unwrap();
+        assert_eq!(reader.get("key7-49").unwrap(), b"value");
+        assert_eq!(reader.durability_window().unsynced_writes, 0);
+    }
+}
@@ -0,0 +1,22 @@
+//! Storage engine of kvs, shared by the service and the offline tools
+
+pub mod blob_store;
+mod bloom_filter;
+mod cache;
+pub mod compression;
+pub mod configuration;
+pub mod dirty_pages;
+pub mod encryption;
+pub mod fsck;
+pub mod group_commit;
+pub mod memory_storage;
+pub mod node_reader;
+pub mod page_storage;
+mod segment_storage;
+pub mod stats;
+pub mod storage;
+pub mod transaction;
+pub mod tree_node;
+pub mod trie_storage;
+pub mod verification;
+pub mod wal;
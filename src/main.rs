@@ -1,36 +1,66 @@
+use actix_web::dev::ServerHandle;
 use actix_web::{web, App, HttpServer};
-use configuration::{Configuration, FSyncStrategy};
+use kvs::blob_store::{BlobReader, BlobStore};
+use kvs::configuration::{Configuration, FSyncStrategy, StorageEngine};
+use kvs::dirty_pages::DirtyPages;
+use kvs::encryption::Keyring;
+use kvs::group_commit::GroupCommit;
+use kvs::memory_storage::MemoryStorage;
+use kvs::node_reader::NodeReader;
+use kvs::page_storage;
+use kvs::stats::Stats;
+use kvs::storage::Storage;
+use kvs::transaction::{Precondition, TransactionWrite, Version};
+use kvs::tree_node::PageOptions;
+use kvs::trie_storage::TrieStorage;
+use kvs::verification::Verification;
 use log::{error, info};
-use node_reader::NodeReader;
-use reqwest::blocking::Client;
+use reqwest::blocking::{Body, Client, RequestBuilder};
+use reqwest::header::CONTENT_TYPE;
 use routes::*;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicUsize;
 use std::sync::mpsc::Sender;
 use std::sync::Arc;
 use std::sync::{mpsc, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
 
-mod cache;
-mod configuration;
-mod node_reader;
 mod routes;
-mod tree_node;
 
 const CONFIGURATION_PATH: &str = "config.json";
+/// Command line argument running the key rotation instead of the service
+const ROTATE_KEYS_COMMAND: &str = "rotate-keys";
+/// A stopping replica stops its replication server once no write was received for this long
+const REPLICATION_QUIET_PERIOD: Duration = Duration::from_secs(2);
+/// A stopping replica stops its replication server after this long, even if the main still
+/// sends writes
+const MAX_REPLICATION_DRAIN: Duration = Duration::from_secs(60);
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init();
 
     let configuration = Configuration::read(CONFIGURATION_PATH).unwrap();
-    let use_strict_fsync = configuration.fsync() == FSyncStrategy::Strict;
 
     info!("Listening on port: {}", configuration.port());
-    info!("Use fsynch strict: {}", use_strict_fsync);
+    info!("FSync strategy: {:?}", configuration.fsync());
     info!("Cache size: {}MB", configuration.cache_size() / 1024 / 1024);
+    info!("Compaction ratio: {}", configuration.compaction_ratio());
+    info!(
+        "Case-sensitive keys: {}",
+        configuration.case_sensitive_keys()
+    );
+    info!("Storage engine: {:?}", configuration.storage());
+    info!("Compression: {:?}", configuration.compression());
+    info!("Encryption: {}", configuration.encryption().is_some());
+    info!(
+        "Memory-mapped reads: {}",
+        configuration.memory_mapped_reads()
+    );
     info!(
         "Max range response: {:#?}",
         match configuration.max_range_response() {
@@ -42,89 +72,289 @@ async fn main() -> std::io::Result<()> {
     let path = Path::new("data").to_path_buf();
     create_data_directory(&path).expect("Failed to create data directory");
 
-    let mut store = NodeReader::new(
-        path,
-        configuration.cache_size(),
-        configuration.max_range_response(),
-        configuration.fsync() == FSyncStrategy::Strict,
-    )
+    let mut options = PageOptions {
+        fsync: configuration.fsync(),
+        compaction_ratio: configuration.compaction_ratio(),
+        case_sensitive: configuration.case_sensitive_keys(),
+        compression: configuration.compression(),
+        memory_mapped_reads: configuration.memory_mapped_reads(),
+        stats: Arc::new(Stats::default()),
+        encryption: configuration.encryption().map(|e| {
+            Arc::new(
+                Keyring::load(&e.key, &e.previous_keys)
+                    .expect("Failed to load the encryption keys"),
+            )
+        }),
+        ..PageOptions::default()
+    };
+
+    if std::env::args().nth(1).as_deref() == Some(ROTATE_KEYS_COMMAND) {
+        return rotate_keys(&configuration, path, options);
+    }
+
+    if configuration.fsync() == FSyncStrategy::Strict {
+        options.group_commit = Some(Arc::new(GroupCommit::default()));
+    }
+
+    let mut store = match configuration.storage() {
+        StorageEngine::Memory => NodeReader::with_storage(
+            Box::new(MemoryStorage::open(path.clone()).expect("Failed to open the storage")),
+            path,
+            configuration.max_range_response(),
+            options,
+        ),
+        engine => {
+            options.storage =
+                page_storage::open(engine, &path).expect("Failed to open the storage");
+            options.dirty_pages = Some(Arc::new(
+                DirtyPages::open(&path).expect("Failed to read the shutdown state"),
+            ));
+
+            NodeReader::new(
+                path,
+                configuration.cache_size(),
+                configuration.max_range_response(),
+                options,
+            )
+        }
+    }
     .expect("Failed to create NodeReader");
 
-    info!("Starting sanity check");
-    store.sanity_check().unwrap();
-    info!("Sanity check completed");
+    info!("Starting recovery");
+    let recovery = store.recover().unwrap();
+    info!(
+        "Recovery completed after a {:?} shutdown: {} pages checked, {} writes applied, {} quarantined",
+        recovery.shutdown,
+        recovery.checked_pages,
+        recovery.applied_writes,
+        recovery.quarantined_writes
+    );
+
+    let store = Arc::new(RwLock::new(store));
+    let verification = Verification::start(store.clone(), &recovery);
+    NodeReader::sync_periodically(&store);
 
     info!("Starting service: ...");
     let (tx, rx) = mpsc::channel::<WriteEvent>();
     let replicas = Arc::new(configuration.replicas().clone());
+    let writes = Arc::new(WriteGate::default());
 
-    thread::spawn(move || event_listener(rx, replicas));
+    let listener = thread::spawn(move || event_listener(rx, replicas));
 
     if configuration.is_replica() {
-        start_replica(configuration, store, tx).await
+        start_replica(
+            configuration,
+            store.clone(),
+            verification,
+            writes,
+            tx.clone(),
+        )
+        .await?;
     } else {
-        start_main(configuration, store, tx).await
+        start_main(
+            configuration,
+            store.clone(),
+            verification,
+            writes,
+            tx.clone(),
+        )
+        .await?;
     }
+
+    info!("Sending the remaining writes to the replicas");
+    let _ = tx.send(WriteEvent::Stop);
+    if listener.join().is_err() {
+        error!("The event listener failed: some writes may not have reached the replicas");
+    }
+
+    info!("Closing the store");
+    match store.write() {
+        Ok(mut store) => store.close().expect("Failed to close the store"),
+        Err(_) => {
+            error!("The store was left in an inconsistent state: it is checked at the next start")
+        }
+    }
+
+    Ok(())
 }
 
 async fn start_main(
     configuration: Configuration,
-    node_reader: NodeReader,
+    store: Arc<RwLock<NodeReader>>,
+    verification: Arc<Verification>,
+    writes: Arc<WriteGate>,
     tx: Sender<WriteEvent>,
 ) -> Result<(), std::io::Error> {
-    let store = Arc::new(RwLock::new(node_reader));
-
-    HttpServer::new(move || {
+    let server_writes = writes.clone();
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(store.clone()))
+            .app_data(web::Data::new(verification.clone()))
             .app_data(web::Data::new(AtomicUsize::new(0)))
             .app_data(web::Data::new(tx.clone()))
+            .app_data(web::Data::new(server_writes.clone()))
             .service(get)
             .service(get_range)
+            .service(get_stats)
+            .service(get_status)
             .service(insert)
             .service(bulk_insert)
+            .service(transaction)
             .service(delete)
     })
+    .disable_signals()
     .bind(("::", configuration.port()))?
-    .run()
-    .await
+    .run();
+
+    tokio::spawn(stop_on_signal(vec![server.handle()], writes));
+
+    server.await
 }
 
 async fn start_replica(
     configuration: Configuration,
-    node_reader: NodeReader,
+    store: Arc<RwLock<NodeReader>>,
+    verification: Arc<Verification>,
+    writes: Arc<WriteGate>,
     tx: Sender<WriteEvent>,
 ) -> Result<(), std::io::Error> {
-    let store = Arc::new(RwLock::new(node_reader));
     let public_store = store.clone();
     let public = HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(public_store.clone()))
+            .app_data(web::Data::new(verification.clone()))
             .app_data(web::Data::new(AtomicUsize::new(0)))
             .service(get)
             .service(get_range)
+            .service(get_stats)
+            .service(get_status)
     })
+    .disable_signals()
     .bind(("::", configuration.port()))?
     .run();
 
+    let server_writes = writes.clone();
     let replication = HttpServer::new(move || {
         App::new()
             .app_data(web::Data::new(store.clone()))
             .app_data(web::Data::new(AtomicUsize::new(0)))
             .app_data(web::Data::new(tx.clone()))
+            .app_data(web::Data::new(server_writes.clone()))
+            .app_data(web::Data::new(ReplicatedWrites))
             .service(insert)
             .service(bulk_insert)
+            .service(transaction)
             .service(delete)
     })
+    .disable_signals()
     .bind(("::", configuration.replication_port()))?
     .run();
 
+    tokio::spawn(stop_replica_on_signal(
+        public.handle(),
+        replication.handle(),
+        writes,
+    ));
+
     tokio::join!(public, replication).0
 }
 
+/// Waits for SIGINT or SIGTERM, then refuses new writes and stops the servers once the requests
+/// being handled are completed
+async fn stop_on_signal(servers: Vec<ServerHandle>, writes: Arc<WriteGate>) {
+    wait_for_signal().await;
+
+    info!("Stopping the service: writes are refused from now on");
+    writes.close();
+    for server in servers {
+        server.stop(true).await;
+    }
+}
+
+/// Waits for SIGINT or SIGTERM, then stops the public server of a replica. The replication
+/// server keeps accepting the writes of the main, which sends the writes it accepted before
+/// stopping: it is only stopped once no write was received for a while
+async fn stop_replica_on_signal(
+    public: ServerHandle,
+    replication: ServerHandle,
+    writes: Arc<WriteGate>,
+) {
+    wait_for_signal().await;
+
+    info!("Stopping the service: waiting for the main to send its remaining writes");
+    public.stop(true).await;
+
+    let stopping = Instant::now();
+    while writes
+        .last_write()
+        .is_some_and(|t| t.elapsed() < REPLICATION_QUIET_PERIOD)
+    {
+        if stopping.elapsed() >= MAX_REPLICATION_DRAIN {
+            error!("The main is still sending writes: the writes it sends from now on are lost");
+            break;
+        }
+
+        tokio::time::sleep(REPLICATION_QUIET_PERIOD / 4).await;
+    }
+
+    info!("Stopping the replication: writes are refused from now on");
+    writes.close();
+    replication.stop(true).await;
+}
+
+async fn wait_for_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to handle SIGTERM");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT"),
+        _ = terminate.recv() => info!("Received SIGTERM"),
+    }
+}
+
+/// Rewrites the WAL, the pages and the blobs that aren't encrypted with the current key, then
+/// exits. The service must be stopped while the keys are rotated
+fn rotate_keys(
+    configuration: &Configuration,
+    path: PathBuf,
+    mut options: PageOptions,
+) -> std::io::Result<()> {
+    options.storage = page_storage::open(configuration.storage(), &path)?;
+
+    // The writes of the WAL, which may be encrypted with a previous key, are applied to the
+    // pages first: the emptied WAL is then written again with the current key
+    let mut store = NodeReader::new(
+        path.clone(),
+        configuration.cache_size(),
+        None,
+        PageOptions {
+            dirty_pages: Some(Arc::new(DirtyPages::open(&path)?)),
+            ..options.clone()
+        },
+    )
+    .expect("Failed to open the store");
+    store.recover().expect("Failed to apply the WAL");
+    store.close().expect("Failed to close the store");
+    drop(store);
+
+    let blobs = BlobStore::open(&path, options.encryption.clone())?;
+    let mut storage = TrieStorage::open(path, configuration.cache_size(), options)
+        .expect("Failed to open the storage");
+
+    info!("Starting sanity check");
+    storage.sanity_check().unwrap();
+
+    info!("Rotating the encryption keys");
+    let rotated = storage.rotate_keys().expect("Failed to rotate the keys");
+    let rotated_blobs = blobs.rotate_keys()?;
+    info!(
+        "Rewrote {rotated} pages and {rotated_blobs} blobs: the previous keys are no longer needed"
+    );
+
+    Ok(())
+}
+
 fn create_data_directory(path: &Path) -> std::io::Result<()> {
     if !path.exists() {
-        fs::create_dir_all(&path)?;
+        fs::create_dir_all(path)?;
         info!("Directory created: {}", path.to_str().unwrap());
     } else {
         info!("Directory already exists: {}", path.to_str().unwrap());
@@ -133,17 +363,31 @@ fn create_data_directory(path: &Path) -> std::io::Result<()> {
     Ok(())
 }
 
+/// A write applied by the main. Replicas give the entries written the version they have on the
+/// main
 #[derive(Debug)]
 enum WriteEvent {
-    BulkInsert(HashMap<String, String>),
-    Insert(String, String),
-    Delete(String),
+    /// Normalized entries, in key order
+    BulkInsert(Vec<(String, Vec<u8>)>, Version),
+    /// The normalized writes of a transaction, in order. Replicas apply them atomically,
+    /// without checking the versions read
+    Transaction(Vec<TransactionWrite>, Version),
+    /// A value with its expiry timestamp, if any, and the conditions of the write, checked
+    /// again by the replicas
+    Insert(String, Vec<u8>, Option<u64>, Precondition, Version),
+    /// A value stored in a blob file, with its length. The file is streamed to the replicas
+    InsertBlob(String, BlobReader, u64, Option<u64>, Precondition, Version),
+    Delete(String, Precondition),
+    /// Sent once the servers are stopped: the listener stops after sending the previous events
+    Stop,
 }
 
 fn event_listener(rx: mpsc::Receiver<WriteEvent>, replicas: Arc<Vec<String>>) {
     info!("[Event Listener] Started event listener");
-    if replicas.len() == 0 {
-        for _ in rx {}
+    if replicas.is_empty() {
+        rx.iter()
+            .take_while(|e| !matches!(e, WriteEvent::Stop))
+            .for_each(drop);
     } else {
         for r in &*replicas {
             info!("[Event Listener] Replica: {r}");
@@ -151,24 +395,73 @@ fn event_listener(rx: mpsc::Receiver<WriteEvent>, replicas: Arc<Vec<String>>) {
 
         let client = Client::new();
 
-        for received in rx.iter() {
+        'events: for received in rx.iter() {
             for replica in &*replicas {
                 let result = match received {
-                    WriteEvent::Insert(ref key, ref value) => {
-                        let mut url = replica.clone();
-                        url.push_str(&format!("/kv/{key}"));
-                        client.post(url).json(value).send()
+                    WriteEvent::Insert(
+                        ref key,
+                        ref value,
+                        expires_at,
+                        ref precondition,
+                        version,
+                    ) => {
+                        let url = insert_url(replica, key, expires_at, version);
+                        with_precondition(client.post(url), precondition)
+                            .header(CONTENT_TYPE, "application/octet-stream")
+                            .body(value.clone())
+                            .send()
+                    }
+                    WriteEvent::InsertBlob(
+                        ref key,
+                        ref file,
+                        length,
+                        expires_at,
+                        ref precondition,
+                        version,
+                    ) => {
+                        let url = insert_url(replica, key, expires_at, version);
+                        let file = match file.try_clone() {
+                            Ok(f) => f,
+                            Err(e) => {
+                                error!("Failed to read blob: {e:#?}");
+                                continue;
+                            }
+                        };
+
+                        with_precondition(client.post(url), precondition)
+                            .header(CONTENT_TYPE, "application/octet-stream")
+                            .body(Body::sized(file, length))
+                            .send()
                     }
-                    WriteEvent::BulkInsert(ref entries) => {
-                        let mut url = replica.clone();
-                        url.push_str(&format!("/bulk"));
+                    WriteEvent::BulkInsert(ref entries, version) => {
+                        let url = format!("{replica}/bulk?version={}", format_version(version));
+                        let entries: BTreeMap<&String, JsonValue> = entries
+                            .iter()
+                            .map(|(key, value)| (key, value.clone().into()))
+                            .collect();
                         client.post(url).json(&entries).send()
                     }
-                    WriteEvent::Delete(ref key) => {
-                        let mut url = replica.clone();
-                        url.push_str(&format!("/kv/{key}"));
-                        client.delete(url).send()
+                    WriteEvent::Transaction(ref writes, version) => {
+                        let url = format!("{replica}/txn?version={}", format_version(version));
+                        let mut request = TransactionRequest::default();
+                        for write in writes {
+                            match write {
+                                TransactionWrite::Put { key, value } => {
+                                    request.writes.insert(key.clone(), value.clone().into());
+                                }
+                                TransactionWrite::Delete { key } => {
+                                    request.deletes.push(key.clone())
+                                }
+                            }
+                        }
+                        client.post(url).json(&request).send()
                     }
+                    WriteEvent::Delete(ref key, ref precondition) => {
+                        let url = key_url(replica, key);
+                        with_precondition(client.delete(url), precondition).send()
+                    }
+                    // The events sent before were all sent to every replica
+                    WriteEvent::Stop => break 'events,
                 };
 
                 match result {
@@ -181,3 +474,44 @@ fn event_listener(rx: mpsc::Receiver<WriteEvent>, replicas: Arc<Vec<String>>) {
         }
     }
 }
+
+/// Adds the conditions of a write to its request: a replica that doesn't hold the same entries
+/// as the main refuses the write rather than diverging further
+fn with_precondition(mut request: RequestBuilder, precondition: &Precondition) -> RequestBuilder {
+    for (name, value) in precondition_headers(precondition) {
+        request = request.header(name.as_str(), value);
+    }
+
+    request
+}
+
+/// Returns the URL inserting a key on a replica, with the version of the entry. The absolute
+/// expiry is sent, so that the entry expires at the same time on every node
+fn insert_url(replica: &str, key: &str, expires_at: Option<u64>, version: Version) -> String {
+    let url = format!(
+        "{}?version={}",
+        key_url(replica, key),
+        format_version(version)
+    );
+
+    match expires_at {
+        Some(expires_at) => format!("{url}&expires_at={expires_at}"),
+        None => url,
+    }
+}
+
+/// Returns the URL of a key on a replica. The key is percent-encoded, since it may contain
+/// characters such as `/`, `?` or `#`
+fn key_url(replica: &str, key: &str) -> String {
+    let mut url = format!("{replica}/kv/");
+    for b in key.bytes() {
+        match b {
+            b'0'..=b'9' | b'a'..=b'z' | b'A'..=b'Z' | b'-' | b'.' | b'_' | b'~' => {
+                url.push(b as char)
+            }
+            _ => url.push_str(&format!("%{b:02X}")),
+        }
+    }
+
+    url
+}
@@ -0,0 +1,263 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    ops::Bound::Included,
+    path::PathBuf,
+};
+
+use crate::{
+    blob_store::BlobStore,
+    dirty_pages::Shutdown,
+    page_storage,
+    storage::{PageCheck, Recovery, Storage},
+    transaction::Version,
+    tree_node::{self, TrieError, Value},
+};
+
+/// Keeps the entries in memory only: they are lost when the process stops. Large values are
+/// still written to the blob store, and removed when the storage is closed
+pub struct MemoryStorage {
+    entries: BTreeMap<String, (Value, Option<u64>, Version)>,
+    base_path: PathBuf,
+}
+
+impl MemoryStorage {
+    /// Opens an empty storage. The data directory must not hold the pages or the blobs of a
+    /// persistent storage: the blobs it doesn't reference would be lost otherwise
+    pub fn open(base_path: PathBuf) -> Result<MemoryStorage, TrieError> {
+        if page_storage::has_pages(&base_path) {
+            return Err(TrieError::InvalidConfiguration(
+                "the data directory holds the pages of another storage engine".to_string(),
+            ));
+        }
+
+        if !BlobStore::open(&base_path, None)?.ids()?.is_empty() {
+            return Err(TrieError::InvalidConfiguration(
+                "the data directory holds blobs, either of another storage engine or left by a \
+                 memory storage that crashed: remove them to use the memory storage engine"
+                    .to_string(),
+            ));
+        }
+
+        Ok(MemoryStorage {
+            entries: BTreeMap::new(),
+            base_path,
+        })
+    }
+
+    fn remove_blob(&self, previous: Option<Value>) -> Result<(), TrieError> {
+        if let Some(Value::Blob(blob)) = previous {
+            BlobStore::remove(&self.base_path, &blob)?;
+        }
+
+        Ok(())
+    }
+
+    fn check_key(key: &str) -> Result<(), TrieError> {
+        if !tree_node::is_valid_key(key) {
+            return Err(TrieError::KeyError);
+        }
+
+        Ok(())
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&mut self, key: &str) -> Result<(Value, Version), TrieError> {
+        Self::check_key(key)?;
+
+        match self.entries.get(key) {
+            Some((value, expires_at, version))
+                if !is_expired(*expires_at, tree_node::unix_time()) =>
+            {
+                Ok((value.clone(), *version))
+            }
+            _ => Err(TrieError::NotFound),
+        }
+    }
+
+    fn put(
+        &mut self,
+        key: String,
+        value: Value,
+        expires_at: Option<u64>,
+        version: Version,
+    ) -> Result<(), TrieError> {
+        Self::check_key(&key)?;
+
+        // A write applied again keeps the blob it references
+        let written_blob = match &value {
+            Value::Blob(blob) => Some(blob.id),
+            Value::Inline(_) => None,
+        };
+        let previous = self.entries.insert(key, (value, expires_at, version));
+        self.remove_blob(
+            previous
+                .map(|(v, _, _)| v)
+                .filter(|v| !matches!(v, Value::Blob(b) if Some(b.id) == written_blob)),
+        )
+    }
+
+    fn delete(&mut self, key: String) -> Result<(), TrieError> {
+        Self::check_key(&key)?;
+
+        let previous = self.entries.remove(&key);
+        self.remove_blob(previous.map(|(value, _, _)| value))
+    }
+
+    fn range(
+        &mut self,
+        start_key: &str,
+        end_key: &str,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, Value)>, TrieError> {
+        if start_key > end_key {
+            return Ok(vec![]);
+        }
+
+        let now = tree_node::unix_time();
+
+        Ok(self
+            .entries
+            .range::<str, _>((Included(start_key), Included(end_key)))
+            .filter(|(_, (_, expires_at, _))| !is_expired(*expires_at, now))
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|(key, (value, _, _))| (key.clone(), value.clone()))
+            .collect())
+    }
+
+    fn flush(&mut self) -> Result<(), TrieError> {
+        Ok(())
+    }
+
+    /// The entries are lost once the process stops, so are their blobs
+    fn close(&mut self) -> Result<(), TrieError> {
+        for (_, (value, _, _)) in std::mem::take(&mut self.entries) {
+            self.remove_blob(Some(value))?;
+        }
+
+        Ok(())
+    }
+
+    fn sanity_check(&mut self) -> Result<HashSet<u64>, TrieError> {
+        Ok(self
+            .entries
+            .values()
+            .filter_map(|(value, _, _)| match value {
+                Value::Blob(blob) => Some(blob.id),
+                Value::Inline(_) => None,
+            })
+            .collect())
+    }
+
+    /// Blobs are never removed at startup: the data directory held none
+    fn recover(&mut self) -> Result<Recovery, TrieError> {
+        Ok(Recovery {
+            shutdown: Shutdown::Unknown,
+            checked_pages: 0,
+            applied_writes: 0,
+            quarantined_writes: 0,
+            referenced_blobs: None,
+        })
+    }
+
+    fn owns_blobs(&self) -> bool {
+        false
+    }
+
+    /// The entries are all held by a single root page
+    fn verify_page(&self, prefix: &[u8]) -> Result<Option<PageCheck>, TrieError> {
+        if !prefix.is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(PageCheck {
+            blobs: self
+                .entries
+                .values()
+                .filter_map(|(value, _, _)| match value {
+                    Value::Blob(blob) => Some(blob.id),
+                    Value::Inline(_) => None,
+                })
+                .collect(),
+            ..PageCheck::default()
+        }))
+    }
+}
+
+fn is_expired(expires_at: Option<u64>, now: u64) -> bool {
+    expires_at.is_some_and(|t| t <= now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_entries_are_kept_in_order() {
+        let temp_dir = tempdir().unwrap();
+        let mut storage = MemoryStorage::open(temp_dir.path().to_path_buf()).unwrap();
+
+        for (version, key) in (1..).zip(["b", "a", "d", "c"]) {
+            storage
+                .put(
+                    key.to_string(),
+                    Value::Inline(key.as_bytes().to_vec()),
+                    None,
+                    version,
+                )
+                .unwrap();
+        }
+        storage
+            .put("bb".to_string(), Value::Inline(vec![]), Some(0), 5)
+            .unwrap();
+        storage.delete("c".to_string()).unwrap();
+        storage.delete("missing".to_string()).unwrap();
+
+        assert_eq!(storage.get("a").unwrap(), (Value::Inline(b"a".to_vec()), 2));
+        assert!(matches!(storage.get("bb"), Err(TrieError::NotFound)));
+        assert!(matches!(storage.get("c"), Err(TrieError::NotFound)));
+
+        let keys: Vec<String> = storage
+            .range("b", "z", Some(2))
+            .unwrap()
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect();
+        assert_eq!(keys, vec!["b", "d"]);
+        assert!(storage.range("z", "a", None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_persisted_data_is_kept() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().to_path_buf();
+
+        // Blobs of a persistent storage
+        let blobs = BlobStore::open(&path, None).unwrap();
+        let blob = blobs.write(b"persisted").unwrap();
+        assert!(matches!(
+            MemoryStorage::open(path.clone()),
+            Err(TrieError::InvalidConfiguration(_))
+        ));
+        assert_eq!(blobs.read(&blob).unwrap().unwrap(), b"persisted");
+
+        BlobStore::remove(&path, &blob).unwrap();
+        std::fs::write(path.join("_root.dat"), b"page").unwrap();
+        assert!(matches!(
+            MemoryStorage::open(path.clone()),
+            Err(TrieError::InvalidConfiguration(_))
+        ));
+
+        // The blobs of a memory storage are removed once it is closed
+        std::fs::remove_file(path.join("_root.dat")).unwrap();
+        let mut storage = MemoryStorage::open(path.clone()).unwrap();
+        let blob = blobs.write(b"large").unwrap();
+        storage
+            .put("key".to_string(), Value::Blob(blob), None, 1)
+            .unwrap();
+        storage.close().unwrap();
+        assert!(blobs.ids().unwrap().is_empty());
+        MemoryStorage::open(path).unwrap();
+    }
+}
@@ -1,63 +1,232 @@
-use log::debug;
+use log::{error, info};
 
 use crate::{
-    cache::Cache,
-    tree_node::{self, FindRangeChildrenResult, SearchResult, TreeNode, TrieError},
+    blob_store::{BlobReader, BlobStore},
+    configuration::FSyncStrategy,
+    dirty_pages::DirtyPages,
+    group_commit::{Commit, GroupCommit},
+    stats::{DurabilityWindow, Stats, StatsSnapshot},
+    storage::{PageCheck, PendingSync, Recovery, Storage},
+    transaction::{Precondition, Transaction, TransactionWrite, Version},
+    tree_node::{self, PageOptions, TrieError, Value},
+    trie_storage::TrieStorage,
+    wal::{Wal, WalOperation, WAL_CHECKPOINT_LENGTH},
 };
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::ErrorKind,
+    mem,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    thread,
+    time::Duration,
+};
+
+/// A value ready to be sent: values stored in a blob are read from their file as they are sent
+pub enum ValueReader {
+    Inline(Vec<u8>),
+    Blob(BlobReader, u64),
+}
 
+/// Entry point of the routes: normalizes the keys, stores the large values in blob files and
+/// applies the limits of the store, whatever storage holds the entries
 pub struct NodeReader {
-    metadata_cache: Cache<String, TreeNode>,
-    data_cache: Cache<String, TreeNode>,
-    root: TreeNode,
+    storage: Box<dyn Storage>,
     base_path: PathBuf,
     max_range_response_size: Option<usize>,
-    sync_after_write: bool,
+    case_sensitive: bool,
+    stats: Arc<Stats>,
+    blobs: Arc<BlobStore>,
+    dirty_pages: Option<Arc<DirtyPages>>,
+    fsync: FSyncStrategy,
+    /// Writes acknowledged since the last sync. Not tracked when every write is synced
+    unsynced_writes: u64,
+    group_commit: Option<Arc<GroupCommit>>,
+    /// Sync the last write waits for, when writes are synced by group commit
+    commit: Option<Commit>,
+    /// Error of the sync of the last write, when it failed after the write was applied
+    sync_error: Option<TrieError>,
+    /// Log every write is appended to before it is applied to the storage. Storages kept in
+    /// memory have none
+    wal: Option<Wal>,
+    /// Writes of the WAL that may not have reached the storage, applied again by `recover`
+    unapplied: Vec<WalOperation>,
+    /// Rest of a batch of writes that failed to be applied after being logged. It is applied
+    /// before any other read or write, so that the batch is never partly visible
+    interrupted: VecDeque<WalOperation>,
+    /// Set once the rest of a batch failed to be applied again: writes are refused until the
+    /// node restarts and applies the batch from the WAL, rather than retrying it forever
+    read_only: bool,
+    /// Sequence number of the last write
+    sequence: u64,
 }
 
 impl NodeReader {
-    /// Instantiates a new NodeReader
+    /// Instantiates a new NodeReader storing its entries in a trie of pages, behind a WAL.
+    /// `recover` must be called before writing
     pub fn new(
         base_path: PathBuf,
         cache_size: usize,
         max_range_response_size: Option<usize>,
-        sync_after_write: bool,
-    ) -> Result<NodeReader, std::io::Error> {
+        options: PageOptions,
+    ) -> Result<NodeReader, TrieError> {
+        let (wal, unapplied) = Wal::open(&base_path, options.encryption.clone())?;
+
+        // Writes are made durable by the WAL: the pages are only synced by checkpoints
+        let page_options = PageOptions {
+            fsync: FSyncStrategy::Default,
+            ..options.clone()
+        };
+        let storage = TrieStorage::open(base_path.clone(), cache_size, page_options)?;
+
+        let mut reader = Self::with_storage(
+            Box::new(storage),
+            base_path,
+            max_range_response_size,
+            options,
+        )?;
+        reader.sequence = wal.sequence();
+        reader.wal = Some(wal);
+        reader.unapplied = unapplied;
+
+        Ok(reader)
+    }
+
+    /// Instantiates a new NodeReader on top of the given storage. Blobs are stored in the data
+    /// directory whatever the storage
+    pub fn with_storage(
+        storage: Box<dyn Storage>,
+        base_path: PathBuf,
+        max_range_response_size: Option<usize>,
+        options: PageOptions,
+    ) -> Result<NodeReader, TrieError> {
         Ok(NodeReader {
-            root: Self::read_root(&base_path, sync_after_write)?,
-            data_cache: Cache::new(cache_size / tree_node::SPLIT_THRESHOLD),
-            metadata_cache: Cache::new(10000),
+            storage,
+            blobs: Arc::new(BlobStore::open(&base_path, options.encryption.clone())?),
             base_path,
             max_range_response_size,
-            sync_after_write,
+            case_sensitive: options.case_sensitive,
+            stats: options.stats,
+            dirty_pages: options.dirty_pages,
+            fsync: options.fsync,
+            unsynced_writes: 0,
+            group_commit: options.group_commit,
+            commit: None,
+            sync_error: None,
+            wal: None,
+            unapplied: vec![],
+            interrupted: VecDeque::new(),
+            read_only: false,
+            sequence: 0,
         })
     }
 
     /// Removes an entry
     pub fn delete(&mut self, key: String) -> Result<(), TrieError> {
-        self.on_owner(&key.clone(), |n| {
-            n.delete(key)?;
-            Ok(())
-        })
+        let key = self.normalize_key(key);
+
+        self.write(vec![WalOperation::Delete { key }])
+    }
+
+    /// Runs a sanity check of the storage, then removes the blobs it doesn't reference
+    pub fn sanity_check(&mut self) -> Result<(), TrieError> {
+        let referenced_blobs = self.storage.sanity_check()?;
+        if !self.owns_blobs() {
+            return Ok(());
+        }
+
+        let removed = self
+            .blobs
+            .remove_unreferenced(&referenced_blobs, u64::MAX)?;
+        if removed > 0 {
+            info!("Removed {removed} unreferenced blobs");
+        }
+
+        Ok(())
+    }
+
+    /// Prepares the storage to serve requests after a restart. When the whole storage was
+    /// checked, the blobs it doesn't reference are removed right away, otherwise they are
+    /// removed by the background verification
+    pub fn recover(&mut self) -> Result<Recovery, TrieError> {
+        let mut recovery = self.storage.recover()?;
+
+        let unapplied = mem::take(&mut self.unapplied);
+        if !unapplied.is_empty() {
+            info!("Applying {} writes of the WAL", unapplied.len());
+            recovery.applied_writes = unapplied.len();
+
+            for operation in unapplied {
+                if let WalOperation::Put {
+                    value: Value::Blob(blob),
+                    ..
+                } = &operation
+                {
+                    // The blob is only removed once a later write of the entry reached the page
+                    match self.blobs.open_blob(blob) {
+                        Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                        Err(e) => return Err(e.into()),
+                        Ok(_) => {}
+                    }
+
+                    if let Some(referenced_blobs) = recovery.referenced_blobs.as_mut() {
+                        referenced_blobs.insert(blob.id);
+                    }
+                }
+
+                if let Err(e) = self.apply(operation.clone()) {
+                    error!("Failed to apply a write of the WAL again: {e:?}, {operation:?}");
+                    recovery.quarantined_writes += 1;
+                }
+            }
+
+            // The writes that can't be applied are kept aside rather than blocking the startup
+            if recovery.quarantined_writes > 0 {
+                let path = self.wal.as_ref().unwrap().quarantine()?;
+                error!(
+                    "{} writes of the WAL were not applied: the WAL is kept in {path:?}",
+                    recovery.quarantined_writes
+                );
+            }
+            self.checkpoint()?;
+        }
+
+        if let Some(referenced_blobs) = &recovery.referenced_blobs {
+            let removed = self.blobs.remove_unreferenced(referenced_blobs, u64::MAX)?;
+            if removed > 0 {
+                info!("Removed {removed} unreferenced blobs");
+            }
+        }
+
+        Ok(recovery)
     }
 
-    /// Runs a sanity check (opens all partitions)
-    pub fn sanity_check(&mut self) -> Result<(), std::io::Error> {
-        let mut nodes = self.root.get_children_prefixes();
+    /// Whether the blobs the storage doesn't reference can be removed
+    pub fn owns_blobs(&self) -> bool {
+        self.storage.owns_blobs()
+    }
 
-        while nodes.len() > 0 {
-            let node_prefix = nodes.pop().unwrap();
-            debug!("Checking: {node_prefix}");
+    /// Checks a page of the storage without modifying it
+    pub fn verify_page(&self, prefix: &[u8]) -> Result<Option<PageCheck>, TrieError> {
+        self.storage.verify_page(prefix)
+    }
+
+    /// Flushes the entries and records a clean shutdown, so that the next startup doesn't
+    /// check the pages. Nothing must be written afterwards
+    pub fn close(&mut self) -> Result<(), TrieError> {
+        self.storage.close()?;
+        self.unsynced_writes = 0;
 
-            let node = TreeNode::from(
-                self.base_path.clone(),
-                &node_prefix,
-                true,
-                true,
-                self.sync_after_write,
-            )?;
+        if let Some(wal) = self
+            .wal
+            .as_mut()
+            .filter(|_| self.unapplied.is_empty() && self.interrupted.is_empty())
+        {
+            wal.reset()?;
+        }
 
-            nodes.append(&mut node.get_children_prefixes());
+        if let Some(dirty_pages) = &self.dirty_pages {
+            dirty_pages.close()?;
         }
 
         Ok(())
@@ -66,137 +235,554 @@ impl NodeReader {
     /// Returns a list of entries whose keys are withing the given range
     pub fn get_range(
         &mut self,
-        start_key: &String,
-        end_key: &String,
-    ) -> Result<Vec<(String, String)>, TrieError> {
-        let FindRangeChildrenResult {
-            values: mut result,
-            child_prefixes: mut nodes,
-        } = self
-            .root
-            .find_range_children(start_key, end_key, self.max_range_response_size)?;
-
-        nodes.reverse();
+        start_key: &str,
+        end_key: &str,
+    ) -> Result<Vec<(String, Vec<u8>)>, TrieError> {
+        self.complete_interrupted()?;
+        let start_key = &self.normalize_key(start_key.to_string());
+        let end_key = &self.normalize_key(end_key.to_string());
 
-        while nodes.len() > 0 && result.len() < self.max_range_response_size.unwrap_or(usize::MAX) {
-            let limit = self.max_range_response_size.map(|l| l - result.len());
-            let node_prefix = nodes.pop().unwrap();
-            let mut r = self.on_owner(&node_prefix, |n| {
-                n.find_range_children(start_key, end_key, limit)
-            })?;
+        self.storage
+            .range(start_key, end_key, self.max_range_response_size)?
+            .into_iter()
+            .map(|(key, value)| Ok((key, self.read_value(value)?)))
+            .collect()
+    }
 
-            r.child_prefixes.reverse();
+    /// Inserts an entry. Values too large to fit in a page are written to a blob file
+    pub fn insert(&mut self, key: String, value: Vec<u8>) -> Result<(), TrieError> {
+        self.insert_with_expiry(key, value, None)
+    }
 
-            result.append(&mut r.values);
-            nodes.append(&mut r.child_prefixes);
+    /// Inserts an entry that is hidden, then reclaimed, from the given unix timestamp (in
+    /// seconds). Large values are written to their blob file while the store is held: callers
+    /// that can write them beforehand use `insert_value`
+    pub fn insert_with_expiry(
+        &mut self,
+        key: String,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+    ) -> Result<(), TrieError> {
+        if value.len() > tree_node::MAX_VALUE_LEN {
+            return Err(TrieError::ValueError);
         }
 
-        Ok(result)
+        let value = if value.len() > tree_node::MAX_INLINE_VALUE_LEN {
+            Value::Blob(self.blobs.write(&value)?)
+        } else {
+            Value::Inline(value)
+        };
+        self.insert_value(key, value, expires_at, &Precondition::default(), None)?;
+
+        Ok(())
     }
 
-    /// Inserts an entry
-    pub fn insert(&mut self, mut key: String, value: String) -> Result<(), TrieError> {
-        key = key.to_lowercase();
+    /// Inserts an entry whose value is ready to be stored: values too large to fit in a page
+    /// are written to a blob file beforehand (e.g. while they are being uploaded). The entry is
+    /// written provided its current version satisfies the precondition, with the given
+    /// version, e.g. the one given by the main to a replica, or else the sequence number of the
+    /// write. The blob is removed if the entry can't be inserted. Returns the new version
+    pub fn insert_value(
+        &mut self,
+        key: String,
+        value: Value,
+        expires_at: Option<u64>,
+        precondition: &Precondition,
+        version: Option<Version>,
+    ) -> Result<Version, TrieError> {
+        let precondition = self.check_precondition(&key, precondition);
+        let version = version.unwrap_or(self.next_version(1));
+        let operations = vec![WalOperation::Put {
+            key: self.normalize_key(key),
+            value,
+            expires_at,
+            version,
+        }];
+        if let Err(e) = precondition {
+            self.remove_blobs(&operations)?;
+            return Err(e);
+        }
 
-        self.on_owner(&key.clone(), |n| {
-            n.insert(key.to_lowercase(), value)?;
-            Ok(())
-        })
+        self.write(operations)?;
+        Ok(version)
     }
 
-    /// Bulk inserts a list of entries
-    pub fn bulk_insert(&mut self, entries: HashMap<String, String>) -> Result<(), TrieError> {
-        for (key, value) in entries {
-            self.insert(key, value)?;
+    /// Returns a conflict unless the current version of an entry satisfies the precondition.
+    /// The entry can't change before the write that follows as long as the store stays locked
+    pub fn check_precondition(
+        &mut self,
+        key: &str,
+        precondition: &Precondition,
+    ) -> Result<(), TrieError> {
+        if *precondition != Precondition::default() && !precondition.holds(self.version(key)?) {
+            return Err(TrieError::Conflict(vec![key.to_string()]));
         }
 
         Ok(())
     }
 
-    /// Returns the value of an entry
-    pub fn get(&mut self, key: &str) -> Result<String, TrieError> {
-        self.on_owner(key, move |n| n.get(key))
+    /// Replaces the value of an entry only if it is still `expected` (`None`: the entry
+    /// doesn't exist), compared byte for byte. A `None` value deletes the entry. Returns a
+    /// conflict otherwise
+    pub fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<&[u8]>,
+        value: Option<Vec<u8>>,
+    ) -> Result<(), TrieError> {
+        let current = match self.get_entry(&key) {
+            Ok((current, _)) => Some(current),
+            Err(TrieError::NotFound) => None,
+            Err(e) => return Err(e),
+        };
+        let matches = match (current, expected) {
+            (None, None) => true,
+            (Some(Value::Inline(current)), Some(expected)) => current == expected,
+            // Blobs are only read when their length matches
+            (Some(Value::Blob(blob)), Some(expected)) => {
+                blob.length == expected.len() as u64
+                    && self.read_value(Value::Blob(blob))? == expected
+            }
+            _ => false,
+        };
+        if !matches {
+            return Err(TrieError::Conflict(vec![key]));
+        }
+
+        match value {
+            Some(value) => self.insert(key, value),
+            None => self.delete(key),
+        }
     }
 
-    fn read_root(base_path: &PathBuf, sync_after_write: bool) -> Result<TreeNode, std::io::Error> {
-        let root = match TreeNode::from(base_path.clone(), "", true, true, sync_after_write) {
-            Ok(r) => r,
-            Err(_) => TreeNode::create(base_path.clone(), "", sync_after_write)?,
-        };
+    /// Inserts a list of entries atomically, in order: either all of them or none of them are
+    /// inserted, even across a crash. A key given several times keeps its last value, but
+    /// distinct keys that are the same once normalized are refused. The entries get the given
+    /// version, or else the sequence number of the last write. Returns the version of the entries
+    pub fn bulk_insert(
+        &mut self,
+        entries: Vec<(String, Vec<u8>)>,
+        version: Option<Version>,
+    ) -> Result<Version, TrieError> {
+        let mut keys = HashMap::with_capacity(entries.len());
+        for (key, _) in &entries {
+            let given = keys.entry(self.normalize_key(key.clone())).or_insert(key);
+            if *given != key {
+                return Err(TrieError::KeyError);
+            }
+        }
 
-        Ok(root)
+        self.transaction(
+            Transaction {
+                reads: vec![],
+                writes: entries
+                    .into_iter()
+                    .map(|(key, value)| TransactionWrite::Put { key, value })
+                    .collect(),
+            },
+            version,
+        )
     }
 
-    /// Iterates over the tree structure to find the owning node, then executed an operation against it
-    /// Used by all other methods in this struct
-    fn on_owner<T, U: FnOnce(&mut TreeNode) -> Result<T, TrieError>>(
+    /// Applies the writes of a transaction atomically, in order, unless one of the entries it
+    /// read has another version by now: the keys of those entries are returned as a conflict.
+    /// The writes are all checked before any is written, then logged as a single WAL record.
+    /// The entries written get the given version, or else the sequence number of the last
+    /// write. Returns the version of the entries written
+    pub fn transaction(
         &mut self,
-        key: &str,
-        func: U,
-    ) -> Result<T, TrieError> {
-        let mut node = &mut self.root;
-        let mut traversed_nodes = vec![];
-        loop {
-            node = match node.find_owner(&key) {
-                SearchResult::Current() => {
-                    break;
+        transaction: Transaction,
+        version: Option<Version>,
+    ) -> Result<Version, TrieError> {
+        let writes: Vec<TransactionWrite> = transaction
+            .writes
+            .into_iter()
+            .map(|write| match write {
+                TransactionWrite::Put { key, value } => TransactionWrite::Put {
+                    key: self.normalize_key(key),
+                    value,
+                },
+                TransactionWrite::Delete { key } => TransactionWrite::Delete {
+                    key: self.normalize_key(key),
+                },
+            })
+            .collect();
+        for write in &writes {
+            if !tree_node::is_valid_key(write.key()) {
+                return Err(TrieError::KeyError);
+            }
+            if let TransactionWrite::Put { value, .. } = write {
+                if value.len() > tree_node::MAX_VALUE_LEN {
+                    return Err(TrieError::ValueError);
                 }
-                SearchResult::Child(prefix) => {
-                    if let Some(entry) = self
-                        .data_cache
-                        .remove(&prefix.to_string())
-                        .or(self.metadata_cache.remove(&prefix.to_string()))
-                    {
-                        traversed_nodes.push(entry);
-                        traversed_nodes.last_mut().unwrap()
+            }
+        }
+
+        let mut conflicts = vec![];
+        for (key, version) in transaction.reads {
+            if self.version(&key)? != version {
+                conflicts.push(key);
+            }
+        }
+        if !conflicts.is_empty() {
+            return Err(TrieError::Conflict(conflicts));
+        }
+
+        let version = version.unwrap_or(self.next_version(writes.len() as u64));
+        let mut operations = Vec::with_capacity(writes.len());
+        for write in writes {
+            operations.push(match write {
+                TransactionWrite::Put { key, value } => {
+                    let value = if value.len() > tree_node::MAX_INLINE_VALUE_LEN {
+                        match self.blobs.write(&value) {
+                            Ok(blob) => Value::Blob(blob),
+                            Err(e) => {
+                                self.remove_blobs(&operations)?;
+                                return Err(e.into());
+                            }
+                        }
                     } else {
-                        debug!("Cache miss: {prefix}");
-                        traversed_nodes.push(TreeNode::from(
-                            self.base_path.clone(),
-                            &prefix,
-                            true,
-                            false,
-                            self.sync_after_write,
-                        )?);
-                        traversed_nodes.last_mut().unwrap()
+                        Value::Inline(value)
+                    };
+
+                    WalOperation::Put {
+                        key,
+                        value,
+                        expires_at: None,
+                        version,
                     }
                 }
-                SearchResult::NonExistingChild(prefix) => {
-                    let n =
-                        TreeNode::create(self.base_path.clone(), &prefix, self.sync_after_write)?;
-                    node.register_child(prefix.clone());
-                    node.save_metadata()?;
-
-                    traversed_nodes.push(n);
-                    traversed_nodes.last_mut().unwrap()
-                }
+                TransactionWrite::Delete { key } => WalOperation::Delete { key },
+            });
+        }
+
+        self.write(operations)?;
+        Ok(version)
+    }
+
+    /// Returns the version of an entry, or `None` if it doesn't exist
+    pub fn version(&mut self, key: &str) -> Result<Option<Version>, TrieError> {
+        match self.get_entry(key) {
+            Ok((_, version)) => Ok(Some(version)),
+            Err(TrieError::NotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Returns the value of an entry
+    pub fn get(&mut self, key: &str) -> Result<Vec<u8>, TrieError> {
+        let (value, _) = self.get_entry(key)?;
+
+        self.read_value(value)
+    }
+
+    /// Returns the value of an entry with its version, without reading the value from its
+    /// blob file. The blob is opened right away, so it can still be read after the entry is
+    /// overwritten
+    pub fn open(&mut self, key: &str) -> Result<(ValueReader, Version), TrieError> {
+        let (value, version) = self.get_entry(key)?;
+
+        match value {
+            Value::Inline(value) => Ok((ValueReader::Inline(value), version)),
+            Value::Blob(blob) => Ok((
+                ValueReader::Blob(self.blobs.open_blob(&blob)?, blob.length),
+                version,
+            )),
+        }
+    }
+
+    /// Makes the entries written so far durable
+    pub fn flush(&mut self) -> Result<(), TrieError> {
+        match &self.wal {
+            Some(wal) => wal.sync()?,
+            None => self.storage.flush()?,
+        }
+        self.unsynced_writes = 0;
+
+        Ok(())
+    }
+
+    /// Syncs the pages, then empties the WAL. The WAL is kept while some of its writes
+    /// haven't been applied, e.g. again by `recover`
+    pub fn checkpoint(&mut self) -> Result<(), TrieError> {
+        let Some(wal) = self
+            .wal
+            .as_mut()
+            .filter(|_| self.unapplied.is_empty() && self.interrupted.is_empty())
+        else {
+            return Ok(());
+        };
+
+        self.storage.flush()?;
+        wal.reset()?;
+        self.unsynced_writes = 0;
+
+        Ok(())
+    }
+
+    /// Returns what must be synced to make the writes made so far durable
+    pub fn pending_sync(&mut self) -> Result<PendingSync, TrieError> {
+        match &self.wal {
+            Some(wal) => Ok(PendingSync::file(Box::new(wal.handle()?))),
+            None => self.storage.pending_sync(),
+        }
+    }
+
+    /// Sequence number of the last write. Writes are numbered in the order they are applied,
+    /// and the numbers keep increasing across restarts when the store has a WAL
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Returns the sync the last write must wait for before it is acknowledged, when writes
+    /// are synced by group commit. It must be waited for once the store is released. Returns
+    /// an error if the last write was applied, and may be replicated, but its sync failed
+    pub fn commit(&mut self) -> Result<Option<Commit>, TrieError> {
+        match self.sync_error.take() {
+            Some(e) => Err(e),
+            None => Ok(self.commit.take()),
+        }
+    }
+
+    /// Syncs the written pages every N seconds in the background, when the store is
+    /// configured to. The thread stops once the store is dropped
+    pub fn sync_periodically(store: &Arc<RwLock<NodeReader>>) {
+        let FSyncStrategy::EverySeconds(seconds) = store.read().unwrap().fsync else {
+            return;
+        };
+
+        let store = Arc::downgrade(store);
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(seconds.get()));
+
+            let Some(store) = store.upgrade() else {
+                return;
+            };
+            let Ok(mut store) = store.write() else {
+                return;
             };
+
+            store.sync_unsynced_writes();
+        });
+    }
+
+    /// Syncs the writes acknowledged since the last sync, if any. A failed sync is retried by
+    /// the next one
+    fn sync_unsynced_writes(&mut self) {
+        if self.unsynced_writes > 0 {
+            if let Err(e) = self.flush() {
+                error!("Failed to sync the written pages: {e:?}");
+            }
+        }
+    }
+
+    /// Returns the writes that a crash of the host can lose
+    pub fn durability_window(&self) -> DurabilityWindow {
+        DurabilityWindow::new(self.fsync, self.unsynced_writes)
+    }
+
+    /// Returns the store of the values too large to fit in a page
+    pub fn blob_store(&self) -> Arc<BlobStore> {
+        self.blobs.clone()
+    }
+
+    /// Returns the counters of the store
+    pub fn stats(&self) -> StatsSnapshot {
+        self.stats.snapshot()
+    }
+
+    /// Version of the entries written by the next batch of writes: the sequence number of its
+    /// last write, as numbered by `log`
+    fn next_version(&self, writes: u64) -> Version {
+        self.sequence + writes
+    }
+
+    /// Appends a batch of writes to the WAL, then applies them to the storage. The blobs of
+    /// writes that can't be logged are removed
+    fn write(&mut self, operations: Vec<WalOperation>) -> Result<(), TrieError> {
+        let writes = operations.len() as u64;
+        if let Err(e) = self.log(&operations) {
+            self.remove_blobs(&operations)?;
+            return Err(e);
+        }
+
+        let mut operations = operations.into_iter();
+        while let Some(operation) = operations.next() {
+            if let Err(e) = self.apply(operation.clone()) {
+                // The batch is logged: the rest of it must be applied before anything is read
+                self.interrupted = std::iter::once(operation).chain(operations).collect();
+                error!("Failed to apply a batch of writes, the rest of it is applied again by the next request: {e:?}");
+                return Err(e);
+            }
         }
 
-        let r = func(node);
+        // The writes are applied from now on: they are acknowledged and replicated even if
+        // they can't be synced
+        self.record_writes(writes);
 
-        for node in traversed_nodes.into_iter() {
-            if node.has_data() && node.prefix() != "" {
-                self.data_cache.set(node.prefix().to_string(), node);
-            } else {
-                self.metadata_cache.set(node.prefix().to_string(), node);
+        Ok(())
+    }
+
+    /// Appends a batch of writes to the WAL, numbering them
+    fn log(&mut self, operations: &[WalOperation]) -> Result<(), TrieError> {
+        if self.read_only {
+            return Err(TrieError::ReadOnly);
+        }
+
+        // Writes that the storage would refuse must not reach the WAL
+        for operation in operations {
+            match operation {
+                WalOperation::Put { key, value, .. } => tree_node::check_write(key, Some(value))?,
+                WalOperation::Delete { key } => tree_node::check_write(key, None)?,
+            }
+        }
+        self.complete_interrupted()?;
+
+        let writes = operations.len() as u64;
+        self.sequence = match self.wal.as_mut() {
+            Some(wal) if writes > 0 => {
+                let sequence = wal.append(operations)?;
+                // The pages may reach the disk before the log: the log must hold the whole
+                // batch first, or a crash could leave part of it in the pages
+                if writes > 1 {
+                    wal.sync()?;
+                }
+                sequence
             }
+            _ => self.sequence + writes,
+        };
+
+        Ok(())
+    }
+
+    /// Applies the rest of a batch that failed to be applied. Should it fail again, the store
+    /// becomes read-only: reads are then served, and may see part of the batch
+    fn complete_interrupted(&mut self) -> Result<(), TrieError> {
+        if self.read_only {
+            return Ok(());
         }
 
-        r
+        while let Some(operation) = self.interrupted.front() {
+            if let Err(e) = self.apply(operation.clone()) {
+                error!("Failed to complete a batch of writes, writes are refused until the node restarts: {e:?}");
+                self.read_only = true;
+                return Err(e);
+            }
+            self.interrupted.pop_front();
+        }
+
+        Ok(())
+    }
+
+    fn remove_blobs(&self, operations: &[WalOperation]) -> Result<(), TrieError> {
+        for operation in operations {
+            if let WalOperation::Put {
+                value: Value::Blob(blob),
+                ..
+            } = operation
+            {
+                BlobStore::remove(&self.base_path, blob)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn apply(&mut self, operation: WalOperation) -> Result<(), TrieError> {
+        match operation {
+            WalOperation::Put {
+                key,
+                value,
+                expires_at,
+                version,
+            } => self.storage.put(key, value, expires_at, version),
+            WalOperation::Delete { key } => self.storage.delete(key),
+        }
+    }
+
+    /// Syncs the writes as set by the strategy, and checkpoints the WAL once it is too long
+    fn record_writes(&mut self, writes: u64) {
+        match (self.fsync, &self.group_commit, &self.wal) {
+            (FSyncStrategy::Strict, Some(group_commit), _) => {
+                self.commit = Some(group_commit.record_write(self.sequence));
+            }
+            // Like with group commit, the sync failure is reported once the writes are replicated
+            (FSyncStrategy::Strict, None, Some(wal)) => {
+                if let Err(e) = wal.sync() {
+                    self.unsynced_writes += writes;
+                    self.sync_error = Some(TrieError::IoError(e));
+                }
+            }
+            // The storage synced every write
+            (FSyncStrategy::Strict, None, None) => {}
+            (FSyncStrategy::EveryOperations(n), ..) => {
+                self.unsynced_writes += writes;
+                if self.unsynced_writes >= n.get() {
+                    self.sync_unsynced_writes();
+                }
+            }
+            _ => self.unsynced_writes += writes,
+        }
+
+        if self
+            .wal
+            .as_ref()
+            .is_some_and(|wal| wal.length() >= WAL_CHECKPOINT_LENGTH)
+        {
+            if let Err(e) = self.checkpoint() {
+                error!("Failed to checkpoint the WAL: {e:?}");
+            }
+        }
+    }
+
+    fn get_entry(&mut self, key: &str) -> Result<(Value, Version), TrieError> {
+        self.complete_interrupted()?;
+        let key = self.normalize_key(key.to_string());
+
+        self.storage.get(&key)
+    }
+
+    /// Returns the bytes of a value, reading them from its blob file if needed
+    fn read_value(&self, value: Value) -> Result<Vec<u8>, TrieError> {
+        match value {
+            Value::Inline(value) => Ok(value),
+            Value::Blob(blob) => self.blobs.read(&blob)?.ok_or_else(|| {
+                TrieError::Corrupted(format!("blob {:016x}: checksum mismatch", blob.id))
+            }),
+        }
+    }
+
+    /// Returns the key an entry is stored under: keys are lowercased unless the store is
+    /// case-sensitive
+    pub fn normalize_key(&self, key: String) -> String {
+        if self.case_sensitive {
+            key
+        } else {
+            key.to_lowercase()
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::blob_store::BLOB_DIRECTORY;
+    use crate::configuration::StorageEngine;
+    use crate::memory_storage::MemoryStorage;
+    use crate::page_storage;
+    use crate::transaction::Versions;
+    use crate::tree_node::{MAX_INLINE_VALUE_LEN, MAX_VALUE_LEN};
+    use crate::wal::WAL_FILE;
+    use std::fs;
+    use std::io::Read;
+    use std::sync::atomic::Ordering;
     use tempfile::tempdir;
 
     #[test]
     fn test_node_reader_creation() {
         let temp_dir = tempdir().unwrap();
         let path = temp_dir.path().to_path_buf();
-        let reader = NodeReader::new(path, 10, Some(1000), false);
+        let reader = NodeReader::new(path, 10, Some(1000), PageOptions::default());
 
         assert!(reader.is_ok());
     }
@@ -205,14 +791,14 @@ mod tests {
     fn test_node_reader_cache_retrieval() {
         let temp_dir = tempdir().unwrap();
         let path = temp_dir.path().to_path_buf();
-        let mut reader = NodeReader::new(path, 10, Some(1000), false).unwrap();
+        let mut reader = NodeReader::new(path, 10, Some(1000), PageOptions::default()).unwrap();
 
         for i in 0..100000 {
             reader
-                .insert(format!("key{i:0>8}"), format!("value{i:0>8}"))
+                .insert(format!("key{i:0>8}"), format!("value{i:0>8}").into_bytes())
                 .unwrap();
             let read_result = reader.get(&format!("key{i:0>8}")).unwrap();
-            assert_eq!(read_result, format!("value{i:0>8}"));
+            assert_eq!(read_result, format!("value{i:0>8}").into_bytes());
         }
     }
 
@@ -220,41 +806,990 @@ mod tests {
     fn test_get_range() {
         let temp_dir = tempdir().unwrap();
         let path = temp_dir.path().to_path_buf();
-        let mut reader = NodeReader::new(path, 10, None, false).unwrap();
+        let mut reader = NodeReader::new(path, 10, None, PageOptions::default()).unwrap();
 
         for i in 0..100000 {
             reader
-                .insert(format!("key{i:0>8}"), format!("value{i:0>8}"))
+                .insert(format!("key{i:0>8}"), format!("value{i:0>8}").into_bytes())
                 .unwrap();
         }
 
-        assert_eq!(
-            reader
-                .get_range(&"key00090000".to_string(), &"z".to_string())
-                .unwrap()
-                .len(),
-            10000
-        );
+        assert_eq!(reader.get_range("key00090000", "z").unwrap().len(), 10000);
     }
 
     #[test]
     fn test_get_range_limit() {
         let temp_dir = tempdir().unwrap();
         let path = temp_dir.path().to_path_buf();
-        let mut reader = NodeReader::new(path, 10, Some(1000), false).unwrap();
+        let mut reader = NodeReader::new(path, 10, Some(1000), PageOptions::default()).unwrap();
 
         for i in 0..100000 {
             reader
-                .insert(format!("key{i:0>8}"), format!("value{i:0>8}"))
+                .insert(format!("key{i:0>8}"), format!("value{i:0>8}").into_bytes())
+                .unwrap();
+        }
+
+        assert_eq!(reader.get_range("key00090000", "z").unwrap().len(), 1000);
+    }
+
+    #[test]
+    fn test_overwrites_are_compacted() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        let mut reader = NodeReader::new(path.clone(), 10, None, PageOptions::default()).unwrap();
+
+        // Without compaction the root page would grow past the split threshold
+        let value = "v".repeat(tree_node::MAX_INLINE_VALUE_LEN - 3);
+        for i in 0..400 {
+            reader
+                .insert("key".to_string(), format!("{value}{i:0>3}").into_bytes())
+                .unwrap();
+        }
+
+        let stats = reader.stats();
+        assert!(stats.compactions > 0);
+        assert_eq!(stats.splits, 0);
+        assert!(
+            fs::metadata(path.join("_root.dat")).unwrap().len() < tree_node::SPLIT_THRESHOLD as u64
+        );
+
+        let mut reader = NodeReader::new(path, 10, None, PageOptions::default()).unwrap();
+        assert!(reader.get("key").unwrap().ends_with(b"399"));
+    }
+
+    /// Inserts entries until the root page splits and returns the number of entries inserted
+    fn insert_until_split(reader: &mut NodeReader) -> usize {
+        let value = vec![b'v'; 4096];
+        let mut count = 0;
+
+        while reader.stats().splits == 0 {
+            reader
+                .insert(format!("key{count:0>8}"), value.clone())
+                .unwrap();
+            count += 1;
+        }
+
+        count
+    }
+
+    /// Deletes the last entries inserted by `insert_until_split` until the children of the root
+    /// are merged back, and returns the number of entries deleted
+    fn delete_until_merge(reader: &mut NodeReader, count: usize) -> usize {
+        let mut deleted = 0;
+        while reader.stats().merges == 0 {
+            deleted += 1;
+            reader
+                .delete(format!("key{:0>8}", count - deleted))
+                .unwrap();
+        }
+
+        deleted
+    }
+
+    /// Runs in a child process spawned by `test_split_recovers_from_crash`, which aborts at the
+    /// step set in `KVS_CRASH_POINT`
+    #[test]
+    #[ignore]
+    fn split_until_crash() {
+        let path = PathBuf::from(std::env::var("KVS_CRASH_DIR").unwrap());
+        let mut reader = NodeReader::new(path, 10, None, PageOptions::default()).unwrap();
+
+        insert_until_split(&mut reader);
+    }
+
+    #[test]
+    fn test_split_recovers_from_crash() {
+        let temp_dir = tempdir().unwrap();
+        let mut reader = NodeReader::new(
+            temp_dir.path().to_path_buf(),
+            10,
+            None,
+            PageOptions::default(),
+        )
+        .unwrap();
+        let count = insert_until_split(&mut reader);
+
+        for step in [
+            "split:children_written",
+            "split:parent_written",
+            "split:intent_written",
+            "split:child_renamed",
+            "split:parent_renamed",
+        ] {
+            let temp_dir = tempdir().unwrap();
+            let path = temp_dir.path().to_path_buf();
+
+            let status = std::process::Command::new(std::env::current_exe().unwrap())
+                .args([
+                    "--exact",
+                    "node_reader::tests::split_until_crash",
+                    "--ignored",
+                ])
+                .env("KVS_CRASH_POINT", step)
+                .env("KVS_CRASH_DIR", &path)
+                .stdout(std::process::Stdio::null())
+                .stderr(std::process::Stdio::null())
+                .status()
+                .unwrap();
+            assert!(!status.success(), "{step}: the process did not crash");
+
+            let mut reader =
+                NodeReader::new(path.clone(), 10, None, PageOptions::default()).unwrap();
+            reader.sanity_check().unwrap();
+
+            // Every entry is visible exactly once, either in the old page or in the new children
+            let entries = reader.get_range("key", "z").unwrap();
+            assert_eq!(entries.len(), count, "{step}");
+            for i in 0..count {
+                assert!(reader.get(&format!("key{i:0>8}")).is_ok(), "{step}: key{i}");
+            }
+
+            for entry in fs::read_dir(&path).unwrap() {
+                let file_path = entry.unwrap().path();
+                if file_path.ends_with(WAL_FILE) {
+                    continue;
+                }
+
+                match file_path.extension().unwrap().to_str().unwrap() {
+                    "dat" => {}
+                    tree_node::BLOOM_FILTER_EXTENSION => {
+                        assert!(file_path.with_extension("dat").exists(), "{step}")
+                    }
+                    extension => panic!("{step}: unexpected {extension} file"),
+                }
+            }
+
+            reader
+                .insert("key99999999".to_string(), b"value".to_vec())
+                .unwrap();
+            assert_eq!(reader.get("key99999999").unwrap(), b"value");
+        }
+    }
+
+    /// Runs in a child process spawned by `test_merge_recovers_from_crash`, which aborts at the
+    /// step set in `KVS_CRASH_POINT`
+    #[test]
+    #[ignore]
+    fn merge_until_crash() {
+        let path = PathBuf::from(std::env::var("KVS_CRASH_DIR").unwrap());
+        let mut reader =
+            NodeReader::new(path, 100 * 1024 * 1024, None, PageOptions::default()).unwrap();
+
+        let count = insert_until_split(&mut reader);
+        delete_until_merge(&mut reader, count);
+    }
+
+    #[test]
+    fn test_merge_recovers_from_crash() {
+        let temp_dir = tempdir().unwrap();
+        let mut reader = NodeReader::new(
+            temp_dir.path().to_path_buf(),
+            100 * 1024 * 1024,
+            None,
+            PageOptions::default(),
+        )
+        .unwrap();
+        let count = insert_until_split(&mut reader);
+        let deleted = delete_until_merge(&mut reader, count);
+
+        for step in [
+            "merge:parent_written",
+            "merge:intent_written",
+            "merge:parent_renamed",
+            "merge:child_removed",
+        ] {
+            let temp_dir = tempdir().unwrap();
+            let path = temp_dir.path().to_path_buf();
+
+            let status = std::process::Command::new(std::env::current_exe().unwrap())
+                .args([
+                    "--exact",
+                    "node_reader::tests::merge_until_crash",
+                    "--ignored",
+                ])
+                .env("KVS_CRASH_POINT", step)
+                .env("KVS_CRASH_DIR", &path)
+                .stdout(std::process::Stdio::null())
+                .stderr(std::process::Stdio::null())
+                .status()
+                .unwrap();
+            assert!(!status.success(), "{step}: the process did not crash");
+
+            let mut reader =
+                NodeReader::new(path.clone(), 10, None, PageOptions::default()).unwrap();
+            reader.sanity_check().unwrap();
+
+            // The entries left are visible exactly once, either in the children or in the parent
+            let entries = reader.get_range("key", "z").unwrap();
+            assert_eq!(entries.len(), count - deleted, "{step}");
+            for i in 0..count {
+                let key = format!("key{i:0>8}");
+                assert_eq!(
+                    reader.get(&key).is_ok(),
+                    i < count - deleted,
+                    "{step}: {key}"
+                );
+            }
+
+            for entry in fs::read_dir(&path).unwrap() {
+                let file_path = entry.unwrap().path();
+                if file_path.ends_with(WAL_FILE) {
+                    continue;
+                }
+
+                match file_path.extension().unwrap().to_str().unwrap() {
+                    "dat" => {}
+                    tree_node::BLOOM_FILTER_EXTENSION => {
+                        assert!(file_path.with_extension("dat").exists(), "{step}")
+                    }
+                    extension => panic!("{step}: unexpected {extension} file"),
+                }
+            }
+
+            reader
+                .insert("key99999999".to_string(), b"value".to_vec())
                 .unwrap();
+            assert_eq!(reader.get("key99999999").unwrap(), b"value");
+        }
+    }
+
+    #[test]
+    fn test_underfilled_children_are_merged() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        let mut reader = NodeReader::new(
+            path.clone(),
+            100 * 1024 * 1024,
+            None,
+            PageOptions::default(),
+        )
+        .unwrap();
+        let count = insert_until_split(&mut reader);
+        assert!(path.join("k.dat").exists());
+
+        let deleted = delete_until_merge(&mut reader, count);
+
+        assert!(!path.join("k.dat").exists());
+        for entry in fs::read_dir(&path).unwrap() {
+            let file_path = entry.unwrap().path();
+            assert!(
+                file_path.file_stem().unwrap() == "_root" || file_path.ends_with(WAL_FILE),
+                "{file_path:?}"
+            );
+        }
+
+        let mut reader = NodeReader::new(path, 10, None, PageOptions::default()).unwrap();
+        reader.sanity_check().unwrap();
+        let entries = reader.get_range("key", "z").unwrap();
+        assert_eq!(entries.len(), count - deleted);
+        assert!(reader
+            .get(&format!("key{:0>8}", count - deleted - 1))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_binary_values() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        let mut reader = NodeReader::new(path.clone(), 10, None, PageOptions::default()).unwrap();
+
+        let value: Vec<u8> = (0..=255).chain((0..=255).rev()).collect();
+        reader.insert("binary".to_string(), value.clone()).unwrap();
+
+        let mut reader = NodeReader::new(path, 10, None, PageOptions::default()).unwrap();
+        assert_eq!(reader.get("binary").unwrap(), value);
+    }
+
+    #[test]
+    fn test_unicode_keys() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        let options = PageOptions {
+            case_sensitive: true,
+            ..PageOptions::default()
+        };
+        let mut reader = NodeReader::new(path.clone(), 10, None, options.clone()).unwrap();
+
+        // Enough entries to split the pages on multi-byte characters and separators
+        let keys: Vec<String> = (0..9000)
+            .map(|i| match i % 3 {
+                0 => format!("user/Ünïcödé:{i:0>5}"),
+                1 => format!("user/ключ-{i:0>5}"),
+                _ => format!("User:{i:0>5}/🔑"),
+            })
+            .collect();
+        let value = vec![b'v'; 4096];
+        for key in &keys {
+            reader.insert(key.clone(), value.clone()).unwrap();
+        }
+        assert!(reader.stats().splits > 1);
+
+        let mut reader = NodeReader::new(path, 10, None, options).unwrap();
+        reader.sanity_check().unwrap();
+        for key in &keys {
+            assert_eq!(reader.get(key).unwrap(), value);
+        }
+
+        let mut expected: Vec<&String> = keys.iter().filter(|k| k.starts_with("user/")).collect();
+        expected.sort();
+        let entries = reader.get_range("user/", "user0").unwrap();
+        assert_eq!(entries.iter().map(|(k, _)| k).collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn test_key_case_sensitivity() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().to_path_buf();
+
+        let mut reader = NodeReader::new(path.clone(), 10, None, PageOptions::default()).unwrap();
+        reader
+            .insert("Key1".to_string(), b"value".to_vec())
+            .unwrap();
+        assert_eq!(reader.get("KEY1").unwrap(), b"value");
+        assert_eq!(reader.get("key1").unwrap(), b"value");
+
+        // Case-insensitive stores can be switched to case-sensitive keys, not the other way
+        let options = PageOptions {
+            case_sensitive: true,
+            ..PageOptions::default()
+        };
+        let mut reader = NodeReader::new(path.clone(), 10, None, options).unwrap();
+        reader
+            .insert("Key1".to_string(), b"other".to_vec())
+            .unwrap();
+        assert_eq!(reader.get("key1").unwrap(), b"value");
+        assert_eq!(reader.get("Key1").unwrap(), b"other");
+        assert!(matches!(reader.get("KEY1"), Err(TrieError::NotFound)));
+
+        assert!(matches!(
+            NodeReader::new(path, 10, None, PageOptions::default()),
+            Err(TrieError::InvalidConfiguration(_))
+        ));
+    }
+
+    #[test]
+    fn test_large_values() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        let blob_count = || fs::read_dir(path.join(BLOB_DIRECTORY)).unwrap().count();
+        let mut reader = NodeReader::new(path.clone(), 10, None, PageOptions::default()).unwrap();
+
+        let value: Vec<u8> = (0..5 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        reader.insert("large".to_string(), value.clone()).unwrap();
+        assert_eq!(reader.get("large").unwrap(), value);
+        assert_eq!(blob_count(), 1);
+
+        // Overwritten blobs are removed
+        let other = vec![b'o'; MAX_INLINE_VALUE_LEN + 1];
+        reader.insert("large".to_string(), other.clone()).unwrap();
+        assert_eq!(blob_count(), 1);
+        match reader.open("large").unwrap() {
+            (ValueReader::Blob(mut file, length), version) => {
+                let mut content = vec![];
+                file.read_to_end(&mut content).unwrap();
+                assert_eq!(length, other.len() as u64);
+                assert_eq!(content, other);
+                // Blobs are versioned like inline values, by the write that stored them
+                assert_eq!(version, reader.sequence());
+            }
+            (ValueReader::Inline(_), _) => panic!("Expected a blob"),
         }
 
+        // Blobs left over by an interrupted upload are removed at startup
+        reader.blob_store().write(b"orphan").unwrap();
+        assert_eq!(blob_count(), 2);
+
+        let mut reader = NodeReader::new(path.clone(), 10, None, PageOptions::default()).unwrap();
+        reader.sanity_check().unwrap();
+        assert_eq!(blob_count(), 1);
+        assert_eq!(reader.get("large").unwrap(), other);
+
+        reader.delete("large".to_string()).unwrap();
+        assert_eq!(blob_count(), 0);
+
+        assert!(matches!(
+            reader.insert("large".to_string(), vec![0; MAX_VALUE_LEN + 1]),
+            Err(TrieError::ValueError)
+        ));
+    }
+
+    #[test]
+    fn test_expired_keys_are_hidden() {
+        let temp_dir = tempdir().unwrap();
+        let mut reader = NodeReader::new(
+            temp_dir.path().to_path_buf(),
+            10,
+            None,
+            PageOptions::default(),
+        )
+        .unwrap();
+
+        let now = tree_node::unix_time();
+        reader
+            .insert_with_expiry("session1".to_string(), b"old".to_vec(), Some(now))
+            .unwrap();
+        reader
+            .insert_with_expiry("session2".to_string(), b"new".to_vec(), Some(now + 3600))
+            .unwrap();
+        reader
+            .insert_with_expiry(
+                "session3".to_string(),
+                vec![b'v'; MAX_INLINE_VALUE_LEN + 1],
+                Some(now),
+            )
+            .unwrap();
+
+        assert!(matches!(reader.get("session1"), Err(TrieError::NotFound)));
+        assert!(matches!(reader.get("session3"), Err(TrieError::NotFound)));
+        assert_eq!(reader.get("session2").unwrap(), b"new");
         assert_eq!(
+            reader.get_range("session0", "session9").unwrap(),
+            vec![("session2".to_string(), b"new".to_vec())]
+        );
+
+        // Inserting the key again without expiry makes it permanent
+        reader
+            .insert("session1".to_string(), b"value".to_vec())
+            .unwrap();
+        assert_eq!(reader.get("session1").unwrap(), b"value");
+    }
+
+    #[test]
+    fn test_segment_storage() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        let options = || PageOptions {
+            storage: page_storage::open(StorageEngine::Segments, &path).unwrap(),
+            ..PageOptions::default()
+        };
+
+        let mut reader = NodeReader::new(path.clone(), 10, None, options()).unwrap();
+        let count = insert_until_split(&mut reader);
+        reader
+            .insert("blob".to_string(), vec![b'v'; MAX_INLINE_VALUE_LEN + 1])
+            .unwrap();
+        drop(reader);
+
+        let mut reader = NodeReader::new(path.clone(), 10, None, options()).unwrap();
+        reader.sanity_check().unwrap();
+        assert_eq!(reader.get_range("key", "z").unwrap().len(), count);
+        for i in 0..count {
+            assert!(reader.get(&format!("key{i:0>8}")).is_ok(), "key{i}");
+        }
+        assert_eq!(reader.get("blob").unwrap().len(), MAX_INLINE_VALUE_LEN + 1);
+
+        // Pages are only stored in the segments
+        for entry in fs::read_dir(&path).unwrap() {
+            let file_path = entry.unwrap().path();
+            let extension = file_path.extension().and_then(|e| e.to_str());
+            assert!(
+                extension == Some("seg")
+                    || extension == Some("table")
+                    || file_path.ends_with(BLOB_DIRECTORY)
+                    || file_path.ends_with(WAL_FILE),
+                "{file_path:?}"
+            );
+        }
+
+        assert!(page_storage::open(StorageEngine::Files, &path).is_err());
+    }
+
+    #[test]
+    fn test_storages_behave_alike() {
+        let temp_dir = tempdir().unwrap();
+        let trie_path = temp_dir.path().join("trie");
+        let memory_path = temp_dir.path().join("memory");
+        fs::create_dir_all(&trie_path).unwrap();
+        fs::create_dir_all(&memory_path).unwrap();
+
+        let readers = [
+            NodeReader::new(trie_path, 10, Some(100), PageOptions::default()).unwrap(),
+            NodeReader::with_storage(
+                Box::new(MemoryStorage::open(memory_path.clone()).unwrap()),
+                memory_path,
+                Some(100),
+                PageOptions::default(),
+            )
+            .unwrap(),
+        ];
+
+        let now = tree_node::unix_time();
+        for mut reader in readers {
+            for i in 0..1000 {
+                reader
+                    .insert(format!("Key{i:0>4}"), format!("value{i}").into_bytes())
+                    .unwrap();
+            }
             reader
-                .get_range(&"key00090000".to_string(), &"z".to_string())
-                .unwrap()
-                .len(),
-            1000
+                .insert("key0001".to_string(), vec![b'v'; MAX_INLINE_VALUE_LEN + 1])
+                .unwrap();
+            reader
+                .insert_with_expiry("key0002".to_string(), b"expired".to_vec(), Some(now))
+                .unwrap();
+            reader.delete("KEY0003".to_string()).unwrap();
+            reader.flush().unwrap();
+
+            assert_eq!(reader.get("key0000").unwrap(), b"value0");
+            assert_eq!(
+                reader.get("key0001").unwrap().len(),
+                MAX_INLINE_VALUE_LEN + 1
+            );
+            assert!(matches!(reader.get("key0002"), Err(TrieError::NotFound)));
+            assert!(matches!(reader.get("key0003"), Err(TrieError::NotFound)));
+            assert!(matches!(
+                reader.get(&"k".repeat(tree_node::MAX_KEY_LEN + 1)),
+                Err(TrieError::KeyError)
+            ));
+            assert!(matches!(
+                reader.insert(String::new(), b"value".to_vec()),
+                Err(TrieError::KeyError)
+            ));
+
+            let entries = reader.get_range("key0000", "key0999").unwrap();
+            assert_eq!(entries.len(), 100);
+            assert_eq!(entries[2].0, "key0004");
+            // Inverted bounds hold no key, once normalized as well
+            assert!(reader.get_range("key0999", "key0000").unwrap().is_empty());
+            assert!(reader.get_range("KEY0999", "key0001").unwrap().is_empty());
+
+            reader.sanity_check().unwrap();
+            assert_eq!(
+                reader.get("key0001").unwrap().len(),
+                MAX_INLINE_VALUE_LEN + 1
+            );
+        }
+    }
+
+    #[test]
+    fn test_writes_are_synced_by_the_strategy() {
+        let open = |fsync| {
+            let temp_dir = tempdir().unwrap();
+            let options = PageOptions {
+                fsync,
+                ..PageOptions::default()
+            };
+            let reader =
+                NodeReader::new(temp_dir.path().to_path_buf(), 1024, None, options).unwrap();
+
+            (temp_dir, reader)
+        };
+        let unsynced = |reader: &NodeReader| reader.durability_window().unsynced_writes;
+
+        let (_dir, mut reader) = open(FSyncStrategy::Strict);
+        reader.insert("a".to_string(), b"1".to_vec()).unwrap();
+        assert_eq!(unsynced(&reader), 0);
+        assert_eq!(reader.durability_window().max_unsynced_writes, Some(0));
+
+        let every_3 = FSyncStrategy::EveryOperations(3.try_into().unwrap());
+        let (_dir, mut reader) = open(every_3);
+        assert_eq!(reader.durability_window().max_unsynced_writes, Some(2));
+        reader.insert("a".to_string(), b"1".to_vec()).unwrap();
+        reader.delete("a".to_string()).unwrap();
+        assert_eq!(unsynced(&reader), 2);
+        reader.insert("b".to_string(), b"2".to_vec()).unwrap();
+        assert_eq!(unsynced(&reader), 0);
+
+        let every_second = FSyncStrategy::EverySeconds(1.try_into().unwrap());
+        let (_dir, mut reader) = open(every_second);
+        assert_eq!(reader.durability_window().max_unsynced_seconds, Some(1));
+        reader.insert("a".to_string(), b"1".to_vec()).unwrap();
+        assert_eq!(unsynced(&reader), 1);
+        reader.sync_unsynced_writes();
+        assert_eq!(unsynced(&reader), 0);
+    }
+
+    #[test]
+    fn test_writes_are_applied_again_from_the_wal() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        let open = || {
+            let mut reader =
+                NodeReader::new(path.clone(), 1024 * 1024, None, PageOptions::default()).unwrap();
+            let recovery = reader.recover().unwrap();
+
+            (reader, recovery)
+        };
+
+        let (mut reader, _) = open();
+        reader.insert("a".to_string(), b"1".to_vec()).unwrap();
+        reader.close().unwrap();
+        drop(reader);
+        let root_path = path.join("_root.dat");
+        let checkpointed_root = fs::read(&root_path).unwrap();
+
+        let (mut reader, recovery) = open();
+        assert_eq!(recovery.applied_writes, 0);
+        assert_eq!(reader.sequence(), 1);
+        reader.insert("b".to_string(), b"2".to_vec()).unwrap();
+        reader.delete("a".to_string()).unwrap();
+        reader
+            .insert("c".to_string(), vec![b'v'; MAX_INLINE_VALUE_LEN + 1])
+            .unwrap();
+        reader.flush().unwrap();
+        drop(reader);
+
+        // Crash: the writes since the checkpoint never reached the page
+        fs::write(&root_path, checkpointed_root).unwrap();
+
+        let (mut reader, recovery) = open();
+        assert_eq!(recovery.applied_writes, 3);
+        assert_eq!(reader.sequence(), 4);
+        assert_eq!(reader.get("b").unwrap(), b"2");
+        assert!(matches!(reader.get("a"), Err(TrieError::NotFound)));
+        assert_eq!(reader.get("c").unwrap().len(), MAX_INLINE_VALUE_LEN + 1);
+        drop(reader);
+
+        // The writes were checkpointed
+        let (reader, recovery) = open();
+        assert_eq!(recovery.applied_writes, 0);
+        assert_eq!(reader.sequence(), 4);
+        drop(reader);
+
+        // A write that can't be applied again is quarantined rather than blocking the startup
+        let (mut wal, _) = Wal::open(&path, None).unwrap();
+        let invalid_key = "k".repeat(tree_node::MAX_KEY_LEN + 1);
+        wal.append(&[WalOperation::Delete { key: invalid_key }])
+            .unwrap();
+        wal.append(&[WalOperation::Delete {
+            key: "b".to_string(),
+        }])
+        .unwrap();
+        drop(wal);
+
+        let (mut reader, recovery) = open();
+        assert_eq!(recovery.applied_writes, 2);
+        assert_eq!(recovery.quarantined_writes, 1);
+        assert!(matches!(reader.get("b"), Err(TrieError::NotFound)));
+        assert!(path.join("wal.6.quarantined").exists());
+        assert_eq!(Wal::unapplied(&path, None).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn test_bulk_insert_is_all_or_nothing() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        let blob_count = || fs::read_dir(path.join(BLOB_DIRECTORY)).map_or(0, |d| d.count());
+        let mut reader = NodeReader::new(path.clone(), 10, None, PageOptions::default()).unwrap();
+        reader.recover().unwrap();
+
+        let large = vec![b'l'; MAX_INLINE_VALUE_LEN + 1];
+        let entries = |extra: (String, Vec<u8>)| {
+            vec![
+                ("a".to_string(), b"1".to_vec()),
+                ("large".to_string(), large.clone()),
+                extra,
+            ]
+        };
+
+        // Nothing is written when an entry is refused, not even the blobs of the others
+        assert!(matches!(
+            reader.bulk_insert(
+                entries(("k".repeat(tree_node::MAX_KEY_LEN + 1), vec![])),
+                None
+            ),
+            Err(TrieError::KeyError)
+        ));
+        assert!(matches!(
+            reader.bulk_insert(entries(("b".to_string(), vec![0; MAX_VALUE_LEN + 1])), None),
+            Err(TrieError::ValueError)
+        ));
+        assert!(matches!(
+            reader.bulk_insert(entries(("A".to_string(), b"2".to_vec())), None),
+            Err(TrieError::KeyError)
+        ));
+        assert!(reader.get("a").is_err());
+        assert!(reader.get("large").is_err());
+        assert_eq!(blob_count(), 0);
+        assert_eq!(reader.sequence(), 0);
+
+        // A batch is numbered as one write per entry, and applied again whole after a crash.
+        // Its entries share the number of its last write as their version
+        let version = reader
+            .bulk_insert(entries(("b".to_string(), b"2".to_vec())), None)
+            .unwrap();
+        assert_eq!(reader.sequence(), 3);
+        assert_eq!(version, 3);
+        drop(reader);
+
+        let mut reader = NodeReader::new(path.clone(), 10, None, PageOptions::default()).unwrap();
+        assert_eq!(reader.recover().unwrap().applied_writes, 3);
+        assert_eq!(reader.get("a").unwrap(), b"1");
+        assert_eq!(reader.get("b").unwrap(), b"2");
+        assert_eq!(reader.get("large").unwrap(), large);
+        assert_eq!(reader.version("large").unwrap(), Some(3));
+        assert_eq!(blob_count(), 1);
+
+        // The last value of a key given several times is kept
+        let version = reader
+            .bulk_insert(
+                vec![
+                    ("a".to_string(), b"3".to_vec()),
+                    ("b".to_string(), b"3".to_vec()),
+                    ("a".to_string(), b"4".to_vec()),
+                ],
+                None,
+            )
+            .unwrap();
+        assert_eq!(reader.get("a").unwrap(), b"4");
+        assert_eq!(reader.get("b").unwrap(), b"3");
+        assert_eq!(reader.version("a").unwrap(), Some(version));
+    }
+
+    /// Memory storage whose writes of the keys starting with `fail` fail while it is broken
+    struct FailingStorage {
+        storage: MemoryStorage,
+        broken: Arc<std::sync::atomic::AtomicBool>,
+    }
+
+    impl Storage for FailingStorage {
+        fn get(&mut self, key: &str) -> Result<(Value, Version), TrieError> {
+            self.storage.get(key)
+        }
+
+        fn put(
+            &mut self,
+            key: String,
+            value: Value,
+            expires_at: Option<u64>,
+            version: Version,
+        ) -> Result<(), TrieError> {
+            if key.starts_with("fail") && self.broken.load(Ordering::SeqCst) {
+                return Err(TrieError::IoError(ErrorKind::StorageFull.into()));
+            }
+
+            self.storage.put(key, value, expires_at, version)
+        }
+
+        fn delete(&mut self, key: String) -> Result<(), TrieError> {
+            self.storage.delete(key)
+        }
+
+        fn range(
+            &mut self,
+            start_key: &str,
+            end_key: &str,
+            limit: Option<usize>,
+        ) -> Result<Vec<(String, Value)>, TrieError> {
+            self.storage.range(start_key, end_key, limit)
+        }
+
+        fn flush(&mut self) -> Result<(), TrieError> {
+            self.storage.flush()
+        }
+
+        fn sanity_check(&mut self) -> Result<std::collections::HashSet<u64>, TrieError> {
+            self.storage.sanity_check()
+        }
+
+        fn verify_page(&self, prefix: &[u8]) -> Result<Option<PageCheck>, TrieError> {
+            self.storage.verify_page(prefix)
+        }
+    }
+
+    #[test]
+    fn test_interrupted_batches_are_completed_or_make_the_store_read_only() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        let broken = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let mut reader = NodeReader::with_storage(
+            Box::new(FailingStorage {
+                storage: MemoryStorage::open(path.clone()).unwrap(),
+                broken: broken.clone(),
+            }),
+            path,
+            None,
+            PageOptions::default(),
+        )
+        .unwrap();
+        let put = |key: &str| TransactionWrite::Put {
+            key: key.to_string(),
+            value: b"1".to_vec(),
+        };
+        let batch = |keys: &[&str]| Transaction {
+            reads: vec![],
+            writes: keys.iter().map(|key| put(key)).collect(),
+        };
+
+        // The rest of the batch is applied by the next request once the storage recovers
+        assert!(reader
+            .transaction(batch(&["a", "fail1", "b"]), None)
+            .is_err());
+        broken.store(false, Ordering::SeqCst);
+        assert_eq!(reader.get("b").unwrap(), b"1");
+        assert_eq!(reader.get("fail1").unwrap(), b"1");
+
+        // It is only applied again once: the store then refuses writes but serves reads
+        broken.store(true, Ordering::SeqCst);
+        assert!(reader
+            .transaction(batch(&["c", "fail2", "d"]), None)
+            .is_err());
+        assert!(matches!(reader.get("c"), Err(TrieError::IoError(_))));
+        broken.store(false, Ordering::SeqCst);
+        assert_eq!(reader.get("c").unwrap(), b"1");
+        assert!(matches!(reader.get("d"), Err(TrieError::NotFound)));
+        assert!(matches!(
+            reader.insert("e".to_string(), b"1".to_vec()),
+            Err(TrieError::ReadOnly)
+        ));
+    }
+
+    #[test]
+    fn test_transactions_conflict_on_changed_reads() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        let mut reader = NodeReader::new(path, 10, None, PageOptions::default()).unwrap();
+        reader.recover().unwrap();
+
+        reader.insert("alice".to_string(), b"100".to_vec()).unwrap();
+        let version = reader.version("alice").unwrap();
+        assert!(version.is_some());
+        assert_eq!(reader.version("bob").unwrap(), None);
+
+        let written = reader
+            .transaction(
+                Transaction {
+                    reads: vec![("alice".to_string(), version), ("bob".to_string(), None)],
+                    writes: vec![
+                        TransactionWrite::Put {
+                            key: "bob".to_string(),
+                            value: b"100".to_vec(),
+                        },
+                        TransactionWrite::Delete {
+                            key: "alice".to_string(),
+                        },
+                    ],
+                },
+                None,
+            )
+            .unwrap();
+        assert!(matches!(reader.get("alice"), Err(TrieError::NotFound)));
+        assert_eq!(reader.get("bob").unwrap(), b"100");
+        assert_eq!(reader.version("bob").unwrap(), Some(written));
+
+        // Every write gives a new version, even when it writes back a previous value or only
+        // changes the expiry
+        let bob = reader.version("bob").unwrap();
+        reader.insert("bob".to_string(), b"101".to_vec()).unwrap();
+        assert_ne!(reader.version("bob").unwrap(), bob);
+        reader.insert("bob".to_string(), b"100".to_vec()).unwrap();
+        assert!(reader.version("bob").unwrap() > bob);
+        let bob = reader.version("bob").unwrap();
+        reader
+            .insert_with_expiry("bob".to_string(), b"100".to_vec(), Some(u64::MAX))
+            .unwrap();
+        assert!(reader.version("bob").unwrap() > bob);
+
+        // Replicas are given the version of the main
+        reader
+            .insert_value(
+                "dave".to_string(),
+                Value::Inline(b"1".to_vec()),
+                None,
+                &Precondition::default(),
+                Some(42),
+            )
+            .unwrap();
+        assert_eq!(reader.version("dave").unwrap(), Some(42));
+
+        // Nothing is written when a read entry changed
+        let result = reader.transaction(
+            Transaction {
+                reads: vec![("alice".to_string(), version), ("bob".to_string(), None)],
+                writes: vec![TransactionWrite::Put {
+                    key: "carol".to_string(),
+                    value: b"1".to_vec(),
+                }],
+            },
+            None,
         );
+        match result {
+            Err(TrieError::Conflict(keys)) => assert_eq!(keys, vec!["alice", "bob"]),
+            _ => panic!("Expected a conflict"),
+        }
+        assert_eq!(reader.version("carol").unwrap(), None);
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        let mut reader = NodeReader::new(path.clone(), 10, None, PageOptions::default()).unwrap();
+        reader.recover().unwrap();
+
+        // Inserts only if absent
+        reader
+            .compare_and_swap("key".to_string(), None, Some(b"1".to_vec()))
+            .unwrap();
+        assert!(matches!(
+            reader.compare_and_swap("key".to_string(), None, Some(b"2".to_vec())),
+            Err(TrieError::Conflict(_))
+        ));
+        assert!(matches!(
+            reader.compare_and_swap("key".to_string(), Some(b"2"), None),
+            Err(TrieError::Conflict(_))
+        ));
+        assert_eq!(reader.get("key").unwrap(), b"1");
+
+        reader
+            .compare_and_swap("key".to_string(), Some(b"1"), Some(b"2".to_vec()))
+            .unwrap();
+        assert_eq!(reader.get("key").unwrap(), b"2");
+        reader
+            .compare_and_swap("key".to_string(), Some(b"2"), None)
+            .unwrap();
+        assert!(matches!(reader.get("key"), Err(TrieError::NotFound)));
+
+        // A blob that can't be inserted is removed
+        let if_absent = Precondition {
+            if_none_match: Some(Versions::Any),
+            ..Default::default()
+        };
+        let large = vec![b'l'; MAX_INLINE_VALUE_LEN + 1];
+        let blob = reader.blob_store().write(&large).unwrap();
+        reader
+            .insert_value(
+                "large".to_string(),
+                Value::Blob(blob),
+                None,
+                &if_absent,
+                None,
+            )
+            .unwrap();
+        let blob = reader.blob_store().write(&large).unwrap();
+        assert!(matches!(
+            reader.insert_value(
+                "large".to_string(),
+                Value::Blob(blob),
+                None,
+                &if_absent,
+                None
+            ),
+            Err(TrieError::Conflict(_))
+        ));
+        assert_eq!(fs::read_dir(path.join(BLOB_DIRECTORY)).unwrap().count(), 1);
+
+        let if_match = Precondition {
+            if_match: reader
+                .version("large")
+                .unwrap()
+                .map(|v| Versions::Listed(vec![v])),
+            ..Default::default()
+        };
+        reader.check_precondition("large", &if_match).unwrap();
+        assert!(reader.check_precondition("key", &if_match).is_err());
+
+        // Values stored in a blob are compared byte for byte as well
+        let mut other = large.clone();
+        other[MAX_INLINE_VALUE_LEN] = b'o';
+        for expected in [b"1".as_slice(), &other] {
+            assert!(matches!(
+                reader.compare_and_swap("large".to_string(), Some(expected), None),
+                Err(TrieError::Conflict(_))
+            ));
+        }
+        reader
+            .compare_and_swap("large".to_string(), Some(&large), Some(b"1".to_vec()))
+            .unwrap();
+        assert_eq!(reader.get("large").unwrap(), b"1");
     }
 }
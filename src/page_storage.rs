@@ -0,0 +1,159 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Error, ErrorKind, Read, Seek, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use memmap2::{Mmap, MmapOptions};
+
+use crate::configuration::StorageEngine;
+use crate::segment_storage::{SegmentStorage, TABLE_FILE_NAME};
+use crate::wal::WAL_FILE;
+
+/// Where the page files, intent files and Bloom filters are stored. Paths are the ones of the
+/// one-file-per-page layout, so that pages don't depend on the storage engine
+pub trait PageStorage: Send + Sync {
+    /// Opens an existing file for reading and writing
+    fn open(&self, path: &Path) -> Result<Box<dyn PageFile>, Error>;
+    /// Opens a file for reading and writing, creating it if needed
+    fn create(&self, path: &Path, truncate: bool) -> Result<Box<dyn PageFile>, Error>;
+    fn read(&self, path: &Path) -> Result<Vec<u8>, Error>;
+    /// Replaces the content of a file, creating it if needed
+    fn write(&self, path: &Path, data: &[u8]) -> Result<(), Error>;
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), Error>;
+    fn remove(&self, path: &Path) -> Result<(), Error>;
+    fn exists(&self, path: &Path) -> bool;
+    /// Returns the paths of the files of a directory
+    fn list(&self, directory: &Path) -> Result<Vec<PathBuf>, Error>;
+    /// Makes the renames and removals done in a directory durable
+    fn sync_directory(&self, directory: &Path) -> Result<(), Error>;
+}
+
+/// An open page file
+pub trait PageFile: Read + Write + Seek + Send + Sync {
+    fn length(&self) -> Result<u64, Error>;
+    /// Makes the data written to the file durable
+    fn sync(&self) -> Result<(), Error>;
+    /// Maps a range of the file in memory. The range must never be modified or truncated while
+    /// it is mapped. Returns `None` if the storage can't map its files, and an error if the
+    /// range extends past the end of the file
+    fn map(&self, _offset: u64, _length: usize) -> Result<Option<Mmap>, Error> {
+        Ok(None)
+    }
+}
+
+/// One file per page in the data directory
+pub struct FileStorage;
+
+/// Whether a data directory holds the pages of a persistent storage engine, or the write-ahead
+/// log of their entries
+pub fn has_pages(base_path: &Path) -> bool {
+    [TABLE_FILE_NAME, "_root.dat", WAL_FILE]
+        .iter()
+        .any(|name| base_path.join(name).exists())
+}
+
+/// Opens the storage of a data directory. A data directory can't be opened with another engine
+/// than the one that created it
+pub fn open(engine: StorageEngine, base_path: &Path) -> Result<Arc<dyn PageStorage>, Error> {
+    let has_table = base_path.join(TABLE_FILE_NAME).exists();
+    let has_pages = base_path.join("_root.dat").exists();
+
+    match engine {
+        StorageEngine::Files if has_table => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "the data directory was created by the segments storage engine",
+        )),
+        StorageEngine::Segments if has_pages && !has_table => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "the data directory was created by the files storage engine",
+        )),
+        StorageEngine::Files => Ok(Arc::new(FileStorage)),
+        StorageEngine::Segments => Ok(Arc::new(SegmentStorage::open(base_path)?)),
+        StorageEngine::Memory => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "the memory storage engine doesn't store pages",
+        )),
+    }
+}
+
+impl PageStorage for FileStorage {
+    fn open(&self, path: &Path) -> Result<Box<dyn PageFile>, Error> {
+        Ok(Box::new(
+            OpenOptions::new().read(true).write(true).open(path)?,
+        ))
+    }
+
+    fn create(&self, path: &Path, truncate: bool) -> Result<Box<dyn PageFile>, Error> {
+        Ok(Box::new(
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(truncate)
+                .open(path)?,
+        ))
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>, Error> {
+        fs::read(path)
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> Result<(), Error> {
+        fs::write(path, data)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), Error> {
+        fs::rename(from, to)
+    }
+
+    fn remove(&self, path: &Path) -> Result<(), Error> {
+        fs::remove_file(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn list(&self, directory: &Path) -> Result<Vec<PathBuf>, Error> {
+        let mut paths = vec![];
+        for entry in fs::read_dir(directory)? {
+            paths.push(entry?.path());
+        }
+
+        Ok(paths)
+    }
+
+    fn sync_directory(&self, directory: &Path) -> Result<(), Error> {
+        File::open(directory)?.sync_all()
+    }
+}
+
+impl PageFile for File {
+    fn length(&self) -> Result<u64, Error> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        self.sync_all()
+    }
+
+    fn map(&self, offset: u64, length: usize) -> Result<Option<Mmap>, Error> {
+        // Reading a mapped range past the end of the file raises SIGBUS instead of an error
+        if self.metadata()?.len() < offset + length as u64 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "the mapped range extends past the end of the file",
+            ));
+        }
+
+        // SAFETY: the range is within the file. Pages are only appended to, and rewritten by
+        // replacing their file: the sorted records that get mapped are never modified or
+        // truncated while the store runs. A page truncated by another process would still
+        // raise SIGBUS, so the data directory must not be repaired while the store runs
+        let mapping = unsafe { MmapOptions::new().offset(offset).len(length).map(self)? };
+
+        Ok(Some(mapping))
+    }
+}
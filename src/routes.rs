@@ -1,83 +1,587 @@
-use crate::node_reader::NodeReader;
-use crate::tree_node::TrieError;
 use crate::WriteEvent;
-use serde::Deserialize;
-use std::collections::HashMap;
+use futures_util::{stream, Stream, StreamExt};
+use kvs::blob_store::{BlobReader, BlobRef, BlobStore, BlobWriter};
+use kvs::group_commit::Commit;
+use kvs::node_reader::{NodeReader, ValueReader};
+use kvs::stats::{DurabilityWindow, StatsSnapshot};
+use kvs::transaction::{Precondition, Transaction, TransactionWrite, Version, Versions};
+use kvs::tree_node::{self, TrieError, Value};
+use kvs::verification::{Verification, VerificationStatus};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{Read, Seek};
 use std::sync::mpsc::Sender;
 use std::sync::{
-    atomic::{AtomicUsize, Ordering},
-    Arc, RwLock,
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc, Mutex, RwLock,
 };
+use std::time::Instant;
 
 use actix_web::{
-    delete, error, get, post,
+    delete, error, get,
+    http::header::{self, ContentType},
+    post,
     web::{self, Json},
-    Result,
+    HttpRequest, HttpResponse, Result,
 };
 
+/// Size of the chunks in which blobs are sent
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
 #[derive(Debug, Deserialize)]
 pub struct RangeParameters {
     start_key: String,
     end_key: String,
 }
 
-#[get("/kv/{key}")]
+/// Expiry of an inserted entry: either a time to live or an absolute unix timestamp, in seconds
+#[derive(Debug, Deserialize)]
+pub struct ExpiryParameters {
+    ttl: Option<u64>,
+    expires_at: Option<u64>,
+}
+
+/// Version given by the main to the entries of a replicated write, formatted like the ETags.
+/// Only the replication server accepts it: the other writes are numbered by the node
+#[derive(Debug, Deserialize)]
+pub struct VersionParameters {
+    version: Option<String>,
+}
+
+impl VersionParameters {
+    fn version(self, replicated: Option<web::Data<ReplicatedWrites>>) -> Result<Option<Version>> {
+        match (self.version, replicated) {
+            (None, _) => Ok(None),
+            (Some(version), Some(_)) => parse_version(&version).map(Some),
+            (Some(_), None) => Err(error::ErrorBadRequest(
+                "Versions are only given by the main",
+            )),
+        }
+    }
+}
+
+/// Marks the server receiving the writes of the main
+pub struct ReplicatedWrites;
+
+/// A value as it appears in JSON bodies: a string when it is valid UTF-8,
+/// an array of bytes otherwise
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum JsonValue {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl From<Vec<u8>> for JsonValue {
+    fn from(value: Vec<u8>) -> Self {
+        match String::from_utf8(value) {
+            Ok(text) => JsonValue::Text(text),
+            Err(e) => JsonValue::Binary(e.into_bytes()),
+        }
+    }
+}
+
+/// Body of `POST /txn`: the versions of the entries read (`null` if they didn't exist), then the
+/// entries to write and the keys to delete. Versions are the ETags of `GET /kv/{key}`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct TransactionRequest {
+    #[serde(default)]
+    pub reads: HashMap<String, Option<String>>,
+    #[serde(default)]
+    pub writes: BTreeMap<String, JsonValue>,
+    #[serde(default)]
+    pub deletes: Vec<String>,
+}
+
+/// New versions of the entries written by a transaction, `null` for the deleted ones
+#[derive(Serialize)]
+pub struct TransactionResponse {
+    versions: HashMap<String, Option<String>>,
+}
+
+/// Keys read by a transaction that were written since
+#[derive(Serialize)]
+pub struct ConflictResponse {
+    conflicts: Vec<String>,
+}
+
+/// Counters of the store, and the writes a crash can lose
+#[derive(Serialize)]
+pub struct StatsResponse {
+    #[serde(flatten)]
+    stats: StatsSnapshot,
+    durability_window: DurabilityWindow,
+}
+
+/// Whether the node accepts writes. Writes are refused once the node starts shutting down, so
+/// that every accepted write is sent to the replicas before the store is closed
+#[derive(Default)]
+pub struct WriteGate {
+    closed: AtomicBool,
+    /// When the last write was accepted, if any
+    last_write: Mutex<Option<Instant>>,
+}
+
+impl WriteGate {
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+
+    /// Returns when the last write was accepted, if any
+    pub fn last_write(&self) -> Option<Instant> {
+        *self.last_write.lock().unwrap()
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.closed.load(Ordering::SeqCst) {
+            return Err(error::ErrorServiceUnavailable("The node is shutting down"));
+        }
+
+        *self.last_write.lock().unwrap() = Some(Instant::now());
+        Ok(())
+    }
+}
+
+/// A value read from a request body
+enum RequestValue {
+    /// A value small enough to be stored in a page
+    Bytes(Vec<u8>),
+    /// Values too large to fit in a page are streamed to a blob file
+    Blob(BlobRef),
+}
+
+impl ExpiryParameters {
+    /// Returns the unix timestamp from which the entry expires
+    fn expires_at(self) -> Result<Option<u64>> {
+        match (self.ttl, self.expires_at) {
+            (Some(_), Some(_)) => Err(error::ErrorBadRequest(
+                "Only one of ttl and expires_at can be set",
+            )),
+            (Some(ttl), None) => Ok(Some(tree_node::unix_time().saturating_add(ttl))),
+            (None, expires_at) => Ok(expires_at),
+        }
+    }
+}
+
+impl From<JsonValue> for Vec<u8> {
+    fn from(value: JsonValue) -> Self {
+        match value {
+            JsonValue::Text(text) => text.into_bytes(),
+            JsonValue::Binary(bytes) => bytes,
+        }
+    }
+}
+
+/// Decodes a JSON value (a string, or an array of bytes) chunk by chunk, so that a large value
+/// is written to a blob as it is received rather than held in memory
+#[derive(Default)]
+struct JsonValueDecoder {
+    state: JsonState,
+    is_text: bool,
+    /// Digits of a `\u` escape or of a byte, which may be split between two chunks
+    digits: String,
+    /// Leading surrogate of a `\u` escape, waiting for the trailing one
+    surrogate: Option<u32>,
+    /// End of the decoded text that starts a character completed by the next chunk
+    incomplete: Vec<u8>,
+}
+
+#[derive(Default, Clone, Copy, PartialEq)]
+enum JsonState {
+    #[default]
+    Start,
+    Text,
+    Escape,
+    Unicode,
+    /// Right after `[`: a byte or `]`
+    ArrayStart,
+    /// After a `,`: a byte
+    Byte,
+    Digits,
+    /// After a byte: `,` or `]`
+    AfterByte,
+    End,
+}
+
+impl JsonValueDecoder {
+    /// Decodes a chunk of the body, appending the bytes of the value to `output`
+    fn decode(&mut self, chunk: &[u8], output: &mut Vec<u8>) -> Result<()> {
+        let start = output.len();
+        for &b in chunk {
+            self.step(b, output)
+                .ok_or_else(|| error::ErrorBadRequest("Invalid value"))?;
+        }
+
+        if self.is_text {
+            // Characters split between two chunks are checked with the next one
+            let mut text = std::mem::take(&mut self.incomplete);
+            text.extend_from_slice(&output[start..]);
+            match std::str::from_utf8(&text) {
+                Ok(_) => {}
+                Err(e) if e.error_len().is_none() => {
+                    self.incomplete = text.split_off(e.valid_up_to());
+                }
+                Err(_) => return Err(error::ErrorBadRequest("Invalid value")),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the body held a whole value
+    fn finish(&self) -> Result<()> {
+        if self.state != JsonState::End || !self.incomplete.is_empty() {
+            return Err(error::ErrorBadRequest("Invalid value"));
+        }
+
+        Ok(())
+    }
+
+    fn step(&mut self, b: u8, output: &mut Vec<u8>) -> Option<()> {
+        let is_whitespace = matches!(b, b' ' | b'\t' | b'\n' | b'\r');
+        self.state = match (self.state, b) {
+            // A leading surrogate must be followed by the escape of a trailing one
+            (JsonState::Text, b'\\') => JsonState::Escape,
+            (JsonState::Text, _) if self.surrogate.is_some() => return None,
+            (JsonState::Text, b'"') => JsonState::End,
+            (JsonState::Text, 0..=0x1F) => return None,
+            (JsonState::Text, b) => {
+                output.push(b);
+                JsonState::Text
+            }
+            (JsonState::Escape, b'u') => JsonState::Unicode,
+            (JsonState::Escape, _) if self.surrogate.is_some() => return None,
+            (JsonState::Escape, b) => {
+                output.push(match b {
+                    b'"' | b'\\' | b'/' => b,
+                    b'b' => 0x08,
+                    b'f' => 0x0C,
+                    b'n' => b'\n',
+                    b'r' => b'\r',
+                    b't' => b'\t',
+                    _ => return None,
+                });
+                JsonState::Text
+            }
+            (JsonState::Unicode, b) if b.is_ascii_hexdigit() => {
+                self.digits.push(b as char);
+                if self.digits.len() < 4 {
+                    JsonState::Unicode
+                } else {
+                    self.unicode_escape(output)?;
+                    JsonState::Text
+                }
+            }
+            (
+                JsonState::Start
+                | JsonState::ArrayStart
+                | JsonState::Byte
+                | JsonState::AfterByte
+                | JsonState::End,
+                _,
+            ) if is_whitespace => self.state,
+            (JsonState::Start, b'"') => {
+                self.is_text = true;
+                JsonState::Text
+            }
+            (JsonState::Start, b'[') => JsonState::ArrayStart,
+            (JsonState::ArrayStart, b']') => JsonState::End,
+            (JsonState::ArrayStart | JsonState::Byte | JsonState::Digits, b'0'..=b'9') => {
+                // Leading zeros aren't valid JSON
+                if self.digits == "0" || self.digits.len() == 3 {
+                    return None;
+                }
+                self.digits.push(b as char);
+                JsonState::Digits
+            }
+            (JsonState::Digits, b) => {
+                output.push(std::mem::take(&mut self.digits).parse().ok()?);
+                self.state = JsonState::AfterByte;
+                return self.step(b, output);
+            }
+            (JsonState::AfterByte, b',') => JsonState::Byte,
+            (JsonState::AfterByte, b']') => JsonState::End,
+            _ => return None,
+        };
+
+        Some(())
+    }
+
+    /// Decodes the code point of a `\u` escape. Characters outside of the basic plane are
+    /// escaped as a pair of surrogates
+    fn unicode_escape(&mut self, output: &mut Vec<u8>) -> Option<()> {
+        let code = u32::from_str_radix(&std::mem::take(&mut self.digits), 16).ok()?;
+        let code = match (self.surrogate.take(), code) {
+            (None, 0xD800..=0xDBFF) => {
+                self.surrogate = Some(code);
+                return Some(());
+            }
+            (Some(leading), 0xDC00..=0xDFFF) => {
+                0x10000 + ((leading - 0xD800) << 10) + (code - 0xDC00)
+            }
+            (Some(_), _) => return None,
+            (None, code) => code,
+        };
+
+        let mut buffer = [0; 4];
+        output.extend_from_slice(char::from_u32(code)?.encode_utf8(&mut buffer).as_bytes());
+        Some(())
+    }
+}
+
+#[get("/kv/{key:.*}")]
 async fn get(
+    request: HttpRequest,
     path: web::Path<String>,
     store: web::Data<Arc<RwLock<NodeReader>>>,
     counter: web::Data<AtomicUsize>,
-) -> Result<Json<String>> {
+) -> Result<HttpResponse> {
     let key = path.into_inner();
     counter.fetch_add(1, Ordering::SeqCst);
 
-    match store.write() {
-        Ok(mut store) => to_json(store.get(&key)),
-        Err(_) => Err(error::ErrorInternalServerError("")),
-    }
+    let (value, version) = match store.write() {
+        Ok(mut store) => store.open(&key).map_err(process_error)?,
+        Err(_) => return Err(error::ErrorInternalServerError("")),
+    };
+
+    let mut response = match value {
+        ValueReader::Inline(value) => value_response(&request, value),
+        ValueReader::Blob(file, length) => {
+            // Large values are sent as JSON strings too, as long as they are valid UTF-8
+            let (file, is_text) = if wants_bytes(&request) {
+                (file, false)
+            } else {
+                blocking(move || {
+                    let mut file = file;
+                    let is_text = is_utf8(&mut file)?;
+                    file.rewind()?;
+                    Ok((file, is_text))
+                })
+                .await?
+            };
+
+            if is_text {
+                HttpResponse::Ok()
+                    .content_type(ContentType::json())
+                    .streaming(blob_stream(file, true))
+            } else {
+                HttpResponse::Ok()
+                    .content_type(ContentType::octet_stream())
+                    .no_chunking(length)
+                    .streaming(blob_stream(file, false))
+            }
+        }
+    };
+    response.headers_mut().insert(
+        header::ETAG,
+        header::HeaderValue::from_str(&format!("\"{}\"", format_version(version))).unwrap(),
+    );
+
+    Ok(response)
 }
 
-#[post("/kv/{key}")]
+#[post("/kv/{key:.*}")]
+#[allow(clippy::too_many_arguments)]
 async fn insert(
+    request: HttpRequest,
     path: web::Path<String>,
-    body: web::Json<String>,
+    expiry: web::Query<ExpiryParameters>,
+    version: web::Query<VersionParameters>,
+    replicated: Option<web::Data<ReplicatedWrites>>,
+    payload: web::Payload,
     store: web::Data<Arc<RwLock<NodeReader>>>,
     channel: web::Data<Sender<WriteEvent>>,
     counter: web::Data<AtomicUsize>,
+    writes: web::Data<Arc<WriteGate>>,
 ) -> Result<()> {
+    writes.check()?;
     let key = path.into_inner();
-    let value = body.into_inner();
+    let expires_at = expiry.into_inner().expires_at()?;
+    let version = version.into_inner().version(replicated)?;
+    let precondition = precondition(&request)?;
     let sender = channel.into_inner();
     counter.fetch_add(1, Ordering::SeqCst);
 
-    match store.write() {
-        Ok(mut store) => send_event(
-            sender,
-            to_empty(store.insert(key.clone(), value.clone())),
-            WriteEvent::Insert(key, value),
-        ),
-        Err(_) => Err(error::ErrorInternalServerError("")),
-    }
+    let blobs = match store.read() {
+        Ok(store) => store.blob_store(),
+        Err(_) => return Err(error::ErrorInternalServerError("")),
+    };
+    let value = request_value(&request, payload, &blobs).await?;
+
+    let commit = match (store.write(), value) {
+        (Ok(mut store), RequestValue::Bytes(value)) => {
+            let result = store.insert_value(
+                key.clone(),
+                Value::Inline(value.clone()),
+                expires_at,
+                &precondition,
+                version,
+            );
+            send_event(sender, result.map_err(precondition_error), |version| {
+                WriteEvent::Insert(key, value, expires_at, precondition, *version)
+            })?;
+            store.commit()
+        }
+        (Ok(mut store), RequestValue::Blob(blob)) => {
+            let version = store
+                .insert_value(
+                    key.clone(),
+                    Value::Blob(blob),
+                    expires_at,
+                    &precondition,
+                    version,
+                )
+                .map_err(precondition_error)?;
+
+            // The blob is opened while the store is locked, so that it can still be sent to
+            // the replicas if the entry is overwritten in the meantime
+            let file = blobs
+                .open_blob(&blob)
+                .map_err(|e| process_error(e.into()))?;
+            send_event(sender, Ok(version), |version| {
+                WriteEvent::InsertBlob(key, file, blob.length, expires_at, precondition, *version)
+            })?;
+            store.commit()
+        }
+        (Err(_), _) => return Err(error::ErrorInternalServerError("")),
+    };
+
+    wait_for_commit(commit, store).await
 }
 
-#[delete("/kv/{key}")]
+#[delete("/kv/{key:.*}")]
 async fn delete(
+    request: HttpRequest,
     path: web::Path<String>,
     store: web::Data<Arc<RwLock<NodeReader>>>,
     channel: web::Data<Sender<WriteEvent>>,
     counter: web::Data<AtomicUsize>,
+    writes: web::Data<Arc<WriteGate>>,
 ) -> Result<()> {
+    writes.check()?;
     let key = path.into_inner();
+    let precondition = precondition(&request)?;
     let sender = channel.into_inner();
     counter.fetch_add(1, Ordering::SeqCst);
 
-    match store.write() {
-        Ok(mut store) => send_event(
-            sender,
-            to_empty(store.delete(key.clone())),
-            WriteEvent::Delete(key),
-        ),
-        Err(_) => Err(error::ErrorInternalServerError("")),
+    let commit = match store.write() {
+        Ok(mut store) => {
+            let result = store
+                .check_precondition(&key, &precondition)
+                .and_then(|_| store.delete(key.clone()));
+            send_event(sender, result.map_err(precondition_error), |_| {
+                WriteEvent::Delete(key, precondition)
+            })?;
+            store.commit()
+        }
+        Err(_) => return Err(error::ErrorInternalServerError("")),
+    };
+
+    wait_for_commit(commit, store).await
+}
+
+/// Applies writes and deletes atomically, provided the entries read by the client still have the
+/// versions it read. Replicas receive the writes only, once they are applied
+#[post("/txn")]
+async fn transaction(
+    request_body: web::Json<TransactionRequest>,
+    version: web::Query<VersionParameters>,
+    replicated: Option<web::Data<ReplicatedWrites>>,
+    store: web::Data<Arc<RwLock<NodeReader>>>,
+    channel: web::Data<Sender<WriteEvent>>,
+    counter: web::Data<AtomicUsize>,
+    writes: web::Data<Arc<WriteGate>>,
+) -> Result<Json<TransactionResponse>> {
+    writes.check()?;
+    let TransactionRequest {
+        reads,
+        writes: puts,
+        deletes,
+    } = request_body.into_inner();
+    let reads = reads
+        .into_iter()
+        .map(|(key, version)| Ok((key, version.as_deref().map(parse_version).transpose()?)))
+        .collect::<Result<Vec<_>>>()?;
+    let version = version.into_inner().version(replicated)?;
+    let sender = channel.into_inner();
+    counter.fetch_add(1, Ordering::SeqCst);
+
+    let (commit, versions) = match store.write() {
+        Ok(mut store) => {
+            let writes = transaction_writes(&store, puts, deletes)?;
+            let result = store
+                .transaction(
+                    Transaction {
+                        reads,
+                        writes: writes.clone(),
+                    },
+                    version,
+                )
+                .map_err(process_error);
+
+            let written = send_event(sender, result, |version| {
+                WriteEvent::Transaction(writes.clone(), *version)
+            })
+            .map(|version| {
+                writes
+                    .into_iter()
+                    .map(|write| match write {
+                        TransactionWrite::Put { key, .. } => (key, Some(format_version(version))),
+                        TransactionWrite::Delete { key } => (key, None),
+                    })
+                    .collect()
+            });
+
+            (store.commit(), written)
+        }
+        Err(_) => return Err(error::ErrorInternalServerError("")),
+    };
+
+    wait_for_commit(commit, store).await?;
+    let versions = versions?;
+
+    Ok(web::Json(TransactionResponse { versions }))
+}
+
+/// Returns the writes of a transaction under the keys they are stored with: the entries written
+/// in key order, then the keys deleted in key order. Keys written or deleted twice once
+/// normalized are refused
+fn transaction_writes(
+    store: &NodeReader,
+    puts: BTreeMap<String, JsonValue>,
+    deletes: Vec<String>,
+) -> Result<Vec<TransactionWrite>> {
+    let mut normalized_puts = BTreeMap::new();
+    for (key, value) in puts {
+        if normalized_puts
+            .insert(store.normalize_key(key), Vec::<u8>::from(value))
+            .is_some()
+        {
+            return Err(error::ErrorBadRequest(
+                "Several keys are the same once normalized",
+            ));
+        }
     }
+
+    let deletes: BTreeSet<String> = deletes
+        .into_iter()
+        .map(|key| store.normalize_key(key))
+        .collect();
+    if deletes.iter().any(|key| normalized_puts.contains_key(key)) {
+        return Err(error::ErrorBadRequest(
+            "A key can't be both written and deleted",
+        ));
+    }
+
+    Ok(normalized_puts
+        .into_iter()
+        .map(|(key, value)| TransactionWrite::Put { key, value })
+        .chain(
+            deletes
+                .into_iter()
+                .map(|key| TransactionWrite::Delete { key }),
+        )
+        .collect())
 }
 
 #[get("/bulk/range")]
@@ -85,49 +589,282 @@ async fn get_range(
     range_params: web::Query<RangeParameters>,
     store: web::Data<Arc<RwLock<NodeReader>>>,
     counter: web::Data<AtomicUsize>,
-) -> Result<Json<Vec<(String, String)>>> {
+) -> Result<Json<Vec<(String, JsonValue)>>> {
     let RangeParameters { start_key, end_key } = range_params.into_inner();
     counter.fetch_add(1, Ordering::SeqCst);
 
-    match store.write() {
-        Ok(mut store) => to_json(store.get_range(&start_key, &end_key)),
+    let entries = match store.write() {
+        Ok(mut store) => store.get_range(&start_key, &end_key),
+        Err(_) => return Err(error::ErrorInternalServerError("")),
+    };
+
+    to_json(entries.map(|entries| {
+        entries
+            .into_iter()
+            .map(|(key, value)| (key, value.into()))
+            .collect()
+    }))
+}
+
+#[get("/stats")]
+async fn get_stats(store: web::Data<Arc<RwLock<NodeReader>>>) -> Result<Json<StatsResponse>> {
+    match store.read() {
+        Ok(store) => Ok(web::Json(StatsResponse {
+            stats: store.stats(),
+            durability_window: store.durability_window(),
+        })),
         Err(_) => Err(error::ErrorInternalServerError("")),
     }
 }
 
+#[get("/status")]
+async fn get_status(verification: web::Data<Arc<Verification>>) -> Json<VerificationStatus> {
+    web::Json(verification.status())
+}
+
+/// Inserts the entries of a JSON object atomically. A key given several times keeps its last
+/// value, but distinct keys that are the same once normalized are refused
 #[post("/bulk")]
 async fn bulk_insert(
-    request_body: web::Json<HashMap<String, String>>,
+    request_body: web::Json<HashMap<String, JsonValue>>,
+    version: web::Query<VersionParameters>,
+    replicated: Option<web::Data<ReplicatedWrites>>,
     store: web::Data<Arc<RwLock<NodeReader>>>,
     channel: web::Data<Sender<WriteEvent>>,
     counter: web::Data<AtomicUsize>,
+    writes: web::Data<Arc<WriteGate>>,
 ) -> Result<()> {
-    let entries = request_body.into_inner();
+    writes.check()?;
+    let entries: HashMap<String, Vec<u8>> = request_body
+        .into_inner()
+        .into_iter()
+        .map(|(key, value)| (key, value.into()))
+        .collect();
+    let version = version.into_inner().version(replicated)?;
     let sender = channel.into_inner();
     counter.fetch_add(1, Ordering::SeqCst);
 
-    match store.write() {
-        Ok(mut store) => send_event(
-            sender,
-            to_empty(store.bulk_insert(entries.clone())),
-            WriteEvent::BulkInsert(entries),
-        ),
+    let commit = match store.write() {
+        Ok(mut store) => {
+            // Replicas receive the entries as they are stored, in key order
+            let mut entries: Vec<(String, Vec<u8>)> = entries
+                .into_iter()
+                .map(|(key, value)| (store.normalize_key(key), value))
+                .collect();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            if entries.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+                return Err(error::ErrorBadRequest(
+                    "Several keys are the same once normalized",
+                ));
+            }
+
+            let result = store.bulk_insert(entries.clone(), version);
+            send_event(sender, result.map_err(process_error), |version| {
+                WriteEvent::BulkInsert(entries, *version)
+            })?;
+            store.commit()
+        }
+        Err(_) => return Err(error::ErrorInternalServerError("")),
+    };
+
+    wait_for_commit(commit, store).await
+}
+
+/// Reads a value from a request body: JSON bodies hold a string (or an array
+/// of bytes), anything else is stored verbatim. Values too large to fit in a
+/// page are written to a blob file as they are received, JSON ones as they
+/// are decoded
+async fn request_value(
+    request: &HttpRequest,
+    mut payload: web::Payload,
+    blobs: &Arc<BlobStore>,
+) -> Result<RequestValue> {
+    let is_json = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("application/json"));
+    let mut decoder = is_json.then(JsonValueDecoder::default);
+
+    let mut body = vec![];
+    let mut blob: Option<BlobWriter> = None;
+    let mut length = 0;
+
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        let previous_length = body.len();
+        match decoder.as_mut() {
+            Some(decoder) => decoder.decode(&chunk, &mut body)?,
+            None => body.extend_from_slice(&chunk),
+        }
+        length += body.len() - previous_length;
+        if length > tree_node::MAX_VALUE_LEN {
+            return Err(process_error(TrieError::ValueError));
+        }
+
+        // Once the value goes to a blob, it is written in chunks of about `STREAM_CHUNK_SIZE`
+        let threshold = match blob {
+            Some(_) => STREAM_CHUNK_SIZE,
+            None => tree_node::MAX_INLINE_VALUE_LEN + 1,
+        };
+        if body.len() >= threshold {
+            let writer = blob.take();
+            let blobs = blobs.clone();
+            let chunk = std::mem::take(&mut body);
+            blob = Some(
+                blocking(move || {
+                    let mut writer = match writer {
+                        Some(writer) => writer,
+                        None => blobs.create()?,
+                    };
+                    writer.write(&chunk)?;
+                    Ok(writer)
+                })
+                .await?,
+            );
+        }
+    }
+
+    if let Some(decoder) = decoder {
+        decoder.finish()?;
+    }
+
+    match blob {
+        Some(mut writer) => {
+            let blob = blocking(move || {
+                writer.write(&body)?;
+                writer.finish()
+            })
+            .await?;
+            Ok(RequestValue::Blob(blob))
+        }
+        None => Ok(RequestValue::Bytes(body)),
+    }
+}
+
+/// Sends a blob file in chunks, so that it is never fully loaded in memory. Blobs sent as JSON
+/// strings must be valid UTF-8, and are escaped chunk by chunk
+fn blob_stream(
+    file: BlobReader,
+    as_json: bool,
+) -> impl Stream<Item = Result<web::Bytes, std::io::Error>> {
+    let chunks = stream::unfold(Some((file, vec![])), move |state| async move {
+        let (mut file, mut pending) = state?;
+        let result = web::block(move || {
+            let mut buffer = vec![0; STREAM_CHUNK_SIZE];
+            let n = file.read(&mut buffer)?;
+            buffer.truncate(n);
+            if !as_json {
+                return Ok((n, buffer, file, pending));
+            }
+
+            // Characters split between two chunks are sent with the next one
+            pending.extend_from_slice(&buffer);
+            let valid = match std::str::from_utf8(&pending) {
+                Ok(_) => pending.len(),
+                Err(e) => e.valid_up_to(),
+            };
+            let rest = pending.split_off(valid);
+            let text = String::from_utf8(pending).map_err(std::io::Error::other)?;
+            let escaped = serde_json::to_string(&text)?;
+            let escaped = escaped.as_bytes()[1..escaped.len() - 1].to_vec();
+            Ok::<_, std::io::Error>((n, escaped, file, rest))
+        })
+        .await;
+
+        match result {
+            Ok(Ok((0, ..))) => None,
+            Ok(Ok((_, chunk, file, pending))) => {
+                Some((Ok(web::Bytes::from(chunk)), Some((file, pending))))
+            }
+            Ok(Err(e)) => Some((Err(e), None)),
+            Err(e) => Some((Err(std::io::Error::other(e)), None)),
+        }
+    });
+
+    let quote = move || stream::iter(as_json.then(|| Ok(web::Bytes::from_static(b"\""))));
+    quote().chain(chunks).chain(quote())
+}
+
+/// Whether a file is valid UTF-8, read in chunks so that it is never fully loaded in memory
+fn is_utf8(file: &mut BlobReader) -> Result<bool, std::io::Error> {
+    let mut buffer = vec![0; STREAM_CHUNK_SIZE];
+    let mut pending = 0;
+    loop {
+        let n = file.read(&mut buffer[pending..])?;
+        if n == 0 {
+            return Ok(pending == 0);
+        }
+
+        let length = pending + n;
+        // A character may be split by the end of the buffer, and is completed by the next read
+        pending = match std::str::from_utf8(&buffer[..length]) {
+            Ok(_) => 0,
+            Err(e) if e.error_len().is_none() => length - e.valid_up_to(),
+            Err(_) => return Ok(false),
+        };
+        buffer.copy_within(length - pending..length, 0);
+    }
+}
+
+/// Whether the client asked for raw bytes rather than JSON strings
+fn wants_bytes(request: &HttpRequest) -> bool {
+    request
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/octet-stream"))
+}
+
+/// Returns a value as a JSON string, unless the client asked for raw bytes or
+/// the value is not valid UTF-8
+fn value_response(request: &HttpRequest, value: Vec<u8>) -> HttpResponse {
+    match JsonValue::from(value) {
+        JsonValue::Text(text) if !wants_bytes(request) => HttpResponse::Ok().json(text),
+        value => HttpResponse::Ok()
+            .content_type(ContentType::octet_stream())
+            .body(Vec::<u8>::from(value)),
+    }
+}
+
+/// Waits until the writes of a request are durable, when they are synced by group commit. The
+/// wait runs on the blocking thread pool, so that the workers keep serving requests. Fails if
+/// the writes were applied but couldn't be synced
+async fn wait_for_commit(
+    commit: Result<Option<Commit>, TrieError>,
+    store: web::Data<Arc<RwLock<NodeReader>>>,
+) -> Result<()> {
+    let Some(commit) = commit.map_err(process_error)? else {
+        return Ok(());
+    };
+
+    let store = store.into_inner();
+    match web::block(move || commit.wait(&store)).await {
+        Ok(result) => to_empty(result),
+        Err(_) => Err(error::ErrorInternalServerError("")),
+    }
+}
+
+/// Runs blob file I/O on the blocking thread pool, so that the workers keep serving requests
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, std::io::Error> + Send + 'static,
+) -> Result<T> {
+    match web::block(f).await {
+        Ok(result) => result.map_err(|e| process_error(e.into())),
         Err(_) => Err(error::ErrorInternalServerError("")),
     }
 }
 
+/// Sends the event of a write to the replicas once it is applied
 fn send_event<T>(
     channel: Arc<Sender<WriteEvent>>,
     result: Result<T>,
-    event: WriteEvent,
+    event: impl FnOnce(&T) -> WriteEvent,
 ) -> Result<T> {
-    match result {
-        Ok(_) => {
-            if let Err(e) = channel.send(event) {
-                log::error!("Error while sending event: {:#?}", e);
-            }
+    if let Ok(written) = &result {
+        if let Err(e) = channel.send(event(written)) {
+            log::error!("Error while sending event: {:#?}", e);
         }
-        Err(_) => {}
     }
 
     result
@@ -147,11 +884,563 @@ fn to_json<T>(result: Result<T, TrieError>) -> Result<Json<T>> {
     }
 }
 
+/// Versions are sent as hexadecimal strings, since JSON numbers can't hold every `u64`
+pub fn format_version(version: Version) -> String {
+    format!("{version:016x}")
+}
+
+fn parse_version(version: &str) -> Result<Version> {
+    Version::from_str_radix(version.trim_matches('"'), 16)
+        .map_err(|_| error::ErrorBadRequest("Invalid version"))
+}
+
+/// Reads the conditions of a write from its `If-Match` and `If-None-Match` headers
+fn precondition(request: &HttpRequest) -> Result<Precondition> {
+    Ok(Precondition {
+        if_match: versions(request, header::IF_MATCH)?,
+        if_none_match: versions(request, header::IF_NONE_MATCH)?,
+    })
+}
+
+/// Returns the headers sending the conditions of a write to a replica
+pub fn precondition_headers(precondition: &Precondition) -> Vec<(header::HeaderName, String)> {
+    [
+        (header::IF_MATCH, &precondition.if_match),
+        (header::IF_NONE_MATCH, &precondition.if_none_match),
+    ]
+    .into_iter()
+    .filter_map(|(name, versions)| {
+        let value = match versions.as_ref()? {
+            Versions::Any => "*".to_string(),
+            Versions::Listed(versions) => versions
+                .iter()
+                .map(|version| format!("\"{}\"", format_version(*version)))
+                .collect::<Vec<_>>()
+                .join(", "),
+        };
+
+        Some((name, value))
+    })
+    .collect()
+}
+
+/// Reads the ETags listed by a header, or `*`. Weak ETags (`W/"{etag}"`) match the entry with
+/// the same version in `If-None-Match`, and never match in `If-Match`, which needs a strong one
+fn versions(request: &HttpRequest, name: header::HeaderName) -> Result<Option<Versions>> {
+    let mut versions = None;
+    for value in request.headers().get_all(&name) {
+        let value = value
+            .to_str()
+            .map_err(|_| error::ErrorBadRequest("Invalid version"))?;
+        for tag in value.split(',').map(str::trim) {
+            if tag == "*" {
+                return Ok(Some(Versions::Any));
+            }
+
+            let versions = versions.get_or_insert_with(Vec::new);
+            match tag.strip_prefix("W/") {
+                Some(tag) if name == header::IF_NONE_MATCH => versions.push(parse_version(tag)?),
+                Some(_) => {}
+                None => versions.push(parse_version(tag)?),
+            }
+        }
+    }
+
+    Ok(versions.map(Versions::Listed))
+}
+
+/// Conditional writes that don't hold are refused with a 412
+fn precondition_error(e: TrieError) -> actix_web::Error {
+    match e {
+        TrieError::Conflict(_) => error::ErrorPreconditionFailed("Precondition failed"),
+        e => process_error(e),
+    }
+}
+
 fn process_error(e: TrieError) -> actix_web::Error {
     match e {
+        TrieError::Conflict(conflicts) => error::InternalError::from_response(
+            "Conflict",
+            HttpResponse::Conflict().json(ConflictResponse { conflicts }),
+        )
+        .into(),
         TrieError::KeyError => error::ErrorBadRequest("Invalid key"),
         TrieError::ValueError => error::ErrorBadRequest("Invalid value"),
-        TrieError::NotFound => error::ErrorBadRequest("Key not found"),
-        _ => error::ErrorInternalServerError(""),
+        TrieError::NotFound => error::ErrorNotFound("Key not found"),
+        TrieError::Corrupted(reason) => {
+            log::error!("Corrupted page: {reason}");
+            error::ErrorInternalServerError("")
+        }
+        TrieError::IoError(e) => {
+            log::error!("I/O error: {e:#?}");
+            error::ErrorInternalServerError("")
+        }
+        TrieError::InvalidConfiguration(reason) => {
+            log::error!("Invalid configuration: {reason}");
+            error::ErrorInternalServerError("")
+        }
+        TrieError::ReadOnly => error::ErrorServiceUnavailable(
+            "The store is read-only: a batch of writes failed to be applied, restart the node to apply it again",
+        ),
+        TrieError::WrongNode(prefix) => {
+            log::error!("Key routed to the wrong node, expected: {prefix}");
+            error::ErrorInternalServerError("")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{
+        http::StatusCode,
+        test::{self, TestRequest},
+        App, FromRequest,
+    };
+    use kvs::memory_storage::MemoryStorage;
+    use kvs::tree_node::PageOptions;
+    use std::path::Path;
+    use std::sync::mpsc::{self, Receiver};
+    use tempfile::tempdir;
+
+    /// Registers the routes on a store kept in memory. Writes carry a version only when they
+    /// are served as replicated ones
+    fn routes(
+        path: &Path,
+        replicated: bool,
+    ) -> (impl FnOnce(&mut web::ServiceConfig), Receiver<WriteEvent>) {
+        let storage = MemoryStorage::open(path.to_path_buf()).unwrap();
+        let store = NodeReader::with_storage(
+            Box::new(storage),
+            path.to_path_buf(),
+            None,
+            PageOptions::default(),
+        )
+        .unwrap();
+        let store = Arc::new(RwLock::new(store));
+        let (tx, rx) = mpsc::channel();
+
+        let configure = move |config: &mut web::ServiceConfig| {
+            config
+                .app_data(web::Data::new(store))
+                .app_data(web::Data::new(tx))
+                .app_data(web::Data::new(AtomicUsize::new(0)))
+                .app_data(web::Data::new(Arc::new(WriteGate::default())))
+                .service(get)
+                .service(insert)
+                .service(delete)
+                .service(bulk_insert)
+                .service(transaction);
+            if replicated {
+                config.app_data(web::Data::new(ReplicatedWrites));
+            }
+        };
+
+        (configure, rx)
+    }
+
+    fn etag(response: &actix_web::dev::ServiceResponse) -> String {
+        response
+            .headers()
+            .get(header::ETAG)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    fn request(headers: &[(header::HeaderName, &str)]) -> HttpRequest {
+        headers
+            .iter()
+            .fold(TestRequest::default(), |request, (name, value)| {
+                request.append_header((name.clone(), *value))
+            })
+            .to_http_request()
+    }
+
+    /// Decodes a body split in chunks of the given length
+    fn decode(body: &[u8], chunk_length: usize) -> Option<Vec<u8>> {
+        let mut decoder = JsonValueDecoder::default();
+        let mut output = vec![];
+        for chunk in body.chunks(chunk_length) {
+            decoder.decode(chunk, &mut output).ok()?;
+        }
+
+        decoder.finish().ok().map(|_| output)
+    }
+
+    #[test]
+    fn test_json_values_are_decoded_like_serde() {
+        let bodies: [&[u8]; 22] = [
+            br#""value""#,
+            br#" "a\"b\\c\/d\b\f\n\r\t" "#,
+            br#""\u00e9\u4e2d\ud83d\ude00""#,
+            "\"été 😀\"".as_bytes(),
+            br#"[]"#,
+            br#" [ 0, 1 ,255 ] "#,
+            br#""""#,
+            br#""\ud83d""#,
+            br#""\ude00""#,
+            br#""\ud83dx""#,
+            br#""\ud83d\n""#,
+            br#"[256]"#,
+            br#"[01]"#,
+            br#"[1,]"#,
+            br#"[,1]"#,
+            br#""value"#,
+            br#""a" "b""#,
+            br#"123"#,
+            br#"{}"#,
+            br#""\x""#,
+            b"\"\xff\"",
+            b"\"a\nb\"",
+        ];
+
+        for body in bodies {
+            let expected = serde_json::from_slice::<JsonValue>(body)
+                .ok()
+                .map(Vec::<u8>::from);
+            for chunk_length in 1..=body.len() {
+                assert_eq!(
+                    decode(body, chunk_length),
+                    expected,
+                    "{}",
+                    String::from_utf8_lossy(body)
+                );
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_large_json_values_are_streamed_to_a_blob() {
+        let temp_dir = tempdir().unwrap();
+        let blobs = Arc::new(BlobStore::open(temp_dir.path(), None).unwrap());
+
+        let value = "é\n".repeat(tree_node::MAX_INLINE_VALUE_LEN);
+        let (request, mut payload) = TestRequest::post()
+            .insert_header(ContentType::json())
+            .set_payload(serde_json::to_vec(&value).unwrap())
+            .to_http_parts();
+        let payload = web::Payload::from_request(&request, &mut payload)
+            .await
+            .unwrap();
+
+        let RequestValue::Blob(blob) = request_value(&request, payload, &blobs).await.unwrap()
+        else {
+            panic!("the value wasn't written to a blob");
+        };
+        assert_eq!(blobs.read(&blob).unwrap().unwrap(), value.as_bytes());
+
+        let (request, mut payload) = TestRequest::post()
+            .insert_header(ContentType::json())
+            .set_payload(format!("{value:?}x"))
+            .to_http_parts();
+        let payload = web::Payload::from_request(&request, &mut payload)
+            .await
+            .unwrap();
+        assert!(request_value(&request, payload, &blobs).await.is_err());
+        assert_eq!(blobs.ids().unwrap(), vec![blob.id]);
+    }
+
+    #[test]
+    fn test_versions_are_read_from_conditions() {
+        let if_match = |value| versions(&request(&[(header::IF_MATCH, value)]), header::IF_MATCH);
+        let if_none_match = |value| {
+            versions(
+                &request(&[(header::IF_NONE_MATCH, value)]),
+                header::IF_NONE_MATCH,
+            )
+        };
+        let listed = |versions: &[Version]| Some(Versions::Listed(versions.to_vec()));
+
+        assert_eq!(versions(&request(&[]), header::IF_MATCH).unwrap(), None);
+        assert_eq!(if_match("\"000000000000002a\"").unwrap(), listed(&[42]));
+        assert_eq!(if_match("*").unwrap(), Some(Versions::Any));
+        assert_eq!(if_match("\"01\", *").unwrap(), Some(Versions::Any));
+        assert_eq!(if_match(" \"01\" ,\"02\"").unwrap(), listed(&[1, 2]));
+
+        // Weak ETags only match in If-None-Match
+        assert_eq!(if_match("W/\"2a\"").unwrap(), listed(&[]));
+        assert_eq!(if_match("W/\"2a\", \"01\"").unwrap(), listed(&[1]));
+        assert_eq!(if_none_match("W/\"2a\"").unwrap(), listed(&[42]));
+
+        // Several headers add up
+        let request = request(&[(header::IF_MATCH, "\"01\""), (header::IF_MATCH, "\"02\"")]);
+        assert_eq!(
+            versions(&request, header::IF_MATCH).unwrap(),
+            listed(&[1, 2])
+        );
+
+        for malformed in [
+            "\"xyz\"",
+            "",
+            "\"01\",",
+            "W/\"-1\"",
+            "\"10000000000000000\"",
+        ] {
+            let error = if_none_match(malformed).unwrap_err();
+            assert_eq!(
+                error.as_response_error().status_code(),
+                StatusCode::BAD_REQUEST,
+                "{malformed}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_preconditions_are_sent_as_they_are_read() {
+        let preconditions = [
+            Precondition::default(),
+            Precondition {
+                if_match: Some(Versions::Listed(vec![1, u64::MAX])),
+                if_none_match: Some(Versions::Any),
+            },
+            Precondition {
+                if_match: Some(Versions::Any),
+                if_none_match: Some(Versions::Listed(vec![42])),
+            },
+        ];
+
+        for sent in preconditions {
+            let headers = precondition_headers(&sent);
+            let headers: Vec<_> = headers
+                .iter()
+                .map(|(name, value)| (name.clone(), value.as_str()))
+                .collect();
+            assert_eq!(precondition(&request(&headers)).unwrap(), sent);
+        }
+
+        let status = |e: TrieError| precondition_error(e).as_response_error().status_code();
+        assert_eq!(
+            status(TrieError::Conflict(vec!["key".to_string()])),
+            StatusCode::PRECONDITION_FAILED
+        );
+        assert_eq!(status(TrieError::NotFound), StatusCode::NOT_FOUND);
+        assert_eq!(status(TrieError::KeyError), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_conditional_writes() {
+        let temp_dir = tempdir().unwrap();
+        let (routes, events) = routes(temp_dir.path(), false);
+        let app = test::init_service(App::new().configure(routes)).await;
+        let write = |if_match: Option<&str>, if_none_match: Option<&str>| {
+            let mut request = TestRequest::post()
+                .uri("/kv/key")
+                .insert_header(ContentType::octet_stream());
+            if let Some(value) = if_match {
+                request = request.insert_header((header::IF_MATCH, value));
+            }
+            if let Some(value) = if_none_match {
+                request = request.insert_header((header::IF_NONE_MATCH, value));
+            }
+            request.set_payload("value").to_request()
+        };
+
+        let response = test::call_service(&app, write(None, Some("*"))).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response =
+            test::call_service(&app, TestRequest::get().uri("/kv/key").to_request()).await;
+        let version = etag(&response);
+
+        for (if_match, if_none_match, status) in [
+            (None, Some("*".to_string()), StatusCode::PRECONDITION_FAILED),
+            (
+                Some(format!("W/{version}")),
+                None,
+                StatusCode::PRECONDITION_FAILED,
+            ),
+            (
+                None,
+                Some(format!("W/{version}")),
+                StatusCode::PRECONDITION_FAILED,
+            ),
+            (Some("\"xyz\"".to_string()), None, StatusCode::BAD_REQUEST),
+            (
+                Some(format!("\"01\", {version}")),
+                None,
+                StatusCode::NO_CONTENT,
+            ),
+            // The write gave the entry a new version
+            (Some(version.clone()), None, StatusCode::PRECONDITION_FAILED),
+        ] {
+            let request = write(if_match.as_deref(), if_none_match.as_deref());
+            let response = test::call_service(&app, request).await;
+            assert_eq!(response.status(), status, "{if_match:?} {if_none_match:?}");
+        }
+
+        // A missing entry matches no version
+        let request = TestRequest::delete()
+            .uri("/kv/missing")
+            .insert_header((header::IF_MATCH, "*"))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+        let response =
+            test::call_service(&app, TestRequest::get().uri("/kv/missing").to_request()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // Only the writes applied are sent to the replicas, with their conditions and versions
+        let sent: Vec<_> = events
+            .try_iter()
+            .map(|event| match event {
+                WriteEvent::Insert(key, value, None, precondition, version) => {
+                    assert_eq!(
+                        (key.as_str(), value.as_slice()),
+                        ("key", b"value".as_slice())
+                    );
+                    (precondition, version)
+                }
+                event => panic!("unexpected event {event:?}"),
+            })
+            .collect();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].0.if_none_match, Some(Versions::Any));
+        assert_eq!(format!("\"{}\"", format_version(sent[0].1)), version);
+        assert!(sent[1].1 > sent[0].1);
+
+        // Versions are only given by the main
+        let request = TestRequest::post()
+            .uri("/kv/key?version=01")
+            .set_payload("value")
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_bulk_inserts_keep_the_last_value_of_a_key() {
+        let temp_dir = tempdir().unwrap();
+        let (routes, events) = routes(temp_dir.path(), false);
+        let app = test::init_service(App::new().configure(routes)).await;
+        let post = |body: &'static str| {
+            TestRequest::post()
+                .uri("/bulk")
+                .insert_header(ContentType::json())
+                .set_payload(body)
+                .to_request()
+        };
+
+        let response = test::call_service(&app, post(r#"{"a": "1", "b": "2", "a": "3"}"#)).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = test::call_service(&app, post(r#"{"c": "1", "C": "2"}"#)).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        for (key, value) in [("a", "\"3\""), ("b", "\"2\"")] {
+            let request = TestRequest::get().uri(&format!("/kv/{key}")).to_request();
+            assert_eq!(test::call_and_read_body(&app, request).await, value);
+        }
+        let response = test::call_service(&app, TestRequest::get().uri("/kv/c").to_request()).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let sent: Vec<_> = events.try_iter().collect();
+        assert_eq!(sent.len(), 1);
+        let WriteEvent::BulkInsert(entries, _) = &sent[0] else {
+            panic!("unexpected event {:?}", sent[0]);
+        };
+        assert_eq!(
+            entries,
+            &vec![
+                ("a".to_string(), b"3".to_vec()),
+                ("b".to_string(), b"2".to_vec())
+            ]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_transactions() {
+        let temp_dir = tempdir().unwrap();
+        let (routes, events) = routes(temp_dir.path(), false);
+        let app = test::init_service(App::new().configure(routes)).await;
+        let post =
+            |body: serde_json::Value| TestRequest::post().uri("/txn").set_json(body).to_request();
+
+        let request = post(serde_json::json!({
+            "reads": {"alice": null},
+            "writes": {"alice": "100", "Bob": [0, 255]},
+        }));
+        let response: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        let versions = &response["versions"];
+        let alice = versions["alice"].as_str().unwrap().to_string();
+        assert_eq!(versions["bob"], alice);
+
+        let response =
+            test::call_service(&app, TestRequest::get().uri("/kv/alice").to_request()).await;
+        assert_eq!(etag(&response), format!("\"{alice}\""));
+        let response = test::read_body(response).await;
+        assert_eq!(response, "\"100\"");
+
+        // Nothing is written once a read entry changed
+        let request = post(serde_json::json!({
+            "reads": {"alice": "01", "bob": alice, "carol": null},
+            "writes": {"carol": "1"},
+        }));
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let response: serde_json::Value = test::read_body_json(response).await;
+        assert_eq!(response, serde_json::json!({"conflicts": ["alice"]}));
+
+        let request = post(serde_json::json!({
+            "reads": {"alice": alice},
+            "writes": {"carol": "1"},
+            "deletes": ["ALICE"],
+        }));
+        let response: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(response["versions"]["alice"], serde_json::Value::Null);
+        assert!(response["versions"]["carol"].as_str().unwrap() > alice.as_str());
+
+        for invalid in [
+            serde_json::json!({"reads": {"alice": "xyz"}}),
+            serde_json::json!({"writes": {"dave": "1"}, "deletes": ["Dave"]}),
+            serde_json::json!({"writes": {"dave": "1", "DAVE": "2"}}),
+        ] {
+            let response = test::call_service(&app, post(invalid.clone())).await;
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST, "{invalid}");
+        }
+
+        // Replicas receive the writes applied, in order, with their version
+        let sent: Vec<_> = events.try_iter().collect();
+        assert_eq!(sent.len(), 2);
+        let WriteEvent::Transaction(writes, version) = &sent[1] else {
+            panic!("unexpected event {:?}", sent[1]);
+        };
+        assert_eq!(
+            writes,
+            &vec![
+                TransactionWrite::Put {
+                    key: "carol".to_string(),
+                    value: b"1".to_vec(),
+                },
+                TransactionWrite::Delete {
+                    key: "alice".to_string(),
+                },
+            ]
+        );
+        assert!(format_version(*version) > alice);
+    }
+
+    #[actix_web::test]
+    async fn test_replicated_writes_keep_the_version_of_the_main() {
+        let temp_dir = tempdir().unwrap();
+        let (routes, _events) = routes(temp_dir.path(), true);
+        let app = test::init_service(App::new().configure(routes)).await;
+
+        for request in [
+            TestRequest::post()
+                .uri("/kv/key?version=00000000000000ff&expires_at=18446744073709551615")
+                .set_payload("value"),
+            TestRequest::post()
+                .uri("/txn?version=00000000000000ff")
+                .set_json(serde_json::json!({"writes": {"other": "value"}})),
+        ] {
+            let response = test::call_service(&app, request.to_request()).await;
+            assert!(response.status().is_success());
+        }
+
+        for key in ["key", "other"] {
+            let request = TestRequest::get().uri(&format!("/kv/{key}")).to_request();
+            let response = test::call_service(&app, request).await;
+            assert_eq!(etag(&response), "\"00000000000000ff\"");
+        }
     }
 }
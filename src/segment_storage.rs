@@ -0,0 +1,1032 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{Error, ErrorKind, Read, Seek, SeekFrom, Write},
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use log::{error, info};
+
+use crate::page_storage::{PageFile, PageStorage};
+
+/// Log of the changes made to the files stored in the segments
+pub const TABLE_FILE_NAME: &str = "pages.table";
+const SEGMENT_EXTENSION: &str = "seg";
+/// A new segment is started once the active one reaches this size
+const SEGMENT_SIZE: u64 = 256 * 1024 * 1024;
+/// The page table is rewritten when it grows past this size and holds mostly superseded records
+const MIN_TABLE_COMPACTION_SIZE: u64 = 4 * 1024 * 1024;
+/// Space reserved for the next appends of a growing file: a quarter of its length, within these
+/// bounds. The extents of a file then grow geometrically instead of one per append
+const RESERVATION_RATIO: u64 = 4;
+const MIN_RESERVATION: u64 = 16 * 1024;
+const MAX_RESERVATION: u64 = 1024 * 1024;
+/// Bytes moved by a single garbage collection step, which holds the storage lock
+const GC_BUDGET: u64 = 16 * 1024 * 1024;
+const CHECKSUM_LENGTH: usize = size_of::<u32>();
+
+/// Data appended to a file
+const OP_APPEND: u8 = 0;
+/// All the extents of a file: creation, truncation and relocation
+const OP_SET: u8 = 1;
+const OP_REMOVE: u8 = 2;
+const OP_RENAME: u8 = 3;
+
+/// Packs all the files of a data directory in a few large segment files. Data is only ever
+/// appended to the active segment (besides in-place writes such as page headers), and the
+/// location of each file is recorded in the page table. Segments that are mostly made of
+/// superseded data are garbage collected
+pub struct SegmentStorage {
+    base_path: PathBuf,
+    state: Arc<Mutex<SegmentState>>,
+}
+
+pub struct SegmentFile {
+    state: Arc<Mutex<SegmentState>>,
+    name: String,
+    position: u64,
+}
+
+/// A contiguous part of a file, stored in a segment
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Extent {
+    segment: u32,
+    offset: u64,
+    length: u64,
+}
+
+struct Segment {
+    file: File,
+    length: u64,
+    /// Bytes of the segment still used by a file
+    live: u64,
+}
+
+enum TableRecord {
+    Append(String, Extent),
+    Set(String, Vec<Extent>),
+    Remove(String),
+    Rename(String, String),
+}
+
+enum DecodeResult {
+    Record(TableRecord, usize),
+    IncompleteRead,
+    /// The length is known when the record framing is intact
+    Corrupted(Option<usize>),
+}
+
+struct SegmentState {
+    base_path: PathBuf,
+    segments: BTreeMap<u32, Segment>,
+    segment_size: u64,
+    active: u32,
+    files: HashMap<String, Vec<Extent>>,
+    table: File,
+    table_length: u64,
+    /// Table length at which compaction is checked again
+    next_compaction_check: u64,
+    /// Segments written since the last sync
+    dirty_segments: HashSet<u32>,
+    /// Space of the active segment reserved for the next appends of a file, right after its
+    /// last extent. Reservations are given up when a new segment is started
+    reservations: HashMap<String, Extent>,
+}
+
+impl SegmentStorage {
+    /// Opens the segments of a data directory and replays the page table
+    pub fn open(base_path: &Path) -> Result<SegmentStorage, Error> {
+        Self::open_with_segment_size(base_path, SEGMENT_SIZE)
+    }
+
+    fn open_with_segment_size(
+        base_path: &Path,
+        segment_size: u64,
+    ) -> Result<SegmentStorage, Error> {
+        let mut segments = BTreeMap::new();
+        for entry in fs::read_dir(base_path)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == SEGMENT_EXTENSION) {
+                let id = path
+                    .file_stem()
+                    .and_then(|s| s.to_str())
+                    .and_then(|s| s.parse::<u32>().ok())
+                    .ok_or_else(|| invalid_data(format!("{path:?}: invalid segment name")))?;
+
+                let file = OpenOptions::new().read(true).write(true).open(&path)?;
+                let length = file.metadata()?.len();
+                segments.insert(
+                    id,
+                    Segment {
+                        file,
+                        length,
+                        live: 0,
+                    },
+                );
+            }
+        }
+
+        let table_path = base_path.join(TABLE_FILE_NAME);
+        let mut table = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .truncate(false)
+            .open(&table_path)?;
+        let mut data = vec![];
+        table.read_to_end(&mut data)?;
+
+        let (files, valid_length) = Self::replay(&data)?;
+        if valid_length < data.len() {
+            error!("The page table ends with a record that was not fully committed");
+            table.set_len(valid_length as u64)?;
+        }
+
+        let active = segments.keys().last().copied().unwrap_or(0);
+        let mut state = SegmentState {
+            base_path: base_path.to_path_buf(),
+            segments,
+            segment_size,
+            active,
+            files,
+            table,
+            table_length: valid_length as u64,
+            next_compaction_check: MIN_TABLE_COMPACTION_SIZE,
+            dirty_segments: HashSet::new(),
+            reservations: HashMap::new(),
+        };
+
+        if !state.segments.contains_key(&active) {
+            state.create_segment(active)?;
+        }
+
+        for extents in state.files.values() {
+            for extent in extents {
+                match state.segments.get_mut(&extent.segment) {
+                    Some(segment) if extent.offset + extent.length <= segment.length => {
+                        segment.live += extent.length
+                    }
+                    _ => {
+                        return Err(invalid_data(format!(
+                            "the page table references missing data in segment {}",
+                            extent.segment
+                        )))
+                    }
+                }
+            }
+        }
+
+        state.collect_garbage()?;
+        state.compact_table_if_needed()?;
+
+        Ok(SegmentStorage {
+            base_path: base_path.to_path_buf(),
+            state: Arc::new(Mutex::new(state)),
+        })
+    }
+
+    /// Rebuilds the files from the page table. Returns them with the length of the valid records
+    fn replay(data: &[u8]) -> Result<(HashMap<String, Vec<Extent>>, usize), Error> {
+        let mut files = HashMap::new();
+        let mut position = 0;
+
+        while position < data.len() {
+            match TableRecord::decode(&data[position..]) {
+                DecodeResult::Record(record, length) => {
+                    record.apply(&mut files);
+                    position += length;
+                }
+                DecodeResult::IncompleteRead => break,
+                DecodeResult::Corrupted(length) => {
+                    // Only the last record can be torn: anything else means the table is damaged
+                    if length.is_some_and(|l| position + l == data.len())
+                        || data[position..].iter().all(|b| *b == 0)
+                    {
+                        break;
+                    }
+
+                    return Err(invalid_data(format!(
+                        "the page table is corrupted at offset {position}"
+                    )));
+                }
+            }
+        }
+
+        Ok((files, position))
+    }
+
+    fn name(&self, path: &Path) -> Result<String, Error> {
+        path.strip_prefix(&self.base_path)
+            .ok()
+            .and_then(|p| p.to_str())
+            .map(|p| p.to_string())
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("{path:?} is not in the data directory"),
+                )
+            })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SegmentState> {
+        self.state.lock().unwrap()
+    }
+
+    fn file(&self, name: String) -> Box<dyn PageFile> {
+        Box::new(SegmentFile {
+            state: self.state.clone(),
+            name,
+            position: 0,
+        })
+    }
+}
+
+impl PageStorage for SegmentStorage {
+    fn open(&self, path: &Path) -> Result<Box<dyn PageFile>, Error> {
+        let name = self.name(path)?;
+        if !self.lock().files.contains_key(&name) {
+            return Err(not_found(&name));
+        }
+
+        Ok(self.file(name))
+    }
+
+    fn create(&self, path: &Path, truncate: bool) -> Result<Box<dyn PageFile>, Error> {
+        let name = self.name(path)?;
+        let mut state = self.lock();
+        if truncate || !state.files.contains_key(&name) {
+            state.set(&name, vec![])?;
+            state.collect_garbage()?;
+        }
+
+        Ok(self.file(name))
+    }
+
+    fn read(&self, path: &Path) -> Result<Vec<u8>, Error> {
+        let name = self.name(path)?;
+        let state = self.lock();
+        let length = state.length(&name)?;
+
+        let mut data = vec![0; length as usize];
+        state.read_at(&name, 0, &mut data)?;
+
+        Ok(data)
+    }
+
+    fn write(&self, path: &Path, data: &[u8]) -> Result<(), Error> {
+        let name = self.name(path)?;
+        let mut state = self.lock();
+        state.set(&name, vec![])?;
+        state.append(&name, data)?;
+
+        state.collect_garbage()
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<(), Error> {
+        let (from, to) = (self.name(from)?, self.name(to)?);
+        let mut state = self.lock();
+        let extents = state.files.remove(&from).ok_or_else(|| not_found(&from))?;
+
+        state.write_record(&TableRecord::Rename(from.clone(), to.clone()))?;
+        state.reservations.remove(&from);
+        state.reservations.remove(&to);
+        if let Some(previous) = state.files.insert(to, extents) {
+            state.release(&previous);
+        }
+
+        state.collect_garbage()
+    }
+
+    fn remove(&self, path: &Path) -> Result<(), Error> {
+        let name = self.name(path)?;
+        let mut state = self.lock();
+        let extents = state.files.remove(&name).ok_or_else(|| not_found(&name))?;
+
+        state.reservations.remove(&name);
+        state.write_record(&TableRecord::Remove(name))?;
+        state.release(&extents);
+
+        state.collect_garbage()
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.name(path)
+            .is_ok_and(|name| self.lock().files.contains_key(&name))
+    }
+
+    fn list(&self, directory: &Path) -> Result<Vec<PathBuf>, Error> {
+        Ok(self
+            .lock()
+            .files
+            .keys()
+            .map(|name| self.base_path.join(name))
+            .filter(|path| path.parent() == Some(directory))
+            .collect())
+    }
+
+    fn sync_directory(&self, _directory: &Path) -> Result<(), Error> {
+        self.lock().sync()
+    }
+}
+
+impl SegmentState {
+    fn length(&self, name: &str) -> Result<u64, Error> {
+        match self.files.get(name) {
+            Some(extents) => Ok(extents.iter().map(|e| e.length).sum()),
+            None => Err(not_found(name)),
+        }
+    }
+
+    fn read_at(&self, name: &str, position: u64, buffer: &mut [u8]) -> Result<usize, Error> {
+        let extents = self.files.get(name).ok_or_else(|| not_found(name))?;
+
+        let mut read = 0;
+        let mut start = 0;
+        for extent in extents {
+            if read == buffer.len() {
+                break;
+            }
+
+            let end = start + extent.length;
+            let at = position + read as u64;
+            if read < buffer.len() && at >= start && at < end {
+                let length = (end - at).min((buffer.len() - read) as u64) as usize;
+                self.segments[&extent.segment]
+                    .file
+                    .read_exact_at(&mut buffer[read..read + length], extent.offset + at - start)?;
+                read += length;
+            }
+            start = end;
+        }
+
+        Ok(read)
+    }
+
+    /// Writes over the existing bytes of a file, appending what goes past its end
+    fn write_at(&mut self, name: &str, position: u64, data: &[u8]) -> Result<(), Error> {
+        let length = self.length(name)?;
+        if position > length {
+            self.append(name, &vec![0; (position - length) as usize])?;
+        }
+
+        let mut written = 0;
+        let mut start = 0;
+        for extent in &self.files[name] {
+            if written == data.len() {
+                break;
+            }
+
+            let end = start + extent.length;
+            let at = position + written as u64;
+            if written < data.len() && at >= start && at < end {
+                let length = (end - at).min((data.len() - written) as u64) as usize;
+                self.segments[&extent.segment]
+                    .file
+                    .write_all_at(&data[written..written + length], extent.offset + at - start)?;
+                self.dirty_segments.insert(extent.segment);
+                written += length;
+            }
+            start = end;
+        }
+
+        if written < data.len() {
+            self.append(name, &data[written..])?;
+        }
+
+        Ok(())
+    }
+
+    /// Appends data to a file, in the active segment. Files that keep growing get space reserved
+    /// after their last extent, so that their appends extend it instead of interleaving with the
+    /// appends of the other files
+    fn append(&mut self, name: &str, data: &[u8]) -> Result<(), Error> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let length = data.len() as u64;
+        let extents = self.files.get(name);
+        let last = extents.and_then(|e| e.last().copied());
+        let reservation = self.reservations.get(name).copied().filter(|r| {
+            r.length >= length
+                && last.is_some_and(|l| l.segment == r.segment && l.offset + l.length == r.offset)
+        });
+
+        let extent = match reservation {
+            Some(reservation) => reservation,
+            None => {
+                // New files get the space they need only: most are written at once
+                let file_length: u64 = extents.map_or(0, |e| e.iter().map(|e| e.length).sum());
+                let reserved = if file_length == 0 {
+                    length
+                } else {
+                    length.max(
+                        (file_length / RESERVATION_RATIO).clamp(MIN_RESERVATION, MAX_RESERVATION),
+                    )
+                };
+
+                self.allocate(reserved)?
+            }
+        };
+
+        self.reservations.remove(name);
+        if extent.length > length {
+            let rest = Extent {
+                segment: extent.segment,
+                offset: extent.offset + length,
+                length: extent.length - length,
+            };
+            self.reservations.insert(name.to_string(), rest);
+        }
+
+        let extent = Extent { length, ..extent };
+        let segment = self.segments.get_mut(&extent.segment).unwrap();
+        segment.file.write_all_at(data, extent.offset)?;
+        segment.live += length;
+        self.dirty_segments.insert(extent.segment);
+
+        let record = TableRecord::Append(name.to_string(), extent);
+        self.write_record(&record)?;
+        record.apply(&mut self.files);
+
+        Ok(())
+    }
+
+    /// Allocates space at the end of the active segment, starting a new segment when it is full
+    fn allocate(&mut self, length: u64) -> Result<Extent, Error> {
+        if self.segments[&self.active].length >= self.segment_size {
+            self.create_segment(self.active + 1)?;
+            // The space left in the previous segment is given up, so that it can be collected
+            self.reservations.clear();
+        }
+
+        let segment = self.segments.get_mut(&self.active).unwrap();
+        let extent = Extent {
+            segment: self.active,
+            offset: segment.length,
+            length,
+        };
+        segment.length += length;
+
+        Ok(extent)
+    }
+
+    /// Replaces the extents of a file
+    fn set(&mut self, name: &str, extents: Vec<Extent>) -> Result<(), Error> {
+        self.write_record(&TableRecord::Set(name.to_string(), extents.clone()))?;
+        self.reservations.remove(name);
+
+        for extent in &extents {
+            if let Some(segment) = self.segments.get_mut(&extent.segment) {
+                segment.live += extent.length;
+            }
+        }
+        if let Some(previous) = self.files.insert(name.to_string(), extents) {
+            self.release(&previous);
+        }
+
+        Ok(())
+    }
+
+    /// Accounts for extents that are not used anymore
+    fn release(&mut self, extents: &[Extent]) {
+        for extent in extents {
+            if let Some(segment) = self.segments.get_mut(&extent.segment) {
+                segment.live -= extent.length;
+            }
+        }
+    }
+
+    /// Moves the data still used in mostly superseded segments to the active segment, then
+    /// removes them. Every call moves about `GC_BUDGET` bytes at most, so that the operations
+    /// waiting for the storage lock are only delayed briefly: the next calls move the rest. The
+    /// page table is made durable before a segment is removed, so that it never references a
+    /// removed segment
+    fn collect_garbage(&mut self) -> Result<(), Error> {
+        let collected: Vec<u32> = self
+            .segments
+            .iter()
+            .filter(|(id, s)| **id != self.active && s.live * 2 <= s.length)
+            .map(|(id, _)| *id)
+            .collect();
+
+        let mut moved = 0;
+        let mut emptied = vec![];
+        for id in collected {
+            let names: Vec<String> = self
+                .files
+                .iter()
+                .filter(|(_, extents)| extents.iter().any(|e| e.segment == id))
+                .map(|(name, _)| name.clone())
+                .collect();
+
+            for name in names {
+                if moved >= GC_BUDGET {
+                    break;
+                }
+
+                moved += self.relocate(&name, id)?;
+            }
+
+            if self.files.values().flatten().any(|e| e.segment == id) {
+                break;
+            }
+            emptied.push(id);
+        }
+
+        if emptied.is_empty() {
+            return Ok(());
+        }
+
+        self.sync()?;
+        for id in emptied {
+            info!("Removing collected segment {id}");
+            self.segments.remove(&id);
+            fs::remove_file(segment_path(&self.base_path, id))?;
+        }
+
+        File::open(&self.base_path)?.sync_all()
+    }
+
+    /// Moves the extents of a file stored in a segment to the active segment. Returns the number
+    /// of bytes moved
+    fn relocate(&mut self, name: &str, id: u32) -> Result<u64, Error> {
+        let mut moved = 0;
+        let mut extents: Vec<Extent> = vec![];
+        for extent in self.files[name].clone() {
+            let extent = if extent.segment == id {
+                let mut data = vec![0; extent.length as usize];
+                self.segments[&id]
+                    .file
+                    .read_exact_at(&mut data, extent.offset)?;
+
+                let relocated = self.allocate(extent.length)?;
+                self.segments[&relocated.segment]
+                    .file
+                    .write_all_at(&data, relocated.offset)?;
+                self.dirty_segments.insert(relocated.segment);
+                moved += extent.length;
+
+                relocated
+            } else {
+                extent
+            };
+
+            match extents.last_mut() {
+                Some(last)
+                    if last.segment == extent.segment
+                        && last.offset + last.length == extent.offset =>
+                {
+                    last.length += extent.length
+                }
+                _ => extents.push(extent),
+            }
+        }
+
+        self.set(name, extents)?;
+
+        Ok(moved)
+    }
+
+    fn create_segment(&mut self, id: u32) -> Result<(), Error> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(segment_path(&self.base_path, id))?;
+        File::open(&self.base_path)?.sync_all()?;
+
+        self.segments.insert(
+            id,
+            Segment {
+                file,
+                length: 0,
+                live: 0,
+            },
+        );
+        self.active = id;
+
+        Ok(())
+    }
+
+    fn write_record(&mut self, record: &TableRecord) -> Result<(), Error> {
+        let buffer = record.encode();
+        self.table.write_all(&buffer)?;
+        self.table_length += buffer.len() as u64;
+
+        Ok(())
+    }
+
+    /// Makes the data written to the segments and the page table durable
+    fn sync(&mut self) -> Result<(), Error> {
+        for id in self.dirty_segments.drain() {
+            if let Some(segment) = self.segments.get(&id) {
+                segment.file.sync_data()?;
+            }
+        }
+
+        self.table.sync_data()
+    }
+
+    /// Rewrites the page table with a single record per file when it is mostly made of
+    /// superseded records
+    fn compact_table_if_needed(&mut self) -> Result<(), Error> {
+        if self.table_length < self.next_compaction_check {
+            return Ok(());
+        }
+
+        let mut buffer = vec![];
+        for (name, extents) in &self.files {
+            buffer.extend(TableRecord::Set(name.clone(), extents.clone()).encode());
+        }
+
+        self.next_compaction_check = (buffer.len() as u64 * 2).max(MIN_TABLE_COMPACTION_SIZE);
+        if self.table_length < self.next_compaction_check {
+            return Ok(());
+        }
+
+        self.sync()?;
+
+        let table_path = self.base_path.join(TABLE_FILE_NAME);
+        let mut temp_path = table_path.clone().into_os_string();
+        temp_path.push(".tmp");
+        {
+            let mut file = File::create(&temp_path)?;
+            file.write_all(&buffer)?;
+            file.sync_all()?;
+        }
+
+        fs::rename(&temp_path, &table_path)?;
+        File::open(&self.base_path)?.sync_all()?;
+
+        info!(
+            "Compacted the page table: {} -> {} bytes",
+            self.table_length,
+            buffer.len()
+        );
+        self.table = OpenOptions::new().append(true).open(&table_path)?;
+        self.table_length = buffer.len() as u64;
+
+        Ok(())
+    }
+}
+
+impl Read for SegmentFile {
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+        let read = self
+            .state
+            .lock()
+            .unwrap()
+            .read_at(&self.name, self.position, buffer)?;
+        self.position += read as u64;
+
+        Ok(read)
+    }
+}
+
+impl Write for SegmentFile {
+    fn write(&mut self, data: &[u8]) -> Result<usize, Error> {
+        let mut state = self.state.lock().unwrap();
+        state.write_at(&self.name, self.position, data)?;
+        state.compact_table_if_needed()?;
+        self.position += data.len() as u64;
+
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl Seek for SegmentFile {
+    fn seek(&mut self, position: SeekFrom) -> Result<u64, Error> {
+        let position = match position {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(p) => self.length()?.checked_add_signed(p),
+            SeekFrom::Current(p) => self.position.checked_add_signed(p),
+        };
+
+        self.position = position.ok_or_else(|| {
+            Error::new(ErrorKind::InvalidInput, "seek before the start of the file")
+        })?;
+
+        Ok(self.position)
+    }
+}
+
+impl PageFile for SegmentFile {
+    fn length(&self) -> Result<u64, Error> {
+        self.state.lock().unwrap().length(&self.name)
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        self.state.lock().unwrap().sync()
+    }
+}
+
+impl TableRecord {
+    fn encode(&self) -> Vec<u8> {
+        let (op, name) = match self {
+            TableRecord::Append(name, _) => (OP_APPEND, name),
+            TableRecord::Set(name, _) => (OP_SET, name),
+            TableRecord::Remove(name) => (OP_REMOVE, name),
+            TableRecord::Rename(name, _) => (OP_RENAME, name),
+        };
+
+        let mut buffer = vec![op];
+        push_name(&mut buffer, name);
+        match self {
+            TableRecord::Append(_, extent) => extent.encode(&mut buffer),
+            TableRecord::Set(_, extents) => {
+                buffer.extend_from_slice(&(extents.len() as u32).to_le_bytes());
+                for extent in extents {
+                    extent.encode(&mut buffer);
+                }
+            }
+            TableRecord::Remove(_) => {}
+            TableRecord::Rename(_, to) => push_name(&mut buffer, to),
+        }
+
+        buffer.extend_from_slice(&crc32fast::hash(&buffer).to_le_bytes());
+
+        buffer
+    }
+
+    fn decode(buffer: &[u8]) -> DecodeResult {
+        let mut reader = RecordReader {
+            buffer,
+            position: 1,
+        };
+
+        let Some(op) = buffer.first() else {
+            return DecodeResult::IncompleteRead;
+        };
+
+        let Some(name) = reader.name() else {
+            return DecodeResult::IncompleteRead;
+        };
+
+        let record = match *op {
+            OP_APPEND => reader.extent().map(|e| TableRecord::Append(name, e)),
+            OP_SET => reader.u32().and_then(|count| {
+                let extents: Option<Vec<Extent>> = (0..count).map(|_| reader.extent()).collect();
+                extents.map(|e| TableRecord::Set(name, e))
+            }),
+            OP_REMOVE => Some(TableRecord::Remove(name)),
+            OP_RENAME => reader.name().map(|to| TableRecord::Rename(name, to)),
+            _ => return DecodeResult::Corrupted(None),
+        };
+
+        let Some(record) = record else {
+            return DecodeResult::IncompleteRead;
+        };
+
+        let length = reader.position + CHECKSUM_LENGTH;
+        let Some(checksum) = buffer.get(reader.position..length) else {
+            return DecodeResult::IncompleteRead;
+        };
+
+        if crc32fast::hash(&buffer[..reader.position]).to_le_bytes() != checksum {
+            return DecodeResult::Corrupted(Some(length));
+        }
+
+        DecodeResult::Record(record, length)
+    }
+
+    fn apply(&self, files: &mut HashMap<String, Vec<Extent>>) {
+        match self {
+            TableRecord::Append(name, extent) => {
+                let extents = files.entry(name.clone()).or_default();
+                match extents.last_mut() {
+                    Some(last)
+                        if last.segment == extent.segment
+                            && last.offset + last.length == extent.offset =>
+                    {
+                        last.length += extent.length
+                    }
+                    _ => extents.push(*extent),
+                }
+            }
+            TableRecord::Set(name, extents) => {
+                files.insert(name.clone(), extents.clone());
+            }
+            TableRecord::Remove(name) => {
+                files.remove(name);
+            }
+            TableRecord::Rename(from, to) => {
+                if let Some(extents) = files.remove(from) {
+                    files.insert(to.clone(), extents);
+                }
+            }
+        }
+    }
+}
+
+impl Extent {
+    fn encode(&self, buffer: &mut Vec<u8>) {
+        buffer.extend_from_slice(&self.segment.to_le_bytes());
+        buffer.extend_from_slice(&self.offset.to_le_bytes());
+        buffer.extend_from_slice(&self.length.to_le_bytes());
+    }
+}
+
+/// Reads the fields of a page table record. Returns `None` past the end of the buffer
+struct RecordReader<'a> {
+    buffer: &'a [u8],
+    position: usize,
+}
+
+impl RecordReader<'_> {
+    fn bytes(&mut self, length: usize) -> Option<&[u8]> {
+        let bytes = self.buffer.get(self.position..self.position + length)?;
+        self.position += length;
+
+        Some(bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn name(&mut self) -> Option<String> {
+        let length = u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()) as usize;
+
+        Some(String::from_utf8_lossy(self.bytes(length)?).into_owned())
+    }
+
+    fn extent(&mut self) -> Option<Extent> {
+        Some(Extent {
+            segment: self.u32()?,
+            offset: self.u64()?,
+            length: self.u64()?,
+        })
+    }
+}
+
+fn push_name(buffer: &mut Vec<u8>, name: &str) {
+    buffer.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    buffer.extend_from_slice(name.as_bytes());
+}
+
+fn segment_path(base_path: &Path, id: u32) -> PathBuf {
+    base_path.join(format!("{id:08}.{SEGMENT_EXTENSION}"))
+}
+
+fn not_found(name: &str) -> Error {
+    Error::new(ErrorKind::NotFound, format!("{name}: file not found"))
+}
+
+fn invalid_data(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn read_all(storage: &SegmentStorage, path: &Path) -> Vec<u8> {
+        let mut file = storage.open(path).unwrap();
+        let mut buffer = vec![];
+        file.read_to_end(&mut buffer).unwrap();
+
+        buffer
+    }
+
+    #[test]
+    fn test_files_survive_reopen() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path();
+        let storage = SegmentStorage::open(path).unwrap();
+
+        let page = path.join("a.dat");
+        let mut file = storage.create(&page, false).unwrap();
+        file.write_all(b"header").unwrap();
+        storage.write(&path.join("b.bloom"), b"filter").unwrap();
+        file.write_all(b" records").unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.write_all(b"HEADER").unwrap();
+        file.seek(SeekFrom::End(0)).unwrap();
+        file.write_all(b" tail").unwrap();
+        file.sync().unwrap();
+        assert_eq!(file.length().unwrap(), 19);
+
+        storage.write(&path.join("c.tmp"), b"new page").unwrap();
+        storage
+            .rename(&path.join("c.tmp"), &path.join("b.dat"))
+            .unwrap();
+        storage.remove(&path.join("b.bloom")).unwrap();
+        assert!(matches!(
+            storage.open(&path.join("c.tmp")).map(|_| ()),
+            Err(e) if e.kind() == ErrorKind::NotFound
+        ));
+        drop(storage);
+
+        let storage = SegmentStorage::open(path).unwrap();
+        assert_eq!(read_all(&storage, &page), b"HEADER records tail");
+        assert_eq!(storage.read(&path.join("b.dat")).unwrap(), b"new page");
+        assert!(!storage.exists(&path.join("b.bloom")));
+
+        let mut files = storage.list(path).unwrap();
+        files.sort();
+        assert_eq!(files, vec![path.join("a.dat"), path.join("b.dat")]);
+    }
+
+    #[test]
+    fn test_torn_table_record_is_discarded() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path();
+        let storage = SegmentStorage::open(path).unwrap();
+        storage.write(&path.join("a.dat"), b"page").unwrap();
+        storage.sync_directory(path).unwrap();
+        drop(storage);
+
+        let table_path = path.join(TABLE_FILE_NAME);
+        let length = fs::metadata(&table_path).unwrap().len();
+        let record = TableRecord::Remove("a.dat".to_string()).encode();
+        let mut table = OpenOptions::new().append(true).open(&table_path).unwrap();
+        table.write_all(&record[..record.len() - 1]).unwrap();
+
+        let storage = SegmentStorage::open(path).unwrap();
+        assert_eq!(storage.read(&path.join("a.dat")).unwrap(), b"page");
+        assert_eq!(fs::metadata(&table_path).unwrap().len(), length);
+
+        // A damaged record followed by valid ones is not a torn write
+        drop(storage);
+        let mut buffer = fs::read(&table_path).unwrap();
+        buffer[4] ^= 0xFF;
+        fs::write(&table_path, buffer).unwrap();
+        assert!(matches!(
+            SegmentStorage::open(path).map(|_| ()),
+            Err(e) if e.kind() == ErrorKind::InvalidData
+        ));
+    }
+
+    #[test]
+    fn test_interleaved_appends_extend_their_extents() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path();
+        let storage = SegmentStorage::open(path).unwrap();
+
+        let mut a = storage.create(&path.join("a.dat"), false).unwrap();
+        let mut b = storage.create(&path.join("b.dat"), false).unwrap();
+        for i in 0..1000 {
+            a.write_all(&[i as u8; 100]).unwrap();
+            b.write_all(&[i as u8; 200]).unwrap();
+        }
+
+        for name in ["a.dat", "b.dat"] {
+            let extents = storage.lock().files[name].len();
+            assert!(extents < 20, "{extents} extents");
+        }
+        drop((a, b, storage));
+
+        let storage = SegmentStorage::open(path).unwrap();
+        let a = read_all(&storage, &path.join("a.dat"));
+        assert_eq!(a.len(), 100_000);
+        assert!(a.chunks(100).enumerate().all(|(i, c)| c == [i as u8; 100]));
+        assert_eq!(storage.read(&path.join("b.dat")).unwrap().len(), 200_000);
+    }
+
+    #[test]
+    fn test_superseded_segments_are_collected() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path();
+        let storage = SegmentStorage::open_with_segment_size(path, 4096).unwrap();
+
+        storage.write(&path.join("kept.dat"), &[1; 1000]).unwrap();
+        for i in 0..100 {
+            storage
+                .write(&path.join("page.dat"), &[i as u8; 1000])
+                .unwrap();
+        }
+
+        let segments = || {
+            fs::read_dir(path)
+                .unwrap()
+                .filter(|e| {
+                    e.as_ref()
+                        .unwrap()
+                        .path()
+                        .extension()
+                        .is_some_and(|e| e == SEGMENT_EXTENSION)
+                })
+                .count()
+        };
+        assert!(segments() <= 3, "{} segments", segments());
+        assert_eq!(storage.read(&path.join("kept.dat")).unwrap(), [1; 1000]);
+        assert_eq!(storage.read(&path.join("page.dat")).unwrap(), [99; 1000]);
+        drop(storage);
+
+        let storage = SegmentStorage::open_with_segment_size(path, 4096).unwrap();
+        assert_eq!(storage.read(&path.join("kept.dat")).unwrap(), [1; 1000]);
+        assert_eq!(storage.read(&path.join("page.dat")).unwrap(), [99; 1000]);
+    }
+}
@@ -0,0 +1,94 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Serialize;
+
+use crate::configuration::FSyncStrategy;
+
+/// Counters shared by all the pages of a store
+#[derive(Default)]
+pub struct Stats {
+    compactions: AtomicU64,
+    compacted_bytes: AtomicU64,
+    splits: AtomicU64,
+    merges: AtomicU64,
+    filtered_lookups: AtomicU64,
+    mapped_reads: AtomicU64,
+}
+
+#[derive(Serialize)]
+pub struct StatsSnapshot {
+    pub compactions: u64,
+    pub compacted_bytes: u64,
+    pub splits: u64,
+    pub merges: u64,
+    /// Lookups of missing keys answered by a Bloom filter without reading the page
+    pub filtered_lookups: u64,
+    /// Reads served from the sorted records of pages mapped in memory
+    pub mapped_reads: u64,
+}
+
+/// The writes a crash of the host can lose, returned by `GET /stats`
+#[derive(Serialize)]
+pub struct DurabilityWindow {
+    pub fsync: FSyncStrategy,
+    /// Most acknowledged writes that can be lost, if bounded
+    pub max_unsynced_writes: Option<u64>,
+    /// Most seconds of acknowledged writes that can be lost, if bounded
+    pub max_unsynced_seconds: Option<u64>,
+    /// Writes acknowledged since the last sync
+    pub unsynced_writes: u64,
+}
+
+impl DurabilityWindow {
+    pub fn new(fsync: FSyncStrategy, unsynced_writes: u64) -> DurabilityWindow {
+        let (max_unsynced_writes, max_unsynced_seconds) = match fsync {
+            FSyncStrategy::Default => (None, None),
+            FSyncStrategy::Strict => (Some(0), Some(0)),
+            // The last write of the batch is acknowledged once the batch is synced
+            FSyncStrategy::EveryOperations(n) => (Some(n.get() - 1), None),
+            FSyncStrategy::EverySeconds(n) => (None, Some(n.get())),
+        };
+
+        DurabilityWindow {
+            fsync,
+            max_unsynced_writes,
+            max_unsynced_seconds,
+            unsynced_writes,
+        }
+    }
+}
+
+impl Stats {
+    pub fn record_compaction(&self, reclaimed_bytes: usize) {
+        self.compactions.fetch_add(1, Ordering::Relaxed);
+        self.compacted_bytes
+            .fetch_add(reclaimed_bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_split(&self) {
+        self.splits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_merge(&self) {
+        self.merges.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_filtered_lookup(&self) {
+        self.filtered_lookups.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_mapped_read(&self) {
+        self.mapped_reads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            compactions: self.compactions.load(Ordering::Relaxed),
+            compacted_bytes: self.compacted_bytes.load(Ordering::Relaxed),
+            splits: self.splits.load(Ordering::Relaxed),
+            merges: self.merges.load(Ordering::Relaxed),
+            filtered_lookups: self.filtered_lookups.load(Ordering::Relaxed),
+            mapped_reads: self.mapped_reads.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use std::{collections::HashSet, path::PathBuf, sync::Arc};
+
+use serde::Serialize;
+
+use crate::dirty_pages::Shutdown;
+use crate::page_storage::{PageFile, PageStorage};
+use crate::transaction::Version;
+use crate::tree_node::{TrieError, Value};
+
+/// Where the entries of a store are kept. Keys are already normalized and values already
+/// validated: values too large to be stored inline are written to the blob store beforehand,
+/// and a storage removes the blob of a value once it is overwritten or deleted
+pub trait Storage: Send + Sync {
+    /// Returns the value of an entry and its version, unless it doesn't exist or has expired
+    fn get(&mut self, key: &str) -> Result<(Value, Version), TrieError>;
+    /// Inserts or overwrites an entry with the version of the write, hidden from the given
+    /// unix timestamp (in seconds)
+    fn put(
+        &mut self,
+        key: String,
+        value: Value,
+        expires_at: Option<u64>,
+        version: Version,
+    ) -> Result<(), TrieError>;
+    /// Removes an entry. Removing a missing entry is not an error
+    fn delete(&mut self, key: String) -> Result<(), TrieError>;
+    /// Returns the live entries whose keys are within the given range, bounds included, in
+    /// key order. A range whose start is after its end holds no entry
+    fn range(
+        &mut self,
+        start_key: &str,
+        end_key: &str,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, Value)>, TrieError>;
+    /// Makes the changes made so far durable
+    fn flush(&mut self) -> Result<(), TrieError>;
+    /// Returns what must be synced to make the changes made so far durable, so that it can be
+    /// synced without holding the storage
+    fn pending_sync(&mut self) -> Result<PendingSync, TrieError> {
+        Ok(PendingSync::default())
+    }
+    /// Makes the changes durable before the process stops. Nothing is written afterwards
+    fn close(&mut self) -> Result<(), TrieError> {
+        self.flush()
+    }
+    /// Checks the storage at startup, repairing what a crash left behind. Returns the ids of
+    /// the blobs still referenced, the others are removed
+    fn sanity_check(&mut self) -> Result<HashSet<u64>, TrieError>;
+    /// Prepares the storage to serve requests, repairing what a crash left behind. Storages
+    /// that know which pages were written since the last clean shutdown only check these
+    /// pages, the others are checked in the background by `verify_page`
+    fn recover(&mut self) -> Result<Recovery, TrieError> {
+        Ok(Recovery {
+            shutdown: Shutdown::Unknown,
+            checked_pages: 0,
+            applied_writes: 0,
+            quarantined_writes: 0,
+            referenced_blobs: Some(self.sanity_check()?),
+        })
+    }
+    /// Whether every blob of the data directory that the storage doesn't reference can be
+    /// removed. Storages that don't persist their entries can't tell
+    fn owns_blobs(&self) -> bool {
+        true
+    }
+    /// Checks a page without modifying it, while the storage serves requests. The root page
+    /// has an empty prefix. Returns `None` if the page doesn't exist
+    fn verify_page(&self, prefix: &[u8]) -> Result<Option<PageCheck>, TrieError>;
+}
+
+/// How a storage was prepared at startup
+#[derive(Serialize, Clone, Debug)]
+pub struct Recovery {
+    /// How the store was stopped
+    pub shutdown: Shutdown,
+    /// Number of pages checked before serving requests, besides the root page
+    pub checked_pages: usize,
+    /// Number of writes of the WAL applied again
+    pub applied_writes: usize,
+    /// Number of writes of the WAL that failed to be applied again, kept in a copy of the WAL
+    pub quarantined_writes: usize,
+    /// Ids of the blobs still referenced, when the whole storage was checked
+    #[serde(skip)]
+    pub referenced_blobs: Option<HashSet<u64>>,
+}
+
+/// Files holding changes that aren't durable yet
+#[derive(Default)]
+pub struct PendingSync {
+    files: Vec<Box<dyn PageFile>>,
+    /// Directory whose renames and removals must be synced as well
+    directory: Option<(Arc<dyn PageStorage>, PathBuf)>,
+}
+
+impl PendingSync {
+    pub fn new(
+        files: Vec<Box<dyn PageFile>>,
+        storage: Arc<dyn PageStorage>,
+        directory: PathBuf,
+    ) -> PendingSync {
+        PendingSync {
+            files,
+            directory: Some((storage, directory)),
+        }
+    }
+
+    /// A single file, e.g. a log, whose directory is already durable
+    pub fn file(file: Box<dyn PageFile>) -> PendingSync {
+        PendingSync {
+            files: vec![file],
+            directory: None,
+        }
+    }
+
+    pub fn sync(self) -> Result<(), TrieError> {
+        for file in self.files {
+            file.sync()?;
+        }
+
+        if let Some((storage, directory)) = self.directory {
+            storage.sync_directory(&directory)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// What the verification of a page found
+#[derive(Default)]
+pub struct PageCheck {
+    /// Prefixes of the child pages
+    pub children: Vec<Vec<u8>>,
+    /// Ids of the blobs referenced by the entries of the page
+    pub blobs: Vec<u64>,
+    pub problems: Vec<String>,
+}
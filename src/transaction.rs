@@ -0,0 +1,101 @@
+/// Version of an entry, as checked by transactions: the sequence number of the write that
+/// gave the entry its value and expiry. The writes of a batch share the number of its last
+/// write. Replicas are given the versions of the main, so that every node holding the same
+/// entries gives them the same versions. Entries written before versions were recorded have
+/// version 0
+pub type Version = u64;
+
+/// A set of writes applied atomically, provided the entries it read were not written since
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Transaction {
+    /// Entries read by the transaction, with the version they had when they were read, or
+    /// `None` if they didn't exist
+    pub reads: Vec<(String, Option<Version>)>,
+    pub writes: Vec<TransactionWrite>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransactionWrite {
+    Put { key: String, value: Vec<u8> },
+    Delete { key: String },
+}
+
+impl TransactionWrite {
+    pub fn key(&self) -> &str {
+        match self {
+            TransactionWrite::Put { key, .. } | TransactionWrite::Delete { key } => key,
+        }
+    }
+}
+
+/// Versions listed by an `If-Match` or `If-None-Match` condition
+#[derive(Debug, Clone, PartialEq)]
+pub enum Versions {
+    /// `*`: any version, as long as the entry exists
+    Any,
+    Listed(Vec<Version>),
+}
+
+impl Versions {
+    fn contain(&self, version: Option<Version>) -> bool {
+        match (self, version) {
+            (_, None) => false,
+            (Versions::Any, Some(_)) => true,
+            (Versions::Listed(versions), Some(version)) => versions.contains(&version),
+        }
+    }
+}
+
+/// Conditions of a write on the current version of its entry. The default one always holds
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Precondition {
+    /// The entry must have one of these versions
+    pub if_match: Option<Versions>,
+    /// The entry must have none of these versions
+    pub if_none_match: Option<Versions>,
+}
+
+impl Precondition {
+    /// Whether an entry with the given version (`None` if it doesn't exist) can be written
+    pub fn holds(&self, version: Option<Version>) -> bool {
+        self.if_match.as_ref().is_none_or(|v| v.contain(version))
+            && !self
+                .if_none_match
+                .as_ref()
+                .is_some_and(|v| v.contain(version))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_preconditions() {
+        let precondition = |if_match, if_none_match| Precondition {
+            if_match,
+            if_none_match,
+        };
+
+        assert!(Precondition::default().holds(None));
+        assert!(Precondition::default().holds(Some(1)));
+
+        let absent = precondition(None, Some(Versions::Any));
+        assert!(absent.holds(None));
+        assert!(!absent.holds(Some(1)));
+
+        let exists = precondition(Some(Versions::Any), None);
+        assert!(!exists.holds(None));
+        assert!(exists.holds(Some(1)));
+
+        let matches = precondition(Some(Versions::Listed(vec![1, 2])), None);
+        assert!(matches.holds(Some(2)));
+        assert!(!matches.holds(Some(3)));
+        assert!(!matches.holds(None));
+
+        let changed = precondition(None, Some(Versions::Listed(vec![1])));
+        assert!(!changed.holds(Some(1)));
+        assert!(changed.holds(Some(2)));
+        assert!(changed.holds(None));
+    }
+}
@@ -2,20 +2,128 @@ use std::io::BufWriter;
 use std::ops::Bound::Included;
 use std::str;
 use std::{
+    cmp::Ordering,
     collections::BTreeMap,
-    fs::{File, OpenOptions},
-    io::{BufReader, Read, Seek, SeekFrom, Write},
+    io::{Read, Seek, SeekFrom, Write},
     ops::Bound,
-    path::PathBuf,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use log::{debug, error};
+use memmap2::Mmap;
+use xxhash_rust::xxh3::xxh3_128;
+
+use crate::blob_store::{BlobRef, BlobStore, BLOB_REF_LENGTH};
+use crate::bloom_filter::BloomFilter;
+use crate::compression::Compression;
+use crate::configuration::FSyncStrategy;
+use crate::dirty_pages::DirtyPages;
+use crate::encryption::{
+    random_u64, Cipher, Keyring, OpenResult, FRAME_OVERHEAD, KEY_CHECK_LENGTH, NONCE_LENGTH,
+    TAG_LENGTH,
+};
+use crate::group_commit::GroupCommit;
+use crate::page_storage::{FileStorage, PageFile, PageStorage};
+use crate::stats::Stats;
+use crate::transaction::Version;
 
 pub const SPLIT_THRESHOLD: usize = 8 * 1024 * 1024; // 8MB
-pub const IO_BUFFER_SIZE: usize = MAX_VALUE_LEN + MAX_KEY_LEN * 2;
+pub const IO_BUFFER_SIZE: usize = MAX_INLINE_VALUE_LEN + MAX_KEY_LEN * 2;
 pub const MAX_KEY_LEN: usize = u8::MAX as usize;
-pub const MAX_VALUE_LEN: usize = 32 * 1024; // 1MB
-pub const METADATA_LENGTH: usize = MAX_KEY_LEN + size_of::<u8>() + size_of::<u32>() + 36; // 8KB
+/// Larger values are stored in blob files instead of the page
+pub const MAX_INLINE_VALUE_LEN: usize = 32 * 1024; // 32KB
+pub const MAX_VALUE_LEN: usize = 256 * 1024 * 1024; // 256MB
+pub const HEADER_LENGTH: usize = 512;
+pub const DEFAULT_COMPACTION_RATIO: f64 = 1.0;
+/// Pages smaller than this are never compacted
+const MIN_COMPACTION_SIZE: usize = 256 * 1024;
+/// The children of a node are merged back into it when their live entries fit in this size
+pub const MERGE_THRESHOLD: usize = SPLIT_THRESHOLD / 4;
+/// The sparse index of a page has one key for every block of this size
+const INDEX_BLOCK_SIZE: usize = 4 * 1024;
+/// Pages are read through their sparse index until they serve this many reads, then their data
+/// is loaded
+const HOT_PAGE_READS: usize = 16;
+
+pub const SPLIT_INTENT_EXTENSION: &str = "split";
+pub const BLOOM_FILTER_EXTENSION: &str = "bloom";
+pub const MERGE_INTENT_EXTENSION: &str = "merge";
+/// Longest escaped prefix used as is in a file name. Longer ones are shortened and suffixed
+/// with a hash, so that the names of the page and of its `.bloom.tmp` file fit in the 255 bytes
+/// most file systems allow
+const MAX_FILE_STEM_LEN: usize = 245;
+/// Longest file name, in bytes, allowed by most file systems
+const MAX_FILE_NAME_LEN: usize = 255;
+/// Length of the escaped prefix kept in shortened file names
+const SHORTENED_STEM_LEN: usize = 200;
+pub const LEGACY_METADATA_LENGTH: usize =
+    MAX_KEY_LEN + size_of::<u8>() + size_of::<u32>() + LEGACY_FAN_OUT;
+
+/// Number of children of a node: one per value of the byte following its prefix
+const FAN_OUT: usize = 256;
+/// Pages written before the byte fan-out only had children for `0-9a-z`
+const LEGACY_FAN_OUT: usize = 36;
+
+/// Pages written before records carried a checksum
+const LEGACY_FORMAT_VERSION: u16 = 0;
+/// Every record is followed by the CRC32 of its bytes, still in the legacy metadata block
+const CHECKSUM_FORMAT_VERSION: u16 = 1;
+/// The page starts with a header (magic bytes, version and flags)
+const HEADER_FORMAT_VERSION: u16 = 2;
+/// The children are indexed by the byte following the prefix, stored as a bitmap
+const FAN_OUT_FORMAT_VERSION: u16 = 3;
+/// Records can reference a value stored in a blob file
+const BLOB_FORMAT_VERSION: u16 = 4;
+/// Records can carry an expiry timestamp
+const EXPIRY_FORMAT_VERSION: u16 = 5;
+/// Rewritten pages start with their entries sorted by key, followed by a sparse index
+const SORTED_FORMAT_VERSION: u16 = 6;
+/// The blocks of sorted records can be compressed, the codec is recorded in the header
+const COMPRESSION_FORMAT_VERSION: u16 = 7;
+/// The prefix, the children and the records of a page can be encrypted
+const ENCRYPTION_FORMAT_VERSION: u16 = 8;
+/// Records carry the version of their entry
+const CURRENT_FORMAT_VERSION: u16 = 9;
+const CHECKSUM_LENGTH: usize = size_of::<u32>();
+const EXPIRY_LENGTH: usize = size_of::<u64>();
+const VERSION_LENGTH: usize = size_of::<Version>();
+
+/// Legacy pages start with the prefix length, followed by a lowercase prefix, so they can never
+/// begin with these bytes
+const MAGIC: &[u8; 4] = b"KVSP";
+const VERSION_OFFSET: usize = 4;
+const FLAGS_OFFSET: usize = 6;
+const PREFIX_OFFSET: usize = 8;
+const CHILDREN_OFFSET: usize = PREFIX_OFFSET + 1 + MAX_KEY_LEN;
+const SORTED_LENGTH_OFFSET: usize = CHILDREN_OFFSET + FAN_OUT / 8;
+const INDEX_LENGTH_OFFSET: usize = SORTED_LENGTH_OFFSET + size_of::<u32>();
+const BLOOM_CHECKSUM_OFFSET: usize = INDEX_LENGTH_OFFSET + size_of::<u32>();
+const COMPRESSION_OFFSET: usize = BLOOM_CHECKSUM_OFFSET + size_of::<u32>();
+const UNCOMPRESSED_LENGTH_OFFSET: usize = COMPRESSION_OFFSET + size_of::<u8>();
+const KEY_CHECK_OFFSET: usize = UNCOMPRESSED_LENGTH_OFFSET + size_of::<u32>();
+const SEAL_OFFSET: usize = KEY_CHECK_OFFSET + KEY_CHECK_LENGTH;
+const SEAL_LENGTH: usize = NONCE_LENGTH + TAG_LENGTH;
+const GENERATION_OFFSET: usize = SEAL_OFFSET + SEAL_LENGTH;
+const GENERATION_LENGTH: usize = size_of::<u64>();
+const FLAG_LEAF: u16 = 1;
+/// Only set on the root page: keys are stored as they are instead of lowercased
+const FLAG_CASE_SENSITIVE: u16 = 2;
+/// A Bloom filter of the sorted records was written next to the page
+const FLAG_BLOOM_FILTER: u16 = 4;
+/// The prefix, the children and the records of the page are encrypted
+const FLAG_ENCRYPTED: u16 = 8;
+
+/// Frames are bound to their position in the records; the Bloom filter is stored in its own
+/// file, at a position no record can have
+const BLOOM_FILTER_POSITION: usize = usize::MAX;
+/// Encrypted intent files start with these bytes, followed by the key check and a frame holding
+/// the plain intent
+const INTENT_MAGIC: &[u8; 4] = b"KVSI";
+
+/// Position of the format version in the legacy metadata block (after the children bitmap)
+const LEGACY_FORMAT_VERSION_OFFSET: usize = MAX_KEY_LEN + 2 + LEGACY_FAN_OUT;
 
 #[derive(Debug)]
 pub enum TrieError {
@@ -24,69 +132,180 @@ pub enum TrieError {
     ValueError,
     WrongNode(String),
     NotFound,
+    Corrupted(String),
+    /// The data directory can't be opened with the given options
+    InvalidConfiguration(String),
+    /// Keys read by a transaction that were written since
+    Conflict(Vec<String>),
+    /// A batch of writes couldn't be completed: writes are refused until the node restarts
+    ReadOnly,
+}
+
+/// Settings shared by all the pages of a store
+#[derive(Clone)]
+pub struct PageOptions {
+    /// When the written pages are synced
+    pub fsync: FSyncStrategy,
+    /// A page is compacted when the bytes used by overwritten and deleted entries exceed the
+    /// bytes used by live entries times this ratio
+    pub compaction_ratio: f64,
+    /// Keys that only differ by their case are distinct entries
+    pub case_sensitive: bool,
+    pub stats: Arc<Stats>,
+    /// Where the page files are stored
+    pub storage: Arc<dyn PageStorage>,
+    /// Codec of the sorted records of the pages written from now on
+    pub compression: Compression,
+    /// Keys of the encrypted pages. Pages written from now on are encrypted with the current key
+    pub encryption: Option<Arc<Keyring>>,
+    /// The sorted records of cold pages are mapped in memory and read in place, and the pages
+    /// are no longer loaded when they are read often
+    pub memory_mapped_reads: bool,
+    /// Journal of the pages written since the last clean shutdown
+    pub dirty_pages: Option<Arc<DirtyPages>>,
+    /// Syncs shared by the writers of the `Strict` strategy. Pages are then synced once the
+    /// writers release the store instead of by every write
+    pub group_commit: Option<Arc<GroupCommit>>,
 }
 
+/// First key and offset of each block of sorted records
+type SparseIndex = Vec<(String, usize)>;
+
 pub struct TreeNode {
     is_leaf: Option<bool>,
-    prefix: String,
+    prefix: Vec<u8>,
     base_path: PathBuf,
     file_path: PathBuf,
-    file: Option<File>,
-    children: [Option<char>; 36],
-    entries: Option<BTreeMap<String, String>>,
-    sync_after_write: bool,
+    file: Option<Box<dyn PageFile>>,
+    children: [bool; FAN_OUT],
+    /// Whether the header of the page was written by a case-sensitive store
+    is_case_sensitive: bool,
+    entries: Option<BTreeMap<String, Entry>>,
+    options: PageOptions,
+    format_version: u16,
+    /// Size of the records stored in the page file
+    data_length: usize,
+    /// Size the live entries would take once serialized. Only tracked when the data is loaded
+    live_length: usize,
+    /// Data length at which a page without loaded data is checked for compaction again
+    next_compaction_check: usize,
+    /// Blobs of the expired entries dropped from the loaded data. They are removed once the
+    /// page has been rewritten without their records
+    expired_blobs: Vec<BlobRef>,
+    /// Size of the sorted records written when the page was last rewritten
+    sorted_length: usize,
+    /// Size of the sparse index following the sorted records
+    index_length: usize,
+    /// Codec of the blocks of sorted records
+    compression: Compression,
+    /// Size of the sorted records once uncompressed
+    uncompressed_length: usize,
+    /// Key of the page, if it is encrypted
+    cipher: Option<Arc<Cipher>>,
+    /// Bytes taken by the encryption of the records appended after the sorted records
+    encryption_overhead: usize,
+    /// Random number drawn every time the page is rewritten. Encrypted frames are bound to it,
+    /// so that frames of a previous version of the page can't be replayed into this one
+    generation: u64,
+    /// Sorted records mapped in memory. Only mapped while the data isn't loaded
+    mapping: Option<Mmap>,
+    /// First key and offset of each block of sorted records. Only loaded while the data isn't
+    index: Option<SparseIndex>,
+    /// Records appended after the sparse index (`None` for deletes). Only loaded while the data
+    /// isn't
+    tail: Option<BTreeMap<String, Option<Entry>>>,
+    /// Number of reads served through the sparse index
+    index_reads: usize,
+    /// Checksum of the Bloom filter file written with the sorted records. A filter file that
+    /// doesn't match was written for another version of the page, and is ignored
+    bloom_checksum: Option<u32>,
+    /// Bloom filter of the sorted records. Only loaded while the data isn't
+    bloom_filter: Option<BloomFilter>,
+}
+
+/// A value as stored in a page: either the value itself or a reference to the blob file
+/// holding it
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Inline(Vec<u8>),
+    Blob(BlobRef),
+}
+
+/// A live entry of a page
+#[derive(Clone, Debug, PartialEq)]
+struct Entry {
+    value: Value,
+    /// Unix timestamp (in seconds) from which the entry is hidden, then reclaimed
+    expires_at: Option<u64>,
+    /// Version given by the write of the entry. Entries written before versions were recorded
+    /// have version 0
+    version: Version,
+}
+
+/// The records of a page read by `TreeNode::scan`
+pub struct PageScan {
+    /// Live entries, with their expiry timestamp and their version
+    pub entries: BTreeMap<String, (Value, Option<u64>, Version)>,
+    /// Keys of the live entries that don't belong to the page
+    pub misplaced_keys: Vec<String>,
+    /// Damaged records, with their position in the page file
+    pub problems: Vec<String>,
+    /// Torn write at the end of the page, with its position in the page file. It is truncated
+    /// when the page is next loaded
+    pub torn_tail: Option<String>,
 }
 
 pub struct FindRangeChildrenResult {
-    pub values: Vec<(String, String)>,
-    pub child_prefixes: Vec<String>,
+    pub values: Vec<(String, Value)>,
+    pub child_prefixes: Vec<Vec<u8>>,
 }
 
 enum DeserializeResult {
-    Set(String, String, usize),
+    Set(String, Entry, usize),
     Delete(String, usize),
     IncompleteRead,
     EmptyBuffer,
+    /// The record can't be trusted. The length is known when the record framing is intact
+    /// (e.g. checksum mismatch) and unknown when the header itself is invalid
+    Corrupted(&'static str, Option<usize>),
 }
 
 enum Operation<'a> {
-    Put { key: &'a str, value: &'a str },
+    Put { key: &'a str, entry: &'a Entry },
     Delete { key: &'a str },
 }
 
 pub enum SearchResult {
     Current(),
-    Child(String),
-    NonExistingChild(String),
+    Child(Vec<u8>),
+    NonExistingChild(Vec<u8>),
 }
 
 impl TreeNode {
     /// Creates a new TreeNode with a specific prefix and path
     pub fn create(
         base_path: PathBuf,
-        prefix: &str,
-        sync_after_write: bool,
+        prefix: &[u8],
+        options: PageOptions,
     ) -> Result<TreeNode, std::io::Error> {
-        let file_path = Self::file_name(&base_path, &prefix);
+        let mut node = Self::new_leaf(base_path, prefix, options);
 
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&file_path)?;
+        node.file = Some(node.options.storage.create(&node.file_path, false)?);
+        node.save_metadata()?;
 
-        let mut node = TreeNode {
-            is_leaf: Some(true),
-            prefix: prefix.to_string(),
-            file: Some(file),
-            children: [const { None }; 36],
-            entries: Some(BTreeMap::new()),
-            file_path,
-            base_path,
-            sync_after_write,
-        };
+        Ok(node)
+    }
 
-        node.save_metadata()?;
+    /// Opens the page stored in a file, whose prefix is read from its header. Used for the pages
+    /// that aren't reached from the root, whose file name may have been shortened
+    pub fn from_file(
+        base_path: PathBuf,
+        file_path: PathBuf,
+        options: PageOptions,
+    ) -> Result<TreeNode, TrieError> {
+        let mut node = Self::from(base_path, b"", false, false, options)?;
+        node.file_path = file_path;
+        node.read_metadata()?;
 
         Ok(node)
     }
@@ -94,22 +313,41 @@ impl TreeNode {
     /// Creates a TreeNode from an existing file and loads the metadata and data as necessary
     pub fn from(
         base_path: PathBuf,
-        prefix: &str,
+        prefix: &[u8],
         load_metadata: bool,
         load_data: bool,
-        sync_after_write: bool,
-    ) -> Result<TreeNode, std::io::Error> {
+        options: PageOptions,
+    ) -> Result<TreeNode, TrieError> {
         let file_path = Self::file_name(&base_path, prefix);
 
         let mut node = TreeNode {
             base_path,
             file_path,
-            prefix: prefix.to_string(),
+            prefix: prefix.to_vec(),
             is_leaf: None,
             file: None,
-            children: [const { None }; 36],
+            children: [false; FAN_OUT],
+            is_case_sensitive: false,
             entries: None,
-            sync_after_write,
+            options,
+            format_version: LEGACY_FORMAT_VERSION,
+            data_length: 0,
+            live_length: 0,
+            next_compaction_check: MIN_COMPACTION_SIZE,
+            expired_blobs: vec![],
+            sorted_length: 0,
+            index_length: 0,
+            compression: Compression::None,
+            uncompressed_length: 0,
+            cipher: None,
+            encryption_overhead: 0,
+            generation: 0,
+            mapping: None,
+            index: None,
+            tail: None,
+            index_reads: 0,
+            bloom_checksum: None,
+            bloom_filter: None,
         };
 
         if load_metadata || load_data {
@@ -125,18 +363,8 @@ impl TreeNode {
 
     /// Saves the metadata (prefix, leaf status, children) to disk
     pub fn save_metadata(&mut self) -> Result<(), std::io::Error> {
-        let mut buffer = [0; METADATA_LENGTH];
-        buffer[0] = self.prefix.len() as u8;
-        if self.prefix.len() > 0 {
-            // Root
-            buffer[1..(self.prefix.len() + 1)].copy_from_slice(self.prefix.as_bytes());
-        }
-        buffer[MAX_KEY_LEN + 1] = if self.is_leaf.unwrap() { 1 } else { 0 };
-        for (ix, c) in self.children.iter().enumerate() {
-            if c.is_some() {
-                buffer[MAX_KEY_LEN + 2 + ix] = 1;
-            }
-        }
+        self.mark_dirty()?;
+        let buffer = self.encode_metadata();
 
         let file = self.file.as_mut().unwrap();
         file.seek(SeekFrom::Start(0))?;
@@ -145,18 +373,72 @@ impl TreeNode {
         Ok(())
     }
 
-    /// Retrieves a value for a given key
-    pub fn get(&mut self, key: &str) -> Result<String, TrieError> {
+    /// Rewrites a page created by an older build with the current header and record format.
+    /// Returns false if the page was already up to date
+    pub fn upgrade(&mut self) -> Result<bool, TrieError> {
         self.read_metadata()?;
-        if !Self::is_valid_key(&key) || !self.owns_key(&key) {
-            return Err(TrieError::KeyError);
+        if self.format_version == CURRENT_FORMAT_VERSION {
+            return Ok(false);
+        }
+
+        self.read_data()?;
+        self.format_version = CURRENT_FORMAT_VERSION;
+        self.rewrite()?;
+
+        Ok(true)
+    }
+
+    /// Rewrites a page that isn't encrypted with the current key, including the pages that
+    /// aren't encrypted while a key is configured. Returns false if the page was already
+    /// encrypted with the current key
+    pub fn rotate_key(&mut self) -> Result<bool, TrieError> {
+        self.read_metadata()?;
+        let current_key_check = Self::current_cipher(&self.options).map(|c| c.key_check());
+        if self.cipher.as_ref().map(|c| c.key_check()) == current_key_check {
+            return Ok(false);
+        }
+
+        self.read_data()?;
+        self.format_version = CURRENT_FORMAT_VERSION;
+        self.rewrite()?;
+
+        Ok(true)
+    }
+
+    /// Rewrites a page that isn't encrypted while a key is configured before it is written to,
+    /// so that no plain record is appended to it
+    fn encrypt_if_needed(&mut self) -> Result<(), TrieError> {
+        if self.cipher.is_some() || self.options.encryption.is_none() {
+            return Ok(());
         }
 
+        // Loading the page may already have compacted it with the current key
         self.read_data()?;
+        if self.cipher.is_none() {
+            self.format_version = CURRENT_FORMAT_VERSION;
+            self.rewrite()?;
+        }
+
+        Ok(())
+    }
+
+    /// Retrieves the value for a given key, with its version
+    pub fn get(&mut self, key: &str) -> Result<(Value, Version), TrieError> {
+        self.read_metadata()?;
+        if !is_valid_key(key) || !self.owns_key(key.as_bytes()) {
+            return Err(TrieError::KeyError);
+        }
+
+        let entry = if self.use_index()? {
+            self.find_indexed(key)?
+        } else {
+            self.read_data()?;
+            self.entries.as_ref().unwrap().get(key).cloned()
+        };
 
-        match self.entries.as_ref().unwrap().get(key) {
-            Some(r) => Ok(r.clone()),
-            None => Err(TrieError::NotFound),
+        match entry {
+            Some(e) if !e.is_expired(unix_time()) => Ok((e.value, e.version)),
+            _ => Err(TrieError::NotFound),
         }
     }
 
@@ -166,14 +448,19 @@ impl TreeNode {
         start_key: &String,
         end_key: &String,
         limit: Option<usize>,
-    ) -> Result<Vec<(String, String)>, TrieError> {
-        if !Self::is_valid_key(start_key) || !Self::is_valid_key(end_key) {
+    ) -> Result<Vec<(String, Value)>, TrieError> {
+        if !Self::is_valid_bound(start_key) || !Self::is_valid_bound(end_key) {
             return Err(TrieError::KeyError);
         }
 
         self.read_metadata()?;
+        if self.use_index()? {
+            return self.get_range_indexed(start_key, end_key, limit);
+        }
+
         self.read_data()?;
 
+        let now = unix_time();
         let iterator = self
             .entries
             .as_ref()
@@ -182,26 +469,35 @@ impl TreeNode {
                 Included(start_key),
                 Included(end_key),
             ))
-            .map(|(k, v)| (k.clone(), v.clone()));
+            .filter(|(_, e)| !e.is_expired(now))
+            .map(|(k, e)| (k.clone(), e.value.clone()));
 
         let result = match limit {
             Some(l) => iterator.take(l).collect(),
             None => iterator.collect(),
         };
 
-        return Ok(result);
+        Ok(result)
     }
 
-    /// Inserts a key-value pair
-    pub fn insert(&mut self, key: String, value: String) -> Result<(), TrieError> {
+    /// Inserts a key-value pair with its version, hidden from the given unix timestamp (in
+    /// seconds) if any
+    pub fn insert(
+        &mut self,
+        key: String,
+        value: Value,
+        expires_at: Option<u64>,
+        version: Version,
+    ) -> Result<(), TrieError> {
         self.read_metadata()?;
-        if !Self::is_valid_key(&key) {
+        if !is_valid_key(&key) {
             return Err(TrieError::KeyError);
         }
 
-        if !self.owns_key(&key) {
+        if !self.owns_key(key.as_bytes()) {
+            let child_prefix = &key.as_bytes()[..(self.prefix.len() + 1)];
             return Err(TrieError::WrongNode(
-                key[..(self.prefix.len() + 1)].to_string(),
+                String::from_utf8_lossy(child_prefix).into_owned(),
             ));
         }
 
@@ -209,14 +505,62 @@ impl TreeNode {
             return Err(TrieError::ValueError);
         }
 
+        self.encrypt_if_needed()?;
+
+        // Blob references, expiry timestamps and versions need the current format
+        if let Value::Blob(_) = value {
+            // The data is loaded so that the blob of the previous value is known and removed
+            // when a large value is overwritten
+            self.upgrade()?;
+            self.read_data()?;
+        } else if expires_at.is_some() || version != 0 {
+            self.upgrade()?;
+        }
+
+        // On a cold page, the previous value is looked up through the index, so that the blob
+        // it may reference is removed once it is overwritten. Older formats have no blobs
+        let mut previous_value = None;
+        if !self.has_data() && self.format_version == CURRENT_FORMAT_VERSION {
+            if self.use_index()? {
+                previous_value = self.find_indexed(&key)?.map(|previous| previous.value);
+            } else {
+                self.read_data()?;
+            }
+        }
+
+        // A write applied again, e.g. from the WAL, keeps the blob it references
+        let written_blob = match &value {
+            Value::Blob(blob) => Some(blob.id),
+            Value::Inline(_) => None,
+        };
+        let entry = Entry {
+            value,
+            expires_at,
+            version,
+        };
         let operation = Operation::Put {
             key: &key,
-            value: &value,
+            entry: &entry,
         };
 
         self.save_operation(operation)?;
-        self.entries.as_mut().and_then(|e| e.insert(key, value));
 
+        let format_version = self.format_version;
+        if let Some(entries) = self.entries.as_mut() {
+            self.live_length += Self::put_length(&key, &entry, format_version);
+            if let Some(previous) = entries.insert(key.clone(), entry) {
+                self.live_length -= Self::put_length(&key, &previous, format_version);
+                previous_value = Some(previous.value);
+            }
+        } else if let Some(tail) = self.tail.as_mut() {
+            tail.insert(key.clone(), Some(entry));
+        }
+
+        self.remove_blob(
+            previous_value.filter(|v| !matches!(v, Value::Blob(b) if Some(b.id) == written_blob)),
+        )?;
+
+        self.compact_if_needed()?;
         self.split()?;
 
         Ok(())
@@ -225,18 +569,45 @@ impl TreeNode {
     /// Deletes a key
     pub fn delete(&mut self, key: String) -> Result<(), TrieError> {
         self.read_metadata()?;
-        if !Self::is_valid_key(&key) || !self.owns_key(&key) {
+        if !is_valid_key(&key) || !self.owns_key(key.as_bytes()) {
             return Err(TrieError::KeyError);
         }
 
-        if self.is_leaf.unwrap() || key == self.prefix {
-            if !key.starts_with(&self.prefix) {
+        if self.is_leaf.unwrap() || key.as_bytes() == self.prefix {
+            if !key.as_bytes().starts_with(&self.prefix) {
                 panic!("error!");
             }
 
+            self.encrypt_if_needed()?;
+
+            // Cold pages are looked up through their index. Only the pages small enough to be
+            // merged are loaded, since their live size must be known to merge them
+            if !self.has_data() && self.records_length() > MERGE_THRESHOLD && self.use_index()? {
+                let Some(previous) = self.find_indexed(&key)? else {
+                    return Ok(());
+                };
+
+                self.save_operation(Operation::Delete { key: &key })?;
+                self.tail.as_mut().unwrap().insert(key, None);
+                self.remove_blob(Some(previous.value))?;
+                return self.compact_if_needed();
+            }
+
+            self.read_data()?;
+            if !self.entries.as_ref().unwrap().contains_key(&key) {
+                return Ok(());
+            }
+
             self.save_operation(Operation::Delete { key: &key })?;
 
-            self.entries.as_mut().and_then(|e| e.remove(&key));
+            let format_version = self.format_version;
+            let previous = self.entries.as_mut().and_then(|e| e.remove(&key));
+            if let Some(previous) = &previous {
+                self.live_length -= Self::put_length(&key, previous, format_version);
+            }
+
+            self.remove_blob(previous.map(|e| e.value))?;
+            self.compact_if_needed()?;
         }
 
         Ok(())
@@ -250,11 +621,11 @@ impl TreeNode {
         end_key: &String,
         limit: Option<usize>,
     ) -> Result<FindRangeChildrenResult, TrieError> {
-        if !Self::is_valid_key(start_key) || !Self::is_valid_key(end_key) {
+        if !Self::is_valid_bound(start_key) || !Self::is_valid_bound(end_key) {
             return Err(TrieError::KeyError);
         }
 
-        let values = if self.is_leaf.unwrap() || *start_key <= self.prefix {
+        let values = if self.is_leaf.unwrap() || start_key.as_bytes() <= self.prefix.as_slice() {
             self.get_range(start_key, end_key, limit)?
         } else {
             vec![]
@@ -263,30 +634,25 @@ impl TreeNode {
         let mut child_prefixes = vec![];
 
         if !self.is_leaf.unwrap() {
-            let start_ix = match (start_key, &self.prefix) {
-                (s, p) if s.len() <= p.len() && s <= p => Some(0),
-                (s, p) if s.len() <= p.len() => None,
-                (s, p) => {
-                    let child_prefix = &s[0..=p.len()];
-                    Some(Self::last_char_to_index(child_prefix))
-                }
+            let prefix = self.prefix.as_slice();
+            let start_ix = match start_key.as_bytes().get(prefix.len()) {
+                Some(b) => Some(*b as usize),
+                None if start_key.as_bytes() <= prefix => Some(0),
+                None => None,
             };
 
-            let end_ix = match (end_key, &self.prefix) {
-                (e, p) if e.len() <= p.len() && e >= p => Some(self.children.len() - 1),
-                (e, p) if e.len() <= p.len() => None,
-                (e, p) => {
-                    let child_prefix = &e[0..=p.len()];
-                    Some(Self::last_char_to_index(child_prefix))
-                }
+            let end_ix = match end_key.as_bytes().get(prefix.len()) {
+                Some(b) => Some(*b as usize),
+                None if end_key.as_bytes() >= prefix => Some(FAN_OUT - 1),
+                None => None,
             };
 
             // these options should never be empty
             if let (Some(s), Some(e)) = (start_ix, end_ix) {
                 for ix in s..=e {
-                    if self.children[ix].is_some() {
+                    if self.children[ix] {
                         let mut cp = self.prefix.clone();
-                        cp.push(Self::index_to_char(ix));
+                        cp.push(ix as u8);
                         child_prefixes.push(cp);
                     }
                 }
@@ -299,13 +665,13 @@ impl TreeNode {
         })
     }
 
-    pub fn get_children_prefixes(&self) -> Vec<String> {
+    pub fn get_children_prefixes(&self) -> Vec<Vec<u8>> {
         let mut child_prefixes = vec![];
 
         for ix in 0..self.children.len() {
-            if self.children[ix].is_some() {
+            if self.children[ix] {
                 let mut cp = self.prefix.clone();
-                cp.push(Self::index_to_char(ix));
+                cp.push(ix as u8);
                 child_prefixes.push(cp);
             }
         }
@@ -314,36 +680,241 @@ impl TreeNode {
     }
 
     /// Returns the prefix of the node
-    pub fn prefix(&self) -> &String {
+    pub fn prefix(&self) -> &[u8] {
         &self.prefix
     }
 
+    /// Returns true if the node has no children
+    pub fn is_leaf(&self) -> bool {
+        self.is_leaf.unwrap()
+    }
+
+    /// Returns the size of the live entries, loading the data of the node
+    pub fn live_length(&mut self) -> Result<usize, TrieError> {
+        self.read_metadata()?;
+        self.read_data()?;
+
+        Ok(self.live_length)
+    }
+
+    /// Returns true if the node is a leaf small enough to be merged into its parent
+    pub fn is_underfilled(&self) -> bool {
+        self.is_leaf() && self.has_data() && self.live_length <= MERGE_THRESHOLD
+    }
+
+    /// Moves the entries of the children back into the node, which becomes a leaf again.
+    /// The children must all be leaves. Like splits, merges are committed by an intent file
+    pub fn merge_children(&mut self, children: Vec<TreeNode>) -> Result<(), TrieError> {
+        self.read_metadata()?;
+        self.read_data()?;
+
+        let mut child_prefixes = vec![];
+        for mut child in children {
+            child.read_metadata()?;
+            child.read_data()?;
+
+            if !child.is_leaf() {
+                panic!("Failed to merge page");
+            }
+
+            self.entries
+                .as_mut()
+                .unwrap()
+                .append(child.entries.as_mut().unwrap());
+            self.expired_blobs.append(&mut child.expired_blobs);
+            child_prefixes.push(child.prefix);
+        }
+
+        self.is_leaf = Some(true);
+        self.children = [false; FAN_OUT];
+        self.format_version = CURRENT_FORMAT_VERSION;
+        let (total_written, live_length) = self.write_temp_file()?;
+        crash_point("merge:parent_written");
+
+        Self::write_intent(
+            self.options.storage.as_ref(),
+            self.options.encryption.as_deref(),
+            &self.base_path,
+            &self.prefix,
+            &child_prefixes,
+            MERGE_INTENT_EXTENSION,
+        )?;
+        crash_point("merge:intent_written");
+
+        Self::complete_merge(
+            self.options.storage.as_ref(),
+            &self.base_path,
+            &self.prefix,
+            &child_prefixes,
+        )?;
+        self.reopen(total_written, live_length)?;
+        self.options.stats.record_merge();
+
+        Ok(())
+    }
+
     /// Registers a new child in the node (used when new child nodes are created)
-    pub fn register_child(&mut self, prefix: String) {
-        let ix = Self::last_char_to_index(&prefix[0..=self.prefix.len()]);
-        self.register_child_int(ix);
+    pub fn register_child(&mut self, prefix: &[u8]) {
+        self.children[prefix[self.prefix.len()] as usize] = true;
     }
 
     /// Returns `SearchResult::Current` if the node owns the key. Otherwise returns the prefix
     /// of a child that owns the node
-    pub fn find_owner(&self, key: &str) -> SearchResult {
+    pub fn find_owner(&self, key: &[u8]) -> SearchResult {
         if self.owns_key(key) {
             SearchResult::Current()
         } else {
-            let child_prefix = &key[0..=self.prefix.len()];
-            let ix = Self::last_char_to_index(child_prefix);
-            match &self.children[ix] {
-                Some(c) => {
-                    let mut prefix = self.prefix.clone();
-                    prefix.push(*c);
-                    SearchResult::Child(prefix)
+            let child_prefix = key[0..=self.prefix.len()].to_vec();
+            if self.children[key[self.prefix.len()] as usize] {
+                SearchResult::Child(child_prefix)
+            } else {
+                SearchResult::NonExistingChild(child_prefix)
+            }
+        }
+    }
+
+    /// Returns the blobs referenced by the live entries, loading the data of the node
+    pub fn blobs(&mut self) -> Result<Vec<BlobRef>, TrieError> {
+        self.read_metadata()?;
+        self.read_data()?;
+
+        Ok(self
+            .entries
+            .as_ref()
+            .unwrap()
+            .values()
+            .filter_map(|e| match e.value {
+                Value::Blob(blob) => Some(blob),
+                Value::Inline(_) => None,
+            })
+            .collect())
+    }
+
+    /// Reads every record of the page without repairing or rewriting it. Damaged records are
+    /// reported and skipped when their length is known, so that the entries following them
+    /// can still be recovered
+    pub fn scan(&mut self) -> Result<PageScan, TrieError> {
+        self.read_metadata()?;
+
+        let data_offset = self.data_offset();
+        let file = self.file.as_mut().unwrap();
+        file.seek(SeekFrom::Start(data_offset as u64))?;
+        let mut data = vec![];
+        file.read_to_end(&mut data)?;
+
+        let mut entries = BTreeMap::new();
+        let mut problems = vec![];
+        let mut torn_tail = None;
+        let mut position = 0;
+
+        if self.has_framed_blocks() {
+            while position < self.sorted_length {
+                let records = self
+                    .open_block(&data[position..self.sorted_length], position)
+                    .and_then(|(block, length)| {
+                        Some((self.decode_sorted_records(&block, 0).ok()?, length))
+                    });
+
+                let Some((records, length)) = records else {
+                    problems.push(format!(
+                        "invalid block at offset {}: the following blocks can't be read",
+                        data_offset + position
+                    ));
+                    break;
+                };
+
+                entries.extend(records);
+                position += length;
+            }
+
+            position = self.sorted_length;
+        }
+
+        loop {
+            if position == self.sorted_length {
+                position += self.index_length;
+            }
+
+            if position < data.len() && data[position..].iter().all(|b| *b == 0) {
+                torn_tail = Some(format!(
+                    "zero-filled tail at offset {}",
+                    data_offset + position
+                ));
+                break;
+            }
+
+            match self.read_record(&data[position..], position) {
+                DeserializeResult::Set(key, entry, length) => {
+                    entries.insert(key, entry);
+                    position += length;
                 }
-                None => {
-                    let p = child_prefix.to_string();
-                    SearchResult::NonExistingChild(p)
+                DeserializeResult::Delete(key, length) => {
+                    entries.remove(&key);
+                    position += length;
+                }
+                DeserializeResult::IncompleteRead => {
+                    torn_tail = Some(format!(
+                        "incomplete record at offset {}",
+                        data_offset + position
+                    ));
+                    break;
+                }
+                DeserializeResult::Corrupted(reason, Some(length))
+                    if position + length < data.len() =>
+                {
+                    problems.push(format!("{reason} at offset {}", data_offset + position));
+                    position += length;
+                }
+                // Like an incomplete record, a damaged last record is left by a torn write
+                DeserializeResult::Corrupted(reason, Some(_)) => {
+                    torn_tail = Some(format!(
+                        "{reason} at offset {} in the last record",
+                        data_offset + position
+                    ));
+                    break;
+                }
+                DeserializeResult::Corrupted(reason, _) => {
+                    problems.push(format!(
+                        "{reason} at offset {}: the following records can't be read",
+                        data_offset + position
+                    ));
+                    break;
+                }
+                DeserializeResult::EmptyBuffer => {
+                    break;
                 }
             }
         }
+
+        let misplaced_keys = entries
+            .keys()
+            .filter(|k| !self.owns_key(k.as_bytes()))
+            .cloned()
+            .collect();
+
+        Ok(PageScan {
+            entries: entries
+                .into_iter()
+                .map(|(k, e)| (k, (e.value, e.expires_at, e.version)))
+                .collect(),
+            misplaced_keys,
+            problems,
+            torn_tail,
+        })
+    }
+
+    /// Returns true if the page was written by a case-sensitive store. Only meaningful for the
+    /// root page
+    pub fn is_case_sensitive(&self) -> bool {
+        self.is_case_sensitive
+    }
+
+    /// Makes the data written to the page file durable, if the file is open
+    pub fn sync(&self) -> Result<(), std::io::Error> {
+        match &self.file {
+            Some(file) => file.sync(),
+            None => Ok(()),
+        }
     }
 
     /// Returns true if the data of the node has been retrieved from disk
@@ -351,130 +922,255 @@ impl TreeNode {
         self.entries.is_some()
     }
 
-    fn read_metadata(&mut self) -> Result<(), std::io::Error> {
+    fn read_metadata(&mut self) -> Result<(), TrieError> {
         if self.has_metadata() {
             return Ok(());
         }
 
-        let mut file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .open(&self.file_path)?;
+        let mut file = self.options.storage.open(&self.file_path)?;
 
-        let mut buffer = [0; METADATA_LENGTH];
-        file.read(&mut buffer).unwrap();
+        let mut buffer = [0; HEADER_LENGTH];
+        let mut length = 0;
+        while length < HEADER_LENGTH {
+            match file.read(&mut buffer[length..])? {
+                0 => break,
+                n => length += n,
+            }
+        }
 
-        let prefix_len = buffer[0] as usize;
+        let (format_version, is_leaf, prefix_offset, children_offset) = if buffer.starts_with(MAGIC)
+        {
+            if length < HEADER_LENGTH {
+                return Err(self.corrupted("incomplete header"));
+            }
 
-        for ix in 0..self.children.len() {
-            if buffer[MAX_KEY_LEN + 2 + ix] == 1 {
-                self.children[ix] = Some(Self::index_to_char(ix));
+            let version = u16::from_le_bytes([buffer[VERSION_OFFSET], buffer[VERSION_OFFSET + 1]]);
+            let flags = u16::from_le_bytes([buffer[FLAGS_OFFSET], buffer[FLAGS_OFFSET + 1]]);
+
+            match version {
+                HEADER_FORMAT_VERSION
+                | FAN_OUT_FORMAT_VERSION
+                | BLOB_FORMAT_VERSION
+                | EXPIRY_FORMAT_VERSION
+                | SORTED_FORMAT_VERSION
+                | COMPRESSION_FORMAT_VERSION
+                | ENCRYPTION_FORMAT_VERSION
+                | CURRENT_FORMAT_VERSION => {
+                    self.cipher = if flags & FLAG_ENCRYPTED != 0 {
+                        Some(self.open_header(&mut buffer)?)
+                    } else {
+                        None
+                    };
+                    self.is_case_sensitive = flags & FLAG_CASE_SENSITIVE != 0;
+                    self.generation = u64::from_le_bytes(
+                        buffer[GENERATION_OFFSET..GENERATION_OFFSET + GENERATION_LENGTH]
+                            .try_into()
+                            .unwrap(),
+                    );
+                    self.bloom_checksum = (flags & FLAG_BLOOM_FILTER != 0).then(|| {
+                        u32::from_le_bytes(
+                            buffer[BLOOM_CHECKSUM_OFFSET..BLOOM_CHECKSUM_OFFSET + 4]
+                                .try_into()
+                                .unwrap(),
+                        )
+                    });
+                    (
+                        version,
+                        flags & FLAG_LEAF != 0,
+                        PREFIX_OFFSET,
+                        CHILDREN_OFFSET,
+                    )
+                }
+                _ => return Err(self.corrupted(&format!("unknown format version {version}"))),
+            }
+        } else {
+            if length < LEGACY_METADATA_LENGTH {
+                return Err(self.corrupted("not a kvs page"));
+            }
+
+            let version = buffer[LEGACY_FORMAT_VERSION_OFFSET] as u16;
+            if version > CHECKSUM_FORMAT_VERSION {
+                return Err(self.corrupted("not a kvs page"));
+            }
+
+            (version, buffer[MAX_KEY_LEN + 1] == 1, 0, MAX_KEY_LEN + 2)
+        };
+
+        let prefix_len = buffer[prefix_offset] as usize;
+        let prefix = &buffer[(prefix_offset + 1)..(prefix_offset + prefix_len + 1)];
+
+        // Pages opened from their file name only learn their prefix from the header
+        if prefix != self.prefix {
+            if Self::file_name(&self.base_path, prefix) != self.file_path {
+                return Err(self.corrupted("prefix does not match the file name"));
             }
+            self.prefix = prefix.to_vec();
         }
 
-        self.prefix = str::from_utf8(&buffer[1..(prefix_len + 1)])
-            .unwrap()
-            .to_string();
-        self.file = Some(file);
-        self.is_leaf = Some(if buffer[MAX_KEY_LEN + 1] == 1 {
-            true
+        if format_version >= FAN_OUT_FORMAT_VERSION {
+            for ix in 0..FAN_OUT {
+                self.children[ix] = buffer[children_offset + ix / 8] & (1 << (ix % 8)) != 0;
+            }
         } else {
-            false
-        });
+            for ix in 0..LEGACY_FAN_OUT {
+                if buffer[children_offset + ix] == 1 {
+                    self.children[Self::legacy_index_to_byte(ix) as usize] = true;
+                }
+            }
+        }
+
+        if format_version >= SORTED_FORMAT_VERSION {
+            let read_u32 = |offset: usize| {
+                u32::from_le_bytes(buffer[offset..offset + 4].try_into().unwrap()) as usize
+            };
+            self.sorted_length = read_u32(SORTED_LENGTH_OFFSET);
+            self.index_length = read_u32(INDEX_LENGTH_OFFSET);
+            self.uncompressed_length = self.sorted_length;
+
+            if format_version >= COMPRESSION_FORMAT_VERSION {
+                self.compression = match Compression::from_id(buffer[COMPRESSION_OFFSET]) {
+                    Some(compression) => compression,
+                    None => return Err(self.corrupted("unknown compression")),
+                };
+                self.uncompressed_length = read_u32(UNCOMPRESSED_LENGTH_OFFSET);
+            }
+        }
+
+        self.file = Some(file);
+        self.is_leaf = Some(is_leaf);
+        self.format_version = format_version;
+
+        let records_end = self.data_offset() + self.sorted_length + self.index_length;
+        let file_length = self.file_length()? as usize;
+        if file_length < records_end {
+            return Err(self.corrupted("sorted records extend past the end of the file"));
+        }
+
+        self.data_length = file_length - self.data_offset() - self.index_length;
+        self.next_compaction_check = MIN_COMPACTION_SIZE.max(self.data_length * 2);
 
         Ok(())
     }
 
-    fn read_data(&mut self) -> Result<(), std::io::Error> {
+    fn read_data(&mut self) -> Result<(), TrieError> {
         if self.has_data() {
             return Ok(());
         }
 
+        let data_offset = self.data_offset();
         let file = self.file.as_mut().unwrap();
-        file.seek(SeekFrom::Start(METADATA_LENGTH as u64))?;
-        let mut entries = BTreeMap::new();
+        file.seek(SeekFrom::Start(data_offset as u64))?;
+        let mut data = vec![];
+        file.read_to_end(&mut data)?;
+        if data.len() < self.sorted_length + self.index_length {
+            return Err(self.corrupted("sorted records extend past the end of the file"));
+        }
 
-        let mut buffer = [0; IO_BUFFER_SIZE];
-        let mut reader = BufReader::new(&*file);
-        let mut buffer_read_position = 0;
-        let mut buffer_write_position = 0;
+        let mut entries = BTreeMap::new();
+        let mut position = 0;
         let mut need_fix = false;
 
-        while let Ok(bytes_read) = reader.read(&mut buffer[buffer_write_position..]) {
-            if bytes_read == 0 {
-                if buffer_write_position > 0 {
-                    error!(
-                        "Invalid entry {:#?}\nAn entry was not fully committed.",
-                        &buffer[..buffer_write_position]
-                    );
-                    need_fix = true;
+        // Compressed or encrypted records are read block by block. They were written to a
+        // temporary file before being renamed, so they can't be torn
+        if self.has_framed_blocks() {
+            for (key, entry) in self.decode_blocks(&data[..self.sorted_length], 0)? {
+                if !key.as_bytes().starts_with(&self.prefix) {
+                    return Err(self.corrupted("key outside of the page prefix"));
                 }
 
-                break;
+                entries.insert(key, entry);
             }
 
-            let internal_buffer = &mut buffer[..(buffer_write_position + bytes_read)];
+            position = self.sorted_length;
+        }
 
-            loop {
-                match Self::deserialize(&internal_buffer[buffer_read_position..]) {
-                    DeserializeResult::Set(key, value, position) => {
-                        if !key.starts_with(&self.prefix) {
-                            panic!("File is corrupted!");
-                        }
+        let frame_overhead = if self.cipher.is_some() {
+            FRAME_OVERHEAD
+        } else {
+            0
+        };
+        let mut encryption_overhead = 0;
 
-                        entries.insert(key, value);
-                        buffer_read_position += position;
-                    }
-                    DeserializeResult::Delete(key, position) => {
-                        if !key.starts_with(&self.prefix) {
-                            panic!("File is corrupted!");
-                        }
+        loop {
+            if position == self.sorted_length {
+                position += self.index_length;
+            }
 
-                        entries.remove(&key);
-                        buffer_read_position += position;
+            match self.read_record(&data[position..], position) {
+                DeserializeResult::Set(key, entry, length) => {
+                    if !key.as_bytes().starts_with(&self.prefix) {
+                        return Err(self.corrupted_at(position, "key outside of the page prefix"));
                     }
-                    DeserializeResult::IncompleteRead => {
-                        if buffer_read_position == 0 {
-                            buffer_write_position = internal_buffer.len();
-                        } else {
-                            let left = buffer_read_position;
-                            let right = internal_buffer.len() - buffer_read_position;
-
-                            if left >= right {
-                                let (b1, b2) = internal_buffer.split_at_mut(buffer_read_position);
-                                b1[0..b2.len()].copy_from_slice(&b2);
-                            } else {
-                                let mut b = [0; IO_BUFFER_SIZE];
-                                b[0..right]
-                                    .copy_from_slice(&internal_buffer[buffer_read_position..]);
-                                buffer[0..right].copy_from_slice(&b[0..right]);
-                            }
-                            buffer_write_position = right;
-                            buffer_read_position = 0;
-                        }
 
-                        break;
+                    entries.insert(key, entry);
+                    position += length;
+                    encryption_overhead += frame_overhead;
+                }
+                DeserializeResult::Delete(key, length) => {
+                    if !key.as_bytes().starts_with(&self.prefix) {
+                        return Err(self.corrupted_at(position, "key outside of the page prefix"));
                     }
-                    DeserializeResult::EmptyBuffer => {
-                        buffer_write_position = 0;
-                        buffer_read_position = 0;
 
-                        break;
+                    entries.remove(&key);
+                    position += length;
+                    encryption_overhead += frame_overhead;
+                }
+                DeserializeResult::IncompleteRead => {
+                    error!(
+                        "Invalid entry {:#?}\nAn entry was not fully committed.",
+                        &data[position..]
+                    );
+                    need_fix = true;
+
+                    break;
+                }
+                DeserializeResult::Corrupted(reason, length) => {
+                    // A torn write leaves either a partial record at the end of the file or a
+                    // zero-filled tail: anything else means data in the middle of the page is damaged
+                    let is_last_record = length.is_some_and(|l| position + l == data.len());
+                    if !is_last_record && data[position..].iter().any(|b| *b != 0) {
+                        return Err(self.corrupted_at(position, reason));
                     }
+
+                    error!(
+                        "Invalid entry at the end of {:?} ({reason})\nAn entry was not fully committed.",
+                        self.file_path
+                    );
+                    need_fix = true;
+
+                    break;
+                }
+                DeserializeResult::EmptyBuffer => {
+                    break;
                 }
             }
         }
 
+        self.live_length = entries
+            .iter()
+            .map(|(k, e)| Self::put_length(k, e, self.format_version))
+            .sum();
+        self.encryption_overhead = encryption_overhead;
         self.entries = Some(entries);
+        self.index = None;
+        self.mapping = None;
+        self.tail = None;
+        self.bloom_filter = None;
+
+        // Expired entries are dropped when the page is loaded: their records count as dead bytes
+        // until the page is compacted
+        self.remove_expired();
 
         if need_fix {
-            self.flush_to_disk()?;
+            self.rewrite()?;
+        } else if self.has_too_many_dead_bytes() {
+            self.compact()?;
         }
 
         Ok(())
     }
 
-    fn owns_key(&self, key: &str) -> bool {
+    fn owns_key(&self, key: &[u8]) -> bool {
         if !self.is_leaf.unwrap() {
             self.prefix == key
         } else {
@@ -482,33 +1178,20 @@ impl TreeNode {
         }
     }
 
-    fn last_char_to_index(str: &str) -> usize {
-        let ix = match str.chars().last().unwrap() as u8 {
-            n @ b'0'..=b'9' => n - b'0',
-            l @ b'a'..=b'z' => l - b'a' + 10,
-            l @ b'A'..=b'Z' => l - b'A' + 10,
-            _ => panic!("no"),
-        };
-
-        ix as usize
-    }
-
-    fn index_to_char(ix: usize) -> char {
+    /// Returns the byte following the prefix of a child stored at the given position by the
+    /// older formats
+    fn legacy_index_to_byte(ix: usize) -> u8 {
         match ix {
-            0..=9 => (ix as u8 + b'0') as char,
-            10..36 => (ix as u8 - 10 + b'a') as char,
-            _ => panic!("no"),
+            0..=9 => ix as u8 + b'0',
+            _ => ix as u8 - 10 + b'a',
         }
     }
 
-    fn index_to_range(ix: usize) -> (char, char) {
-        match ix {
-            0..=9 => ((ix as u8 + b'0') as char, (ix as u8 + b'1') as char),
-            10..36 => (
-                (ix as u8 - 10 + b'a') as char,
-                (ix as u8 - 10 + b'b') as char,
-            ),
-            _ => panic!("no"),
+    fn byte_to_legacy_index(byte: u8) -> Option<usize> {
+        match byte {
+            b'0'..=b'9' => Some((byte - b'0') as usize),
+            b'a'..=b'z' => Some((byte - b'a') as usize + 10),
+            _ => None,
         }
     }
 
@@ -516,37 +1199,88 @@ impl TreeNode {
         self.file.is_some() && self.is_leaf.is_some()
     }
 
-    fn set_entries(&mut self, entries: BTreeMap<String, String>) -> Result<(), std::io::Error> {
-        self.entries = Some(entries);
-        self.flush_to_disk()?;
-
-        Ok(())
+    /// Returns an empty leaf that hasn't been written to disk yet
+    fn new_leaf(base_path: PathBuf, prefix: &[u8], options: PageOptions) -> TreeNode {
+        TreeNode {
+            cipher: Self::current_cipher(&options),
+            is_leaf: Some(true),
+            prefix: prefix.to_vec(),
+            file: None,
+            children: [false; FAN_OUT],
+            is_case_sensitive: false,
+            entries: Some(BTreeMap::new()),
+            file_path: Self::file_name(&base_path, prefix),
+            base_path,
+            options,
+            format_version: CURRENT_FORMAT_VERSION,
+            data_length: 0,
+            live_length: 0,
+            next_compaction_check: MIN_COMPACTION_SIZE,
+            expired_blobs: vec![],
+            sorted_length: 0,
+            index_length: 0,
+            compression: Compression::None,
+            uncompressed_length: 0,
+            encryption_overhead: 0,
+            generation: random_u64(),
+            mapping: None,
+            index: None,
+            tail: None,
+            index_reads: 0,
+            bloom_checksum: None,
+            bloom_filter: None,
+        }
     }
 
     fn save_operation(&mut self, operation: Operation) -> Result<(), std::io::Error> {
+        self.mark_dirty()?;
         let mut buffer = [0u8; IO_BUFFER_SIZE];
-        let total_length = Self::serialize(&mut buffer, operation).unwrap();
+        let total_length = Self::serialize(&mut buffer, operation, self.format_version).unwrap();
+        let data_offset = self.data_offset() as u64;
 
         let file = self.file.as_mut().unwrap();
-        file.seek(SeekFrom::End(0))?;
+        let mut offset = file.seek(SeekFrom::End(0))?;
 
-        if file.stream_position()? < METADATA_LENGTH as u64 {
-            file.seek(SeekFrom::Start(METADATA_LENGTH as u64))?;
+        if offset < data_offset {
+            offset = file.seek(SeekFrom::Start(data_offset))?;
         }
 
+        let sealed;
+        let record = match &self.cipher {
+            Some(cipher) => {
+                let associated_data = self.frame_associated_data((offset - data_offset) as usize);
+                sealed = cipher.seal(&buffer[..total_length], &associated_data);
+                self.encryption_overhead += sealed.len() - total_length;
+                &sealed[..]
+            }
+            None => &buffer[..total_length],
+        };
+
+        let file = self.file.as_mut().unwrap();
         let mut buf_writer = BufWriter::new(file);
-        buf_writer.write(&buffer[0..total_length])?;
+        buf_writer.write_all(record)?;
         buf_writer.flush()?;
 
-        if self.sync_after_write {
-            buf_writer.get_ref().sync_all()?;
+        if self.options.sync_after_write() {
+            buf_writer.get_ref().sync()?;
         }
 
+        self.data_length += record.len();
+
         Ok(())
     }
 
-    fn deserialize(buffer: &[u8]) -> DeserializeResult {
-        if buffer.len() == 0 {
+    /// Records that the page file is about to be written in place, so that it is checked
+    /// after a crash
+    fn mark_dirty(&self) -> Result<(), std::io::Error> {
+        match &self.options.dirty_pages {
+            Some(dirty_pages) => dirty_pages.mark(&self.prefix),
+            None => Ok(()),
+        }
+    }
+
+    fn deserialize(buffer: &[u8], format_version: u16) -> DeserializeResult {
+        if buffer.is_empty() {
             return DeserializeResult::EmptyBuffer;
         }
 
@@ -554,178 +1288,2388 @@ impl TreeNode {
             return DeserializeResult::IncompleteRead;
         }
 
+        let checksum_len = Self::checksum_length(format_version);
         let operation_type = buffer[0];
         let key_len = buffer[1] as usize;
-        if key_len + 2 > buffer.len() {
+        let Some(header_len) = Self::header_length(operation_type, key_len, format_version) else {
+            return DeserializeResult::Corrupted("unknown operation", None);
+        };
+
+        if header_len > buffer.len() {
             return DeserializeResult::IncompleteRead;
         }
 
-        let key = str::from_utf8(&buffer[2..(key_len + 2)])
-            .unwrap()
-            .to_string();
-
-        // DELETE
-        if operation_type == 1 {
-            DeserializeResult::Delete(key, key_len + 2)
+        let value_len = if operation_type != 1 {
+            u32::from_le_bytes(buffer[key_len + 2..key_len + 6].try_into().unwrap()) as usize
         } else {
-            if key_len + 6 > buffer.len() {
-                return DeserializeResult::IncompleteRead;
-            }
+            0
+        };
 
-            let value_len =
-                u32::from_le_bytes(buffer[key_len + 2..key_len + 6].try_into().unwrap()) as usize;
-            let total_len = key_len + value_len + 6;
-            if total_len > buffer.len() {
-                DeserializeResult::IncompleteRead
-            } else {
-                let value = str::from_utf8(&buffer[(key_len + 6)..total_len])
-                    .unwrap()
-                    .to_string();
+        if value_len > MAX_INLINE_VALUE_LEN {
+            return DeserializeResult::Corrupted("value length out of bounds", None);
+        }
+
+        let record_len = header_len + value_len;
+        let total_len = record_len + checksum_len;
+        if total_len > buffer.len() {
+            return DeserializeResult::IncompleteRead;
+        }
 
-                DeserializeResult::Set(key, value, total_len)
+        if checksum_len > 0 {
+            let checksum = u32::from_le_bytes(buffer[record_len..total_len].try_into().unwrap());
+            if checksum != crc32fast::hash(&buffer[..record_len]) {
+                return DeserializeResult::Corrupted("checksum mismatch", Some(total_len));
             }
         }
-    }
 
-    fn serialize(buffer: &mut [u8], operation: Operation) -> Option<usize> {
-        let total_length = match &operation {
-            Operation::Put { key, value } => key.len() + value.len() + 6,
-            Operation::Delete { key } => key.len() + 2,
+        let key = match str::from_utf8(&buffer[2..(key_len + 2)]) {
+            Ok(k) => k.to_string(),
+            Err(_) => return DeserializeResult::Corrupted("invalid key", Some(total_len)),
         };
 
-        if total_length > buffer.len() {
-            None
-        } else {
-            match operation {
-                Operation::Put { key, value } => {
-                    buffer[0] = 0;
-                    buffer[1] = key.len() as u8;
-                    buffer[key.len() + 2..key.len() + 6]
-                        .copy_from_slice(&u32::to_le_bytes(value.len() as u32));
-                    buffer[2..(key.len() + 2)].copy_from_slice(key.as_bytes());
-                    buffer[(key.len() + 6)..total_length].copy_from_slice(value.as_bytes());
+        let version_len = Self::version_length(format_version);
+        let version = match (operation_type, version_len) {
+            (1, _) | (_, 0) => 0,
+            _ => u64::from_le_bytes(
+                buffer[key_len + 6..key_len + 6 + VERSION_LENGTH]
+                    .try_into()
+                    .unwrap(),
+            ),
+        };
+        let expires_at = match operation_type {
+            3 | 4 => Some(u64::from_le_bytes(
+                buffer[key_len + 6 + version_len..header_len]
+                    .try_into()
+                    .unwrap(),
+            )),
+            _ => None,
+        };
+
+        let value = &buffer[header_len..record_len];
+        let value = match operation_type {
+            // DELETE
+            1 => return DeserializeResult::Delete(key, total_len),
+            // PUT BLOB
+            2 | 4 => match BlobRef::decode(value) {
+                Some(blob) => Value::Blob(blob),
+                None => {
+                    return DeserializeResult::Corrupted("invalid blob reference", Some(total_len))
                 }
-                Operation::Delete { key } => {
+            },
+            _ => Value::Inline(value.to_vec()),
+        };
+
+        let entry = Entry {
+            value,
+            expires_at,
+            version,
+        };
+        DeserializeResult::Set(key, entry, total_len)
+    }
+
+    /// Returns the key of the record at the start of the buffer and the length of the record,
+    /// without checking the record
+    fn peek_record(buffer: &[u8], format_version: u16) -> Option<(&[u8], usize)> {
+        let operation_type = *buffer.first()?;
+        let key_len = *buffer.get(1)? as usize;
+        let header_len = Self::header_length(operation_type, key_len, format_version)?;
+        let value_len = if operation_type != 1 {
+            u32::from_le_bytes(buffer.get(key_len + 2..key_len + 6)?.try_into().unwrap()) as usize
+        } else {
+            0
+        };
+
+        let total_len = header_len + value_len + Self::checksum_length(format_version);
+        (total_len <= buffer.len()).then(|| (&buffer[2..key_len + 2], total_len))
+    }
+
+    /// Returns the length of a record before its value, or `None` if the operation doesn't
+    /// exist in the given format
+    fn header_length(operation_type: u8, key_len: usize, format_version: u16) -> Option<usize> {
+        let put_len = key_len + 6 + Self::version_length(format_version);
+        match operation_type {
+            0 => Some(put_len),
+            1 => Some(key_len + 2),
+            2 if format_version >= BLOB_FORMAT_VERSION => Some(put_len),
+            3 | 4 if format_version >= EXPIRY_FORMAT_VERSION => Some(put_len + EXPIRY_LENGTH),
+            _ => None,
+        }
+    }
+
+    /// Length of the version in the put records of the given format
+    fn version_length(format_version: u16) -> usize {
+        if format_version >= CURRENT_FORMAT_VERSION {
+            VERSION_LENGTH
+        } else {
+            0
+        }
+    }
+
+    fn checksum_length(format_version: u16) -> usize {
+        if format_version == LEGACY_FORMAT_VERSION {
+            0
+        } else {
+            CHECKSUM_LENGTH
+        }
+    }
+
+    /// Deserializes the record at the start of the buffer, decrypting it first if the page is
+    /// encrypted. The position is the one of the record in the records of the page, the length
+    /// returned is the one of the record in the file
+    fn read_record(&self, buffer: &[u8], position: usize) -> DeserializeResult {
+        let Some(cipher) = &self.cipher else {
+            return Self::deserialize(buffer, self.format_version);
+        };
+
+        if buffer.is_empty() {
+            return DeserializeResult::EmptyBuffer;
+        }
+
+        match cipher.open(buffer, &self.frame_associated_data(position)) {
+            OpenResult::Opened(record, length) => {
+                match Self::deserialize(&record, self.format_version) {
+                    DeserializeResult::Set(key, entry, l) if l == record.len() => {
+                        DeserializeResult::Set(key, entry, length)
+                    }
+                    DeserializeResult::Delete(key, l) if l == record.len() => {
+                        DeserializeResult::Delete(key, length)
+                    }
+                    DeserializeResult::Corrupted(reason, _) => {
+                        DeserializeResult::Corrupted(reason, Some(length))
+                    }
+                    _ => DeserializeResult::Corrupted("invalid encrypted record", Some(length)),
+                }
+            }
+            OpenResult::Incomplete => DeserializeResult::IncompleteRead,
+            OpenResult::Invalid(length) => {
+                DeserializeResult::Corrupted("authentication failed", length)
+            }
+        }
+    }
+
+    fn serialize(buffer: &mut [u8], operation: Operation, format_version: u16) -> Option<usize> {
+        let record_length = match &operation {
+            Operation::Put { key, entry } => key.len() + entry.stored_length(format_version) + 6,
+            Operation::Delete { key } => key.len() + 2,
+        };
+
+        let total_length = if format_version == LEGACY_FORMAT_VERSION {
+            record_length
+        } else {
+            record_length + CHECKSUM_LENGTH
+        };
+
+        if total_length > buffer.len() {
+            None
+        } else {
+            match operation {
+                Operation::Put { key, entry } => {
+                    buffer[1] = key.len() as u8;
+                    buffer[key.len() + 2..key.len() + 6]
+                        .copy_from_slice(&u32::to_le_bytes(entry.value.stored_length() as u32));
+                    buffer[2..(key.len() + 2)].copy_from_slice(key.as_bytes());
+
+                    let mut value_start = key.len() + 6;
+                    if format_version >= CURRENT_FORMAT_VERSION {
+                        buffer[value_start..value_start + VERSION_LENGTH]
+                            .copy_from_slice(&entry.version.to_le_bytes());
+                        value_start += VERSION_LENGTH;
+                    }
+                    if let Some(expires_at) = entry.expires_at {
+                        buffer[value_start..value_start + EXPIRY_LENGTH]
+                            .copy_from_slice(&expires_at.to_le_bytes());
+                        value_start += EXPIRY_LENGTH;
+                    }
+
+                    let value_range = value_start..record_length;
+                    match &entry.value {
+                        Value::Inline(value) => {
+                            buffer[0] = if entry.expires_at.is_some() { 3 } else { 0 };
+                            buffer[value_range].copy_from_slice(value);
+                        }
+                        Value::Blob(blob) => {
+                            buffer[0] = if entry.expires_at.is_some() { 4 } else { 2 };
+                            buffer[value_range].copy_from_slice(&blob.encode());
+                        }
+                    }
+                }
+                Operation::Delete { key } => {
                     buffer[0] = 1;
                     buffer[1] = key.len() as u8;
                     buffer[2..(key.len() + 2)].copy_from_slice(key.as_bytes());
                 }
             };
 
-            Some(total_length)
+            if total_length > record_length {
+                let checksum = crc32fast::hash(&buffer[..record_length]);
+                buffer[record_length..total_length].copy_from_slice(&checksum.to_le_bytes());
+            }
+
+            Some(total_length)
+        }
+    }
+
+    /// Replaces the page file with a new one containing the metadata and the current entries.
+    /// The new page is written to a temporary file and renamed over the old one, so a crash
+    /// leaves either the old or the new page on disk
+    fn rewrite(&mut self) -> Result<(), std::io::Error> {
+        let (total_written, live_length) = self.write_temp_file()?;
+
+        let storage = &self.options.storage;
+        storage.rename(&Self::temp_file_name(&self.file_path), &self.file_path)?;
+        storage.sync_directory(&self.base_path)?;
+
+        self.reopen(total_written, live_length)
+    }
+
+    /// Writes the metadata and the entries of the page to its temporary file. In the current
+    /// format, the entries are followed by their sparse index. Returns the size of the records
+    /// in the file and once uncompressed
+    fn write_temp_file(&mut self) -> Result<(usize, usize), std::io::Error> {
+        let file = self
+            .options
+            .storage
+            .create(&Self::temp_file_name(&self.file_path), true)?;
+
+        // The header holds the size of the records, so it is written last
+        let mut buf_writer = BufWriter::new(file);
+        buf_writer.seek(SeekFrom::Start(self.data_offset() as u64))?;
+        self.compression = if self.format_version >= COMPRESSION_FORMAT_VERSION {
+            self.options.compression
+        } else {
+            Compression::None
+        };
+        self.cipher = if self.format_version >= ENCRYPTION_FORMAT_VERSION {
+            Self::current_cipher(&self.options)
+        } else {
+            None
+        };
+        self.generation = random_u64();
+        let (total_written, uncompressed_length, index) = self.write_entries(&mut buf_writer)?;
+
+        if self.format_version >= SORTED_FORMAT_VERSION {
+            let mut index = Self::encode_index(&index);
+            if let Some(cipher) = &self.cipher {
+                index = cipher.seal(&index, &self.frame_associated_data(total_written));
+            }
+            buf_writer.write_all(&index)?;
+            self.sorted_length = total_written;
+            self.uncompressed_length = uncompressed_length;
+            self.index_length = index.len();
+            self.bloom_checksum = Some(self.write_bloom_filter()?);
+        } else {
+            self.sorted_length = 0;
+            self.uncompressed_length = 0;
+            self.index_length = 0;
+            self.bloom_checksum = None;
+        }
+
+        buf_writer.seek(SeekFrom::Start(0))?;
+        buf_writer.write_all(&self.encode_metadata())?;
+        buf_writer.flush()?;
+        buf_writer.get_ref().sync()?;
+
+        Ok((total_written, uncompressed_length))
+    }
+
+    /// Opens the page file again after it has been replaced. The new page holds no record of
+    /// the expired entries anymore, so their blobs can be removed
+    fn reopen(&mut self, data_length: usize, live_length: usize) -> Result<(), std::io::Error> {
+        self.file = Some(self.options.storage.open(&self.file_path)?);
+        self.data_length = data_length;
+        self.live_length = live_length;
+        self.encryption_overhead = 0;
+        self.index = None;
+        self.mapping = None;
+        self.tail = None;
+        self.bloom_filter = None;
+
+        for blob in std::mem::take(&mut self.expired_blobs) {
+            BlobStore::remove(&self.base_path, &blob)?;
+        }
+
+        Ok(())
+    }
+
+    /// Rewrites the page if overwritten and deleted entries take too much space
+    fn compact_if_needed(&mut self) -> Result<(), TrieError> {
+        if self.data_length < MIN_COMPACTION_SIZE {
+            return Ok(());
+        }
+
+        if !self.has_data() {
+            // Loading a cold page is expensive: check it again only when it doubles in size
+            if self.data_length < self.next_compaction_check {
+                return Ok(());
+            }
+
+            self.next_compaction_check = self.data_length * 2;
+
+            // Compacts the page if needed
+            return self.read_data();
+        }
+
+        if self.has_too_many_dead_bytes() {
+            self.compact()?;
+        }
+
+        Ok(())
+    }
+
+    fn has_too_many_dead_bytes(&self) -> bool {
+        let dead_length = self.records_length().saturating_sub(self.live_length);
+
+        self.data_length >= MIN_COMPACTION_SIZE
+            && dead_length as f64 > self.live_length as f64 * self.options.compaction_ratio
+    }
+
+    /// Rewrites the page keeping only the live entries, upgrading its format if needed
+    fn compact(&mut self) -> Result<(), TrieError> {
+        let previous_length = self.data_length;
+
+        self.remove_expired();
+        self.format_version = CURRENT_FORMAT_VERSION;
+        self.rewrite()?;
+
+        debug!(
+            "Compacted page {:?}: {previous_length} -> {} bytes",
+            self.file_path, self.data_length
+        );
+        self.options
+            .stats
+            .record_compaction(previous_length.saturating_sub(self.data_length));
+
+        Ok(())
+    }
+
+    /// Removes the expired entries from the loaded data
+    fn remove_expired(&mut self) {
+        let now = unix_time();
+        let format_version = self.format_version;
+        let mut expired_length = 0;
+
+        self.entries.as_mut().unwrap().retain(|key, entry| {
+            if !entry.is_expired(now) {
+                return true;
+            }
+
+            expired_length += Self::put_length(key, entry, format_version);
+            if let Value::Blob(blob) = entry.value {
+                self.expired_blobs.push(blob);
+            }
+
+            false
+        });
+        self.live_length -= expired_length;
+    }
+
+    /// Writes the entries in key order, in blocks compressed and encrypted separately. Returns
+    /// their size in the file and once uncompressed, and the first key and offset of each block
+    fn write_entries<W: Write>(
+        &self,
+        writer: &mut W,
+    ) -> Result<(usize, usize, SparseIndex), std::io::Error> {
+        let mut total_written = 0;
+        let mut uncompressed_length = 0;
+        let mut buffer = [0u8; IO_BUFFER_SIZE];
+        let mut block = vec![];
+        let mut index = vec![];
+
+        for (key, entry) in self.entries.as_ref().unwrap() {
+            if index.is_empty() || block.len() >= INDEX_BLOCK_SIZE {
+                total_written += self.write_block(writer, &block, total_written)?;
+                block.clear();
+                index.push((key.clone(), total_written));
+            }
+
+            let size = Self::serialize(
+                &mut buffer,
+                Operation::Put { key, entry },
+                self.format_version,
+            )
+            .unwrap();
+            uncompressed_length += size;
+            block.extend_from_slice(&buffer[..size]);
+        }
+
+        total_written += self.write_block(writer, &block, total_written)?;
+
+        Ok((total_written, uncompressed_length, index))
+    }
+
+    /// Writes a block of sorted records at the given position of the records. Returns its size
+    /// in the file
+    fn write_block<W: Write>(
+        &self,
+        writer: &mut W,
+        block: &[u8],
+        position: usize,
+    ) -> Result<usize, std::io::Error> {
+        if block.is_empty() {
+            return Ok(0);
+        }
+
+        let compressed;
+        let block = match self.compression {
+            Compression::None => block,
+            compression => {
+                compressed = compression.encode_block(block)?;
+                &compressed[..]
+            }
+        };
+
+        let sealed;
+        let block = match &self.cipher {
+            Some(cipher) => {
+                sealed = cipher.seal(block, &self.frame_associated_data(position));
+                &sealed[..]
+            }
+            None => block,
+        };
+
+        writer.write_all(block)?;
+
+        Ok(block.len())
+    }
+
+    /// Serializes a sparse index: the length, bytes and offset of each key, then a checksum
+    fn encode_index(index: &[(String, usize)]) -> Vec<u8> {
+        let mut buffer = vec![];
+        for (key, offset) in index {
+            buffer.push(key.len() as u8);
+            buffer.extend_from_slice(key.as_bytes());
+            buffer.extend_from_slice(&(*offset as u32).to_le_bytes());
+        }
+        buffer.extend_from_slice(&crc32fast::hash(&buffer).to_le_bytes());
+
+        buffer
+    }
+
+    fn decode_index(buffer: &[u8]) -> Option<SparseIndex> {
+        let (content, checksum) = buffer.split_at(buffer.len().checked_sub(CHECKSUM_LENGTH)?);
+        if crc32fast::hash(content) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+            return None;
+        }
+
+        let mut index = vec![];
+        let mut position = 0;
+        while position < content.len() {
+            let key_len = content[position] as usize;
+            let key = content.get((position + 1)..(position + 1 + key_len))?;
+            let offset = content.get((position + 1 + key_len)..(position + 5 + key_len))?;
+
+            index.push((
+                str::from_utf8(key).ok()?.to_string(),
+                u32::from_le_bytes(offset.try_into().unwrap()) as usize,
+            ));
+            position += key_len + 5;
+        }
+
+        Some(index)
+    }
+
+    /// Returns true if a read can be served through the sparse index instead of loading the
+    /// data of the page. The index and the records appended after it are loaded on first use
+    fn use_index(&mut self) -> Result<bool, TrieError> {
+        if self.has_data()
+            || self.index_length == 0
+            || (self.index_reads >= HOT_PAGE_READS && self.mapping.is_none())
+        {
+            return Ok(false);
+        }
+
+        if self.index.is_none() && !self.read_index()? {
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    /// Loads the sparse index and the records appended after it. Returns false if they can't be
+    /// used, in which case the page must be fully loaded (e.g. to repair a torn write)
+    fn read_index(&mut self) -> Result<bool, TrieError> {
+        let index_offset = self.data_offset() + self.sorted_length;
+        let file = self.file.as_mut().unwrap();
+        file.seek(SeekFrom::Start(index_offset as u64))?;
+        let mut data = vec![];
+        file.read_to_end(&mut data)?;
+
+        // A page truncated before the end of its index is loaded in full, which reports it
+        let Some(index) = data.get(..self.index_length) else {
+            error!("Truncated sparse index in {:?}", self.file_path);
+            return Ok(false);
+        };
+        let index = match &self.cipher {
+            Some(cipher) => {
+                match cipher.open(index, &self.frame_associated_data(self.sorted_length)) {
+                    OpenResult::Opened(index, _) => Self::decode_index(&index),
+                    _ => None,
+                }
+            }
+            None => Self::decode_index(index),
+        };
+        let Some(index) = index else {
+            error!("Invalid sparse index in {:?}", self.file_path);
+            return Ok(false);
+        };
+
+        let mut tail = BTreeMap::new();
+        let mut position = self.index_length;
+        loop {
+            match self.read_record(&data[position..], self.sorted_length + position) {
+                DeserializeResult::Set(key, entry, length) => {
+                    tail.insert(key, Some(entry));
+                    position += length;
+                }
+                DeserializeResult::Delete(key, length) => {
+                    tail.insert(key, None);
+                    position += length;
+                }
+                DeserializeResult::EmptyBuffer => break,
+                DeserializeResult::IncompleteRead | DeserializeResult::Corrupted(_, _) => {
+                    return Ok(false)
+                }
+            }
+        }
+
+        let mapping = if self.options.memory_mapped_reads && self.sorted_length > 0 {
+            let file = self.file.as_ref().unwrap();
+            match file.map(self.data_offset() as u64, self.sorted_length) {
+                Ok(mapping) => mapping,
+                // A page truncated since its header was read is loaded in full, which reports it
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    error!("Failed to map {:?}: {e}", self.file_path);
+                    return Ok(false);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        } else {
+            None
+        };
+
+        self.index = Some(index);
+        self.tail = Some(tail);
+        self.bloom_filter = self.read_bloom_filter();
+        self.mapping = mapping;
+
+        Ok(true)
+    }
+
+    /// Writes the Bloom filter of the entries next to the page. Returns its checksum, which is
+    /// recorded in the header of the new page
+    fn write_bloom_filter(&self) -> Result<u32, std::io::Error> {
+        let entries = self.entries.as_ref().unwrap();
+        let mut filter = BloomFilter::new(entries.len());
+        for key in entries.keys() {
+            filter.insert(key.as_bytes());
+        }
+
+        // The page still references the previous filter until it is replaced: a crash in
+        // between leaves a filter whose checksum doesn't match, which is then ignored
+        let mut buffer = filter.encode();
+        if let Some(cipher) = &self.cipher {
+            buffer = cipher.seal(&buffer, &self.frame_associated_data(BLOOM_FILTER_POSITION));
+        }
+        self.options
+            .storage
+            .write(&Self::bloom_filter_file_name(&self.file_path), &buffer)?;
+
+        Ok(crc32fast::hash(&buffer))
+    }
+
+    /// Loads the Bloom filter of the sorted records, if it matches the page
+    fn read_bloom_filter(&self) -> Option<BloomFilter> {
+        let checksum = self.bloom_checksum?;
+        let path = Self::bloom_filter_file_name(&self.file_path);
+        let buffer = match self.options.storage.read(&path) {
+            Ok(buffer) => buffer,
+            Err(e) => {
+                error!(
+                    "Failed to read the Bloom filter of {:?}: {e}",
+                    self.file_path
+                );
+                return None;
+            }
+        };
+
+        if crc32fast::hash(&buffer) != checksum {
+            error!("The Bloom filter of {:?} doesn't match", self.file_path);
+            return None;
+        }
+
+        match &self.cipher {
+            Some(cipher) => {
+                match cipher.open(&buffer, &self.frame_associated_data(BLOOM_FILTER_POSITION)) {
+                    OpenResult::Opened(buffer, _) => BloomFilter::decode(&buffer),
+                    _ => None,
+                }
+            }
+            None => BloomFilter::decode(&buffer),
+        }
+    }
+
+    /// Returns the sorted records of a block of the sparse index
+    fn read_block(&mut self, block: usize) -> Result<Vec<(String, Entry)>, TrieError> {
+        let (start, end) = self.block_bounds(block);
+        if let Some(mapping) = &self.mapping {
+            self.options.stats.record_mapped_read();
+            return self.decode_blocks(&mapping[start..end], start);
+        }
+
+        let mut data = vec![0; end.saturating_sub(start)];
+        let data_offset = self.data_offset();
+        let file = self.file.as_mut().unwrap();
+        file.seek(SeekFrom::Start((data_offset + start) as u64))?;
+        file.read_exact(&mut data)?;
+
+        self.decode_blocks(&data, start)
+    }
+
+    /// Returns the position of the start and the end of a block of the sparse index in the
+    /// sorted records
+    fn block_bounds(&self, block: usize) -> (usize, usize) {
+        let index = self.index.as_ref().unwrap();
+        let start = index[block].1.min(self.sorted_length);
+        let end = index
+            .get(block + 1)
+            .map_or(self.sorted_length, |(_, offset)| *offset)
+            .min(self.sorted_length);
+
+        (start, end.max(start))
+    }
+
+    /// Looks a key up in a block of plain sorted records, only deserializing the record holding
+    /// it
+    fn find_sorted_record(
+        &self,
+        data: &[u8],
+        start: usize,
+        key: &str,
+    ) -> Result<Option<Entry>, TrieError> {
+        let mut position = 0;
+        while position < data.len() {
+            let Some((record_key, length)) =
+                Self::peek_record(&data[position..], self.format_version)
+            else {
+                return Err(self.corrupted_at(start + position, "invalid sorted record"));
+            };
+
+            match record_key.cmp(key.as_bytes()) {
+                Ordering::Less => position += length,
+                Ordering::Equal => {
+                    return match Self::deserialize(&data[position..], self.format_version) {
+                        DeserializeResult::Set(_, entry, _) => Ok(Some(entry)),
+                        _ => Err(self.corrupted_at(start + position, "invalid sorted record")),
+                    }
+                }
+                Ordering::Greater => break,
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Returns the records of blocks of sorted records starting at the given position of the
+    /// sorted records, decrypting and decompressing them if needed
+    fn decode_blocks(&self, data: &[u8], start: usize) -> Result<Vec<(String, Entry)>, TrieError> {
+        if !self.has_framed_blocks() {
+            return self.decode_sorted_records(data, start);
+        }
+
+        let mut records = vec![];
+        let mut position = 0;
+        while position < data.len() {
+            let Some((block, length)) = self.open_block(&data[position..], start + position) else {
+                return Err(self.corrupted_at(start + position, "invalid block"));
+            };
+
+            records.append(
+                &mut self
+                    .decode_sorted_records(&block, 0)
+                    .map_err(|_| self.corrupted_at(start + position, "invalid block"))?,
+            );
+            position += length;
+        }
+
+        Ok(records)
+    }
+
+    /// Returns true if the blocks of sorted records are stored in frames, compressed or
+    /// encrypted, rather than as plain records
+    fn has_framed_blocks(&self) -> bool {
+        self.compression != Compression::None || self.cipher.is_some()
+    }
+
+    /// Decrypts and decompresses the block at the start of the buffer. Returns its records and
+    /// the length of the block in the file
+    fn open_block(&self, buffer: &[u8], position: usize) -> Option<(Vec<u8>, usize)> {
+        let Some(cipher) = &self.cipher else {
+            return self.compression.decode_block(buffer);
+        };
+
+        let OpenResult::Opened(block, length) =
+            cipher.open(buffer, &self.frame_associated_data(position))
+        else {
+            return None;
+        };
+
+        match self.compression {
+            Compression::None => Some((block, length)),
+            compression => compression
+                .decode_block(&block)
+                .filter(|(_, l)| *l == block.len())
+                .map(|(block, _)| (block, length)),
+        }
+    }
+
+    fn decode_sorted_records(
+        &self,
+        data: &[u8],
+        start: usize,
+    ) -> Result<Vec<(String, Entry)>, TrieError> {
+        let mut records = vec![];
+        let mut position = 0;
+        while position < data.len() {
+            match Self::deserialize(&data[position..], self.format_version) {
+                DeserializeResult::Set(key, entry, length) => {
+                    records.push((key, entry));
+                    position += length;
+                }
+                _ => return Err(self.corrupted_at(start + position, "invalid sorted record")),
+            }
+        }
+
+        Ok(records)
+    }
+
+    /// Looks an entry up in the records appended after the index, then in the block of sorted
+    /// records that may hold it unless the Bloom filter rules the key out
+    fn find_indexed(&mut self, key: &str) -> Result<Option<Entry>, TrieError> {
+        if let Some(entry) = self.tail.as_ref().unwrap().get(key) {
+            return Ok(entry.clone());
+        }
+
+        if let Some(filter) = &self.bloom_filter {
+            if !filter.may_contain(key.as_bytes()) {
+                self.options.stats.record_filtered_lookup();
+                return Ok(None);
+            }
+        }
+
+        let block = self
+            .index
+            .as_ref()
+            .unwrap()
+            .partition_point(|(k, _)| k.as_str() <= key);
+        if block == 0 {
+            return Ok(None);
+        }
+
+        self.index_reads += 1;
+        if let Some(mapping) = self.mapping.as_ref().filter(|_| !self.has_framed_blocks()) {
+            let (start, end) = self.block_bounds(block - 1);
+            self.options.stats.record_mapped_read();
+            return self.find_sorted_record(&mapping[start..end], start, key);
+        }
+
+        Ok(self
+            .read_block(block - 1)?
+            .into_iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, entry)| entry))
+    }
+
+    /// Reads a range of entries from the sorted records, starting at the block that may hold
+    /// the start key, and applies the records appended after the index
+    fn get_range_indexed(
+        &mut self,
+        start_key: &String,
+        end_key: &String,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, Value)>, TrieError> {
+        let now = unix_time();
+        let tail: Vec<(String, Option<Entry>)> = self
+            .tail
+            .as_ref()
+            .unwrap()
+            .range::<String, (Bound<&String>, Bound<&String>)>((
+                Included(start_key),
+                Included(end_key),
+            ))
+            .map(|(k, e)| (k.clone(), e.clone()))
+            .collect();
+
+        self.index_reads += 1;
+
+        // Each appended record hides at most one sorted entry
+        let needed = limit.map_or(usize::MAX, |l| l.saturating_add(tail.len()));
+        let index_len = self.index.as_ref().unwrap().len();
+        let mut block = self
+            .index
+            .as_ref()
+            .unwrap()
+            .partition_point(|(k, _)| k <= start_key)
+            .saturating_sub(1);
+
+        let mut entries = BTreeMap::new();
+        'blocks: while block < index_len && entries.len() < needed {
+            for (key, entry) in self.read_block(block)? {
+                if key > *end_key {
+                    break 'blocks;
+                }
+
+                if key >= *start_key && !entry.is_expired(now) {
+                    entries.insert(key, entry);
+                    if entries.len() >= needed {
+                        break 'blocks;
+                    }
+                }
+            }
+
+            block += 1;
+        }
+
+        for (key, entry) in tail {
+            match entry {
+                Some(entry) if !entry.is_expired(now) => entries.insert(key, entry),
+                _ => entries.remove(&key),
+            };
+        }
+
+        let iterator = entries.into_iter().map(|(k, e)| (k, e.value));
+        Ok(match limit {
+            Some(l) => iterator.take(l).collect(),
+            None => iterator.collect(),
+        })
+    }
+
+    fn encode_metadata(&self) -> Vec<u8> {
+        if self.format_version < HEADER_FORMAT_VERSION {
+            let mut buffer = vec![0; LEGACY_METADATA_LENGTH];
+            buffer[0] = self.prefix.len() as u8;
+            buffer[1..(self.prefix.len() + 1)].copy_from_slice(&self.prefix);
+            buffer[MAX_KEY_LEN + 1] = if self.is_leaf.unwrap() { 1 } else { 0 };
+            for ix in self.legacy_children() {
+                buffer[MAX_KEY_LEN + 2 + ix] = 1;
+            }
+            buffer[LEGACY_FORMAT_VERSION_OFFSET] = self.format_version as u8;
+
+            return buffer;
+        }
+
+        let mut flags = 0;
+        if self.is_leaf.unwrap() {
+            flags |= FLAG_LEAF;
+        }
+        if self.prefix.is_empty() && self.options.case_sensitive {
+            flags |= FLAG_CASE_SENSITIVE;
+        }
+        if self.format_version >= SORTED_FORMAT_VERSION && self.bloom_checksum.is_some() {
+            flags |= FLAG_BLOOM_FILTER;
+        }
+        if self.cipher.is_some() {
+            flags |= FLAG_ENCRYPTED;
+        }
+
+        let mut buffer = vec![0; HEADER_LENGTH];
+        buffer[..MAGIC.len()].copy_from_slice(MAGIC);
+        buffer[VERSION_OFFSET..VERSION_OFFSET + 2]
+            .copy_from_slice(&self.format_version.to_le_bytes());
+        buffer[FLAGS_OFFSET..FLAGS_OFFSET + 2].copy_from_slice(&flags.to_le_bytes());
+        buffer[PREFIX_OFFSET] = self.prefix.len() as u8;
+        buffer[(PREFIX_OFFSET + 1)..(PREFIX_OFFSET + self.prefix.len() + 1)]
+            .copy_from_slice(&self.prefix);
+        if self.format_version >= FAN_OUT_FORMAT_VERSION {
+            for (ix, is_child) in self.children.iter().enumerate() {
+                if *is_child {
+                    buffer[CHILDREN_OFFSET + ix / 8] |= 1 << (ix % 8);
+                }
+            }
+        } else {
+            for ix in self.legacy_children() {
+                buffer[CHILDREN_OFFSET + ix] = 1;
+            }
+        }
+
+        if self.format_version >= COMPRESSION_FORMAT_VERSION {
+            buffer[COMPRESSION_OFFSET] = self.compression.id();
+            buffer[UNCOMPRESSED_LENGTH_OFFSET..UNCOMPRESSED_LENGTH_OFFSET + 4]
+                .copy_from_slice(&(self.uncompressed_length as u32).to_le_bytes());
+        }
+
+        if self.format_version >= SORTED_FORMAT_VERSION {
+            if let Some(checksum) = self.bloom_checksum {
+                buffer[BLOOM_CHECKSUM_OFFSET..BLOOM_CHECKSUM_OFFSET + 4]
+                    .copy_from_slice(&checksum.to_le_bytes());
+            }
+            buffer[SORTED_LENGTH_OFFSET..SORTED_LENGTH_OFFSET + 4]
+                .copy_from_slice(&(self.sorted_length as u32).to_le_bytes());
+            buffer[INDEX_LENGTH_OFFSET..INDEX_LENGTH_OFFSET + 4]
+                .copy_from_slice(&(self.index_length as u32).to_le_bytes());
+        }
+
+        if self.format_version >= ENCRYPTION_FORMAT_VERSION {
+            buffer[GENERATION_OFFSET..GENERATION_OFFSET + GENERATION_LENGTH]
+                .copy_from_slice(&self.generation.to_le_bytes());
+        }
+
+        // The prefix and the children are encrypted in place, authenticated along with the rest
+        // of the header
+        if let Some(cipher) = &self.cipher {
+            buffer[KEY_CHECK_OFFSET..SEAL_OFFSET].copy_from_slice(&cipher.key_check());
+            let associated_data = Self::header_associated_data(&buffer);
+            let seal = cipher.seal_detached(
+                &mut buffer[PREFIX_OFFSET..SORTED_LENGTH_OFFSET],
+                &associated_data,
+            );
+            buffer[SEAL_OFFSET..SEAL_OFFSET + SEAL_LENGTH].copy_from_slice(&seal);
         }
+
+        buffer
     }
 
-    fn flush_to_disk(&mut self) -> Result<(), std::io::Error> {
-        if !self.has_data() {
-            debug!("Trying to flush empty page");
+    /// Decrypts the prefix and the children of an encrypted header. Returns the key of the page
+    fn open_header(&self, buffer: &mut [u8]) -> Result<Arc<Cipher>, TrieError> {
+        let key_check = &buffer[KEY_CHECK_OFFSET..SEAL_OFFSET];
+        let Some(cipher) = self
+            .options
+            .encryption
+            .as_ref()
+            .and_then(|k| k.find(key_check))
+            .cloned()
+        else {
+            return Err(TrieError::InvalidConfiguration(format!(
+                "{:?}: the page is encrypted with an unknown key",
+                self.file_path
+            )));
+        };
 
-            return Ok(());
+        let associated_data = Self::header_associated_data(buffer);
+        let (header, seal) = buffer.split_at_mut(SEAL_OFFSET);
+        if !cipher.open_detached(
+            &mut header[PREFIX_OFFSET..SORTED_LENGTH_OFFSET],
+            &seal[..SEAL_LENGTH],
+            &associated_data,
+        ) {
+            return Err(self.corrupted("header authentication failed"));
         }
 
-        let mut total_written = 0;
+        Ok(cipher)
+    }
 
-        {
-            let file = self.file.as_mut().unwrap();
-            file.seek(SeekFrom::Start(METADATA_LENGTH as u64))?;
+    /// Returns the fields of the header that are not encrypted
+    fn header_associated_data(buffer: &[u8]) -> Vec<u8> {
+        [
+            &buffer[..PREFIX_OFFSET],
+            &buffer[SORTED_LENGTH_OFFSET..SEAL_OFFSET],
+            &buffer[GENERATION_OFFSET..GENERATION_OFFSET + GENERATION_LENGTH],
+        ]
+        .concat()
+    }
 
-            let mut buf_writer = BufWriter::new(file);
+    /// Returns the data authenticated along with the frame at the given position of the
+    /// records: a frame can't be moved to another page, another version of the page or another
+    /// position
+    fn frame_associated_data(&self, position: usize) -> Vec<u8> {
+        [
+            &self.prefix[..],
+            &self.generation.to_le_bytes(),
+            &(position as u64).to_le_bytes(),
+        ]
+        .concat()
+    }
 
-            let mut buffer = [0u8; IO_BUFFER_SIZE];
+    /// Returns the key used to encrypt the pages written from now on
+    fn current_cipher(options: &PageOptions) -> Option<Arc<Cipher>> {
+        options.encryption.as_ref().map(|k| k.current().clone())
+    }
 
-            for (key, value) in self.entries.iter().flatten() {
-                let size = Self::serialize(
-                    &mut buffer,
-                    Operation::Put {
-                        key: &key,
-                        value: &value,
-                    },
-                )
-                .unwrap();
-                total_written += size;
-                buf_writer.write(&buffer[..size])?;
-            }
+    /// Returns the positions of the children in the older formats. Pages are upgraded before
+    /// they get a child outside of `0-9a-z`
+    fn legacy_children(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..FAN_OUT)
+            .filter(|ix| self.children[*ix])
+            .map(|ix| Self::byte_to_legacy_index(ix as u8).expect("Page must be upgraded"))
+    }
+
+    fn put_length(key: &str, entry: &Entry, format_version: u16) -> usize {
+        let record_length = key.len() + entry.stored_length(format_version) + 6;
 
-            buf_writer.flush()?;
-            buf_writer.get_ref().sync_all()?;
+        if format_version == LEGACY_FORMAT_VERSION {
+            record_length
+        } else {
+            record_length + CHECKSUM_LENGTH
         }
-        let file = self.file.as_mut().unwrap();
-        file.set_len((METADATA_LENGTH + total_written) as u64)?;
+    }
 
-        Ok(())
+    /// Size of the records of the page once uncompressed
+    fn records_length(&self) -> usize {
+        self.data_length - self.sorted_length + self.uncompressed_length - self.encryption_overhead
     }
 
-    fn register_child_int(&mut self, index: usize) {
-        self.children[index] = Some(Self::index_to_char(index));
+    fn file_length(&self) -> Result<u64, std::io::Error> {
+        self.file.as_ref().unwrap().length()
     }
 
-    fn split(&mut self) -> Result<(), std::io::Error> {
-        let file_size = self
-            .file
-            .as_ref()
-            .map(|f| f.metadata().unwrap().len())
-            .unwrap_or(0) as usize;
+    /// Returns the position of the first record in the page file
+    fn data_offset(&self) -> usize {
+        if self.format_version < HEADER_FORMAT_VERSION {
+            LEGACY_METADATA_LENGTH
+        } else {
+            HEADER_LENGTH
+        }
+    }
+
+    fn split(&mut self) -> Result<(), TrieError> {
+        if self.data_length <= SPLIT_THRESHOLD {
+            return Ok(());
+        }
+
+        self.read_data()?;
+
+        // Most of the page is made of overwritten or deleted entries: splitting it would only
+        // spread the live entries over several small pages
+        if self.live_length <= SPLIT_THRESHOLD / 2 {
+            if self.records_length() > self.live_length {
+                self.compact()?;
+            }
+
+            return Ok(());
+        }
+
+        // The children and the new parent page are first written to temporary files. The split
+        // is committed by the intent file, then the temporary files are renamed: if the process
+        // crashes, `recover_split` either rolls the split forward or discards the temporary files
+        self.remove_expired();
+        let count = self.entries.as_ref().unwrap().len();
         let mut transferred = 0;
+        let mut child_prefixes = vec![];
 
-        if file_size > METADATA_LENGTH && file_size - METADATA_LENGTH > SPLIT_THRESHOLD {
-            self.read_data()?;
-            let count = self.entries.as_ref().unwrap().len();
+        // Entries are grouped by the byte following the prefix: only the entry whose key is
+        // the prefix itself stays in the parent
+        let mut children_entries: BTreeMap<u8, BTreeMap<String, Entry>> = BTreeMap::new();
+        let mut remaining = BTreeMap::new();
+        for (key, entry) in std::mem::take(self.entries.as_mut().unwrap()) {
+            match key.as_bytes().get(self.prefix.len()) {
+                Some(b) => children_entries.entry(*b).or_default().insert(key, entry),
+                None => remaining.insert(key, entry),
+            };
+        }
+        self.entries = Some(remaining);
 
-            for i in (0..36).rev() {
-                let (low, high) = Self::index_to_range(i);
+        for (byte, entries) in children_entries {
+            transferred += entries.len();
 
-                let mut prefix = self.prefix.clone();
-                prefix.push(low);
-                let mut highf = self.prefix.clone();
-                highf.push(high);
+            let mut prefix = self.prefix.clone();
+            prefix.push(byte);
 
-                let entries = self.entries.as_mut().unwrap().split_off(&prefix);
+            let mut node =
+                TreeNode::new_leaf(self.base_path.clone(), &prefix, self.options.clone());
+            node.entries = Some(entries);
+            node.write_temp_file()?;
+            child_prefixes.push(prefix);
+            self.children[byte as usize] = true;
+        }
 
-                if entries.len() > 0 {
-                    transferred += entries.len();
+        crash_point("split:children_written");
 
-                    let mut node =
-                        TreeNode::create(self.base_path.clone(), &prefix, self.sync_after_write)?;
-                    node.set_entries(entries)?;
-                    self.children[i] = Some(low);
-                }
+        if self.entries.as_ref().unwrap().len() > 1 {
+            panic!("Failed to split page");
+        }
+
+        if transferred + self.entries.as_ref().unwrap().len() != count {
+            panic!("Failed to split page");
+        }
+
+        self.is_leaf = Some(false);
+        self.format_version = CURRENT_FORMAT_VERSION;
+        let (total_written, live_length) = self.write_temp_file()?;
+        crash_point("split:parent_written");
+
+        Self::write_intent(
+            self.options.storage.as_ref(),
+            self.options.encryption.as_deref(),
+            &self.base_path,
+            &self.prefix,
+            &child_prefixes,
+            SPLIT_INTENT_EXTENSION,
+        )?;
+        crash_point("split:intent_written");
+
+        Self::complete_split(
+            self.options.storage.as_ref(),
+            &self.base_path,
+            &self.prefix,
+            &child_prefixes,
+        )?;
+        self.reopen(total_written, live_length)?;
+        self.options.stats.record_split();
+
+        Ok(())
+    }
+
+    /// Completes a split or a merge interrupted by a crash after its intent file was written.
+    /// Returns the prefix of the parent page
+    pub fn recover_intent(
+        storage: &dyn PageStorage,
+        encryption: Option<&Keyring>,
+        base_path: &Path,
+        intent_path: &Path,
+    ) -> Result<Vec<u8>, TrieError> {
+        let corrupted = || TrieError::Corrupted(format!("{intent_path:?}: invalid intent"));
+
+        let mut buffer = storage.read(intent_path)?;
+        if let Some(intent) = Self::open_intent(encryption, intent_path, &buffer) {
+            buffer = intent;
+        }
+        if buffer.len() < CHECKSUM_LENGTH {
+            return Err(corrupted());
+        }
+
+        let (content, checksum) = buffer.split_at(buffer.len() - CHECKSUM_LENGTH);
+        if crc32fast::hash(content) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+            return Err(corrupted());
+        }
+
+        let mut prefixes = vec![];
+        let mut position = 0;
+        while position < content.len() {
+            let length = content[position] as usize;
+            let prefix = content
+                .get((position + 1)..(position + length + 1))
+                .ok_or_else(corrupted)?;
+
+            prefixes.push(prefix.to_vec());
+            position += length + 1;
+        }
+
+        if prefixes.is_empty() {
+            return Err(corrupted());
+        }
+
+        let prefix = prefixes.remove(0);
+        match intent_path.extension().and_then(|e| e.to_str()) {
+            Some(SPLIT_INTENT_EXTENSION) => {
+                Self::complete_split(storage, base_path, &prefix, &prefixes)?
+            }
+            Some(MERGE_INTENT_EXTENSION) => {
+                Self::complete_merge(storage, base_path, &prefix, &prefixes)?
             }
+            _ => return Err(corrupted()),
+        }
+
+        Ok(prefix)
+    }
+
+    /// Durably records the list of children involved in a split or a merge: once this file
+    /// exists, the operation must be completed
+    fn write_intent(
+        storage: &dyn PageStorage,
+        encryption: Option<&Keyring>,
+        base_path: &Path,
+        prefix: &[u8],
+        child_prefixes: &[Vec<u8>],
+        extension: &str,
+    ) -> Result<(), std::io::Error> {
+        let mut buffer = vec![];
+        for p in std::iter::once(prefix).chain(child_prefixes.iter().map(Vec::as_slice)) {
+            buffer.push(p.len() as u8);
+            buffer.extend_from_slice(p);
+        }
+        buffer.extend_from_slice(&crc32fast::hash(&buffer).to_le_bytes());
+
+        // The prefixes are encrypted like the pages. The frame is bound to the name of the
+        // intent file, which holds the parent prefix and the kind of operation
+        let intent_path = Self::intent_file_name(base_path, prefix, extension);
+        if let Some(keyring) = encryption {
+            let cipher = keyring.current();
+            let frame = cipher.seal(&buffer, Self::intent_associated_data(&intent_path));
+            buffer = [&INTENT_MAGIC[..], &cipher.key_check(), &frame].concat();
+        }
+
+        let temp_path = Self::temp_file_name(&intent_path);
+        {
+            let mut file = storage.create(&temp_path, true)?;
+            file.write_all(&buffer)?;
+            file.sync()?;
+        }
+
+        storage.rename(&temp_path, &intent_path)?;
+        storage.sync_directory(base_path)?;
+
+        Ok(())
+    }
+
+    /// Decrypts an encrypted intent file. Returns `None` if the intent isn't encrypted
+    fn open_intent(
+        encryption: Option<&Keyring>,
+        intent_path: &Path,
+        buffer: &[u8],
+    ) -> Option<Vec<u8>> {
+        // A plain intent may start with the same bytes, its checksum tells them apart
+        let frame = buffer.strip_prefix(&INTENT_MAGIC[..])?;
+        let (key_check, frame) = frame.split_at_checked(KEY_CHECK_LENGTH)?;
+        let cipher = encryption?.find(key_check)?;
+
+        match cipher.open(frame, Self::intent_associated_data(intent_path)) {
+            OpenResult::Opened(intent, length) if length == frame.len() => Some(intent),
+            _ => None,
+        }
+    }
+
+    fn intent_associated_data(intent_path: &Path) -> &[u8] {
+        intent_path
+            .file_name()
+            .map(|name| name.as_encoded_bytes())
+            .unwrap_or_default()
+    }
 
-            if self.entries.as_ref().unwrap().len() > 1 {
-                panic!("Failed to split page");
+    /// Moves the temporary files of the children and of the parent in place, then removes the
+    /// intent file. Files already moved by a previous attempt are skipped
+    fn complete_split(
+        storage: &dyn PageStorage,
+        base_path: &Path,
+        prefix: &[u8],
+        child_prefixes: &[Vec<u8>],
+    ) -> Result<(), std::io::Error> {
+        for child_prefix in child_prefixes {
+            let file_path = Self::file_name(base_path, child_prefix);
+            let temp_path = Self::temp_file_name(&file_path);
+            if storage.exists(&temp_path) {
+                storage.rename(&temp_path, &file_path)?;
             }
 
-            if transferred + self.entries.as_ref().unwrap().len() != count {
-                panic!("Failed to split page");
+            crash_point("split:child_renamed");
+        }
+
+        let file_path = Self::file_name(base_path, prefix);
+        let temp_path = Self::temp_file_name(&file_path);
+        if storage.exists(&temp_path) {
+            storage.rename(&temp_path, &file_path)?;
+        }
+
+        storage.sync_directory(base_path)?;
+        crash_point("split:parent_renamed");
+
+        storage.remove(&Self::intent_file_name(
+            base_path,
+            prefix,
+            SPLIT_INTENT_EXTENSION,
+        ))?;
+        storage.sync_directory(base_path)?;
+
+        Ok(())
+    }
+
+    /// Moves the temporary file of the parent in place, then removes the files of the merged
+    /// children and the intent file. Once the parent is a leaf, the children are unreachable,
+    /// so a crash in between only leaves orphan files behind
+    fn complete_merge(
+        storage: &dyn PageStorage,
+        base_path: &Path,
+        prefix: &[u8],
+        child_prefixes: &[Vec<u8>],
+    ) -> Result<(), std::io::Error> {
+        let file_path = Self::file_name(base_path, prefix);
+        let temp_path = Self::temp_file_name(&file_path);
+        if storage.exists(&temp_path) {
+            storage.rename(&temp_path, &file_path)?;
+        }
+
+        storage.sync_directory(base_path)?;
+        crash_point("merge:parent_renamed");
+
+        for child_prefix in child_prefixes {
+            let file_path = Self::file_name(base_path, child_prefix);
+            for path in [Self::bloom_filter_file_name(&file_path), file_path] {
+                match storage.remove(&path) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
             }
 
-            self.is_leaf = Some(false);
-            self.save_metadata()?;
+            crash_point("merge:child_removed");
         }
 
+        storage.remove(&Self::intent_file_name(
+            base_path,
+            prefix,
+            MERGE_INTENT_EXTENSION,
+        ))?;
+        storage.sync_directory(base_path)?;
+
         Ok(())
     }
 
-    fn is_valid_key(key: &str) -> bool {
-        key.len() <= MAX_KEY_LEN && key.chars().all(char::is_alphanumeric)
+    fn corrupted(&self, reason: &str) -> TrieError {
+        TrieError::Corrupted(format!("{:?}: {reason}", self.file_path))
     }
 
-    fn is_valid_value(value: &str) -> bool {
-        value.len() <= MAX_VALUE_LEN
+    fn corrupted_at(&self, position: usize, reason: &str) -> TrieError {
+        TrieError::Corrupted(format!(
+            "{:?}: {reason} at offset {}",
+            self.file_path,
+            self.data_offset() + position
+        ))
     }
 
-    fn file_name(base_path: &PathBuf, prefix: &str) -> PathBuf {
-        if prefix.len() == 0 {
+    /// Range bounds may be empty, unlike keys
+    fn is_valid_bound(key: &str) -> bool {
+        key.len() <= MAX_KEY_LEN
+    }
+
+    fn is_valid_value(value: &Value) -> bool {
+        match value {
+            Value::Inline(value) => value.len() <= MAX_INLINE_VALUE_LEN,
+            Value::Blob(blob) => blob.length <= MAX_VALUE_LEN as u64,
+        }
+    }
+
+    /// Removes the blob of a value that was overwritten or deleted. The page is synced first,
+    /// so that the record replacing the value can't be lost once the blob is gone
+    fn remove_blob(&mut self, previous_value: Option<Value>) -> Result<(), std::io::Error> {
+        if let Some(Value::Blob(blob)) = previous_value {
+            if !self.options.sync_after_write() {
+                self.file.as_ref().unwrap().sync()?;
+            }
+
+            BlobStore::remove(&self.base_path, &blob)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn file_name(base_path: &Path, prefix: &[u8]) -> PathBuf {
+        if prefix.is_empty() {
             // root
             base_path.join("_root.dat")
         } else {
-            base_path.join(format!("{prefix}.dat"))
+            // Bytes other than lowercase letters and digits are escaped, so that file names stay
+            // valid and distinct on every file system, including case-insensitive ones
+            let mut file_name = String::new();
+            for b in prefix {
+                match b {
+                    b'0'..=b'9' | b'a'..=b'z' => file_name.push(*b as char),
+                    _ => file_name.push_str(&format!("%{b:02X}")),
+                }
+            }
+
+            // `~` is never escaped into a name, so shortened names can't clash with the others
+            if file_name.len() > MAX_FILE_STEM_LEN {
+                file_name.truncate(SHORTENED_STEM_LEN);
+                file_name.push_str(&format!("~{:032x}", xxh3_128(prefix)));
+            }
+
+            base_path.join(format!("{file_name}.dat"))
+        }
+    }
+
+    fn intent_file_name(base_path: &Path, prefix: &[u8], extension: &str) -> PathBuf {
+        Self::file_name(base_path, prefix).with_extension(extension)
+    }
+
+    fn bloom_filter_file_name(file_path: &Path) -> PathBuf {
+        file_path.with_extension(BLOOM_FILTER_EXTENSION)
+    }
+
+    fn temp_file_name(file_path: &Path) -> PathBuf {
+        let mut file_name = file_path.to_path_buf().into_os_string();
+        file_name.push(".tmp");
+
+        PathBuf::from(file_name)
+    }
+}
+
+/// Aborts the process at the given step when it matches the `KVS_CRASH_POINT` environment
+/// variable. Used by the tests to simulate a crash in the middle of multi-file operations
+#[cfg(test)]
+fn crash_point(step: &str) {
+    if std::env::var("KVS_CRASH_POINT").is_ok_and(|s| s == step) {
+        std::process::abort();
+    }
+}
+
+#[cfg(not(test))]
+fn crash_point(_step: &str) {}
+
+/// Keys are non-empty and at most `MAX_KEY_LEN` bytes long
+pub fn is_valid_key(key: &str) -> bool {
+    !key.is_empty() && key.len() <= MAX_KEY_LEN
+}
+
+/// Returns an error if the storage would refuse to write the given value (`None`: a delete)
+/// under the given key, so that such a write can be refused before it is logged
+pub fn check_write(key: &str, value: Option<&Value>) -> Result<(), TrieError> {
+    // The longest name written for a page is the one of its temporary Bloom filter or intent
+    let longest_file_name = TreeNode::temp_file_name(&TreeNode::intent_file_name(
+        Path::new(""),
+        key.as_bytes(),
+        BLOOM_FILTER_EXTENSION,
+    ));
+    if !is_valid_key(key) || longest_file_name.as_os_str().len() > MAX_FILE_NAME_LEN {
+        return Err(TrieError::KeyError);
+    }
+
+    if value.is_some_and(|v| !TreeNode::is_valid_value(v)) {
+        return Err(TrieError::ValueError);
+    }
+
+    Ok(())
+}
+
+/// Returns the current unix timestamp, in seconds
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl Value {
+    /// Size of the value in a page record
+    fn stored_length(&self) -> usize {
+        match self {
+            Value::Inline(value) => value.len(),
+            Value::Blob(_) => BLOB_REF_LENGTH,
+        }
+    }
+}
+
+impl Entry {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|t| t <= now)
+    }
+
+    /// Size of the value, of the version and of the expiry timestamp in a
+    /// page record of the given format
+    fn stored_length(&self, format_version: u16) -> usize {
+        let length = self.value.stored_length() + TreeNode::version_length(format_version);
+        match self.expires_at {
+            Some(_) => length + EXPIRY_LENGTH,
+            None => length,
         }
     }
 }
 
+impl Default for PageOptions {
+    fn default() -> Self {
+        PageOptions {
+            fsync: FSyncStrategy::Default,
+            compaction_ratio: DEFAULT_COMPACTION_RATIO,
+            case_sensitive: false,
+            stats: Arc::new(Stats::default()),
+            storage: Arc::new(FileStorage),
+            compression: Compression::None,
+            encryption: None,
+            memory_mapped_reads: false,
+            dirty_pages: None,
+            group_commit: None,
+        }
+    }
+}
+
+impl PageOptions {
+    /// Whether every write to a page is synced before it returns
+    pub fn sync_after_write(&self) -> bool {
+        self.fsync == FSyncStrategy::Strict && self.group_commit.is_none()
+    }
+}
+
 impl From<std::io::Error> for TrieError {
     fn from(e: std::io::Error) -> Self {
         TrieError::IoError(e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::KeySource;
+    use crate::segment_storage::SegmentStorage;
+    use tempfile::tempdir;
+
+    fn write_page(path: &Path, format_version: u16) -> PathBuf {
+        let mut node = TreeNode::create(path.to_path_buf(), b"", PageOptions::default()).unwrap();
+        node.format_version = format_version;
+        node.file = Some(node.options.storage.create(&node.file_path, true).unwrap());
+        node.save_metadata().unwrap();
+
+        for i in 0..3 {
+            node.insert(
+                format!("key{i}"),
+                Value::Inline(format!("value{i}").into_bytes()),
+                None,
+                0,
+            )
+            .unwrap();
+        }
+
+        node.file_path.clone()
+    }
+
+    #[test]
+    fn test_checksum_detects_corrupted_record() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = write_page(temp_dir.path(), CURRENT_FORMAT_VERSION);
+
+        // Flip a bit in the value of the second record
+        let mut bytes = std::fs::read(&file_path).unwrap();
+        let record_len = "key0".len() + "value0".len() + 6 + VERSION_LENGTH + CHECKSUM_LENGTH;
+        bytes[HEADER_LENGTH + record_len + 12 + VERSION_LENGTH] ^= 0x01;
+        std::fs::write(&file_path, bytes).unwrap();
+
+        let result = TreeNode::from(
+            temp_dir.path().to_path_buf(),
+            b"",
+            true,
+            true,
+            PageOptions::default(),
+        );
+        assert!(matches!(result, Err(TrieError::Corrupted(_))));
+    }
+
+    #[test]
+    fn test_torn_tail_is_truncated() {
+        let temp_dir = tempdir().unwrap();
+        let file_path = write_page(temp_dir.path(), CURRENT_FORMAT_VERSION);
+
+        // Damage the checksum of the last record, as a torn write would
+        let mut bytes = std::fs::read(&file_path).unwrap();
+        let len = bytes.len();
+        bytes[len - 1] ^= 0xFF;
+        std::fs::write(&file_path, bytes).unwrap();
+
+        let mut node = TreeNode::from(
+            temp_dir.path().to_path_buf(),
+            b"",
+            true,
+            true,
+            PageOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            node.get("key1").unwrap().0,
+            Value::Inline(b"value1".to_vec())
+        );
+        assert!(matches!(node.get("key2"), Err(TrieError::NotFound)));
+        assert!(std::fs::metadata(&file_path).unwrap().len() < len as u64);
+    }
+
+    #[test]
+    fn test_blob_references_upgrade_page() {
+        let temp_dir = tempdir().unwrap();
+        write_page(temp_dir.path(), FAN_OUT_FORMAT_VERSION);
+        let blob = BlobRef {
+            id: 7,
+            length: MAX_INLINE_VALUE_LEN as u64 + 1,
+            checksum: 0xDEADBEEF,
+        };
+
+        let mut node = TreeNode::from(
+            temp_dir.path().to_path_buf(),
+            b"",
+            true,
+            true,
+            PageOptions::default(),
+        )
+        .unwrap();
+        node.insert("blob".to_string(), Value::Blob(blob), None, 0)
+            .unwrap();
+        assert_eq!(node.format_version, CURRENT_FORMAT_VERSION);
+
+        let mut node = TreeNode::from(
+            temp_dir.path().to_path_buf(),
+            b"",
+            true,
+            true,
+            PageOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(node.get("blob").unwrap().0, Value::Blob(blob));
+        assert_eq!(
+            node.get("key0").unwrap().0,
+            Value::Inline(b"value0".to_vec())
+        );
+        assert_eq!(node.blobs().unwrap(), vec![blob]);
+    }
+
+    #[test]
+    fn test_versions_are_stored_with_entries() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        write_page(&path, ENCRYPTION_FORMAT_VERSION);
+        let open = || TreeNode::from(path.clone(), b"", true, true, PageOptions::default());
+
+        // Entries of older pages have version 0
+        let mut node = open().unwrap();
+        assert_eq!(
+            node.get("key0").unwrap(),
+            (Value::Inline(b"value0".to_vec()), 0)
+        );
+        assert_eq!(node.format_version, ENCRYPTION_FORMAT_VERSION);
+
+        node.insert("key1".to_string(), Value::Inline(b"new".to_vec()), None, 5)
+            .unwrap();
+        assert_eq!(node.format_version, CURRENT_FORMAT_VERSION);
+        node.insert("key2".to_string(), Value::Inline(vec![]), Some(u64::MAX), 6)
+            .unwrap();
+
+        for compact in [false, true] {
+            let mut node = open().unwrap();
+            if compact {
+                node.compact().unwrap();
+            }
+            assert_eq!(node.get("key0").unwrap().1, 0);
+            assert_eq!(
+                node.get("key1").unwrap(),
+                (Value::Inline(b"new".to_vec()), 5)
+            );
+            assert_eq!(node.get("key2").unwrap(), (Value::Inline(vec![]), 6));
+        }
+    }
+
+    #[test]
+    fn test_expired_entries_are_hidden_and_reclaimed() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        write_page(&path, BLOB_FORMAT_VERSION);
+        let blob = BlobStore::open(&path, None)
+            .unwrap()
+            .write(b"expired")
+            .unwrap();
+        let open = || TreeNode::from(path.clone(), b"", true, true, PageOptions::default());
+
+        let mut node = open().unwrap();
+        let now = unix_time();
+        node.insert("expired".to_string(), Value::Blob(blob), Some(now - 1), 0)
+            .unwrap();
+        node.insert(
+            "key1".to_string(),
+            Value::Inline(b"later".to_vec()),
+            Some(now + 3600),
+            0,
+        )
+        .unwrap();
+        assert_eq!(node.format_version, CURRENT_FORMAT_VERSION);
+        assert!(matches!(node.get("expired"), Err(TrieError::NotFound)));
+
+        let range = node
+            .get_range(&"a".to_string(), &"z".to_string(), None)
+            .unwrap();
+        let keys: Vec<&str> = range.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys, vec!["key0", "key1", "key2"]);
+
+        // The expired entry is dropped when the page is loaded, and its record when the page
+        // is compacted
+        let mut node = open().unwrap();
+        assert_eq!(
+            node.get("key1").unwrap().0,
+            Value::Inline(b"later".to_vec())
+        );
+        assert!(!node.entries.as_ref().unwrap().contains_key("expired"));
+        assert_eq!(node.expired_blobs, vec![blob]);
+
+        let data_length = node.data_length;
+        node.compact().unwrap();
+        assert!(node.data_length < data_length);
+        assert!(node.expired_blobs.is_empty());
+        assert!(BlobStore::open(&path, None)
+            .unwrap()
+            .open_blob(&blob)
+            .is_err());
+    }
+
+    #[test]
+    fn test_cold_reads_use_sparse_index() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        let open = || TreeNode::from(path.clone(), b"", true, false, PageOptions::default());
+        let value = |i: usize| Value::Inline(format!("value{i:0>64}").into_bytes());
+
+        let mut node = TreeNode::create(path.clone(), b"", PageOptions::default()).unwrap();
+        for i in 0..1000 {
+            node.insert(format!("key{i:0>4}"), value(i), None, 0)
+                .unwrap();
+        }
+        node.compact().unwrap();
+        node.insert("key0500".to_string(), value(0), None, 0)
+            .unwrap();
+        node.delete("key0501".to_string()).unwrap();
+
+        let mut node = open().unwrap();
+        assert!(node.sorted_length > 0);
+        assert!(node.index.is_none());
+        assert_eq!(node.get("key0000").unwrap().0, value(0));
+        assert_eq!(node.get("key0999").unwrap().0, value(999));
+        assert_eq!(node.get("key0500").unwrap().0, value(0));
+        assert!(matches!(node.get("key0501"), Err(TrieError::NotFound)));
+        assert!(matches!(node.get("key1000"), Err(TrieError::NotFound)));
+        assert!(matches!(node.get("aaa"), Err(TrieError::NotFound)));
+        assert!(node.index.as_ref().unwrap().len() > 1);
+
+        let range = node
+            .get_range(&"key0499".to_string(), &"key0600".to_string(), Some(3))
+            .unwrap();
+        let keys: Vec<&str> = range.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys, vec!["key0499", "key0500", "key0502"]);
+        assert_eq!(range[1].1, value(0));
+
+        // Inserts on a cold page are visible to the following reads
+        node.insert("key0501".to_string(), value(1), None, 0)
+            .unwrap();
+        assert_eq!(node.get("key0501").unwrap().0, value(1));
+        assert!(!node.has_data());
+
+        // Pages read often are loaded
+        for _ in 0..HOT_PAGE_READS {
+            node.get("key0001").unwrap();
+        }
+        assert!(node.has_data());
+        assert_eq!(node.get("key0501").unwrap().0, value(1));
+    }
+
+    #[test]
+    fn test_memory_mapped_reads() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        let options = PageOptions {
+            memory_mapped_reads: true,
+            ..PageOptions::default()
+        };
+        let open = || TreeNode::from(path.clone(), b"", true, false, options.clone()).unwrap();
+        let value = |i: usize| Value::Inline(format!("value{i}").into_bytes());
+
+        let mut node = TreeNode::create(path.clone(), b"", options.clone()).unwrap();
+        for i in 0..1000 {
+            node.insert(format!("key{i:0>4}"), value(i), None, 0)
+                .unwrap();
+        }
+        node.compact().unwrap();
+        node.delete("key0501".to_string()).unwrap();
+
+        // Pages read often stay mapped instead of being loaded
+        let mut node = open();
+        for _ in 0..HOT_PAGE_READS * 2 {
+            assert_eq!(node.get("key0999").unwrap().0, value(999));
+        }
+        assert!(node.mapping.is_some());
+        assert!(!node.has_data());
+        assert!(options.stats.snapshot().mapped_reads >= HOT_PAGE_READS as u64 * 2);
+        assert!(matches!(node.get("key0501"), Err(TrieError::NotFound)));
+        assert!(matches!(node.get("key0999a"), Err(TrieError::NotFound)));
+
+        node.insert("key0500".to_string(), value(0), None, 0)
+            .unwrap();
+        let range = node
+            .get_range(&"key0499".to_string(), &"key0600".to_string(), Some(3))
+            .unwrap();
+        let keys: Vec<&str> = range.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys, vec!["key0499", "key0500", "key0502"]);
+        assert_eq!(range[1].1, value(0));
+
+        // The mapping of the replaced file is dropped when the page is rewritten
+        node.read_data().unwrap();
+        assert!(node.mapping.is_none());
+        node.compact().unwrap();
+        let mut node = open();
+        assert_eq!(node.get("key0500").unwrap().0, value(0));
+        assert!(node.mapping.is_some());
+
+        // Ranges past the end of the file are refused instead of raising SIGBUS when read
+        let file = options.storage.open(&node.file_path).unwrap();
+        let length = file.length().unwrap();
+        assert!(file.map(0, length as usize).unwrap().is_some());
+        let error = file.map(0, length as usize + 1).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+
+        // Storages that can't map their files fall back to reads
+        let segments_path = temp_dir.path().join("segments");
+        std::fs::create_dir(&segments_path).unwrap();
+        let options = PageOptions {
+            storage: Arc::new(SegmentStorage::open(&segments_path).unwrap()),
+            ..options.clone()
+        };
+        let mut node = TreeNode::create(segments_path.clone(), b"", options.clone()).unwrap();
+        for i in 0..1000 {
+            node.insert(format!("key{i:0>4}"), value(i), None, 0)
+                .unwrap();
+        }
+        node.compact().unwrap();
+        let mut node = TreeNode::from(segments_path, b"", true, false, options).unwrap();
+        assert_eq!(node.get("key0999").unwrap().0, value(999));
+        assert!(node.mapping.is_none());
+    }
+
+    #[test]
+    fn test_compressed_pages() {
+        let value = |i: usize| {
+            Value::Inline(format!(r#"{{"id": {i}, "name": "user", "active": true}}"#).into_bytes())
+        };
+
+        for compression in [Compression::Lz4, Compression::Zstd] {
+            let temp_dir = tempdir().unwrap();
+            let path = temp_dir.path().to_path_buf();
+            let options = |compression| PageOptions {
+                compression,
+                ..PageOptions::default()
+            };
+            let open = |compression| {
+                TreeNode::from(path.clone(), b"", true, false, options(compression)).unwrap()
+            };
+
+            let mut node = TreeNode::create(path.clone(), b"", options(compression)).unwrap();
+            for i in 0..2000 {
+                node.insert(format!("key{i:0>4}"), value(i), None, 0)
+                    .unwrap();
+            }
+            let data_length = node.data_length;
+            node.compact().unwrap();
+            assert_eq!(node.compression, compression);
+            assert!(node.data_length < data_length / 2, "{compression:?}");
+            assert!(!node.has_too_many_dead_bytes());
+
+            // Cold reads decompress the blocks they need, and records appended later are
+            // stored uncompressed
+            let mut node = open(Compression::None);
+            assert_eq!(node.compression, compression);
+            assert_eq!(node.get("key0000").unwrap().0, value(0));
+            assert_eq!(node.get("key1999").unwrap().0, value(1999));
+            assert!(matches!(node.get("key2000"), Err(TrieError::NotFound)));
+            node.insert("key0500".to_string(), value(0), None, 0)
+                .unwrap();
+            node.delete("key0501".to_string()).unwrap();
+
+            let range = node
+                .get_range(&"key0499".to_string(), &"key0600".to_string(), Some(3))
+                .unwrap();
+            let keys: Vec<&str> = range.iter().map(|(k, _)| k.as_str()).collect();
+            assert_eq!(keys, vec!["key0499", "key0500", "key0502"]);
+
+            let mut node = open(Compression::None);
+            node.read_data().unwrap();
+            assert_eq!(node.entries.as_ref().unwrap().len(), 1999);
+            assert_eq!(node.get("key0500").unwrap().0, value(0));
+
+            // The codec of the page follows the configuration once the page is rewritten
+            node.compact().unwrap();
+            assert_eq!(node.compression, Compression::None);
+            assert!(node.data_length > data_length / 2);
+
+            let mut node = open(Compression::None);
+            assert_eq!(node.compression, Compression::None);
+            assert_eq!(node.get("key1999").unwrap().0, value(1999));
+        }
+    }
+
+    #[test]
+    fn test_encrypted_pages() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        let keyring = |key: u8, previous: &[u8]| {
+            let source = |key: u8| {
+                let key_path = temp_dir.path().join(format!("{key}.key"));
+                std::fs::write(&key_path, format!("{key:02x}").repeat(32)).unwrap();
+                KeySource::File(key_path)
+            };
+            let previous: Vec<KeySource> = previous.iter().map(|k| source(*k)).collect();
+
+            Some(Arc::new(Keyring::load(&source(key), &previous).unwrap()))
+        };
+        let options = |encryption| PageOptions {
+            encryption,
+            ..PageOptions::default()
+        };
+        let open =
+            |encryption| TreeNode::from(path.clone(), b"user", true, false, options(encryption));
+        let value = |i: usize| Value::Inline(format!("value{i}").into_bytes());
+
+        let mut node = TreeNode::create(path.clone(), b"user", options(keyring(1, &[]))).unwrap();
+        for i in 0..1000 {
+            node.insert(format!("user{i:0>4}"), value(i), None, 0)
+                .unwrap();
+        }
+        node.compact().unwrap();
+        node.insert("user0500".to_string(), value(0), None, 0)
+            .unwrap();
+        node.delete("user0501".to_string()).unwrap();
+        assert!(!node.has_too_many_dead_bytes());
+
+        // Neither the prefix, the keys, the values nor the Bloom filter are stored in clear
+        let bytes = std::fs::read(&node.file_path).unwrap();
+        assert!(!bytes.windows(4).any(|w| w == b"user" || w == b"valu"));
+        let mut filter = BloomFilter::new(1000);
+        for i in 0..1000 {
+            filter.insert(format!("user{i:0>4}").as_bytes());
+        }
+        let stored = std::fs::read(TreeNode::bloom_filter_file_name(&node.file_path)).unwrap();
+        assert_eq!(stored.len(), filter.encode().len() + FRAME_OVERHEAD);
+        assert!(!stored.ends_with(&filter.encode()[100..]));
+
+        let mut node = open(keyring(1, &[])).unwrap();
+        assert_eq!(node.get("user0999").unwrap().0, value(999));
+        assert_eq!(node.get("user0500").unwrap().0, value(0));
+        assert!(matches!(node.get("user0501"), Err(TrieError::NotFound)));
+        assert!(matches!(node.get("user2000"), Err(TrieError::NotFound)));
+        assert!(node.bloom_filter.is_some());
+        let range = node
+            .get_range(&"user0499".to_string(), &"user0600".to_string(), Some(3))
+            .unwrap();
+        let keys: Vec<&str> = range.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(keys, vec!["user0499", "user0500", "user0502"]);
+
+        // A torn record is truncated like a plain one
+        let length = bytes.len();
+        std::fs::write(&node.file_path, &bytes[..length - 3]).unwrap();
+        let mut node = open(keyring(1, &[])).unwrap();
+        node.read_data().unwrap();
+        assert_eq!(node.get("user0501").unwrap().0, value(501));
+        assert_eq!(node.get("user0500").unwrap().0, value(0));
+
+        assert!(matches!(
+            open(None),
+            Err(TrieError::InvalidConfiguration(_))
+        ));
+        assert!(matches!(
+            open(keyring(2, &[])),
+            Err(TrieError::InvalidConfiguration(_))
+        ));
+
+        // Pages encrypted with a previous key stay readable until they are rotated
+        let mut node = open(keyring(2, &[1])).unwrap();
+        assert_eq!(node.get("user0999").unwrap().0, value(999));
+        assert!(node.rotate_key().unwrap());
+        assert!(!open(keyring(2, &[])).unwrap().rotate_key().unwrap());
+        let mut node = open(keyring(2, &[])).unwrap();
+        node.read_data().unwrap();
+        assert_eq!(node.entries.as_ref().unwrap().len(), 1000);
+
+        // Blocks are compressed before being encrypted
+        node.options.compression = Compression::Lz4;
+        node.compact().unwrap();
+        let mut node = open(keyring(2, &[])).unwrap();
+        assert_eq!(node.compression, Compression::Lz4);
+        assert_eq!(node.get("user0999").unwrap().0, value(999));
+        node.read_data().unwrap();
+        assert_eq!(node.entries.as_ref().unwrap().len(), 1000);
+
+        // Altered records are detected
+        let mut bytes = std::fs::read(&node.file_path).unwrap();
+        bytes[HEADER_LENGTH + 100] ^= 0xFF;
+        std::fs::write(&node.file_path, bytes).unwrap();
+        let mut node = open(keyring(2, &[])).unwrap();
+        assert!(matches!(node.read_data(), Err(TrieError::Corrupted(_))));
+
+        // Plain pages are encrypted before their next write
+        let open_plain =
+            |encryption| TreeNode::from(path.clone(), b"plain", true, false, options(encryption));
+        let mut node = TreeNode::create(path.clone(), b"plain", options(None)).unwrap();
+        node.insert("plain1".to_string(), value(1), None, 0)
+            .unwrap();
+        let mut node = open_plain(keyring(2, &[])).unwrap();
+        assert!(node.cipher.is_none());
+        node.insert("plain2".to_string(), value(2), None, 0)
+            .unwrap();
+        assert!(node.cipher.is_some());
+        let length = std::fs::read(&node.file_path).unwrap().len();
+        node.insert("plain3".to_string(), value(3), None, 0)
+            .unwrap();
+        node.insert("plain4".to_string(), value(4), None, 0)
+            .unwrap();
+        let bytes = std::fs::read(&node.file_path).unwrap();
+        assert!(!bytes.windows(5).any(|w| w == b"plain"));
+        assert_eq!(
+            open_plain(keyring(2, &[]))
+                .unwrap()
+                .get("plain1")
+                .unwrap()
+                .0,
+            value(1)
+        );
+
+        // Records are bound to their position
+        let record_length = (bytes.len() - length) / 2;
+        let mut swapped = bytes[..length].to_vec();
+        swapped.extend_from_slice(&bytes[length + record_length..]);
+        swapped.extend_from_slice(&bytes[length..length + record_length]);
+        std::fs::write(&node.file_path, swapped).unwrap();
+        let mut node = open_plain(keyring(2, &[])).unwrap();
+        assert!(matches!(node.read_data(), Err(TrieError::Corrupted(_))));
+
+        // Records of a previous version of the page can't be replayed once it is rewritten
+        std::fs::write(&node.file_path, &bytes).unwrap();
+        let mut node = open_plain(keyring(2, &[])).unwrap();
+        node.read_data().unwrap();
+        node.compact().unwrap();
+        node.delete("plain4".to_string()).unwrap();
+        let mut replayed = std::fs::read(&node.file_path).unwrap();
+        replayed.extend_from_slice(&bytes[length + record_length..]);
+        std::fs::write(&node.file_path, replayed).unwrap();
+        let mut node = open_plain(keyring(2, &[])).unwrap();
+        node.read_data().unwrap();
+        assert!(matches!(node.get("plain4"), Err(TrieError::NotFound)));
+        assert_eq!(node.get("plain3").unwrap().0, value(3));
+    }
+
+    #[test]
+    fn test_encrypted_intents() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        let key_path = path.join("key");
+        std::fs::write(&key_path, "01".repeat(32)).unwrap();
+        let keyring = Keyring::load(&KeySource::File(key_path), &[]).unwrap();
+        let storage = PageOptions::default().storage;
+        let intent_path = TreeNode::intent_file_name(&path, b"user", SPLIT_INTENT_EXTENSION);
+
+        TreeNode::write_intent(
+            storage.as_ref(),
+            Some(&keyring),
+            &path,
+            b"user",
+            &[b"users".to_vec()],
+            SPLIT_INTENT_EXTENSION,
+        )
+        .unwrap();
+        let bytes = std::fs::read(&intent_path).unwrap();
+        assert!(bytes.starts_with(INTENT_MAGIC));
+        assert!(!bytes.windows(4).any(|w| w == b"user"));
+
+        // The frame is bound to the name of the intent file
+        let moved_path = TreeNode::intent_file_name(&path, b"user", MERGE_INTENT_EXTENSION);
+        std::fs::write(&moved_path, &bytes).unwrap();
+        assert!(matches!(
+            TreeNode::recover_intent(storage.as_ref(), Some(&keyring), &path, &moved_path),
+            Err(TrieError::Corrupted(_))
+        ));
+
+        let prefix =
+            TreeNode::recover_intent(storage.as_ref(), Some(&keyring), &path, &intent_path)
+                .unwrap();
+        assert_eq!(prefix, b"user");
+        assert!(!intent_path.exists());
+
+        // Plain intents written before a key was configured are still completed
+        TreeNode::write_intent(
+            storage.as_ref(),
+            None,
+            &path,
+            b"user",
+            &[b"users".to_vec()],
+            SPLIT_INTENT_EXTENSION,
+        )
+        .unwrap();
+        let prefix =
+            TreeNode::recover_intent(storage.as_ref(), Some(&keyring), &path, &intent_path)
+                .unwrap();
+        assert_eq!(prefix, b"user");
+    }
+
+    #[test]
+    fn test_cold_deletes_dont_load_the_page() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        let options = PageOptions::default();
+        let open = || TreeNode::from(path.clone(), b"", true, false, options.clone());
+
+        let mut node = TreeNode::create(path.clone(), b"", options.clone()).unwrap();
+        for i in 0..100 {
+            node.insert(format!("key{i}"), Value::Inline(vec![b'v'; 30000]), None, 0)
+                .unwrap();
+        }
+        node.compact().unwrap();
+
+        let mut node = open().unwrap();
+        node.delete("missing".to_string()).unwrap();
+        node.delete("key50".to_string()).unwrap();
+        assert!(!node.has_data());
+        assert!(matches!(node.get("key50"), Err(TrieError::NotFound)));
+        assert!(node.get("key51").is_ok());
+
+        let mut node = open().unwrap();
+        assert!(matches!(node.get("key50"), Err(TrieError::NotFound)));
+        node.read_data().unwrap();
+        assert_eq!(node.entries.as_ref().unwrap().len(), 99);
+    }
+
+    #[test]
+    fn test_truncated_index() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        let options = PageOptions::default();
+
+        let mut node = TreeNode::create(path.clone(), b"", options.clone()).unwrap();
+        for i in 0..100 {
+            node.insert(format!("key{i}"), Value::Inline(vec![b'v'; 30000]), None, 0)
+                .unwrap();
+        }
+        node.compact().unwrap();
+        let file_path = node.file_path.clone();
+        let index_offset = node.data_offset() + node.sorted_length;
+
+        // Pages truncated before their index are refused when opened, so this one is truncated
+        // once its header has been read
+        let mut node = TreeNode::from(path, b"", true, false, options).unwrap();
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&file_path)
+            .unwrap();
+        file.set_len(index_offset as u64 + 10).unwrap();
+
+        // The page is loaded in full instead, which finds the truncated records
+        assert!(!node.use_index().unwrap());
+        assert!(node.get("key0").is_err());
+    }
+
+    #[test]
+    fn test_cold_overwrites_remove_blobs() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        let options = PageOptions::default();
+        let blobs = BlobStore::open(&path, None).unwrap();
+        let blob = blobs.write(b"large").unwrap();
+
+        let mut node = TreeNode::create(path.clone(), b"", options.clone()).unwrap();
+        for i in 0..100 {
+            node.insert(format!("key{i}"), Value::Inline(vec![b'v'; 30000]), None, 0)
+                .unwrap();
+        }
+        node.insert("blob".to_string(), Value::Blob(blob), None, 0)
+            .unwrap();
+        node.compact().unwrap();
+
+        let mut node = TreeNode::from(path.clone(), b"", true, false, options).unwrap();
+        node.insert(
+            "blob".to_string(),
+            Value::Inline(b"small".to_vec()),
+            None,
+            0,
+        )
+        .unwrap();
+        assert!(!node.has_data());
+        assert!(blobs.open_blob(&blob).is_err());
+        assert_eq!(
+            node.get("blob").unwrap().0,
+            Value::Inline(b"small".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_bloom_filter_skips_missing_keys() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        let options = PageOptions::default();
+        let open = || TreeNode::from(path.clone(), b"", true, false, options.clone());
+
+        let mut node = TreeNode::create(path.clone(), b"", options.clone()).unwrap();
+        for i in 0..1000 {
+            node.insert(format!("key{i}"), Value::Inline(b"value".to_vec()), None, 0)
+                .unwrap();
+        }
+        node.compact().unwrap();
+
+        let mut node = open().unwrap();
+        for i in 0..100 {
+            assert!(matches!(
+                node.get(&format!("missing{i}")),
+                Err(TrieError::NotFound)
+            ));
+        }
+        assert!(options.stats.snapshot().filtered_lookups > 90);
+        assert!(node.index_reads < 10);
+        assert!(!node.has_data());
+        assert!(node.get("key500").is_ok());
+
+        // A filter that doesn't match the page is ignored
+        let filter_path = TreeNode::bloom_filter_file_name(&node.file_path);
+        std::fs::write(&filter_path, BloomFilter::new(1).encode()).unwrap();
+        let mut node = open().unwrap();
+        assert!(node.get("key500").is_ok());
+        assert!(node.bloom_filter.is_none());
+    }
+
+    #[test]
+    fn test_legacy_pages_still_open() {
+        let temp_dir = tempdir().unwrap();
+        write_page(temp_dir.path(), LEGACY_FORMAT_VERSION);
+
+        let mut node = TreeNode::from(
+            temp_dir.path().to_path_buf(),
+            b"",
+            true,
+            true,
+            PageOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(node.format_version, LEGACY_FORMAT_VERSION);
+        for i in 0..3 {
+            assert_eq!(
+                node.get(&format!("key{i}")).unwrap().0,
+                Value::Inline(format!("value{i}").into_bytes())
+            );
+        }
+    }
+
+    #[test]
+    fn test_upgrade_legacy_pages() {
+        for format_version in [LEGACY_FORMAT_VERSION, CHECKSUM_FORMAT_VERSION] {
+            let temp_dir = tempdir().unwrap();
+            let file_path = write_page(temp_dir.path(), format_version);
+
+            let mut node = TreeNode::from(
+                temp_dir.path().to_path_buf(),
+                b"",
+                true,
+                false,
+                PageOptions::default(),
+            )
+            .unwrap();
+            assert!(node.upgrade().unwrap());
+            assert!(!node.upgrade().unwrap());
+            node.insert(
+                "key3".to_string(),
+                Value::Inline(b"value3".to_vec()),
+                None,
+                0,
+            )
+            .unwrap();
+
+            assert!(std::fs::read(&file_path).unwrap().starts_with(MAGIC));
+
+            let mut node = TreeNode::from(
+                temp_dir.path().to_path_buf(),
+                b"",
+                true,
+                true,
+                PageOptions::default(),
+            )
+            .unwrap();
+            assert_eq!(node.format_version, CURRENT_FORMAT_VERSION);
+            for i in 0..4 {
+                assert_eq!(
+                    node.get(&format!("key{i}")).unwrap().0,
+                    Value::Inline(format!("value{i}").into_bytes())
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_foreign_file_is_rejected() {
+        let temp_dir = tempdir().unwrap();
+        std::fs::write(temp_dir.path().join("_root.dat"), vec![0xAB; 4096]).unwrap();
+
+        let result = TreeNode::from(
+            temp_dir.path().to_path_buf(),
+            b"",
+            true,
+            false,
+            PageOptions::default(),
+        );
+        assert!(matches!(result, Err(TrieError::Corrupted(_))));
+    }
+
+    #[test]
+    fn test_upgrade_keeps_legacy_children() {
+        for format_version in [CHECKSUM_FORMAT_VERSION, HEADER_FORMAT_VERSION] {
+            let temp_dir = tempdir().unwrap();
+            write_page(temp_dir.path(), format_version);
+
+            let mut node = TreeNode::from(
+                temp_dir.path().to_path_buf(),
+                b"",
+                true,
+                true,
+                PageOptions::default(),
+            )
+            .unwrap();
+            node.is_leaf = Some(false);
+            node.register_child(b"k");
+            node.register_child(b"7");
+            node.save_metadata().unwrap();
+
+            let mut node = TreeNode::from(
+                temp_dir.path().to_path_buf(),
+                b"",
+                true,
+                false,
+                PageOptions::default(),
+            )
+            .unwrap();
+            assert_eq!(
+                node.get_children_prefixes(),
+                vec![b"7".to_vec(), b"k".to_vec()]
+            );
+            assert!(node.upgrade().unwrap());
+
+            node.register_child("é".as_bytes());
+            node.save_metadata().unwrap();
+
+            let node = TreeNode::from(
+                temp_dir.path().to_path_buf(),
+                b"",
+                true,
+                false,
+                PageOptions::default(),
+            )
+            .unwrap();
+            assert_eq!(
+                node.get_children_prefixes(),
+                vec![b"7".to_vec(), b"k".to_vec(), vec![0xC3]]
+            );
+        }
+    }
+
+    #[test]
+    fn test_file_names_are_escaped() {
+        let base_path = Path::new("data");
+
+        assert_eq!(
+            TreeNode::file_name(base_path, b"key01"),
+            base_path.join("key01.dat")
+        );
+        assert_eq!(
+            TreeNode::file_name(base_path, b"Key/:-"),
+            base_path.join("%4Bey%2F%3A%2D.dat")
+        );
+        assert_eq!(
+            TreeNode::file_name(base_path, "é".as_bytes()),
+            base_path.join("%C3%A9.dat")
+        );
+
+        // Long escaped prefixes are shortened, and stay distinct
+        let name = |prefix: &[u8]| {
+            TreeNode::file_name(base_path, prefix)
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .into_owned()
+        };
+        let long = [b'/'; MAX_KEY_LEN];
+        let mut other = long;
+        other[MAX_KEY_LEN - 1] = b'-';
+        assert!(name(&long).len() + ".bloom.tmp".len() <= MAX_FILE_NAME_LEN);
+        assert!(check_write(std::str::from_utf8(&long).unwrap(), None).is_ok());
+        assert_ne!(name(&long), name(&other));
+        assert!(name(&long).starts_with("%2F%2F"));
+    }
+
+    #[test]
+    fn test_long_escaped_keys() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path().to_path_buf();
+        let key = "/".repeat(MAX_KEY_LEN);
+
+        let mut node =
+            TreeNode::create(path.clone(), key.as_bytes(), PageOptions::default()).unwrap();
+        node.insert(key.clone(), Value::Inline(b"value".to_vec()), None, 0)
+            .unwrap();
+        node.compact().unwrap();
+
+        let mut node =
+            TreeNode::from(path, key.as_bytes(), true, false, PageOptions::default()).unwrap();
+        assert_eq!(node.get(&key).unwrap().0, Value::Inline(b"value".to_vec()));
+        assert!(matches!(
+            node.insert(String::new(), Value::Inline(vec![]), None, 0),
+            Err(TrieError::KeyError)
+        ));
+    }
+}
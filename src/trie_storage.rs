@@ -0,0 +1,602 @@
+use log::{debug, info};
+
+use crate::{
+    cache::Cache,
+    dirty_pages::Shutdown,
+    storage::{PageCheck, PendingSync, Recovery, Storage},
+    transaction::Version,
+    tree_node::{
+        self, FindRangeChildrenResult, PageOptions, SearchResult, TreeNode, TrieError, Value,
+    },
+};
+use std::{
+    collections::HashSet,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+/// Stores the entries in a trie of pages, each page holding the keys of a prefix
+pub struct TrieStorage {
+    metadata_cache: Cache<Vec<u8>, TreeNode>,
+    data_cache: Cache<Vec<u8>, TreeNode>,
+    root: TreeNode,
+    base_path: PathBuf,
+    options: PageOptions,
+    /// Pages written since the last flush
+    unsynced_pages: HashSet<Vec<u8>>,
+}
+
+impl TrieStorage {
+    /// Opens the pages of a data directory, creating the root page if needed
+    pub fn open(
+        base_path: PathBuf,
+        cache_size: usize,
+        options: PageOptions,
+    ) -> Result<TrieStorage, TrieError> {
+        let mut root = Self::read_root(&base_path, &options)?;
+
+        // Case-insensitive stores only hold lowercase keys, so they can be opened as
+        // case-sensitive. The other way around, keys differing by their case would collide
+        if root.is_case_sensitive() != options.case_sensitive {
+            if root.is_case_sensitive() {
+                return Err(TrieError::InvalidConfiguration(
+                    "the store was created with case-sensitive keys".to_string(),
+                ));
+            }
+
+            info!("Switching the store to case-sensitive keys");
+            if !root.upgrade()? {
+                root.save_metadata()?;
+            }
+        }
+
+        Ok(TrieStorage {
+            root,
+            data_cache: Cache::new(cache_size / tree_node::SPLIT_THRESHOLD),
+            metadata_cache: Cache::new(10000),
+            base_path,
+            options,
+            unsynced_pages: HashSet::new(),
+        })
+    }
+
+    fn read_root(base_path: &Path, options: &PageOptions) -> Result<TreeNode, TrieError> {
+        let root = match TreeNode::from(base_path.to_path_buf(), b"", true, true, options.clone()) {
+            Ok(r) => r,
+            Err(TrieError::IoError(e)) if e.kind() == ErrorKind::NotFound => {
+                TreeNode::create(base_path.to_path_buf(), b"", options.clone())?
+            }
+            Err(e) => return Err(e),
+        };
+
+        Ok(root)
+    }
+
+    /// Rewrites the pages that aren't encrypted with the current key. Returns the number of
+    /// pages rewritten
+    pub fn rotate_keys(&mut self) -> Result<usize, TrieError> {
+        // Cached nodes would keep a handle to the files replaced by the rotation
+        self.data_cache.clear();
+        self.metadata_cache.clear();
+
+        let mut rotated = 0;
+        if self.root.rotate_key()? {
+            rotated += 1;
+        }
+
+        let mut nodes = self.root.get_children_prefixes();
+        while let Some(node_prefix) = nodes.pop() {
+            let mut node = TreeNode::from(
+                self.base_path.clone(),
+                &node_prefix,
+                true,
+                false,
+                self.options.clone(),
+            )?;
+
+            if node.rotate_key()? {
+                debug!("Rotated: {}", String::from_utf8_lossy(&node_prefix));
+                rotated += 1;
+            }
+
+            nodes.append(&mut node.get_children_prefixes());
+        }
+
+        Ok(rotated)
+    }
+
+    /// Records that a page must be synced by the next flush
+    fn mark_unsynced(&mut self, prefix: Vec<u8>) {
+        if !self.options.sync_after_write() {
+            self.unsynced_pages.insert(prefix);
+        }
+    }
+
+    /// Merges the children of a node into it if they are all leaves and their entries fit in a
+    /// single page. Returns true if the children were merged
+    fn merge_children(&mut self, prefix: &[u8]) -> Result<bool, TrieError> {
+        let child_prefixes = self.on_owner(prefix, |n| {
+            Ok(if n.is_leaf() {
+                vec![]
+            } else {
+                n.get_children_prefixes()
+            })
+        })?;
+
+        if child_prefixes.is_empty() {
+            return Ok(false);
+        }
+
+        let mut live_length = 0;
+        for child_prefix in &child_prefixes {
+            let (is_leaf, child_live_length) =
+                self.on_owner(child_prefix, |n| Ok((n.is_leaf(), n.live_length()?)))?;
+
+            live_length += child_live_length;
+            if !is_leaf || live_length > tree_node::MERGE_THRESHOLD {
+                return Ok(false);
+            }
+        }
+
+        let mut children = vec![];
+        for child_prefix in &child_prefixes {
+            children.push(match self.data_cache.remove(child_prefix) {
+                Some(node) => node,
+                None => TreeNode::from(
+                    self.base_path.clone(),
+                    child_prefix,
+                    true,
+                    true,
+                    self.options.clone(),
+                )?,
+            });
+            self.metadata_cache.remove(child_prefix);
+        }
+
+        self.on_owner(prefix, |n| n.merge_children(children))?;
+        debug!(
+            "Merged the children of: {}",
+            String::from_utf8_lossy(prefix)
+        );
+
+        Ok(true)
+    }
+
+    /// Completes the splits and merges that were committed but not fully applied before a
+    /// crash. Operations interrupted before their commit are rolled back when the temporary
+    /// files are removed
+    fn recover_interrupted_operations(&mut self) -> Result<(), TrieError> {
+        let mut recovered = 0;
+
+        let storage = self.options.storage.clone();
+        for path in storage.list(&self.base_path)? {
+            if path.extension().is_some_and(|e| {
+                e == tree_node::SPLIT_INTENT_EXTENSION || e == tree_node::MERGE_INTENT_EXTENSION
+            }) {
+                let prefix = TreeNode::recover_intent(
+                    storage.as_ref(),
+                    self.options.encryption.as_deref(),
+                    &self.base_path,
+                    &path,
+                )?;
+                info!(
+                    "Completed interrupted operation on page {:?}: {path:?}",
+                    String::from_utf8_lossy(&prefix)
+                );
+                recovered += 1;
+            }
+        }
+
+        if recovered > 0 {
+            // The nodes loaded so far may point to replaced files
+            self.data_cache.clear();
+            self.metadata_cache.clear();
+            self.root = Self::read_root(&self.base_path, &self.options)?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the files left behind by page rewrites interrupted by a crash, including the
+    /// Bloom filters of pages that were never created
+    fn remove_temporary_files(&self) -> Result<(), std::io::Error> {
+        let storage = &self.options.storage;
+        for path in storage.list(&self.base_path)? {
+            if path.extension().is_some_and(|e| e == "tmp") {
+                info!("Removing incomplete page rewrite: {path:?}");
+                storage.remove(&path)?;
+            } else if path
+                .extension()
+                .is_some_and(|e| e == tree_node::BLOOM_FILTER_EXTENSION)
+                && !storage.exists(&path.with_extension("dat"))
+            {
+                info!("Removing orphan Bloom filter: {path:?}");
+                storage.remove(&path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Iterates over the tree structure to find the owning node, then executed an operation against it
+    /// Used by all other methods in this struct
+    fn on_owner<T, U: FnOnce(&mut TreeNode) -> Result<T, TrieError>>(
+        &mut self,
+        key: &[u8],
+        func: U,
+    ) -> Result<T, TrieError> {
+        let mut node = &mut self.root;
+        let mut traversed_nodes = vec![];
+        // Pages that registered a new child, which is lost if they aren't synced with it
+        let mut parents = vec![];
+        loop {
+            node = match node.find_owner(key) {
+                SearchResult::Current() => {
+                    break;
+                }
+                SearchResult::Child(prefix) => {
+                    if let Some(entry) = self
+                        .data_cache
+                        .remove(&prefix)
+                        .or(self.metadata_cache.remove(&prefix))
+                    {
+                        traversed_nodes.push(entry);
+                        traversed_nodes.last_mut().unwrap()
+                    } else {
+                        debug!("Cache miss: {}", String::from_utf8_lossy(&prefix));
+                        traversed_nodes.push(TreeNode::from(
+                            self.base_path.clone(),
+                            &prefix,
+                            true,
+                            false,
+                            self.options.clone(),
+                        )?);
+                        traversed_nodes.last_mut().unwrap()
+                    }
+                }
+                SearchResult::NonExistingChild(prefix) => {
+                    // Older page formats can only record children for `0-9a-z`
+                    node.upgrade()?;
+
+                    let n =
+                        TreeNode::create(self.base_path.clone(), &prefix, self.options.clone())?;
+                    node.register_child(&prefix);
+                    node.save_metadata()?;
+                    if self.options.sync_after_write() {
+                        node.sync()?;
+                    }
+                    parents.push(node.prefix().to_vec());
+
+                    traversed_nodes.push(n);
+                    traversed_nodes.last_mut().unwrap()
+                }
+            };
+        }
+
+        let r = func(node);
+
+        for node in traversed_nodes.into_iter() {
+            if node.has_data() && !node.prefix().is_empty() {
+                self.data_cache.set(node.prefix().to_vec(), node);
+            } else {
+                self.metadata_cache.set(node.prefix().to_vec(), node);
+            }
+        }
+        for prefix in parents {
+            self.mark_unsynced(prefix);
+        }
+
+        r
+    }
+}
+
+impl Storage for TrieStorage {
+    fn get(&mut self, key: &str) -> Result<(Value, Version), TrieError> {
+        self.on_owner(key.as_bytes(), |n| n.get(key))
+    }
+
+    fn put(
+        &mut self,
+        key: String,
+        value: Value,
+        expires_at: Option<u64>,
+        version: Version,
+    ) -> Result<(), TrieError> {
+        let prefix = self.on_owner(&key.clone().into_bytes(), |n| {
+            n.insert(key, value, expires_at, version)?;
+            Ok(n.prefix().to_vec())
+        })?;
+        self.mark_unsynced(prefix);
+
+        Ok(())
+    }
+
+    /// Removes an entry, then merges the pages that became too small back into their parent
+    fn delete(&mut self, key: String) -> Result<(), TrieError> {
+        let (mut prefix, is_underfilled) = self.on_owner(&key.clone().into_bytes(), |n| {
+            n.delete(key)?;
+            Ok((n.prefix().to_vec(), n.is_underfilled()))
+        })?;
+        self.mark_unsynced(prefix.clone());
+
+        if is_underfilled {
+            while prefix.pop().is_some() {
+                if !self.merge_children(&prefix)? {
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn range(
+        &mut self,
+        start_key: &str,
+        end_key: &str,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, Value)>, TrieError> {
+        if start_key > end_key {
+            return Ok(vec![]);
+        }
+
+        let start_key = &start_key.to_string();
+        let end_key = &end_key.to_string();
+
+        let FindRangeChildrenResult {
+            values: mut result,
+            child_prefixes: mut nodes,
+        } = self.root.find_range_children(start_key, end_key, limit)?;
+
+        nodes.reverse();
+
+        while !nodes.is_empty() && result.len() < limit.unwrap_or(usize::MAX) {
+            let limit = limit.map(|l| l - result.len());
+            let node_prefix = nodes.pop().unwrap();
+            let mut r = self.on_owner(&node_prefix, |n| {
+                n.find_range_children(start_key, end_key, limit)
+            })?;
+
+            r.child_prefixes.reverse();
+
+            result.append(&mut r.values);
+            nodes.append(&mut r.child_prefixes);
+        }
+
+        Ok(result)
+    }
+
+    /// Syncs the pages written since the last flush
+    fn flush(&mut self) -> Result<(), TrieError> {
+        self.pending_sync()?.sync()
+    }
+
+    /// Opens the pages written since the last flush. Pages merged in the meantime are gone,
+    /// their entries were synced with their parent
+    fn pending_sync(&mut self) -> Result<PendingSync, TrieError> {
+        let storage = &self.options.storage;
+        let mut files = vec![];
+        for prefix in std::mem::take(&mut self.unsynced_pages) {
+            match storage.open(&TreeNode::file_name(&self.base_path, &prefix)) {
+                Ok(file) => files.push(file),
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(PendingSync::new(
+            files,
+            storage.clone(),
+            self.base_path.clone(),
+        ))
+    }
+
+    /// Syncs the files of the cached pages as well, then releases them
+    fn close(&mut self) -> Result<(), TrieError> {
+        self.root.sync()?;
+        for node in self.data_cache.values().chain(self.metadata_cache.values()) {
+            node.sync()?;
+        }
+
+        self.flush()?;
+        self.data_cache.clear();
+        self.metadata_cache.clear();
+
+        Ok(())
+    }
+
+    /// Opens all the pages, completing the operations interrupted by a crash and upgrading the
+    /// pages written by older builds
+    fn sanity_check(&mut self) -> Result<HashSet<u64>, TrieError> {
+        self.recover_interrupted_operations()?;
+        self.remove_temporary_files()?;
+
+        let mut upgraded = 0;
+        if self.root.upgrade()? {
+            upgraded += 1;
+        }
+
+        let mut referenced_blobs: HashSet<u64> = self.root.blobs()?.iter().map(|b| b.id).collect();
+
+        let mut nodes = self.root.get_children_prefixes();
+
+        while let Some(node_prefix) = nodes.pop() {
+            debug!("Checking: {}", String::from_utf8_lossy(&node_prefix));
+
+            // Cached nodes would keep a handle to the file replaced by the upgrade
+            self.data_cache.remove(&node_prefix);
+            self.metadata_cache.remove(&node_prefix);
+
+            let mut node = TreeNode::from(
+                self.base_path.clone(),
+                &node_prefix,
+                true,
+                true,
+                self.options.clone(),
+            )?;
+
+            if node.upgrade()? {
+                upgraded += 1;
+            }
+
+            referenced_blobs.extend(node.blobs()?.iter().map(|b| b.id));
+            nodes.append(&mut node.get_children_prefixes());
+        }
+
+        if upgraded > 0 {
+            info!("Upgraded {upgraded} pages to the current format");
+        }
+
+        Ok(referenced_blobs)
+    }
+
+    /// Only checks the pages written since the last clean shutdown, the root page being
+    /// checked when the store is opened. The whole store is checked when these pages are unknown
+    fn recover(&mut self) -> Result<Recovery, TrieError> {
+        let Some(dirty_pages) = self.options.dirty_pages.clone() else {
+            return Ok(Recovery {
+                shutdown: Shutdown::Unknown,
+                checked_pages: 0,
+                applied_writes: 0,
+                quarantined_writes: 0,
+                referenced_blobs: Some(self.sanity_check()?),
+            });
+        };
+
+        let Some(pages) = dirty_pages.pages_to_check() else {
+            let referenced_blobs = self.sanity_check()?;
+            dirty_pages.start()?;
+
+            return Ok(Recovery {
+                shutdown: dirty_pages.shutdown(),
+                checked_pages: 0,
+                applied_writes: 0,
+                quarantined_writes: 0,
+                referenced_blobs: Some(referenced_blobs),
+            });
+        };
+
+        self.recover_interrupted_operations()?;
+        self.remove_temporary_files()?;
+
+        // Loading the data of a page truncates the records torn by the crash
+        let mut checked_pages = 0;
+        for prefix in pages.iter().filter(|p| !p.is_empty()) {
+            debug!("Checking: {}", String::from_utf8_lossy(prefix));
+
+            match TreeNode::from(
+                self.base_path.clone(),
+                prefix,
+                true,
+                true,
+                self.options.clone(),
+            ) {
+                Ok(_) => checked_pages += 1,
+                // The page was merged into its parent since it was written
+                Err(TrieError::IoError(e)) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(Recovery {
+            shutdown: dirty_pages.shutdown(),
+            checked_pages,
+            applied_writes: 0,
+            quarantined_writes: 0,
+            referenced_blobs: None,
+        })
+    }
+
+    /// Reads the page from its file, ignoring the cached nodes: writes are saved to the page
+    /// files right away
+    fn verify_page(&self, prefix: &[u8]) -> Result<Option<PageCheck>, TrieError> {
+        let scan = TreeNode::from(
+            self.base_path.clone(),
+            prefix,
+            true,
+            false,
+            self.options.clone(),
+        )
+        .and_then(|mut node| Ok((node.scan()?, node)));
+
+        let (scan, node) = match scan {
+            Ok(r) => r,
+            Err(TrieError::IoError(e)) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(TrieError::Corrupted(reason)) => {
+                return Ok(Some(PageCheck {
+                    problems: vec![reason],
+                    ..PageCheck::default()
+                }))
+            }
+            Err(e) => return Err(e),
+        };
+
+        let mut check = PageCheck {
+            problems: scan.problems,
+            ..PageCheck::default()
+        };
+
+        for key in scan.misplaced_keys {
+            check
+                .problems
+                .push(format!("key {key:?} is outside of the page prefix"));
+        }
+
+        for (value, _, _) in scan.entries.values() {
+            if let Value::Blob(blob) = value {
+                check.blobs.push(blob.id);
+            }
+        }
+
+        let children = node.get_children_prefixes();
+        if node.is_leaf() && !children.is_empty() {
+            check.problems.push("leaf page with children".to_string());
+        }
+
+        for child in children {
+            if self
+                .options
+                .storage
+                .exists(&TreeNode::file_name(&self.base_path, &child))
+            {
+                check.children.push(child);
+            } else {
+                check.problems.push(format!(
+                    "child {:?} has no page file",
+                    String::from_utf8_lossy(&child)
+                ));
+            }
+        }
+
+        Ok(Some(check))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_parents_of_new_pages_are_synced() {
+        let temp_dir = tempdir().unwrap();
+        let mut storage =
+            TrieStorage::open(temp_dir.path().to_path_buf(), 10, PageOptions::default()).unwrap();
+
+        let mut count = 0;
+        while storage.root.is_leaf() {
+            let value = Value::Inline(vec![b'v'; 4096]);
+            storage
+                .put(format!("key{count:0>8}"), value, None, 1)
+                .unwrap();
+            count += 1;
+        }
+        storage.flush().unwrap();
+
+        // The root records its new child in place: it must be synced with it
+        let value = Value::Inline(b"value".to_vec());
+        storage.put("zz".to_string(), value, None, 1).unwrap();
+        assert!(storage.unsynced_pages.contains(b"z".as_slice()));
+        assert!(storage.unsynced_pages.contains(b"".as_slice()));
+    }
+}
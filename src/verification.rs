@@ -0,0 +1,268 @@
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock, RwLockReadGuard,
+    },
+    thread,
+};
+
+use log::{error, info, warn};
+use serde::Serialize;
+
+use crate::{
+    dirty_pages::Shutdown, node_reader::NodeReader, stats::StatsSnapshot, storage::Recovery,
+    tree_node::TrieError,
+};
+
+/// Verification of every page of the store, run in the background once the store serves
+/// requests
+pub struct Verification {
+    status: Mutex<VerificationStatus>,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum VerificationState {
+    Running,
+    Completed,
+    Failed,
+}
+
+/// Progress of the startup of the store, returned by `GET /status`
+#[derive(Serialize, Clone)]
+pub struct VerificationStatus {
+    /// How the store was stopped before this startup
+    pub shutdown: Shutdown,
+    /// Number of pages written before a crash, checked before serving requests
+    pub recovered_pages: usize,
+    /// Number of writes of the WAL applied again before serving requests
+    pub applied_writes: usize,
+    /// Number of writes of the WAL that failed to be applied again
+    pub quarantined_writes: usize,
+    pub verification: VerificationState,
+    pub verified_pages: usize,
+    /// Damage found in the pages. The store keeps serving requests: `kvs-fsck` tells which
+    /// entries are affected once the service is stopped
+    pub problems: Vec<String>,
+    pub removed_blobs: usize,
+}
+
+impl Verification {
+    /// Verifies the pages in the background, with one thread per core. Pages are read while
+    /// holding a read lock of the store: they are never read while being written, but they can
+    /// be read by several threads at once. The blobs that no page references are removed once
+    /// every page has been read
+    pub fn start(store: Arc<RwLock<NodeReader>>, recovery: &Recovery) -> Arc<Verification> {
+        let verification = Arc::new(Verification {
+            status: Mutex::new(VerificationStatus {
+                shutdown: recovery.shutdown,
+                recovered_pages: recovery.checked_pages,
+                applied_writes: recovery.applied_writes,
+                quarantined_writes: recovery.quarantined_writes,
+                verification: VerificationState::Running,
+                verified_pages: 0,
+                problems: vec![],
+                removed_blobs: 0,
+            }),
+        });
+
+        let background = verification.clone();
+        thread::spawn(move || {
+            let result = background.run(&store);
+
+            let mut status = background.status.lock().unwrap();
+            match result {
+                Ok(removed_blobs) => {
+                    info!(
+                        "Verified {} pages: {} problems found",
+                        status.verified_pages,
+                        status.problems.len()
+                    );
+                    status.verification = VerificationState::Completed;
+                    status.removed_blobs = removed_blobs;
+                }
+                Err(e) => {
+                    error!("Failed to verify the pages: {e:?}");
+                    status.verification = VerificationState::Failed;
+                    status.problems.push(format!("{e:?}"));
+                }
+            }
+        });
+
+        verification
+    }
+
+    pub fn status(&self) -> VerificationStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Verifies the pages level by level, from the root. Returns the number of blobs removed
+    fn run(&self, store: &RwLock<NodeReader>) -> Result<usize, TrieError> {
+        let (blob_store, stats) = {
+            let store = read(store)?;
+            (store.blob_store(), store.stats())
+        };
+        // Blobs created from now on may belong to uploads that aren't referenced by a page yet
+        let below_id = blob_store.next_id();
+
+        let threads = thread::available_parallelism().map_or(1, |n| n.get());
+        let referenced_blobs = Mutex::new(HashSet::new());
+        let mut level = vec![vec![]];
+
+        while !level.is_empty() {
+            let next_level = Mutex::new(vec![]);
+            let position = AtomicUsize::new(0);
+
+            thread::scope(|scope| {
+                let workers: Vec<_> = (0..threads.min(level.len()))
+                    .map(|_| {
+                        scope.spawn(|| -> Result<(), TrieError> {
+                            while let Some(prefix) =
+                                level.get(position.fetch_add(1, Ordering::SeqCst))
+                            {
+                                let Some(check) = read(store)?.verify_page(prefix)? else {
+                                    continue;
+                                };
+
+                                let mut status = self.status.lock().unwrap();
+                                status.verified_pages += 1;
+                                for problem in check.problems {
+                                    let problem = format!(
+                                        "page {:?}: {problem}",
+                                        String::from_utf8_lossy(prefix)
+                                    );
+                                    warn!("{problem}");
+                                    status.problems.push(problem);
+                                }
+                                drop(status);
+
+                                referenced_blobs.lock().unwrap().extend(check.blobs);
+                                next_level.lock().unwrap().extend(check.children);
+                            }
+
+                            Ok(())
+                        })
+                    })
+                    .collect();
+
+                workers
+                    .into_iter()
+                    .try_for_each(|worker| worker.join().unwrap())
+            })?;
+
+            level = next_level.into_inner().unwrap();
+        }
+
+        // Entries moved to a page that was already verified, or away from a page that wasn't
+        // verified yet, may have been missed along with their blobs
+        let restructured = |s: StatsSnapshot| s.splits + s.merges;
+        if !read(store)?.owns_blobs() {
+            return Ok(0);
+        }
+        if restructured(read(store)?.stats()) != restructured(stats) {
+            info!(
+                "Pages were split or merged during the verification: unreferenced blobs are \
+                 removed by the next one"
+            );
+            return Ok(0);
+        }
+
+        let removed =
+            blob_store.remove_unreferenced(&referenced_blobs.into_inner().unwrap(), below_id)?;
+        if removed > 0 {
+            info!("Removed {removed} unreferenced blobs");
+        }
+
+        Ok(removed)
+    }
+}
+
+fn read(store: &RwLock<NodeReader>) -> Result<RwLockReadGuard<'_, NodeReader>, TrieError> {
+    store
+        .read()
+        .map_err(|_| TrieError::IoError(std::io::Error::other("the store lock is poisoned")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob_store::BlobStore;
+    use crate::dirty_pages::DirtyPages;
+    use crate::tree_node::{PageOptions, MAX_INLINE_VALUE_LEN};
+    use std::path::Path;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    fn open(path: &Path) -> (NodeReader, Recovery) {
+        let options = PageOptions {
+            dirty_pages: Some(Arc::new(DirtyPages::open(path).unwrap())),
+            ..PageOptions::default()
+        };
+        let mut reader = NodeReader::new(path.to_path_buf(), 1024 * 1024, None, options).unwrap();
+        let recovery = reader.recover().unwrap();
+
+        (reader, recovery)
+    }
+
+    fn wait(verification: &Verification) -> VerificationStatus {
+        loop {
+            let status = verification.status();
+            if status.verification != VerificationState::Running {
+                return status;
+            }
+
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_pages_are_verified_in_the_background() {
+        let temp_dir = tempdir().unwrap();
+        let path = temp_dir.path();
+
+        let (mut reader, recovery) = open(path);
+        assert_eq!(recovery.shutdown, Shutdown::Unknown);
+        assert!(recovery.referenced_blobs.is_some());
+        reader
+            .insert("blob".to_string(), vec![1; MAX_INLINE_VALUE_LEN + 1])
+            .unwrap();
+        reader.insert("key".to_string(), b"value".to_vec()).unwrap();
+        reader.close().unwrap();
+        drop(reader);
+
+        let unreferenced = BlobStore::open(path, None)
+            .unwrap()
+            .write(b"upload")
+            .unwrap();
+
+        let (reader, recovery) = open(path);
+        assert_eq!(recovery.shutdown, Shutdown::Clean);
+        assert!(recovery.referenced_blobs.is_none());
+
+        let store = Arc::new(RwLock::new(reader));
+        let status = wait(&Verification::start(store.clone(), &recovery));
+        assert_eq!(status.verification, VerificationState::Completed);
+        assert_eq!(status.verified_pages, 1);
+        assert!(status.problems.is_empty(), "{:?}", status.problems);
+        assert_eq!(status.removed_blobs, 1);
+
+        let mut reader = store.write().unwrap();
+        assert!(reader.open("blob").is_ok());
+        assert!(BlobStore::open(path, None)
+            .unwrap()
+            .read(&unreferenced)
+            .is_err());
+
+        // Crash: the pages written since the start are checked by the next one
+        reader
+            .insert("other".to_string(), b"value".to_vec())
+            .unwrap();
+        drop(reader);
+        drop(store);
+
+        let (mut reader, recovery) = open(path);
+        assert_eq!(recovery.shutdown, Shutdown::Crash);
+        assert_eq!(reader.get("other").unwrap(), b"value");
+    }
+}
@@ -0,0 +1,556 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use log::warn;
+
+use crate::{
+    blob_store::{BlobRef, BLOB_REF_LENGTH},
+    encryption::{Cipher, Keyring, OpenResult, KEY_CHECK_LENGTH},
+    transaction::Version,
+    tree_node::{TrieError, Value},
+};
+
+/// Log of the writes applied to the pages since the last checkpoint
+pub const WAL_FILE: &str = "wal";
+/// The pages are synced and the log emptied once it reaches this length
+pub const WAL_CHECKPOINT_LENGTH: u64 = 64 * 1024 * 1024;
+
+const WAL_MAGIC: &[u8; 4] = b"KWAL";
+const WAL_VERSION: u16 = 3;
+/// Magic, version, sequence number of the checkpoint, key check, checksum
+const HEADER_LENGTH: usize = 4 + 2 + 8 + KEY_CHECK_LENGTH + 4;
+/// Length of the record after its checksums, checksum of the length, then checksum of the
+/// record. Records hold the sequence number of their first write then their operations: a batch
+/// of writes is a single record, so a crash keeps all of it or none of it
+const RECORD_HEADER_LENGTH: usize = 4 + 4 + 4;
+
+const PUT_OPERATION: u8 = 0;
+const DELETE_OPERATION: u8 = 1;
+const INLINE_VALUE: u8 = 0;
+const BLOB_VALUE: u8 = 1;
+
+/// A write of the store. Keys are already normalized and values already validated
+#[derive(Clone, PartialEq, Debug)]
+pub enum WalOperation {
+    Put {
+        key: String,
+        value: Value,
+        expires_at: Option<u64>,
+        /// Version of the entry once written
+        version: Version,
+    },
+    Delete {
+        key: String,
+    },
+}
+
+/// Write-ahead log shared by all the pages: every write is appended to it before it is applied
+/// to the pages, which are then synced by checkpoints only. Writes are numbered by a sequence
+/// that keeps increasing across checkpoints and restarts
+pub struct Wal {
+    path: PathBuf,
+    file: File,
+    keyring: Option<Arc<Keyring>>,
+    /// Key of the records, the one of the header
+    cipher: Option<Arc<Cipher>>,
+    /// Sequence number of the last write
+    sequence: u64,
+    length: u64,
+}
+
+struct Header {
+    sequence: u64,
+    key_check: [u8; KEY_CHECK_LENGTH],
+}
+
+impl Wal {
+    /// Opens the log of a data directory, dropping a record torn by a crash. Returns the
+    /// writes that may not have reached the pages, to be applied again in order
+    pub fn open(
+        base_path: &Path,
+        keyring: Option<Arc<Keyring>>,
+    ) -> Result<(Wal, Vec<WalOperation>), TrieError> {
+        let path = base_path.join(WAL_FILE);
+
+        let Some((header, operations, length)) = read(&path, keyring.as_deref())? else {
+            let cipher = keyring.as_ref().map(|k| k.current().clone());
+            let wal = Wal {
+                file: create(&path, 0, cipher.as_deref())?,
+                path,
+                keyring,
+                cipher,
+                sequence: 0,
+                length: HEADER_LENGTH as u64,
+            };
+
+            return Ok((wal, vec![]));
+        };
+
+        let file = OpenOptions::new().append(true).open(&path)?;
+        if file.metadata()?.len() > length {
+            warn!("Dropped the last record of the WAL, torn by a crash");
+            file.set_len(length)?;
+            file.sync_all()?;
+        }
+
+        // An empty log is written again with the current key
+        let cipher = match operations.is_empty() {
+            true => None,
+            false => find_cipher(keyring.as_deref(), &header.key_check)?,
+        };
+        let mut wal = Wal {
+            path,
+            file,
+            cipher,
+            keyring,
+            sequence: header.sequence + operations.len() as u64,
+            length,
+        };
+        if operations.is_empty() {
+            wal.reset()?;
+        }
+
+        Ok((wal, operations))
+    }
+
+    /// Returns the writes of a stopped store that may not have reached the pages, without
+    /// modifying the log
+    pub fn unapplied(
+        base_path: &Path,
+        keyring: Option<&Keyring>,
+    ) -> Result<Vec<WalOperation>, TrieError> {
+        Ok(read(&base_path.join(WAL_FILE), keyring)?
+            .map(|(_, operations, _)| operations)
+            .unwrap_or_default())
+    }
+
+    /// Appends a batch of writes as a single record. Returns the sequence number of the last
+    /// one. The writes are only durable once the log is synced
+    pub fn append(&mut self, operations: &[WalOperation]) -> Result<u64, TrieError> {
+        let record = self.encode_record(self.sequence + 1, operations);
+
+        if let Err(e) = self.file.write_all(&record) {
+            // A partial record would hide the records appended after it
+            self.file.set_len(self.length)?;
+            return Err(e.into());
+        }
+        self.sequence += operations.len() as u64;
+        self.length += record.len() as u64;
+
+        Ok(self.sequence)
+    }
+
+    /// Copies the log next to it, e.g. to keep writes that can't be applied before the log is
+    /// emptied. Returns the path of the copy
+    pub fn quarantine(&self) -> Result<PathBuf, TrieError> {
+        let path = self
+            .path
+            .with_extension(format!("{}.quarantined", self.sequence));
+        fs::copy(&self.path, &path)?;
+        File::open(&path)?.sync_all()?;
+        if let Some(directory) = path.parent() {
+            File::open(directory)?.sync_all()?;
+        }
+
+        Ok(path)
+    }
+
+    pub fn sync(&self) -> Result<(), std::io::Error> {
+        self.file.sync_data()
+    }
+
+    /// Returns a handle to the log, to sync it without holding the store
+    pub fn handle(&self) -> Result<File, std::io::Error> {
+        self.file.try_clone()
+    }
+
+    /// Sequence number of the last write
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    /// Empties the log once the pages holding its writes are synced. The records written from
+    /// now on are encrypted with the current key
+    pub fn reset(&mut self) -> Result<(), TrieError> {
+        self.cipher = self.keyring.as_ref().map(|k| k.current().clone());
+        self.file = create(&self.path, self.sequence, self.cipher.as_deref())?;
+        self.length = HEADER_LENGTH as u64;
+
+        Ok(())
+    }
+
+    fn encode_record(&self, sequence: u64, operations: &[WalOperation]) -> Vec<u8> {
+        let mut payload = vec![];
+        for operation in operations {
+            encode_operation(operation, &mut payload);
+        }
+
+        if let Some(cipher) = &self.cipher {
+            payload = cipher.seal(&payload, &sequence.to_le_bytes());
+        }
+
+        let mut record = Vec::with_capacity(8 + payload.len());
+        record.extend_from_slice(&sequence.to_le_bytes());
+        record.extend_from_slice(&payload);
+
+        let length = (record.len() as u32).to_le_bytes();
+        let mut buffer = Vec::with_capacity(RECORD_HEADER_LENGTH + record.len());
+        buffer.extend_from_slice(&length);
+        buffer.extend_from_slice(&crc32fast::hash(&length).to_le_bytes());
+        buffer.extend_from_slice(&crc32fast::hash(&record).to_le_bytes());
+        buffer.extend_from_slice(&record);
+
+        buffer
+    }
+}
+
+fn encode_operation(operation: &WalOperation, payload: &mut Vec<u8>) {
+    match operation {
+        WalOperation::Put {
+            key,
+            value,
+            expires_at,
+            version,
+        } => {
+            payload.push(PUT_OPERATION);
+            encode_key(key, payload);
+            payload.extend_from_slice(&version.to_le_bytes());
+            match expires_at {
+                Some(expires_at) => {
+                    payload.push(1);
+                    payload.extend_from_slice(&expires_at.to_le_bytes());
+                }
+                None => payload.push(0),
+            }
+            match value {
+                Value::Inline(value) => {
+                    payload.push(INLINE_VALUE);
+                    payload.extend_from_slice(&(value.len() as u32).to_le_bytes());
+                    payload.extend_from_slice(value);
+                }
+                Value::Blob(blob) => {
+                    payload.push(BLOB_VALUE);
+                    payload.extend_from_slice(&blob.encode());
+                }
+            }
+        }
+        WalOperation::Delete { key } => {
+            payload.push(DELETE_OPERATION);
+            encode_key(key, payload);
+        }
+    }
+}
+
+/// Writes an empty log, starting after the given sequence number. The log is written next to
+/// the current one then renamed over it, so a crash keeps one of them whole. Returns a handle
+/// appending to it
+fn create(path: &Path, sequence: u64, cipher: Option<&Cipher>) -> Result<File, TrieError> {
+    let mut header = Vec::with_capacity(HEADER_LENGTH);
+    header.extend_from_slice(WAL_MAGIC);
+    header.extend_from_slice(&WAL_VERSION.to_le_bytes());
+    header.extend_from_slice(&sequence.to_le_bytes());
+    header.extend_from_slice(&cipher.map_or([0; KEY_CHECK_LENGTH], |c| c.key_check()));
+    header.extend_from_slice(&crc32fast::hash(&header).to_le_bytes());
+
+    let temporary_path = path.with_extension("tmp");
+    let mut file = File::create(&temporary_path)?;
+    file.write_all(&header)?;
+    file.sync_all()?;
+    fs::rename(&temporary_path, path)?;
+    if let Some(directory) = path.parent() {
+        File::open(directory)?.sync_all()?;
+    }
+
+    Ok(OpenOptions::new().append(true).open(path)?)
+}
+
+/// Reads a log. Returns its header, its records and the length of the file up to a last record
+/// torn by a crash, or `None` if there is no log
+#[allow(clippy::type_complexity)]
+fn read(
+    path: &Path,
+    keyring: Option<&Keyring>,
+) -> Result<Option<(Header, Vec<WalOperation>, u64)>, TrieError> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let header = decode_header(&data)?;
+    // The key is only needed by the records: the key of an empty log may have been rotated
+    let mut cipher = None;
+
+    let mut operations = vec![];
+    let mut position = HEADER_LENGTH;
+    while let Some(record_header) = data.get(position..position + RECORD_HEADER_LENGTH) {
+        // Only the last record can be torn: anything else means the log is damaged
+        let torn = || data[position..].iter().all(|b| *b == 0);
+        let corrupted =
+            || TrieError::Corrupted(format!("the WAL is corrupted at offset {position}"));
+
+        let length_checksum = u32::from_le_bytes(record_header[4..8].try_into().unwrap());
+        if crc32fast::hash(&record_header[..4]) != length_checksum {
+            if torn() {
+                break;
+            }
+            return Err(corrupted());
+        }
+        let length = u32::from_le_bytes(record_header[..4].try_into().unwrap()) as usize;
+        let checksum = u32::from_le_bytes(record_header[8..].try_into().unwrap());
+        let start = position + RECORD_HEADER_LENGTH;
+        let Some(record) = data.get(start..start + length) else {
+            // The length is checked, so the record really runs past the end of the log
+            break;
+        };
+        if length < 8 || crc32fast::hash(record) != checksum {
+            if start + length == data.len() || torn() {
+                break;
+            }
+            return Err(corrupted());
+        }
+
+        let (sequence, payload) = record.split_at(8);
+        let sequence = u64::from_le_bytes(sequence.try_into().unwrap());
+        if sequence != header.sequence + operations.len() as u64 + 1 {
+            return Err(TrieError::Corrupted(format!(
+                "WAL record {sequence} is out of sequence"
+            )));
+        }
+
+        if operations.is_empty() {
+            cipher = find_cipher(keyring, &header.key_check)?;
+        }
+
+        let payload = match &cipher {
+            Some(cipher) => match cipher.open(payload, &sequence.to_le_bytes()) {
+                OpenResult::Opened(payload, _) => payload,
+                _ => {
+                    return Err(TrieError::Corrupted(format!(
+                        "WAL record {sequence} can't be decrypted"
+                    )))
+                }
+            },
+            None => payload.to_vec(),
+        };
+
+        let mut reader = payload.as_slice();
+        while !reader.is_empty() {
+            operations.push(decode_operation(&mut reader).ok_or_else(|| {
+                TrieError::Corrupted(format!("WAL record {sequence} is invalid"))
+            })?);
+        }
+        position += RECORD_HEADER_LENGTH + length;
+    }
+
+    Ok(Some((header, operations, position as u64)))
+}
+
+fn decode_header(data: &[u8]) -> Result<Header, TrieError> {
+    let invalid = || TrieError::Corrupted("invalid WAL header".to_string());
+    let header = data.get(..HEADER_LENGTH).ok_or_else(invalid)?;
+    let (content, checksum) = header.split_at(HEADER_LENGTH - 4);
+    if &content[..4] != WAL_MAGIC
+        || crc32fast::hash(content) != u32::from_le_bytes(checksum.try_into().unwrap())
+    {
+        return Err(invalid());
+    }
+
+    let version = u16::from_le_bytes(content[4..6].try_into().unwrap());
+    if version != WAL_VERSION {
+        return Err(TrieError::InvalidConfiguration(format!(
+            "unsupported WAL version {version}"
+        )));
+    }
+
+    Ok(Header {
+        sequence: u64::from_le_bytes(content[6..14].try_into().unwrap()),
+        key_check: content[14..14 + KEY_CHECK_LENGTH].try_into().unwrap(),
+    })
+}
+
+/// Returns the key of a log whose header holds the given key check
+fn find_cipher(
+    keyring: Option<&Keyring>,
+    key_check: &[u8; KEY_CHECK_LENGTH],
+) -> Result<Option<Arc<Cipher>>, TrieError> {
+    if key_check == &[0; KEY_CHECK_LENGTH] {
+        return Ok(None);
+    }
+
+    match keyring.and_then(|k| k.find(key_check)) {
+        Some(cipher) => Ok(Some(cipher.clone())),
+        None => Err(TrieError::InvalidConfiguration(
+            "the WAL was encrypted with a key that isn't configured".to_string(),
+        )),
+    }
+}
+
+fn encode_key(key: &str, buffer: &mut Vec<u8>) {
+    buffer.extend_from_slice(&(key.len() as u16).to_le_bytes());
+    buffer.extend_from_slice(key.as_bytes());
+}
+
+/// Decodes the operation at the start of a record, then moves the reader past it
+fn decode_operation(reader: &mut &[u8]) -> Option<WalOperation> {
+    let mut take = |length: usize| -> Option<&[u8]> {
+        let (taken, rest) = reader.split_at_checked(length)?;
+        *reader = rest;
+        Some(taken)
+    };
+
+    let operation = take(1)?[0];
+    let key_length = u16::from_le_bytes(take(2)?.try_into().ok()?) as usize;
+    let key = String::from_utf8(take(key_length)?.to_vec()).ok()?;
+
+    let operation = match operation {
+        PUT_OPERATION => {
+            let version = u64::from_le_bytes(take(8)?.try_into().ok()?);
+            let expires_at = match take(1)?[0] {
+                0 => None,
+                _ => Some(u64::from_le_bytes(take(8)?.try_into().ok()?)),
+            };
+            let value = match take(1)?[0] {
+                INLINE_VALUE => {
+                    let length = u32::from_le_bytes(take(4)?.try_into().ok()?) as usize;
+                    Value::Inline(take(length)?.to_vec())
+                }
+                BLOB_VALUE => Value::Blob(BlobRef::decode(take(BLOB_REF_LENGTH)?)?),
+                _ => return None,
+            };
+
+            WalOperation::Put {
+                key,
+                value,
+                expires_at,
+                version,
+            }
+        }
+        DELETE_OPERATION => WalOperation::Delete { key },
+        _ => return None,
+    };
+
+    Some(operation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encryption::KeySource;
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    fn put(key: &str, value: &[u8]) -> WalOperation {
+        WalOperation::Put {
+            key: key.to_string(),
+            value: Value::Inline(value.to_vec()),
+            expires_at: Some(42),
+            version: 7,
+        }
+    }
+
+    #[test]
+    fn test_unapplied_writes_are_read_back() {
+        let temp_dir = tempdir().unwrap();
+        let base_path = temp_dir.path();
+        let key_path = temp_dir.path().join("key");
+        fs::write(&key_path, "11".repeat(32)).unwrap();
+        let keyring = Arc::new(Keyring::load(&KeySource::File(key_path), &[]).unwrap());
+
+        let (mut wal, operations) = Wal::open(base_path, Some(keyring.clone())).unwrap();
+        assert!(operations.is_empty());
+        let delete = WalOperation::Delete {
+            key: "b".to_string(),
+        };
+        assert_eq!(wal.append(&[put("a", b"1"), delete.clone()]).unwrap(), 2);
+        wal.sync().unwrap();
+        drop(wal);
+
+        // A torn record is dropped
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(base_path.join(WAL_FILE))
+            .unwrap();
+        file.write_all(&[100, 0, 0, 0, 1]).unwrap();
+
+        let unapplied = Wal::unapplied(base_path, Some(&keyring)).unwrap();
+        assert_eq!(unapplied, vec![put("a", b"1"), delete.clone()]);
+        assert!(matches!(
+            Wal::unapplied(base_path, None),
+            Err(TrieError::InvalidConfiguration(_))
+        ));
+
+        let (mut wal, operations) = Wal::open(base_path, Some(keyring.clone())).unwrap();
+        assert_eq!(operations, unapplied);
+        assert_eq!(wal.append(&[put("c", b"3")]).unwrap(), 3);
+        drop(wal);
+
+        let (mut wal, operations) = Wal::open(base_path, Some(keyring.clone())).unwrap();
+        assert_eq!(operations.len(), 3);
+        wal.reset().unwrap();
+        drop(wal);
+
+        // Sequence numbers go on after a checkpoint
+        let (mut wal, operations) = Wal::open(base_path, Some(keyring)).unwrap();
+        assert!(operations.is_empty());
+        assert_eq!(wal.sequence(), 3);
+        assert_eq!(wal.append(&[put("d", b"4")]).unwrap(), 4);
+    }
+
+    #[test]
+    fn test_torn_batches_are_dropped_whole() {
+        let temp_dir = tempdir().unwrap();
+        let base_path = temp_dir.path();
+
+        let (mut wal, _) = Wal::open(base_path, None).unwrap();
+        wal.append(&[put("a", b"1"), put("b", b"2")]).unwrap();
+        let length = wal.length();
+        assert_eq!(wal.append(&[put("c", b"3"), put("d", b"4")]).unwrap(), 4);
+        drop(wal);
+
+        let file = OpenOptions::new()
+            .write(true)
+            .open(base_path.join(WAL_FILE))
+            .unwrap();
+        file.set_len(file.metadata().unwrap().len() - 2).unwrap();
+
+        let (wal, operations) = Wal::open(base_path, None).unwrap();
+        assert_eq!(operations, vec![put("a", b"1"), put("b", b"2")]);
+        assert_eq!(wal.length(), length);
+        assert_eq!(wal.sequence(), 2);
+        drop(wal);
+
+        // A damaged record followed by valid ones is not a torn write
+        let (mut wal, _) = Wal::open(base_path, None).unwrap();
+        wal.append(&[put("c", b"3")]).unwrap();
+        drop(wal);
+        let mut buffer = fs::read(base_path.join(WAL_FILE)).unwrap();
+        buffer[HEADER_LENGTH + RECORD_HEADER_LENGTH + 8] ^= 0xFF;
+        fs::write(base_path.join(WAL_FILE), &buffer).unwrap();
+        assert!(matches!(
+            Wal::open(base_path, None).map(|_| ()),
+            Err(TrieError::Corrupted(_))
+        ));
+
+        // Nor is a record whose length runs past the end of the log because it is damaged
+        buffer[HEADER_LENGTH + RECORD_HEADER_LENGTH + 8] ^= 0xFF;
+        buffer[HEADER_LENGTH + 2] = 0xFF;
+        fs::write(base_path.join(WAL_FILE), &buffer).unwrap();
+        assert!(matches!(
+            Wal::open(base_path, None).map(|_| ()),
+            Err(TrieError::Corrupted(_))
+        ));
+        assert_eq!(
+            fs::read(base_path.join(WAL_FILE)).unwrap().len(),
+            buffer.len()
+        );
+    }
+}